@@ -0,0 +1,45 @@
+#!/bin/sh
+# Self-signed CA and certificates for mutual TLS between web service and video service.
+# For tests and local runs only, production certificates should come from your own CA.
+#
+# usage: scripts/gen-test-certs.sh [dir]
+#
+#   ca.pem, ca-key.pem           CA both services trust
+#   video.pem, video-key.pem     video service nodes, issued for `video-service`
+#   web.pem, web-key.pem         web service instances
+#   rogue.pem, rogue-key.pem     client of another CA, video service must refuse it
+set -e
+
+dir=${1:-certs}
+days=365
+mkdir -p "$dir"
+cd "$dir"
+
+ext=$(mktemp)
+trap 'rm -f "$ext" *.csr *.srl' EXIT
+
+openssl req -x509 -newkey rsa:2048 -nodes -days $days -subj "/CN=video test CA" \
+    -addext "basicConstraints=critical,CA:true" -addext "keyUsage=critical,keyCertSign" \
+    -keyout ca-key.pem -out ca.pem 2>/dev/null
+
+# name certificate `$1` with extensions `$2`, signed by the CA
+issue() {
+    printf '%s\n' "$2" > "$ext"
+    openssl req -newkey rsa:2048 -nodes -subj "/CN=$1" -keyout "$1-key.pem" -out "$1.csr" 2>/dev/null
+    openssl x509 -req -in "$1.csr" -CA ca.pem -CAkey ca-key.pem -CAcreateserial -days $days \
+        -extfile "$ext" -out "$1.pem" 2>/dev/null
+}
+
+# nodes accept clients and connect to their peers, so their certificate serves both sides
+issue video "basicConstraints=CA:false
+keyUsage=critical,digitalSignature,keyEncipherment
+extendedKeyUsage=serverAuth,clientAuth
+subjectAltName=DNS:video-service"
+issue web "basicConstraints=CA:false
+keyUsage=critical,digitalSignature,keyEncipherment
+extendedKeyUsage=clientAuth"
+
+openssl req -x509 -newkey rsa:2048 -nodes -days $days -subj "/CN=rogue" \
+    -keyout rogue-key.pem -out rogue.pem 2>/dev/null
+
+echo "certificates are in $dir"
@@ -0,0 +1,122 @@
+// Throughput of downloads from a node with `sendfile` and with buffered reads,
+// run with `cargo bench --bench download`, `BENCH_SIZE` (MiB), `BENCH_DOWNLOADS`
+// and `BENCH_PARALLEL` change the load
+use {
+    std::{
+        env, fs,
+        io::Write,
+        net::TcpStream,
+        path::Path,
+        process::{Child, Command, Stdio},
+        thread,
+        time::{Duration, Instant},
+    },
+    futures::{stream, StreamExt},
+    video_service::{client::Client, storage::Result},
+};
+
+const ADDR: &str = "127.0.0.1:18891";
+const HTTP_ADDR: &str = "127.0.0.1:19891";
+const FILE: &str = "bench.mp4";
+const MIB: f64 = 1048576.0;
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let size = var("BENCH_SIZE", 256) << 20;
+    let downloads = var("BENCH_DOWNLOADS", 16);
+    let parallel = var("BENCH_PARALLEL", 4) as usize;
+    let dir = env::temp_dir().join(format!("video-service-bench-{}", std::process::id()));
+    prepare(&dir, size)?;
+    println!("{} downloads of {} MiB, {} at once", downloads, size >> 20, parallel);
+    for &sendfile in &[false, true] {
+        let mut server = start(&dir, sendfile)?;
+        let result = run(downloads, parallel, size, server.id()).await;
+        server.kill().ok();
+        server.wait().ok();
+        let (elapsed, cpu) = result?;
+        let total = (size * downloads) as f64;
+        println!(
+            "{:<9} {:>9.1} MiB/s {:>7.3} s of server CPU per GiB",
+            if sendfile { "sendfile" } else { "buffered" },
+            total / MIB / elapsed.as_secs_f64(),
+            cpu.as_secs_f64() / (total / MIB / 1024.0),
+        );
+    }
+    fs::remove_dir_all(&dir).ok();
+    Ok(())
+}
+
+fn var(name: &str, default: u64) -> u64 {
+    env::var(name).ok().and_then(|value| value.parse().ok()).unwrap_or(default)
+}
+
+// processed videos are served from `dist`, so the file needs no processing
+fn prepare(dir: &Path, size: u64) -> Result<()> {
+    fs::create_dir_all(dir.join("dist"))?;
+    let mut file = fs::File::create(dir.join("dist").join(FILE))?;
+    let chunk: Vec<u8> = (0..1 << 20).map(|i: u32| (i.wrapping_mul(2654435761) >> 24) as u8).collect();
+    for _ in 0..size >> 20 {
+        file.write_all(&chunk)?;
+    }
+    Ok(())
+}
+
+fn start(dir: &Path, sendfile: bool) -> Result<Child> {
+    let mut server = Command::new(env!("CARGO_BIN_EXE_main"))
+        .args([ADDR, "--http-addr", HTTP_ADDR])
+        .current_dir(dir)
+        .env("VIDEO_SENDFILE", sendfile.to_string())
+        .env("VIDEO_MIN_FREE_SPACE", "0")
+        .env("RUST_LOG", "warn")
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()?;
+    let started = Instant::now();
+    while TcpStream::connect(ADDR).is_err() {
+        if started.elapsed() > Duration::from_secs(10) {
+            server.kill().ok();
+            return Err(format!("node did not start listening on {}", ADDR).into());
+        }
+        thread::sleep(Duration::from_millis(50));
+    }
+    Ok(server)
+}
+
+// time of all downloads and CPU time the node spent on them
+async fn run(downloads: u64, parallel: usize, size: u64, pid: u32) -> Result<(Duration, Duration)> {
+    let cpu = cpu_time(pid)?;
+    let started = Instant::now();
+    let results: Vec<Result<u64>> = stream::iter(0..downloads)
+        .map(|_| download())
+        .buffer_unordered(parallel)
+        .collect()
+        .await;
+    let elapsed = started.elapsed();
+    for result in results {
+        let received = result?;
+        if received != size {
+            return Err(format!("received {} bytes instead of {}", received, size).into());
+        }
+    }
+    Ok((elapsed, cpu_time(pid)? - cpu))
+}
+
+async fn download() -> Result<u64> {
+    let (_, mut body) = Client::new(ADDR).get(FILE, None).await?;
+    let mut received = 0;
+    while let Some(chunk) = body.next().await {
+        received += chunk?.len() as u64;
+    }
+    Ok(received)
+}
+
+// user and system time of a process, from fields 14 and 15 of `/proc/<pid>/stat`
+fn cpu_time(pid: u32) -> Result<Duration> {
+    let stat = fs::read_to_string(format!("/proc/{}/stat", pid))?;
+    // the command name in parentheses may contain spaces
+    let fields: Vec<&str> = stat[stat.rfind(')').unwrap_or(0) + 2..].split(' ').collect();
+    let ticks: u64 = fields[11].parse::<u64>()? + fields[12].parse::<u64>()?;
+    // safety: sysconf only reads a system constant
+    let per_second = unsafe { libc::sysconf(libc::_SC_CLK_TCK) } as u64;
+    Ok(Duration::from_secs_f64(ticks as f64 / per_second as f64))
+}
@@ -0,0 +1,236 @@
+#![warn(rust_2018_idioms)]
+use {
+    std::{env, path::{Path, PathBuf}, process, time::Duration},
+    tokio::{fs::File, io::{self, AsyncWriteExt}},
+    tokio_util::codec::{BytesCodec, FramedRead},
+    futures::StreamExt,
+    serde::Serialize,
+    video_service::{client::{Client, ClientTimeouts}, config::TlsConfig, storage::{ByteRange, ByteStream}, tls::Connector},
+};
+
+type Result<T> = std::result::Result<T, Box<dyn std::error::Error + Send + Sync>>;
+
+const USAGE: &str = "\
+Manage video service from the command line
+
+usage: admin [--addr <host:port|unix://path>] [--request-id <id>] [--json] [--tls-cert <file> --tls-key <file> --tls-ca <file>] [--auth-key <id> --auth-secret <secret>] [--timeout <seconds>] <command> [arguments]
+
+commands:
+    put <file> [name]             upload a video, prints the stored filename
+    get <name> [file] [range]     download a video into a file or stdout, range is `start-end` or `start-`
+    list [prefix]                 stored videos
+    stat <name>                   size, modification time and stage of a video
+    delete <name>                 delete a video and its upload that waits in staging
+    status <name>                 stage of the job that processes a video
+    queue                         jobs that are not done
+    requeue <name>                validate and compress again a video that failed or was cancelled
+    cancel <name>                 stop a job that is not compressed yet
+    stats                         counters, jobs by stage and disk usage
+    replication                   copies of videos that replicas do not have yet
+    ping                          check that the service answers
+
+address is taken from `--addr`, `VIDEO_ADDR` or is 127.0.0.1:8091 by default,
+TLS files from `--tls-*` flags or `VIDEO_TLS_CERT`, `VIDEO_TLS_KEY`, `VIDEO_TLS_CA` and `VIDEO_TLS_SERVER_NAME`,
+the key from `--auth-*` flags or `VIDEO_AUTH_KEY` and `VIDEO_AUTH_SECRET`,
+a command fails when the service does not connect in 5 seconds, answer in 30 or move content for 60,
+`--timeout` sets all of these limits";
+
+/// Parsed command line
+struct Args {
+    addr: String,
+    request_id: Option<String>,
+    // print JSON instead of text
+    json: bool,
+    // client certificate for a service that listens with TLS
+    tls: TlsConfig,
+    // ID and secret of a key for a service that requires authentication
+    auth_key: Option<String>,
+    auth_secret: Option<String>,
+    // limit of waiting for the service
+    timeout: Option<Duration>,
+    command: String,
+    args: Vec<String>,
+}
+
+impl Args {
+    fn parse<I: Iterator<Item = String>>(mut input: I) -> std::result::Result<Args, String> {
+        let mut addr = env::var("VIDEO_ADDR").unwrap_or_else(|_| "127.0.0.1:8091".to_string());
+        let (mut request_id, mut json) = (None, false);
+        let mut tls = TlsConfig::default();
+        let path = |name: &str| env::var(name).ok().map(PathBuf::from);
+        tls.cert = path("VIDEO_TLS_CERT");
+        tls.key = path("VIDEO_TLS_KEY");
+        tls.ca = path("VIDEO_TLS_CA");
+        if let Ok(name) = env::var("VIDEO_TLS_SERVER_NAME") {
+            tls.server_name = name;
+        }
+        let (mut auth_key, mut auth_secret) = (env::var("VIDEO_AUTH_KEY").ok(), env::var("VIDEO_AUTH_SECRET").ok());
+        let mut timeout = None;
+        let mut positional = Vec::new();
+        while let Some(arg) = input.next() {
+            match arg.as_str() {
+                "--addr" => addr = input.next().ok_or("--addr must be followed by an address")?,
+                "--request-id" => request_id = Some(input.next().ok_or("--request-id must be followed by an ID")?),
+                "--json" => json = true,
+                "--tls-cert" => tls.cert = Some(input.next().ok_or("--tls-cert must be followed by a file")?.into()),
+                "--tls-key" => tls.key = Some(input.next().ok_or("--tls-key must be followed by a file")?.into()),
+                "--tls-ca" => tls.ca = Some(input.next().ok_or("--tls-ca must be followed by a file")?.into()),
+                "--tls-server-name" => tls.server_name = input.next().ok_or("--tls-server-name must be followed by a name")?,
+                "--auth-key" => auth_key = Some(input.next().ok_or("--auth-key must be followed by an ID")?),
+                "--auth-secret" => auth_secret = Some(input.next().ok_or("--auth-secret must be followed by a secret")?),
+                "--timeout" => {
+                    let secs = input.next().ok_or("--timeout must be followed by seconds")?;
+                    match secs.parse::<f64>() {
+                        Ok(secs) if secs.is_finite() && secs > 0.0 => timeout = Some(Duration::from_secs_f64(secs)),
+                        _ => return Err(format!("--timeout must be a positive number of seconds, not {}", secs)),
+                    }
+                },
+                "-h" | "--help" => return Err(USAGE.to_string()),
+                flag if flag.starts_with("--") => return Err(format!("unknown flag {}\n\n{}", flag, USAGE)),
+                _ => positional.push(arg),
+            }
+        }
+        if positional.is_empty() {
+            return Err(USAGE.to_string());
+        }
+        let command = positional.remove(0);
+        Ok(Args { addr, request_id, json, tls, auth_key, auth_secret, timeout, command, args: positional })
+    }
+
+    // required argument of the command
+    fn arg(&self, n: usize, name: &str) -> Result<&str> {
+        self.args.get(n)
+            .map(String::as_str)
+            .ok_or_else(|| format!("{} needs <{}>", self.command, name).into())
+    }
+}
+
+#[tokio::main]
+async fn main() {
+    let args = match Args::parse(env::args().skip(1)) {
+        Ok(args) => args,
+        Err(msg) => {
+            eprintln!("{}", msg);
+            process::exit(2);
+        },
+    };
+    if let Err(e) = run(&args).await {
+        eprintln!("error: {}", e);
+        process::exit(1);
+    }
+}
+
+// run a single command
+async fn run(args: &Args) -> Result<()> {
+    let mut client = Client::new(&args.addr);
+    if let Some(id) = &args.request_id {
+        client = client.with_request_id(id);
+    }
+    if args.tls.enabled() {
+        client = client.with_tls(Connector::new(&args.tls)?);
+    }
+    match (&args.auth_key, &args.auth_secret) {
+        (Some(key), Some(secret)) => client = client.with_auth(key, secret),
+        (None, None) => {},
+        _ => return Err("--auth-key and --auth-secret must be set together".into()),
+    }
+    if let Some(wait) = args.timeout {
+        client = client.with_timeouts(ClientTimeouts { connect: wait, read: wait, idle: wait });
+    }
+    match args.command.as_str() {
+        "put" => {
+            let path = args.arg(0, "file")?;
+            let name = match args.args.get(1) {
+                Some(name) => name.clone(),
+                None => file_name(path)?,
+            };
+            let file = File::open(path).await?;
+            let size = file.metadata().await?.len();
+            let body: ByteStream = Box::pin(FramedRead::new(file, BytesCodec::new())
+                .map(|bytes| bytes.map(|bytes| bytes.freeze())));
+            println!("{}", client.put(&name, Some(size), body).await?);
+        },
+        "get" => {
+            let name = args.arg(0, "name")?;
+            let range = match args.args.get(2) {
+                Some(range) => Some(ByteRange::parse(range)?),
+                None => None,
+            };
+            let (_, mut body) = client.get(name, range).await?;
+            // `-` or no file means stdout
+            let mut out: Box<dyn io::AsyncWrite + Unpin> = match args.args.get(1).map(String::as_str) {
+                Some("-") | None => Box::new(io::stdout()),
+                Some(path) => Box::new(File::create(path).await?),
+            };
+            while let Some(bytes) = body.next().await {
+                out.write_all(&bytes?).await?;
+            }
+            out.flush().await?;
+        },
+        "list" => {
+            let prefix = args.args.first().map_or("", String::as_str);
+            let names = client.list(prefix).await?;
+            print(args, &names, || names.join("\n"))?;
+        },
+        "stat" => {
+            let info = client.stat(args.arg(0, "name")?).await?;
+            print(args, &info, || {
+                let or_dash = |value: Option<String>| value.unwrap_or_else(|| "-".to_string());
+                format!(
+                    "filename: {}\nsize: {}\nmodified: {}\nstatus: {}",
+                    info.filename,
+                    or_dash(info.size.map(|size| size.to_string())),
+                    or_dash(info.modified.map(|secs| secs.to_string())),
+                    or_dash(info.status.clone()),
+                )
+            })?;
+        },
+        "delete" => client.delete(args.arg(0, "name")?).await?,
+        "status" => println!("{}", client.status(args.arg(0, "name")?).await?),
+        "queue" => {
+            let jobs = client.queue().await?;
+            print(args, &jobs, || jobs.iter()
+                .map(|job| format!("{}\t{}", job.filename, job.status))
+                .collect::<Vec<String>>()
+                .join("\n"))?;
+        },
+        "requeue" => client.requeue(args.arg(0, "name")?).await?,
+        "cancel" => client.cancel(args.arg(0, "name")?).await?,
+        "stats" => {
+            let stats = client.stats().await?;
+            // text form is the same JSON, one counter per line
+            print(args, &stats, || serde_json::to_string_pretty(&stats).unwrap_or_default())?;
+        },
+        "replication" => {
+            let replicas = client.replication().await?;
+            print(args, &replicas, || replicas.iter()
+                .map(|replica| format!("{}\t{}\t{}", replica.filename, replica.peer, replica.state))
+                .collect::<Vec<String>>()
+                .join("\n"))?;
+        },
+        "ping" => {
+            client.ping().await?;
+            println!("PONG");
+        },
+        other => return Err(format!("unknown command {}\n\n{}", other, USAGE).into()),
+    }
+    Ok(())
+}
+
+// print value as JSON with `--json` or as text
+fn print<T: Serialize, F: FnOnce() -> String>(args: &Args, value: &T, text: F) -> Result<()> {
+    let out = if args.json { serde_json::to_string(value)? } else { text() };
+    if !out.is_empty() {
+        println!("{}", out);
+    }
+    Ok(())
+}
+
+// name of the uploaded video is the name of the local file by default
+fn file_name(path: &str) -> Result<String> {
+    Path::new(path)
+        .file_name()
+        .and_then(|name| name.to_str())
+        .map(str::to_string)
+        .ok_or_else(|| format!("can not take a filename from {}", path).into())
+}
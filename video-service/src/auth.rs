@@ -0,0 +1,221 @@
+use {
+    std::{collections::BTreeMap, fmt, sync::Arc},
+    hmac::{Hmac, Mac},
+    sha2::Sha256,
+    rand::RngCore,
+    serde::{Deserialize, Serialize},
+    crate::config::KeyConfig,
+};
+
+/// What a key is allowed to do
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Access {
+    /// download videos, read their status and watch events
+    ReadOnly,
+    /// also upload, store, delete, requeue and cancel videos
+    ReadWrite,
+}
+
+impl Access {
+    pub fn parse(input: &str) -> Result<Access, String> {
+        match input {
+            "read-only" => Ok(Access::ReadOnly),
+            "read-write" => Ok(Access::ReadWrite),
+            other => Err(format!("unknown access {}, expected read-only or read-write", other)),
+        }
+    }
+}
+
+impl fmt::Display for Access {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Access::ReadOnly => write!(f, "read-only"),
+            Access::ReadWrite => write!(f, "read-write"),
+        }
+    }
+}
+
+/// What a request needs from the key of the client
+#[derive(Debug)]
+pub struct Need<'a> {
+    pub access: Access,
+    /// name or prefix of videos the request touches, `None` when it sees the whole service
+    pub name: Option<&'a str>,
+    /// request is refused to clients without a key even when the service has no keys
+    pub key_only: bool,
+}
+
+/// Key a client proved to know the secret of
+#[derive(Debug, Clone)]
+pub struct Grant {
+    pub key: String,
+    access: Access,
+    // videos the key may touch, every video if it is empty
+    prefixes: Vec<String>,
+}
+
+impl Grant {
+    // reason of denial if the key can not do what the request needs
+    fn check(&self, need: &Need<'_>) -> Result<(), String> {
+        if need.access > self.access {
+            return Err(format!("key is {}", self.access));
+        }
+        if self.prefixes.is_empty() {
+            return Ok(());
+        }
+        match need.name {
+            Some(name) if self.prefixes.iter().any(|prefix| name.starts_with(prefix.as_str())) => Ok(()),
+            Some(name) => Err(format!("{} is outside of allowed prefixes", name)),
+            None => Err("key is limited to prefixes".to_string()),
+        }
+    }
+}
+
+/// Keys clients authenticate with by HMAC challenge-response,
+/// the protocol is open to everyone when there are none, except for requests that are key-only
+#[derive(Clone, Default)]
+pub struct Auth {
+    keys: Arc<BTreeMap<String, KeyConfig>>,
+}
+
+impl Auth {
+    pub fn new(keys: BTreeMap<String, KeyConfig>) -> Auth {
+        Auth { keys: Arc::new(keys) }
+    }
+
+    pub fn enabled(&self) -> bool {
+        !self.keys.is_empty()
+    }
+
+    /// random challenge the client signs with the secret of its key
+    pub fn challenge(&self) -> String {
+        let mut bytes = [0u8; 16];
+        rand::thread_rng().fill_bytes(&mut bytes);
+        hex::encode(bytes)
+    }
+
+    /// check the signature of the challenge, the reason of denial is for the audit log only
+    pub fn verify(&self, key: &str, challenge: &str, signature: &str) -> Result<Grant, String> {
+        let config = self.keys.get(key).ok_or_else(|| format!("unknown key {}", key))?;
+        let signature = hex::decode(signature).map_err(|_| "signature is not hex".to_string())?;
+        let mut mac = Hmac::<Sha256>::new_from_slice(config.secret.as_bytes()).expect("HMAC accepts keys of any size");
+        mac.update(message(key, challenge).as_bytes());
+        // comparison takes the same time wherever the signature differs
+        mac.verify_slice(&signature).map_err(|_| "wrong signature".to_string())?;
+        Ok(Grant { key: key.to_string(), access: config.access, prefixes: config.prefixes.clone() })
+    }
+
+    /// reason of denial if the client may not send the request,
+    /// everything is allowed when authentication is off
+    pub fn authorize(&self, grant: Option<&Grant>, need: &Need<'_>) -> Result<(), String> {
+        match grant {
+            _ if !self.enabled() && need.key_only => Err("service has no keys".to_string()),
+            _ if !self.enabled() => Ok(()),
+            Some(grant) => grant.check(need),
+            None => Err("not authenticated".to_string()),
+        }
+    }
+}
+
+/// hex encoded signature of a challenge the client answers `AUTH` with
+pub fn sign(key: &str, secret: &str, challenge: &str) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts keys of any size");
+    mac.update(message(key, challenge).as_bytes());
+    hex::encode(mac.finalize().into_bytes())
+}
+
+// the key is signed too, so a signature can not be replayed with another key that shares the secret
+fn message(key: &str, challenge: &str) -> String {
+    format!("{} {}", key, challenge)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn auth() -> Auth {
+        let mut keys = BTreeMap::new();
+        keys.insert("uploader".to_string(), KeyConfig {
+            secret: "s3cret".to_string(),
+            access: Access::ReadWrite,
+            prefixes: vec![],
+        });
+        keys.insert("viewer".to_string(), KeyConfig {
+            secret: "s3cret".to_string(),
+            access: Access::ReadOnly,
+            prefixes: vec!["public-".to_string()],
+        });
+        Auth::new(keys)
+    }
+
+    fn need(access: Access, name: Option<&str>) -> Need<'_> {
+        Need { access, name, key_only: false }
+    }
+
+    #[test]
+    fn accepts_signed_challenge() {
+        let auth = auth();
+        let challenge = auth.challenge();
+        let grant = auth.verify("uploader", &challenge, &sign("uploader", "s3cret", &challenge)).unwrap();
+        assert_eq!(grant.key, "uploader");
+        assert_eq!(auth.authorize(Some(&grant), &need(Access::ReadWrite, Some("a.mp4"))), Ok(()));
+    }
+
+    #[test]
+    fn refuses_wrong_secret() {
+        let auth = auth();
+        let challenge = auth.challenge();
+        let signature = sign("uploader", "guess", &challenge);
+        assert_eq!(auth.verify("uploader", &challenge, &signature).unwrap_err(), "wrong signature");
+        assert!(auth.verify("uploader", &challenge, "not hex").is_err());
+        assert!(auth.verify("uploader", &challenge, "").is_err());
+        assert!(auth.verify("nobody", &challenge, &sign("nobody", "s3cret", &challenge)).is_err());
+    }
+
+    #[test]
+    fn refuses_replayed_signatures() {
+        let auth = auth();
+        let challenge = auth.challenge();
+        let signature = sign("viewer", "s3cret", &challenge);
+        // every challenge is new, so a recorded signature does not answer the next one
+        let next = auth.challenge();
+        assert_ne!(challenge, next);
+        assert!(auth.verify("viewer", &next, &signature).is_err());
+        // nor does it work for another key with the same secret
+        assert!(auth.verify("uploader", &challenge, &signature).is_err());
+    }
+
+    #[test]
+    fn denies_what_the_role_does_not_allow() {
+        let auth = auth();
+        let challenge = auth.challenge();
+        let grant = auth.verify("viewer", &challenge, &sign("viewer", "s3cret", &challenge)).unwrap();
+        assert_eq!(auth.authorize(Some(&grant), &need(Access::ReadOnly, Some("public-a.mp4"))), Ok(()));
+        assert_eq!(
+            auth.authorize(Some(&grant), &need(Access::ReadWrite, Some("public-a.mp4"))),
+            Err("key is read-only".to_string())
+        );
+        assert!(auth.authorize(Some(&grant), &need(Access::ReadOnly, Some("private-a.mp4"))).is_err());
+        assert!(auth.authorize(Some(&grant), &need(Access::ReadOnly, None)).is_err());
+        assert_eq!(auth.authorize(None, &need(Access::ReadOnly, Some("public-a.mp4"))), Err("not authenticated".to_string()));
+    }
+
+    #[test]
+    fn allows_everything_without_keys() {
+        let auth = Auth::default();
+        assert!(!auth.enabled());
+        assert_eq!(auth.authorize(None, &need(Access::ReadWrite, None)), Ok(()));
+    }
+
+    #[test]
+    fn key_only_requests_need_keys() {
+        let key_only = Need { access: Access::ReadWrite, name: Some("a.mp4"), key_only: true };
+        assert_eq!(Auth::default().authorize(None, &key_only), Err("service has no keys".to_string()));
+        let auth = auth();
+        assert!(auth.authorize(None, &key_only).is_err());
+        let challenge = auth.challenge();
+        let grant = auth.verify("uploader", &challenge, &sign("uploader", "s3cret", &challenge)).unwrap();
+        assert_eq!(auth.authorize(Some(&grant), &key_only), Ok(()));
+    }
+}
@@ -0,0 +1,313 @@
+use {
+    std::{collections::HashMap, sync::{Arc, Mutex}, time::{Duration, SystemTime, UNIX_EPOCH}},
+    tokio::{net::TcpStream, time},
+    tokio_util::codec::{BytesCodec, Decoder},
+    futures::{SinkExt, StreamExt},
+    bytes::Bytes,
+    hyper::{client::HttpConnector, Body, Client, Method, Request},
+    hmac::{Hmac, Mac},
+    sha2::Sha256,
+    serde::Serialize,
+    tracing::{error, info, warn, Instrument, Span},
+    crate::{
+        jobs::JobStatus,
+        key::VideoKey,
+        probe::MediaInfo,
+        storage::Result,
+    },
+};
+
+// how many times delivery is attempted before giving up
+const MAX_ATTEMPTS: u32 = 6;
+// delay before the first retry, it doubles after every attempt
+const FIRST_RETRY: Duration = Duration::from_secs(2);
+// how long a single delivery may take
+const DELIVERY_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Where to report outcome of a job
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Target {
+    /// `POST` JSON to `http://` URL, signature is in `X-Signature` header
+    Http(String),
+    /// send `CALLBACK <signature> <json>` to `tcp://host:port` and wait for `OK`
+    Tcp(String),
+}
+
+impl Target {
+    pub fn parse(input: &str) -> std::result::Result<Target, String> {
+        if input.starts_with("http://") {
+            Ok(Target::Http(input.to_string()))
+        } else if let Some(addr) = input.strip_prefix("tcp://") {
+            Ok(Target::Tcp(addr.to_string()))
+        } else {
+            Err(format!("callback must be an http:// or tcp:// address, got {}", input))
+        }
+    }
+
+    /// `host:port` or `host` the callback is sent to
+    pub fn authority(&self) -> &str {
+        match self {
+            Target::Http(url) => {
+                let rest = url.trim_start_matches("http://");
+                rest.split(['/', '?', '#']).next().unwrap_or_default()
+            },
+            Target::Tcp(addr) => addr,
+        }
+    }
+}
+
+/// Body of a callback
+#[derive(Debug, Clone, Serialize)]
+pub struct Outcome {
+    pub filename: String,
+    /// `done`, `failed` or `rejected`
+    pub status: &'static str,
+    pub reason: Option<String>,
+    /// properties of the compressed video, only for `done`
+    pub info: Option<MediaInfo>,
+    /// unix time of the delivery, receivers should refuse old callbacks
+    pub timestamp: u64,
+}
+
+/// Registered callbacks of unfinished jobs
+/// callbacks are kept in memory, so they are lost on restart
+#[derive(Clone)]
+pub struct Callbacks {
+    targets: Arc<Mutex<HashMap<VideoKey, Target>>>,
+    // shared secret for HMAC-SHA256 signatures
+    secret: Arc<Vec<u8>>,
+    // `host` or `host:port` targets must match, so clients can not make the service call anything
+    hosts: Arc<Vec<String>>,
+    client: Client<HttpConnector>,
+}
+
+impl Callbacks {
+    pub fn new(secret: &[u8], hosts: &[String]) -> Callbacks {
+        Callbacks {
+            targets: Arc::new(Mutex::new(HashMap::new())),
+            secret: Arc::new(secret.to_vec()),
+            hosts: Arc::new(hosts.to_vec()),
+            client: Client::new(),
+        }
+    }
+
+    /// check that the target is one of the configured hosts
+    pub fn check(&self, target: &Target) -> std::result::Result<(), String> {
+        if self.hosts.is_empty() {
+            return Err("callbacks are not enabled".to_string());
+        }
+        let authority = target.authority();
+        // an entry without a port allows every port of the host
+        let host = match authority.rsplit_once(':') {
+            Some((host, port)) if port.parse::<u16>().is_ok() => host,
+            _ => authority,
+        };
+        if self.hosts.iter().any(|allowed| allowed == authority || allowed == host) {
+            Ok(())
+        } else {
+            Err(format!("callbacks to {} are not allowed", authority))
+        }
+    }
+
+    /// remember where to report outcome of the job
+    pub fn register(&self, key: &VideoKey, target: Target) {
+        self.targets.lock().unwrap().insert(key.clone(), target);
+    }
+
+    /// deliver outcome of a finished job in background
+    /// does nothing if the job has no callback or is not finished yet
+    pub fn notify(&self, key: &VideoKey, status: &JobStatus, info: Option<MediaInfo>) {
+        let (status, reason) = match status {
+            JobStatus::Done => ("done", None),
+            JobStatus::Failed { reason } => ("failed", Some(reason.clone())),
+            JobStatus::Rejected { reason } => ("rejected", Some(reason.clone())),
+            _ => return,
+        };
+        let target = match self.targets.lock().unwrap().remove(key) {
+            Some(target) => target,
+            None => return,
+        };
+        let outcome = Outcome {
+            filename: key.to_string(),
+            status,
+            reason,
+            info,
+            timestamp: 0,
+        };
+        let callbacks = self.clone();
+        tokio::spawn(async move {
+            callbacks.deliver(target, outcome).await;
+        }.instrument(Span::current()));
+    }
+
+    // try to deliver callback with exponential backoff
+    async fn deliver(&self, target: Target, mut outcome: Outcome) {
+        let mut delay = FIRST_RETRY;
+        for attempt in 1..=MAX_ATTEMPTS {
+            outcome.timestamp = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+            let result = match serde_json::to_vec(&outcome) {
+                Ok(body) => time::timeout(DELIVERY_TIMEOUT, self.send(&target, body)).await
+                    .unwrap_or_else(|_| Err("callback timed out".into())),
+                Err(e) => Err(e.into()),
+            };
+            match result {
+                Ok(()) => {
+                    info!(?target, "callback delivered");
+                    return;
+                },
+                Err(e) => warn!(?target, attempt, max_attempts = MAX_ATTEMPTS, error = %e, "error delivering callback"),
+            }
+            if attempt < MAX_ATTEMPTS {
+                time::delay_for(delay).await;
+                delay *= 2;
+            }
+        }
+        error!(?target, "giving up on callback");
+    }
+
+    // single delivery attempt
+    async fn send(&self, target: &Target, body: Vec<u8>) -> Result<()> {
+        let signature = sign(&self.secret, &body);
+        match target {
+            Target::Http(url) => {
+                let req = Request::builder()
+                    .method(Method::POST)
+                    .uri(url.as_str())
+                    .header("content-type", "application/json")
+                    .header("x-signature", format!("sha256={}", signature))
+                    .body(Body::from(body))?;
+                let res = self.client.request(req).await?;
+                if !res.status().is_success() {
+                    return Err(format!("receiver answered with {}", res.status()).into());
+                }
+                Ok(())
+            },
+            Target::Tcp(addr) => {
+                let stream = TcpStream::connect(addr.as_str()).await?;
+                let mut framed = BytesCodec::new().framed(stream);
+                let cmd = [b"CALLBACK ", signature.as_bytes(), b" ", &body].concat();
+                framed.send(Bytes::from(cmd)).await?;
+                match framed.next().await {
+                    Some(Ok(response)) if response.starts_with(b"OK") => Ok(()),
+                    Some(Ok(response)) => Err(String::from_utf8_lossy(&response).into_owned().into()),
+                    Some(Err(e)) => Err(e.into()),
+                    None => Err("receiver closed connection without response".into()),
+                }
+            },
+        }
+    }
+}
+
+/// hex encoded HMAC-SHA256 of the body
+pub fn sign(secret: &[u8], body: &[u8]) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret).expect("HMAC accepts keys of any size");
+    mac.update(body);
+    hex::encode(mac.finalize().into_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        tokio::{net::TcpListener, io::{AsyncReadExt, AsyncWriteExt}},
+    };
+
+    fn callbacks(hosts: &[&str]) -> Callbacks {
+        Callbacks::new(b"secret", &hosts.iter().map(|host| host.to_string()).collect::<Vec<String>>())
+    }
+
+    // receiver that answers every callback with the next of `answers` and returns what it got
+    async fn receiver(answers: Vec<&'static [u8]>) -> (String, tokio::task::JoinHandle<Vec<Vec<u8>>>) {
+        let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let handle = tokio::spawn(async move {
+            let mut received = Vec::new();
+            for answer in answers {
+                let (mut socket, _) = listener.accept().await.unwrap();
+                let mut buf = vec![0u8; 4096];
+                let n = socket.read(&mut buf).await.unwrap();
+                received.push(buf[..n].to_vec());
+                socket.write_all(answer).await.unwrap();
+            }
+            received
+        });
+        (addr, handle)
+    }
+
+    fn outcome() -> Outcome {
+        Outcome { filename: "a.mp4".to_string(), status: "done", reason: None, info: None, timestamp: 0 }
+    }
+
+    #[test]
+    fn parses_targets() {
+        assert_eq!(Target::parse("http://host:8080/video/callback"), Ok(Target::Http("http://host:8080/video/callback".to_string())));
+        assert_eq!(Target::parse("tcp://host:9000"), Ok(Target::Tcp("host:9000".to_string())));
+        assert!(Target::parse("https://host/").is_err());
+        assert!(Target::parse("host:9000").is_err());
+        assert_eq!(Target::parse("http://host:8080/a?b#c").unwrap().authority(), "host:8080");
+        assert_eq!(Target::parse("http://host?x=1").unwrap().authority(), "host");
+    }
+
+    #[test]
+    fn allows_only_configured_hosts() {
+        let callbacks = callbacks(&["hooks.local", "10.0.0.1:9000"]);
+        assert!(callbacks.check(&Target::parse("http://hooks.local/cb").unwrap()).is_ok());
+        assert!(callbacks.check(&Target::parse("http://hooks.local:8080/cb").unwrap()).is_ok());
+        assert!(callbacks.check(&Target::parse("tcp://10.0.0.1:9000").unwrap()).is_ok());
+        assert!(callbacks.check(&Target::parse("tcp://10.0.0.1:9001").unwrap()).is_err());
+        assert!(callbacks.check(&Target::parse("http://evil.local/cb").unwrap()).is_err());
+        // credentials in front of the host do not make it an allowed one
+        assert!(callbacks.check(&Target::parse("http://hooks.local@evil.local/").unwrap()).is_err());
+        assert!(callbacks.check(&Target::parse("http://hooks.local.evil.local/").unwrap()).is_err());
+        let e = self::callbacks(&[]).check(&Target::parse("http://hooks.local/").unwrap()).unwrap_err();
+        assert_eq!(e, "callbacks are not enabled");
+    }
+
+    #[test]
+    fn signs_with_hmac_sha256() {
+        assert_eq!(
+            sign(b"key", b"The quick brown fox jumps over the lazy dog"),
+            "f7bc83f430538424b13298e6aa6fb143ef4d59a14946175997479dbc2d1a3cd8"
+        );
+    }
+
+    #[tokio::test]
+    async fn delivers_signed_callback_over_tcp() {
+        let (addr, receiver) = receiver(vec![b"OK"]).await;
+        let callbacks = callbacks(&["127.0.0.1"]);
+        callbacks.deliver(Target::Tcp(addr), outcome()).await;
+
+        let received = receiver.await.unwrap();
+        let line = String::from_utf8(received[0].clone()).unwrap();
+        let mut parts = line.splitn(3, ' ');
+        assert_eq!(parts.next(), Some("CALLBACK"));
+        let signature = parts.next().unwrap();
+        let body = parts.next().unwrap();
+        assert_eq!(signature, sign(b"secret", body.as_bytes()));
+        let json: serde_json::Value = serde_json::from_str(body).unwrap();
+        assert_eq!(json["filename"], "a.mp4");
+        assert_eq!(json["status"], "done");
+        // the time of delivery is signed, so receivers can refuse replays
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+        assert!(now - json["timestamp"].as_u64().unwrap() < 5);
+    }
+
+    #[tokio::test]
+    async fn retries_until_the_receiver_accepts() {
+        let (addr, receiver) = receiver(vec![b"ERROR busy", b"OK"]).await;
+        callbacks(&["127.0.0.1"]).deliver(Target::Tcp(addr), outcome()).await;
+        let received = receiver.await.unwrap();
+        assert_eq!(received.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn notifies_only_finished_jobs_once() {
+        let callbacks = callbacks(&["127.0.0.1"]);
+        let key = VideoKey::parse("a.mp4").unwrap();
+        callbacks.register(&key, Target::Tcp("127.0.0.1:1".to_string()));
+        callbacks.notify(&key, &JobStatus::Processing, None);
+        assert!(callbacks.targets.lock().unwrap().contains_key(&key));
+        callbacks.notify(&key, &JobStatus::Failed { reason: "ffmpeg".to_string() }, None);
+        assert!(callbacks.targets.lock().unwrap().is_empty());
+    }
+}
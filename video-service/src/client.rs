@@ -0,0 +1,414 @@
+use {
+    std::{fmt, io, time::Duration},
+    tokio::time::timeout,
+    tokio_util::codec::{BytesCodec, Decoder, Framed},
+    futures::{stream, SinkExt, StreamExt},
+    bytes::{Bytes, BytesMut},
+    serde::de::DeserializeOwned,
+    crate::{
+        auth,
+        jobs::JobInfo,
+        metrics::Stats,
+        replication::ReplicaInfo,
+        storage::{ByteRange, ByteStream, ObjectInfo, Result},
+        net::Stream,
+        tls::Connector,
+    },
+};
+
+type Connection = Framed<Stream, BytesCodec>;
+
+// response line to `GET` is `OK <MIME type>`, a longer one means the service sends something else
+const MAX_GET_LINE: usize = 1024;
+
+/// error message of the service about a video it does not have
+pub const UNKNOWN_VIDEO: &str = "unknown video";
+
+/// Service does not have the video the request is about
+#[derive(Debug)]
+pub struct UnknownVideo;
+
+impl fmt::Display for UnknownVideo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(UNKNOWN_VIDEO)
+    }
+}
+
+impl std::error::Error for UnknownVideo {}
+
+/// Limits of waiting for the service, so a node that accepts connections and hangs
+/// fails requests instead of blocking them forever
+#[derive(Debug, Clone, Copy)]
+pub struct ClientTimeouts {
+    /// connection, TLS handshake and authentication
+    pub connect: Duration,
+    /// response to a command
+    pub read: Duration,
+    /// longest pause between chunks of content in either direction
+    pub idle: Duration,
+}
+
+impl Default for ClientTimeouts {
+    fn default() -> ClientTimeouts {
+        ClientTimeouts { connect: Duration::from_secs(5), read: Duration::from_secs(30), idle: Duration::from_secs(60) }
+    }
+}
+
+/// Client of video service, every request opens a new connection
+#[derive(Debug, Clone)]
+pub struct Client {
+    addr: String,
+    request_id: Option<String>,
+    tls: Option<Connector>,
+    // ID and secret of the key the service requires
+    auth: Option<(String, String)>,
+    timeouts: ClientTimeouts,
+}
+
+impl Client {
+    pub fn new(addr: &str) -> Client {
+        Client { addr: addr.to_string(), request_id: None, tls: None, auth: None, timeouts: ClientTimeouts::default() }
+    }
+
+    /// the same client for another node
+    pub fn at(&self, addr: &str) -> Client {
+        Client { addr: addr.to_string(), ..self.clone() }
+    }
+
+    /// connect over TLS, required when the service has `tls` configured
+    pub fn with_tls(mut self, connector: Connector) -> Client {
+        self.tls = Some(connector);
+        self
+    }
+
+    /// send `id` in front of commands, video service logs it with every line of the request
+    pub fn with_request_id(mut self, id: &str) -> Client {
+        self.request_id = Some(id.to_string());
+        self
+    }
+
+    /// prove the secret of a key before every request, required when the service has `auth.keys`
+    pub fn with_auth(mut self, key: &str, secret: &str) -> Client {
+        self.auth = Some((key.to_string(), secret.to_string()));
+        self
+    }
+
+    /// wait for the service at most this long
+    pub fn with_timeouts(mut self, timeouts: ClientTimeouts) -> Client {
+        self.timeouts = timeouts;
+        self
+    }
+
+    /// check that video service answers
+    pub async fn ping(&self) -> Result<()> {
+        match self.call("PING").await?.as_deref() {
+            Some("PONG") => Ok(()),
+            _ => Err("unexpected response to PING".into()),
+        }
+    }
+
+    /// upload a video, `size` lets the service refuse too large uploads early
+    /// returns the stored filename, its extension matches content of the video
+    pub async fn put(&self, filename: &str, size: Option<u64>, body: ByteStream) -> Result<String> {
+        let cmd = match size {
+            Some(size) => format!("UPLOAD {} {}", filename, size),
+            None => format!("UPLOAD {}", filename),
+        };
+        let (conn, _) = self.request(&cmd).await?;
+        let stored = send_body(conn, body, self.timeouts).await?;
+        stored.ok_or_else(|| "service did not confirm the upload".into())
+    }
+
+    /// store a copy of a video that is already compressed on a replica,
+    /// an older copy is overwritten and the replica does not pass it further
+    pub async fn replicate(&self, filename: &str, body: ByteStream) -> Result<()> {
+        let (conn, _) = self.request(&format!("PUT {} replica", filename)).await?;
+        send_body(conn, body, self.timeouts).await.map(|_| ())
+    }
+
+    /// download a video or a part of it, returns its MIME type and content
+    pub async fn get(&self, filename: &str, range: Option<ByteRange>) -> Result<(String, ByteStream)> {
+        let cmd = match range {
+            Some(ByteRange { start, end: Some(end) }) => format!("GET {} {}-{}", filename, start, end),
+            Some(ByteRange { start, end: None }) => format!("GET {} {}-", filename, start),
+            None => format!("GET {}", filename),
+        };
+        let mut conn = self.connect_and_send(&cmd).await?;
+        // content could arrive in the same chunk as the response line
+        let mut first = next_frame(&mut conn, self.timeouts.read).await?;
+        while !first.contains(&b'\n') && !first.starts_with(b"ERROR") {
+            if first.len() > MAX_GET_LINE {
+                return Err("response to GET has no end of line".into());
+            }
+            first.extend_from_slice(&next_frame(&mut conn, self.timeouts.read).await?);
+        }
+        let (mime_type, head) = split_get_response(first)?;
+        let head = stream::iter(if head.is_empty() { None } else { Some(Ok(head)) });
+        // a node that stops sending in the middle fails the download
+        let idle = self.timeouts.idle;
+        let body = stream::unfold(Some(conn), move |conn| async move {
+            let mut conn = conn?;
+            match timeout(idle, conn.next()).await {
+                Ok(Some(bytes)) => Some((bytes.map(BytesMut::freeze), Some(conn))),
+                Ok(None) => None,
+                Err(_) => Some((Err(timed_out("content", idle)), None)),
+            }
+        });
+        Ok((mime_type, Box::pin(head.chain(body))))
+    }
+
+    /// stage of the job that processes the video, e.g. `queued` or `failed: <reason>`
+    pub async fn status(&self, filename: &str) -> Result<String> {
+        Ok(self.call(&format!("STATUS {}", filename)).await?.unwrap_or_default())
+    }
+
+    /// names of stored videos that start with `prefix`
+    pub async fn list(&self, prefix: &str) -> Result<Vec<String>> {
+        self.call_json(&format!("LIST {}", prefix)).await
+    }
+
+    /// size, modification time and stage of a video
+    pub async fn stat(&self, filename: &str) -> Result<ObjectInfo> {
+        self.call_json(&format!("STAT {}", filename)).await
+    }
+
+    /// delete compressed video and its upload that waits in staging
+    pub async fn delete(&self, filename: &str) -> Result<()> {
+        self.call(&format!("DELETE {}", filename)).await.map(|_| ())
+    }
+
+    /// delete the copy on this node only, its peers keep theirs
+    pub async fn delete_replica(&self, filename: &str) -> Result<()> {
+        self.call(&format!("DELETE {} replica", filename)).await.map(|_| ())
+    }
+
+    /// jobs that are not done
+    pub async fn queue(&self) -> Result<Vec<JobInfo>> {
+        self.call_json("QUEUE").await
+    }
+
+    /// validate and compress again a video that failed or was cancelled
+    pub async fn requeue(&self, filename: &str) -> Result<()> {
+        self.call(&format!("REQUEUE {}", filename)).await.map(|_| ())
+    }
+
+    /// stop a job that is not compressed yet
+    pub async fn cancel(&self, filename: &str) -> Result<()> {
+        self.call(&format!("CANCEL {}", filename)).await.map(|_| ())
+    }
+
+    /// counters of the service, jobs by stage and disk usage
+    pub async fn stats(&self) -> Result<Stats> {
+        self.call_json("STATS").await
+    }
+
+    /// copies of videos that replicas do not have yet
+    pub async fn replication(&self) -> Result<Vec<ReplicaInfo>> {
+        self.call_json("REPLICATION").await
+    }
+
+    // send command that is answered with a single response
+    async fn call(&self, cmd: &str) -> Result<Option<String>> {
+        self.request(cmd).await.map(|(_, value)| value)
+    }
+
+    // send command that is answered with JSON
+    async fn call_json<T: DeserializeOwned>(&self, cmd: &str) -> Result<T> {
+        let value = self.call(cmd).await?.unwrap_or_default();
+        serde_json::from_str(&value).map_err(|e| format!("invalid response to {}: {}", cmd, e).into())
+    }
+
+    // send command and wait for `OK` or `ERROR`
+    async fn request(&self, cmd: &str) -> Result<(Connection, Option<String>)> {
+        let mut conn = self.connect_and_send(cmd).await?;
+        let value = read_response(&mut conn, self.timeouts.read).await?;
+        Ok((conn, value))
+    }
+
+    // open connection and send command with request ID in front of it: `ID <request id> <command>`
+    async fn connect_and_send(&self, cmd: &str) -> Result<Connection> {
+        let wait = self.timeouts.connect;
+        let connect = async {
+            let stream = Stream::connect(&self.addr, self.tls.as_ref()).await?;
+            let mut conn = BytesCodec::new().framed(stream);
+            if let Some((key, secret)) = &self.auth {
+                authenticate(&mut conn, key, secret, self.timeouts.read).await?;
+            }
+            Ok::<Connection, Box<dyn std::error::Error + Send + Sync>>(conn)
+        };
+        let mut conn = timeout(wait, connect).await
+            .map_err(|_| format!("can not connect to {} in {} seconds", self.addr, wait.as_secs()))??;
+        let cmd = match self.request_id.as_ref() {
+            Some(id) => format!("ID {} {}", id, cmd),
+            None => cmd.to_string(),
+        };
+        timeout(self.timeouts.idle, conn.send(Bytes::from(cmd))).await
+            .map_err(|_| timed_out("sending a command", self.timeouts.idle))??;
+        Ok(conn)
+    }
+}
+
+fn timed_out(what: &str, wait: Duration) -> io::Error {
+    io::Error::new(io::ErrorKind::TimedOut, format!("{} timed out after {} seconds", what, wait.as_secs_f64()))
+}
+
+// answer the challenge of the service with the signature of the key
+async fn authenticate(conn: &mut Connection, key: &str, secret: &str, wait: Duration) -> Result<()> {
+    conn.send(Bytes::from(format!("AUTH {}", key))).await?;
+    let challenge = read_response(conn, wait).await?.ok_or("service sent no challenge")?;
+    conn.send(Bytes::from(format!("PROOF {}", auth::sign(key, secret, &challenge)))).await?;
+    read_response(conn, wait).await
+        .map(|_| ())
+        .map_err(|e| format!("authentication failed: {}", e).into())
+}
+
+// send content after the command was accepted, close the writing half and wait for the final response
+async fn send_body(mut conn: Connection, mut body: ByteStream, timeouts: ClientTimeouts) -> Result<Option<String>> {
+    // service stops reading as soon as the upload breaks its rules, its response explains why
+    let mut sent = Ok(());
+    while let Some(bytes) = body.next().await {
+        let bytes = bytes?;
+        match timeout(timeouts.idle, conn.send(bytes)).await {
+            Ok(Ok(())) => {},
+            Ok(Err(e)) => {
+                sent = Err(e);
+                break;
+            },
+            Err(_) => return Err(timed_out("sending content", timeouts.idle).into()),
+        }
+    }
+    if sent.is_ok() {
+        sent = timeout(timeouts.idle, conn.close()).await
+            .unwrap_or_else(|_| Err(timed_out("sending content", timeouts.idle)));
+    }
+    let response = read_response(&mut conn, timeouts.read).await?;
+    sent?;
+    Ok(response)
+}
+
+// next chunk of bytes from the service
+async fn next_frame(conn: &mut Connection, wait: Duration) -> Result<BytesMut> {
+    match timeout(wait, conn.next()).await {
+        Ok(Some(bytes)) => Ok(bytes?),
+        Ok(None) => Err("no response from video service".into()),
+        Err(_) => Err(timed_out("response", wait).into()),
+    }
+}
+
+// read response that is not followed by content
+async fn read_response(conn: &mut Connection, wait: Duration) -> Result<Option<String>> {
+    let frame = next_frame(conn, wait).await?;
+    let line = std::str::from_utf8(&frame).map_err(|_| "response is not a text")?;
+    parse_response(line)
+}
+
+// parse `OK [value]` or `ERROR <message>`
+fn parse_response(line: &str) -> Result<Option<String>> {
+    let mut parts = line.splitn(2, ' ');
+    match (parts.next(), parts.next()) {
+        (Some("OK"), value) => Ok(value.map(str::trim).filter(|v| !v.is_empty()).map(str::to_string)),
+        (Some("ERROR"), Some(msg)) if msg.trim() == UNKNOWN_VIDEO => Err(UnknownVideo.into()),
+        (Some("ERROR"), Some(msg)) => Err(msg.trim().to_string().into()),
+        _ => Err(format!("unexpected response: {}", line).into()),
+    }
+}
+
+// response to `GET` is `OK <MIME type>` and a new line followed by content,
+// errors have no new line, the service closes the connection after them
+fn split_get_response(mut frame: BytesMut) -> Result<(String, Bytes)> {
+    if frame.starts_with(b"ERROR") {
+        let line = String::from_utf8_lossy(&frame);
+        return parse_response(&line).map(|_| (String::new(), Bytes::new()));
+    }
+    let end = frame.iter().position(|b| *b == b'\n').ok_or("response to GET has no end of line")?;
+    let content = frame.split_off(end + 1).freeze();
+    let line = std::str::from_utf8(&frame[..end])
+        .map_err(|_| format!("unexpected response: {}", String::from_utf8_lossy(&frame[..end.min(64)])))?;
+    let mime_type = parse_response(line)?.ok_or("service did not send the MIME type")?;
+    Ok((mime_type, content))
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        tokio::{net::TcpListener, io::{AsyncReadExt, AsyncWriteExt}},
+    };
+
+    fn timeouts(millis: u64) -> ClientTimeouts {
+        let wait = Duration::from_millis(millis);
+        ClientTimeouts { connect: wait, read: wait, idle: wait }
+    }
+
+    // node that accepts a connection, sends `answer` and then hangs with the connection open
+    async fn hung_node(answer: &'static [u8]) -> String {
+        let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut buf = [0u8; 1024];
+            let _ = socket.read(&mut buf).await;
+            socket.write_all(answer).await.ok();
+            tokio::time::delay_for(Duration::from_secs(60)).await;
+        });
+        addr
+    }
+
+    #[tokio::test]
+    async fn silent_node_fails_the_request() {
+        let client = Client::new(&hung_node(b"").await).with_timeouts(timeouts(100));
+        let e = client.ping().await.unwrap_err();
+        assert!(e.to_string().contains("timed out"), "{}", e);
+    }
+
+    #[tokio::test]
+    async fn stalled_download_fails() {
+        let client = Client::new(&hung_node(b"OK video/mp4\nhead").await).with_timeouts(timeouts(100));
+        let (_, mut body) = client.get("a.mp4", None).await.unwrap();
+        assert_eq!(&body.next().await.unwrap().unwrap()[..], b"head");
+        let e = body.next().await.unwrap().unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::TimedOut);
+        assert!(body.next().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn authentication_has_to_finish_in_time() {
+        let timeouts = ClientTimeouts { connect: Duration::from_millis(100), ..ClientTimeouts::default() };
+        let client = Client::new(&hung_node(b"").await).with_auth("web", "s3cret").with_timeouts(timeouts);
+        let e = client.list("").await.unwrap_err();
+        assert!(e.to_string().starts_with("can not connect"), "{}", e);
+    }
+
+    #[test]
+    fn splits_get_response_at_end_of_line() {
+        let (mime_type, content) = split_get_response(BytesMut::from(&b"OK video/mp4\n\x00\x00\x00\x18ftyp"[..])).unwrap();
+        assert_eq!(mime_type, "video/mp4");
+        assert_eq!(&content[..], b"\x00\x00\x00\x18ftyp");
+        // content could start with a new line too
+        let (mime_type, content) = split_get_response(BytesMut::from(&b"OK application/octet-stream\n\n"[..])).unwrap();
+        assert_eq!(mime_type, "application/octet-stream");
+        assert_eq!(&content[..], b"\n");
+        assert_eq!(split_get_response(BytesMut::from(&b"ERROR file does not exist"[..])).unwrap_err().to_string(), "file does not exist");
+        assert!(split_get_response(BytesMut::from(&b"OK\n"[..])).is_err());
+        assert!(split_get_response(BytesMut::from(&b"OK video/mp4"[..])).is_err());
+    }
+
+    #[tokio::test]
+    async fn response_line_without_end_fails() {
+        static ANSWER: [u8; 2048] = [b'O'; 2048];
+        let client = Client::new(&hung_node(&ANSWER).await).with_timeouts(timeouts(100));
+        let e = client.get("a.mp4", None).await.err().unwrap();
+        assert_eq!(e.to_string(), "response to GET has no end of line");
+    }
+
+    #[test]
+    fn parses_responses() {
+        assert_eq!(parse_response("OK").unwrap(), None);
+        assert_eq!(parse_response("OK ").unwrap(), None);
+        assert_eq!(parse_response("OK a.mp4\n").unwrap(), Some("a.mp4".to_string()));
+        assert_eq!(parse_response("ERROR file does not exist").unwrap_err().to_string(), "file does not exist");
+        assert!(parse_response("ERROR unknown video\n").unwrap_err().is::<UnknownVideo>());
+        assert!(!parse_response("ERROR unknown video format").unwrap_err().is::<UnknownVideo>());
+        assert!(parse_response("HELLO").is_err());
+    }
+}
@@ -0,0 +1,697 @@
+use {
+    std::{collections::BTreeMap, env, fmt::Display, fs, net::{SocketAddr, ToSocketAddrs}, path::PathBuf, str::FromStr},
+    serde::{Deserialize, Serialize},
+    crate::{auth::Access, net::UNIX_PREFIX, storage::Result, validation::Validators},
+};
+
+// env variables and keys of settings they override
+const ENV: [(&str, &str); 40] = [
+    ("VIDEO_ADDR", "addr"),
+    ("VIDEO_LISTEN", "listen"),
+    ("VIDEO_SOCKET_MODE", "socket_mode"),
+    ("VIDEO_HTTP_ADDR", "http_addr"),
+    ("VIDEO_STAGING_DIR", "staging_dir"),
+    ("VIDEO_VALIDATORS", "validators"),
+    ("VIDEO_CALLBACK_SECRET", "callback_secret"),
+    ("VIDEO_CALLBACK_HOSTS", "callback_hosts"),
+    ("VIDEO_SHUTDOWN_TIMEOUT", "shutdown_timeout"),
+    ("VIDEO_SENDFILE", "sendfile"),
+    ("VIDEO_STORAGE", "storage.backend"),
+    ("VIDEO_STORAGE_DIR", "storage.dir"),
+    ("VIDEO_STORAGE_SHARD_DEPTH", "storage.shard_depth"),
+    ("S3_ENDPOINT", "storage.s3.endpoint"),
+    ("S3_BUCKET", "storage.s3.bucket"),
+    ("S3_REGION", "storage.s3.region"),
+    ("S3_ACCESS_KEY", "storage.s3.access_key"),
+    ("S3_SECRET_KEY", "storage.s3.secret_key"),
+    ("VIDEO_MAX_UPLOAD_SIZE", "limits.max_upload_size"),
+    ("VIDEO_MIN_FREE_SPACE", "limits.min_free_space"),
+    ("VIDEO_MAX_CONNECTIONS", "limits.max_connections"),
+    ("VIDEO_MAX_CONNECTIONS_PER_IP", "limits.max_connections_per_ip"),
+    ("VIDEO_UPLOAD_RATE", "limits.upload_rate"),
+    ("VIDEO_DOWNLOAD_RATE", "limits.download_rate"),
+    ("VIDEO_REQUEST_TIMEOUT", "timeouts.request"),
+    ("VIDEO_IDLE_TIMEOUT", "timeouts.idle"),
+    ("VIDEO_MIN_UPLOAD_RATE", "timeouts.min_upload_rate"),
+    ("VIDEO_RATE_WINDOW", "timeouts.rate_window"),
+    ("VIDEO_FPS", "compression.fps"),
+    ("VIDEO_RESOLUTION", "compression.resolution"),
+    ("VIDEO_REPLICA_PEERS", "replication.peers"),
+    ("VIDEO_REPLICATION_RETRY", "replication.retry_interval"),
+    ("VIDEO_REPLICATION_DELETES_FILE", "replication.deletes_file"),
+    ("VIDEO_TLS_CERT", "tls.cert"),
+    ("VIDEO_TLS_KEY", "tls.key"),
+    ("VIDEO_TLS_CA", "tls.ca"),
+    ("VIDEO_TLS_SERVER_NAME", "tls.server_name"),
+    ("VIDEO_AUTH_KEYS", "auth.keys"),
+    ("VIDEO_AUTH_PEER_KEY", "auth.peer_key"),
+    ("VIDEO_AUTH_PEER_SECRET", "auth.peer_secret"),
+];
+
+/// Settings of the service
+/// every value is taken from defaults, then from a TOML file, then from env variables and then from command line flags
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// address clients connect to, `host:port` or `unix://<path>`
+    pub addr: String,
+    /// more addresses to listen on next to `addr`
+    pub listen: Vec<String>,
+    /// octal permissions of Unix sockets, e.g. `660`
+    pub socket_mode: String,
+    /// address of `/metrics` and `/health`
+    pub http_addr: String,
+    /// local directory with uploads that wait for compression
+    pub staging_dir: PathBuf,
+    /// checks of uploads separated with `;`, e.g. `max-duration:3600;min-resolution:640x360`
+    pub validators: String,
+    /// callbacks are signed with this secret, it is required when `callback_hosts` is set
+    pub callback_secret: String,
+    /// `host` or `host:port` callbacks may be sent to, callbacks are refused when it is empty
+    pub callback_hosts: Vec<String>,
+    /// how many seconds to wait for work in progress on shutdown
+    pub shutdown_timeout: u64,
+    /// downloads of local files over plain connections are sent with `sendfile` on Linux
+    pub sendfile: bool,
+    pub storage: StorageConfig,
+    pub limits: LimitsConfig,
+    pub timeouts: TimeoutsConfig,
+    pub compression: CompressionConfig,
+    pub replication: ReplicationConfig,
+    pub tls: TlsConfig,
+    pub auth: AuthConfig,
+}
+
+/// Where compressed videos are kept
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct StorageConfig {
+    /// `local`, `memory` or `s3`
+    pub backend: String,
+    /// directory of `local` backend
+    pub dir: PathBuf,
+    /// how many levels of subdirectories `local` backend spreads files over
+    pub shard_depth: usize,
+    pub s3: S3Config,
+}
+
+/// Bucket of `s3` backend
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct S3Config {
+    pub endpoint: String,
+    pub bucket: String,
+    pub region: String,
+    pub access_key: String,
+    pub secret_key: String,
+}
+
+/// Rules for incomming uploads
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LimitsConfig {
+    /// maximum size of a single upload in bytes, unlimited if not set
+    pub max_upload_size: Option<u64>,
+    /// uploads are refused when the disk has less free bytes than this
+    pub min_free_space: u64,
+    /// open connections of all clients, unlimited with 0
+    pub max_connections: usize,
+    /// open connections of a single IP address, unlimited with 0
+    pub max_connections_per_ip: usize,
+    /// bytes per second a connection may send to the service, unlimited with 0
+    pub upload_rate: u64,
+    /// bytes per second a connection may receive from the service, unlimited with 0
+    pub download_rate: u64,
+}
+
+/// Settings of ffmpeg videos are compressed with
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CompressionConfig {
+    pub fps: u32,
+    /// `<width>x<height>`
+    pub resolution: String,
+}
+
+/// Limits of waiting for slow clients, in seconds
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TimeoutsConfig {
+    /// a new connection must send its request within this time
+    pub request: u64,
+    /// longest pause between chunks of a transfer and between requests of a kept-alive connection
+    pub idle: u64,
+    /// uploads slower than this amount of bytes per second are dropped, no floor with 0
+    pub min_upload_rate: u64,
+    /// throughput of uploads is measured over windows of this length
+    pub rate_window: u64,
+}
+
+/// Peers that keep copies of compressed videos
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ReplicationConfig {
+    /// addresses of replicas, videos are not replicated if it is empty
+    pub peers: Vec<String>,
+    /// how many seconds to wait before failed copies are sent again
+    pub retry_interval: u64,
+    /// file with deletes peers did not confirm yet, they are sent again after restart
+    pub deletes_file: PathBuf,
+}
+
+/// Mutual TLS between services, connections are plain TCP if it is not set
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TlsConfig {
+    /// PEM certificate chain of this node, it is shown to clients and to peers
+    pub cert: Option<PathBuf>,
+    /// PEM private key of the certificate
+    pub key: Option<PathBuf>,
+    /// PEM certificate of the CA that signs certificates of web services and peers
+    pub ca: Option<PathBuf>,
+    /// name certificates of peers are issued for
+    pub server_name: String,
+}
+
+/// Keys clients authenticate with, the protocol is open if there are none
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuthConfig {
+    /// key this node authenticates with when it copies videos to peers
+    pub peer_key: String,
+    pub peer_secret: String,
+    /// keys by their ID
+    /// it is the last field, because TOML tables must follow plain values
+    pub keys: BTreeMap<String, KeyConfig>,
+}
+
+/// Shared secret of a client and what it may do
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct KeyConfig {
+    pub secret: String,
+    pub access: Access,
+    /// names of videos the key may touch must start with one of these, any name if it is empty
+    #[serde(default)]
+    pub prefixes: Vec<String>,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            addr: "127.0.0.1:8091".to_string(),
+            listen: Vec::new(),
+            socket_mode: "660".to_string(),
+            http_addr: "127.0.0.1:9091".to_string(),
+            staging_dir: PathBuf::from("./tmp"),
+            validators: String::new(),
+            callback_secret: String::new(),
+            callback_hosts: Vec::new(),
+            shutdown_timeout: 30,
+            sendfile: true,
+            storage: StorageConfig::default(),
+            limits: LimitsConfig::default(),
+            timeouts: TimeoutsConfig::default(),
+            compression: CompressionConfig::default(),
+            replication: ReplicationConfig::default(),
+            tls: TlsConfig::default(),
+            auth: AuthConfig::default(),
+        }
+    }
+}
+
+impl Default for StorageConfig {
+    fn default() -> StorageConfig {
+        StorageConfig {
+            backend: "local".to_string(),
+            dir: PathBuf::from("./dist"),
+            shard_depth: 0,
+            s3: S3Config::default(),
+        }
+    }
+}
+
+impl Default for S3Config {
+    fn default() -> S3Config {
+        S3Config {
+            endpoint: String::new(),
+            bucket: String::new(),
+            region: "us-east-1".to_string(),
+            access_key: String::new(),
+            secret_key: String::new(),
+        }
+    }
+}
+
+impl Default for LimitsConfig {
+    fn default() -> LimitsConfig {
+        // 1 Gb must stay free
+        LimitsConfig {
+            max_upload_size: None,
+            min_free_space: 1073741824,
+            max_connections: 1024,
+            max_connections_per_ip: 64,
+            upload_rate: 0,
+            download_rate: 0,
+        }
+    }
+}
+
+impl Default for CompressionConfig {
+    fn default() -> CompressionConfig {
+        CompressionConfig { fps: 30, resolution: "960x540".to_string() }
+    }
+}
+
+impl Default for TimeoutsConfig {
+    fn default() -> TimeoutsConfig {
+        TimeoutsConfig { request: 30, idle: 60, min_upload_rate: 1024, rate_window: 30 }
+    }
+}
+
+impl Default for ReplicationConfig {
+    fn default() -> ReplicationConfig {
+        ReplicationConfig { peers: Vec::new(), retry_interval: 30, deletes_file: PathBuf::from("./replication-deletes.json") }
+    }
+}
+
+impl Default for TlsConfig {
+    fn default() -> TlsConfig {
+        TlsConfig { cert: None, key: None, ca: None, server_name: "video-service".to_string() }
+    }
+}
+
+impl TlsConfig {
+    pub fn enabled(&self) -> bool {
+        self.cert.is_some() || self.key.is_some() || self.ca.is_some()
+    }
+}
+
+impl CompressionConfig {
+    /// name of the settings in logs and metrics, e.g. `960x540@30`
+    pub fn profile(&self) -> String {
+        format!("{}@{}", self.resolution, self.fps)
+    }
+}
+
+/// Parsed command line
+#[derive(Debug, Default)]
+pub struct Args {
+    /// TOML file passed with `--config`
+    pub config: Option<PathBuf>,
+    /// `--print-config` shows effective settings and exits
+    pub print_config: bool,
+    // settings passed as `--key value` or `--key=value`
+    overrides: Vec<(String, String)>,
+}
+
+impl Args {
+    /// parse arguments without the name of the program
+    /// the first positional argument is the address to listen on
+    pub fn parse<I: Iterator<Item = String>>(mut args: I) -> std::result::Result<Args, String> {
+        let mut parsed = Args::default();
+        while let Some(arg) = args.next() {
+            let flag = match arg.strip_prefix("--") {
+                Some(flag) => flag,
+                None => {
+                    parsed.overrides.push(("addr".to_string(), arg));
+                    continue;
+                },
+            };
+            if flag == "print-config" {
+                parsed.print_config = true;
+                continue;
+            }
+            let (key, value) = match flag.split_once('=') {
+                Some((key, value)) => (key.to_string(), value.to_string()),
+                None => match args.next() {
+                    Some(value) => (flag.to_string(), value),
+                    None => return Err(format!("--{} must be followed by a value", flag)),
+                },
+            };
+            if key == "config" {
+                parsed.config = Some(PathBuf::from(value));
+            } else {
+                // both `--http-addr` and `--http_addr` are accepted
+                parsed.overrides.push((key.replace('-', "_"), value));
+            }
+        }
+        Ok(parsed)
+    }
+}
+
+impl Config {
+    /// merge defaults, file from `--config` or `VIDEO_CONFIG`, env variables and command line flags
+    /// and check that the result is usable
+    pub fn load(args: &Args) -> Result<Config> {
+        let path = args.config.clone().or_else(|| env::var("VIDEO_CONFIG").ok().map(PathBuf::from));
+        let mut config = match path {
+            Some(path) => {
+                let text = fs::read_to_string(&path)
+                    .map_err(|e| format!("can not read config {}: {}", path.display(), e))?;
+                toml::from_str(&text).map_err(|e| format!("invalid config {}: {}", path.display(), e))?
+            },
+            None => Config::default(),
+        };
+        for (name, key) in ENV.iter() {
+            if let Ok(value) = env::var(name) {
+                config.set(key, &value).map_err(|e| format!("{}: {}", name, e))?;
+            }
+        }
+        for (key, value) in &args.overrides {
+            config.set(key, value).map_err(|e| format!("--{}: {}", key, e))?;
+        }
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// change a single setting, `key` is a dotted path like in the TOML file, e.g. `storage.backend`
+    pub fn set(&mut self, key: &str, value: &str) -> std::result::Result<(), String> {
+        match key {
+            "addr" => self.addr = value.to_string(),
+            "listen" => self.listen = split_list(value),
+            "socket_mode" => self.socket_mode = value.to_string(),
+            "http_addr" => self.http_addr = value.to_string(),
+            "staging_dir" => self.staging_dir = PathBuf::from(value),
+            "validators" => self.validators = value.to_string(),
+            "callback_secret" => self.callback_secret = value.to_string(),
+            "callback_hosts" => self.callback_hosts = split_list(value),
+            "shutdown_timeout" => self.shutdown_timeout = parse(value)?,
+            "sendfile" => self.sendfile = parse(value)?,
+            "storage.backend" => self.storage.backend = value.to_string(),
+            "storage.dir" => self.storage.dir = PathBuf::from(value),
+            "storage.shard_depth" => self.storage.shard_depth = parse(value)?,
+            "storage.s3.endpoint" => self.storage.s3.endpoint = value.to_string(),
+            "storage.s3.bucket" => self.storage.s3.bucket = value.to_string(),
+            "storage.s3.region" => self.storage.s3.region = value.to_string(),
+            "storage.s3.access_key" => self.storage.s3.access_key = value.to_string(),
+            "storage.s3.secret_key" => self.storage.s3.secret_key = value.to_string(),
+            "limits.max_upload_size" => self.limits.max_upload_size = Some(parse(value)?),
+            "limits.min_free_space" => self.limits.min_free_space = parse(value)?,
+            "limits.max_connections" => self.limits.max_connections = parse(value)?,
+            "limits.max_connections_per_ip" => self.limits.max_connections_per_ip = parse(value)?,
+            "limits.upload_rate" => self.limits.upload_rate = parse(value)?,
+            "limits.download_rate" => self.limits.download_rate = parse(value)?,
+            "compression.fps" => self.compression.fps = parse(value)?,
+            "compression.resolution" => self.compression.resolution = value.to_string(),
+            "timeouts.request" => self.timeouts.request = parse(value)?,
+            "timeouts.idle" => self.timeouts.idle = parse(value)?,
+            "timeouts.min_upload_rate" => self.timeouts.min_upload_rate = parse(value)?,
+            "timeouts.rate_window" => self.timeouts.rate_window = parse(value)?,
+            "replication.peers" => self.replication.peers = split_list(value),
+            "replication.retry_interval" => self.replication.retry_interval = parse(value)?,
+            "replication.deletes_file" => self.replication.deletes_file = PathBuf::from(value),
+            "tls.cert" => self.tls.cert = Some(PathBuf::from(value)),
+            "tls.key" => self.tls.key = Some(PathBuf::from(value)),
+            "tls.ca" => self.tls.ca = Some(PathBuf::from(value)),
+            "tls.server_name" => self.tls.server_name = value.to_string(),
+            "auth.keys" => self.auth.keys = parse_keys(value)?,
+            "auth.peer_key" => self.auth.peer_key = value.to_string(),
+            "auth.peer_secret" => self.auth.peer_secret = value.to_string(),
+            _ => return Err(format!("unknown setting {}", key)),
+        }
+        Ok(())
+    }
+
+    /// check all settings at once, so every mistake is reported on the first start
+    pub fn validate(&self) -> Result<()> {
+        let mut errors = Vec::new();
+        if !valid_addr(&self.addr) {
+            errors.push(format!("addr: invalid address {}", self.addr));
+        }
+        for addr in &self.listen {
+            if !valid_addr(addr) {
+                errors.push(format!("listen: invalid address {}", addr));
+            }
+        }
+        if self.socket_mode().is_none() {
+            errors.push(format!("socket_mode must be octal permissions like 660, got {}", self.socket_mode));
+        }
+        if self.http_addr.parse::<SocketAddr>().is_err() {
+            errors.push(format!("http_addr: invalid address {}", self.http_addr));
+        }
+        if let Err(e) = Validators::parse(&self.validators) {
+            errors.push(format!("validators: {}", e));
+        }
+        match self.storage.backend.as_str() {
+            "local" | "memory" => {},
+            "s3" => {
+                let s3 = &self.storage.s3;
+                let required = [("endpoint", &s3.endpoint), ("bucket", &s3.bucket), ("access_key", &s3.access_key), ("secret_key", &s3.secret_key)];
+                for (name, value) in required.iter() {
+                    if value.is_empty() {
+                        errors.push(format!("storage.s3.{} must be set for s3 storage", name));
+                    }
+                }
+            },
+            other => errors.push(format!("storage.backend: unknown storage backend {}", other)),
+        }
+        if self.compression.fps == 0 {
+            errors.push("compression.fps must be greater than 0".to_string());
+        }
+        if parse_resolution(&self.compression.resolution).is_none() {
+            errors.push(format!("compression.resolution must look like 960x540, got {}", self.compression.resolution));
+        }
+        for peer in &self.replication.peers {
+            if !valid_addr(peer) {
+                errors.push(format!("replication.peers: invalid address {}", peer));
+            } else if *peer == self.addr {
+                errors.push(format!("replication.peers: {} is the address of this node", peer));
+            }
+        }
+        // unsigned callbacks would let anyone who can reach the receiver forge outcomes
+        if !self.callback_hosts.is_empty() && self.callback_secret.is_empty() {
+            errors.push("callback_secret must be set when callback_hosts is set".to_string());
+        }
+        for (name, value) in [("request", self.timeouts.request), ("idle", self.timeouts.idle), ("rate_window", self.timeouts.rate_window)].iter() {
+            if *value == 0 {
+                errors.push(format!("timeouts.{} must be greater than 0", name));
+            }
+        }
+        // the service would drop uploads it throttles itself
+        if self.limits.upload_rate > 0 && self.timeouts.min_upload_rate > self.limits.upload_rate {
+            errors.push("timeouts.min_upload_rate must not be greater than limits.upload_rate".to_string());
+        }
+        if self.replication.retry_interval == 0 {
+            errors.push("replication.retry_interval must be greater than 0".to_string());
+        }
+        if self.tls.enabled() && (self.tls.cert.is_none() || self.tls.key.is_none() || self.tls.ca.is_none()) {
+            errors.push("tls.cert, tls.key and tls.ca must be set together".to_string());
+        }
+        if self.tls.server_name.is_empty() {
+            errors.push("tls.server_name must not be empty".to_string());
+        }
+        for (id, key) in &self.auth.keys {
+            if key.secret.is_empty() {
+                errors.push(format!("auth.keys.{}.secret must not be empty", id));
+            }
+        }
+        if self.auth.peer_key.is_empty() != self.auth.peer_secret.is_empty() {
+            errors.push("auth.peer_key and auth.peer_secret must be set together".to_string());
+        }
+        if !errors.is_empty() {
+            return Err(format!("invalid config: {}", errors.join("; ")).into());
+        }
+        Ok(())
+    }
+
+    /// permissions of Unix sockets
+    pub fn socket_mode(&self) -> Option<u32> {
+        u32::from_str_radix(&self.socket_mode, 8).ok().filter(|mode| *mode <= 0o777)
+    }
+
+    /// effective settings as TOML with secrets hidden
+    pub fn to_toml(&self) -> Result<String> {
+        let mut config = self.clone();
+        let mut secrets = vec![&mut config.callback_secret, &mut config.storage.s3.secret_key, &mut config.auth.peer_secret];
+        secrets.extend(config.auth.keys.values_mut().map(|key| &mut key.secret));
+        for secret in secrets {
+            if !secret.is_empty() {
+                *secret = "<hidden>".to_string();
+            }
+        }
+        Ok(toml::to_string(&config)?)
+    }
+}
+
+// parse a value of a setting
+fn parse<T: FromStr>(value: &str) -> std::result::Result<T, String>
+where
+    T::Err: Display,
+{
+    value.parse::<T>().map_err(|e| format!("invalid value {}: {}", value, e))
+}
+
+// `host:port` or `unix://<path>`
+fn valid_addr(addr: &str) -> bool {
+    match addr.strip_prefix(UNIX_PREFIX) {
+        Some(path) => !path.is_empty(),
+        None => addr.to_socket_addrs().is_ok(),
+    }
+}
+
+// `host:port,host:port` into a list, duplicates are dropped
+fn split_list(value: &str) -> Vec<String> {
+    let mut list: Vec<String> = Vec::new();
+    for item in value.split(',').map(str::trim).filter(|item| !item.is_empty()) {
+        if !list.iter().any(|known| known == item) {
+            list.push(item.to_string());
+        }
+    }
+    list
+}
+
+// `<id>:<access>:<secret>[:<prefix>|<prefix>]` separated by commas into keys,
+// e.g. `web:read-write:s3cret,cdn:read-only:0ther:public-|thumb-`
+fn parse_keys(value: &str) -> std::result::Result<BTreeMap<String, KeyConfig>, String> {
+    let mut keys = BTreeMap::new();
+    for item in split_list(value) {
+        let mut parts = item.splitn(4, ':');
+        let (id, access, secret) = match (parts.next(), parts.next(), parts.next()) {
+            (Some(id), Some(access), Some(secret)) if !id.is_empty() => (id, access, secret),
+            _ => return Err(format!("{} must look like id:access:secret", item)),
+        };
+        let prefixes = parts.next()
+            .map(|prefixes| prefixes.split('|').filter(|prefix| !prefix.is_empty()).map(str::to_string).collect())
+            .unwrap_or_default();
+        let key = KeyConfig { secret: secret.to_string(), access: Access::parse(access)?, prefixes };
+        keys.insert(id.to_string(), key);
+    }
+    Ok(keys)
+}
+
+// `960x540` into width and height
+fn parse_resolution(resolution: &str) -> Option<(u32, u32)> {
+    let (width, height) = resolution.split_once('x')?;
+    match (width.parse::<u32>(), height.parse::<u32>()) {
+        (Ok(width), Ok(height)) if width > 0 && height > 0 => Some((width, height)),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args: &[&str]) -> Args {
+        Args::parse(args.iter().map(|arg| arg.to_string())).unwrap()
+    }
+
+    #[test]
+    fn parses_command_line() {
+        let parsed = args(&["127.0.0.1:9000", "--http-addr", "127.0.0.1:9001", "--limits.upload_rate=100", "--config", "video.toml", "--print-config"]);
+        assert_eq!(parsed.config, Some(PathBuf::from("video.toml")));
+        assert!(parsed.print_config);
+        assert_eq!(parsed.overrides, vec![
+            ("addr".to_string(), "127.0.0.1:9000".to_string()),
+            ("http_addr".to_string(), "127.0.0.1:9001".to_string()),
+            ("limits.upload_rate".to_string(), "100".to_string()),
+        ]);
+        assert!(Args::parse(vec!["--idle".to_string()].into_iter()).is_err());
+    }
+
+    #[test]
+    fn sets_settings_by_key() {
+        let mut config = Config::default();
+        config.set("storage.backend", "memory").unwrap();
+        config.set("limits.max_upload_size", "1048576").unwrap();
+        config.set("replication.peers", "10.0.0.2:8091, 10.0.0.3:8091,10.0.0.2:8091").unwrap();
+        config.set("auth.keys", "web:read-write:s3cret,cdn:read-only:0ther:public-|thumb-").unwrap();
+        assert_eq!(config.storage.backend, "memory");
+        assert_eq!(config.limits.max_upload_size, Some(1048576));
+        assert_eq!(config.replication.peers, vec!["10.0.0.2:8091", "10.0.0.3:8091"]);
+        assert_eq!(config.auth.keys["web"].access, Access::ReadWrite);
+        assert_eq!(config.auth.keys["cdn"].prefixes, vec!["public-", "thumb-"]);
+
+        assert_eq!(config.set("nope", "1"), Err("unknown setting nope".to_string()));
+        assert!(config.set("timeouts.idle", "soon").is_err());
+        assert!(config.set("sendfile", "yes").is_err());
+        assert!(config.set("auth.keys", "web:admin:s3cret").is_err());
+        assert!(config.set("auth.keys", "web:read-only").is_err());
+    }
+
+    #[test]
+    fn defaults_are_valid() {
+        Config::default().validate().unwrap();
+    }
+
+    #[test]
+    fn reports_every_mistake_at_once() {
+        let mut config = Config::default();
+        for (key, value) in &[
+            ("addr", "nowhere"),
+            ("compression.fps", "0"),
+            ("compression.resolution", "big"),
+            ("storage.backend", "s3"),
+            ("callback_hosts", "hooks.local"),
+            ("tls.cert", "node.pem"),
+            ("auth.peer_key", "node"),
+            ("validators", "max-duration:NaN"),
+        ] {
+            config.set(key, value).unwrap();
+        }
+        let e = config.validate().unwrap_err().to_string();
+        for part in &[
+            "addr: invalid address nowhere",
+            "compression.fps",
+            "compression.resolution",
+            "storage.s3.bucket must be set",
+            "callback_secret must be set",
+            "tls.cert, tls.key and tls.ca must be set together",
+            "auth.peer_key and auth.peer_secret must be set together",
+            "validators: invalid duration",
+        ] {
+            assert!(e.contains(part), "{} is missing in {}", part, e);
+        }
+    }
+
+    #[test]
+    fn refuses_unknown_fields_in_files() {
+        assert!(toml::from_str::<Config>("adr = \"127.0.0.1:1\"").is_err());
+        assert!(toml::from_str::<Config>("[limits]\nmax_upload = 1").is_err());
+        let config: Config = toml::from_str("[limits]\nmax_upload_size = 10").unwrap();
+        assert_eq!(config.limits.max_upload_size, Some(10));
+        assert_eq!(config.limits.min_free_space, LimitsConfig::default().min_free_space);
+    }
+
+    #[test]
+    fn flags_override_env_that_overrides_the_file() {
+        let path = env::temp_dir().join(format!("video-service-config-{}.toml", std::process::id()));
+        fs::write(&path, "http_addr = \"127.0.0.1:9191\"\n[compression]\nfps = 10\nresolution = \"640x360\"\n[timeouts]\nidle = 5\n").unwrap();
+        // the only test that reads the environment
+        env::set_var("VIDEO_FPS", "20");
+        env::set_var("VIDEO_IDLE_TIMEOUT", "7");
+        let config = Config::load(&args(&["--config", path.to_str().unwrap(), "--compression.fps=25"]));
+        env::remove_var("VIDEO_FPS");
+        env::remove_var("VIDEO_IDLE_TIMEOUT");
+        fs::remove_file(&path).ok();
+        let config = config.unwrap();
+        assert_eq!(config.compression.fps, 25);
+        assert_eq!(config.timeouts.idle, 7);
+        assert_eq!(config.compression.resolution, "640x360");
+        assert_eq!(config.http_addr, "127.0.0.1:9191");
+        assert_eq!(config.addr, Config::default().addr);
+    }
+
+    #[test]
+    fn hides_secrets_when_printed() {
+        let mut config = Config::default();
+        config.set("callback_secret", "callback-s3cret").unwrap();
+        config.set("auth.keys", "web:read-write:key-s3cret").unwrap();
+        let toml = config.to_toml().unwrap();
+        assert!(!toml.contains("s3cret"), "{}", toml);
+        assert!(toml.contains("<hidden>"));
+        // printed settings can be loaded again
+        toml::from_str::<Config>(&toml).unwrap();
+    }
+
+    #[test]
+    fn checks_socket_modes() {
+        let mut config = Config::default();
+        assert_eq!(config.socket_mode(), Some(0o660));
+        config.socket_mode = "1777".to_string();
+        assert_eq!(config.socket_mode(), None);
+        config.socket_mode = "rw".to_string();
+        assert_eq!(config.socket_mode(), None);
+    }
+}
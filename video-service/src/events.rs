@@ -0,0 +1,125 @@
+use {
+    std::sync::{Arc, Mutex},
+    futures::channel::mpsc,
+    serde::Serialize,
+    tracing::warn,
+    crate::probe::MediaInfo,
+};
+
+/// Something that happened to a video, pushed to every subscriber
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event {
+    /// upload is stored and waits for validation
+    UploadReceived { filename: String, size: u64 },
+    /// video passed validation and waits for compression
+    Queued { filename: String },
+    /// part of the video that ffmpeg has already compressed, from 0 to 100
+    Progress { filename: String, percent: u8 },
+    /// compressed video is stored
+    Done { filename: String, info: Option<MediaInfo> },
+    /// compression or storing failed
+    Failed { filename: String, reason: String },
+    /// one of validators refused the video
+    Rejected { filename: String, reason: String },
+    /// file was removed
+    Deleted { filename: String },
+}
+
+// events a subscriber may fall behind by before it is dropped
+const QUEUE_SIZE: usize = 1024;
+
+/// Subscribers of events
+/// every subscriber gets its own bounded queue, closed and full queues are dropped on the next event,
+/// so a stalled subscriber does not make the service hold every event for it
+#[derive(Clone, Default)]
+pub struct Events {
+    subscribers: Arc<Mutex<Vec<mpsc::Sender<Event>>>>,
+}
+
+impl Events {
+    pub fn new() -> Events {
+        Events::default()
+    }
+
+    /// start receiving all events that happen from now on,
+    /// the stream ends when the subscriber falls too far behind
+    pub fn subscribe(&self) -> mpsc::Receiver<Event> {
+        let (sender, receiver) = mpsc::channel(QUEUE_SIZE);
+        self.subscribers.lock().unwrap().push(sender);
+        receiver
+    }
+
+    /// send event to every subscriber
+    pub fn publish(&self, event: Event) {
+        self.subscribers.lock().unwrap().retain_mut(|subscriber| match subscriber.try_send(event.clone()) {
+            Ok(()) => true,
+            Err(e) => {
+                if e.is_full() {
+                    warn!(queued = QUEUE_SIZE, "subscriber does not keep up with events, it is dropped");
+                }
+                false
+            },
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        futures::StreamExt,
+    };
+
+    fn queued(n: usize) -> Event {
+        Event::Queued { filename: format!("{}.mp4", n) }
+    }
+
+    #[tokio::test]
+    async fn every_subscriber_gets_every_event() {
+        let events = Events::new();
+        let (mut first, mut second) = (events.subscribe(), events.subscribe());
+        events.publish(queued(1));
+        events.publish(Event::Deleted { filename: "1.mp4".to_string() });
+        for receiver in [&mut first, &mut second].iter_mut() {
+            assert!(matches!(receiver.next().await, Some(Event::Queued { filename }) if filename == "1.mp4"));
+            assert!(matches!(receiver.next().await, Some(Event::Deleted { .. })));
+        }
+    }
+
+    #[tokio::test]
+    async fn lagging_subscriber_is_dropped() {
+        let events = Events::new();
+        let mut lagging = events.subscribe();
+        let mut reading = events.subscribe();
+        // the channel holds one more event than its size for the sender
+        for n in 0..QUEUE_SIZE + 2 {
+            events.publish(queued(n));
+            assert!(reading.next().await.is_some());
+        }
+        assert_eq!(events.subscribers.lock().unwrap().len(), 1);
+        // events that were queued are still delivered, then the stream ends
+        let mut delivered = 0;
+        while lagging.next().await.is_some() {
+            delivered += 1;
+        }
+        assert_eq!(delivered, QUEUE_SIZE + 1);
+        events.publish(queued(0));
+        assert!(reading.next().await.is_some());
+    }
+
+    #[test]
+    fn closed_subscribers_are_dropped() {
+        let events = Events::new();
+        drop(events.subscribe());
+        let _open = events.subscribe();
+        events.publish(queued(1));
+        assert_eq!(events.subscribers.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn events_are_tagged_json() {
+        let json = serde_json::to_string(&Event::Progress { filename: "a.mp4".to_string(), percent: 42 }).unwrap();
+        assert_eq!(json, r#"{"event":"progress","filename":"a.mp4","percent":42}"#);
+    }
+}
@@ -0,0 +1,85 @@
+use {
+    std::{collections::BTreeMap, process::Stdio, time::Duration},
+    tokio::{process::Command, time},
+    serde::Serialize,
+    crate::{limits::UploadLimits, processing::Queue},
+};
+
+// how long `ffmpeg -version` may take
+const FFMPEG_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Result of a single check
+#[derive(Debug, Clone, Serialize)]
+pub struct Check {
+    pub ok: bool,
+    pub detail: String,
+}
+
+impl Check {
+    fn new(result: Result<String, String>) -> Check {
+        match result {
+            Ok(detail) => Check { ok: true, detail },
+            Err(detail) => Check { ok: false, detail },
+        }
+    }
+}
+
+/// Results of all checks, service is healthy only when every check passed
+#[derive(Debug, Clone, Serialize)]
+pub struct Health {
+    /// `ok` or `failing`
+    pub status: &'static str,
+    pub checks: BTreeMap<&'static str, Check>,
+}
+
+impl Health {
+    pub fn is_ok(&self) -> bool {
+        self.status == "ok"
+    }
+}
+
+/// check disk with uploads, processing queue and ffmpeg
+pub async fn check(queue: &Queue, limits: &UploadLimits) -> Health {
+    let mut checks = BTreeMap::new();
+    checks.insert("disk", Check::new(check_disk(limits)));
+    checks.insert("queue", Check::new(check_queue(queue)));
+    checks.insert("ffmpeg", Check::new(check_ffmpeg().await));
+    let status = if checks.values().all(|check| check.ok) { "ok" } else { "failing" };
+    Health { status, checks }
+}
+
+// new uploads are refused when the disk is almost full
+fn check_disk(limits: &UploadLimits) -> Result<String, String> {
+    let usage = limits.usage().map_err(|e| format!("can not check free disk space: {}", e))?;
+    if usage.free < limits.min_free_space {
+        return Err(format!("not enough free space: {}", usage));
+    }
+    Ok(usage.to_string())
+}
+
+// videos are never processed if the processing loop is gone
+fn check_queue(queue: &Queue) -> Result<String, String> {
+    let depth = queue.metrics().queue_depth();
+    if !queue.is_running() {
+        return Err(format!("processing loop is stopped, {} videos are waiting", depth));
+    }
+    Ok(format!("{} videos are waiting", depth))
+}
+
+// ffmpeg must be installed and runnable
+async fn check_ffmpeg() -> Result<String, String> {
+    let output = Command::new("ffmpeg")
+        .arg("-version")
+        .stdin(Stdio::null())
+        .kill_on_drop(true)
+        .output();
+    let output = time::timeout(FFMPEG_TIMEOUT, output).await
+        .map_err(|_| format!("ffmpeg did not answer in {} seconds", FFMPEG_TIMEOUT.as_secs()))?
+        .map_err(|e| format!("can not run ffmpeg: {}", e))?;
+    if !output.status.success() {
+        return Err(format!("ffmpeg exited with {}", output.status));
+    }
+    // first line is like `ffmpeg version 4.2.2 Copyright ...`
+    let version = String::from_utf8_lossy(&output.stdout);
+    Ok(version.lines().next().unwrap_or_default().to_string())
+}
@@ -0,0 +1,40 @@
+use {
+    std::{convert::Infallible, net::SocketAddr, sync::Arc},
+    hyper::{service::{make_service_fn, service_fn}, Body, Request, Response, Server, StatusCode},
+    crate::{health, limits::UploadLimits, processing::Queue, storage::Result},
+};
+
+/// serve `GET /metrics` and `GET /health` over HTTP on `addr`
+pub async fn serve(addr: SocketAddr, queue: Queue, limits: Arc<UploadLimits>) -> Result<()> {
+    let make_service = make_service_fn(move |_| {
+        let (queue, limits) = (queue.clone(), limits.clone());
+        async move {
+            Ok::<_, Infallible>(service_fn(move |req: Request<Body>| {
+                let (queue, limits) = (queue.clone(), limits.clone());
+                async move { route(req, &queue, &limits).await }
+            }))
+        }
+    });
+    Server::bind(&addr).serve(make_service).await?;
+    Ok(())
+}
+
+// answer a single request
+async fn route(req: Request<Body>, queue: &Queue, limits: &UploadLimits) -> hyper::http::Result<Response<Body>> {
+    match req.uri().path() {
+        "/metrics" => Response::builder()
+            .header("content-type", "text/plain; version=0.0.4")
+            .body(Body::from(queue.metrics().render(limits))),
+        "/health" => {
+            let health = health::check(queue, limits).await;
+            let status = if health.is_ok() { StatusCode::OK } else { StatusCode::SERVICE_UNAVAILABLE };
+            Response::builder()
+                .status(status)
+                .header("content-type", "application/json")
+                .body(Body::from(serde_json::to_vec(&health).unwrap_or_default()))
+        },
+        _ => Response::builder()
+            .status(StatusCode::NOT_FOUND)
+            .body(Body::empty()),
+    }
+}
@@ -0,0 +1,202 @@
+use {
+    std::{collections::HashMap, fmt, sync::{Arc, Mutex}},
+    serde::{Deserialize, Serialize},
+    crate::{client::UNKNOWN_VIDEO, key::VideoKey},
+};
+
+/// Stage of a video on its way from upload to storage
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum JobStatus {
+    /// upload is complete and validators are checking it
+    Validating,
+    /// video waits for compression
+    Queued,
+    /// ffmpeg is working on the video
+    Processing,
+    /// compressed video is stored
+    Done,
+    /// compression or storing failed
+    Failed { reason: String },
+    /// one of validators refused the video
+    Rejected { reason: String },
+    /// admin cancelled the job before compression, the upload stays in staging
+    Cancelled,
+}
+
+impl fmt::Display for JobStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            JobStatus::Validating => write!(f, "validating"),
+            JobStatus::Queued => write!(f, "queued"),
+            JobStatus::Processing => write!(f, "processing"),
+            JobStatus::Done => write!(f, "done"),
+            JobStatus::Failed { reason } => write!(f, "failed: {}", reason),
+            JobStatus::Rejected { reason } => write!(f, "rejected: {}", reason),
+            JobStatus::Cancelled => write!(f, "cancelled"),
+        }
+    }
+}
+
+impl JobStatus {
+    /// stage without details, e.g. `failed`
+    pub fn name(&self) -> &'static str {
+        match self {
+            JobStatus::Validating => "validating",
+            JobStatus::Queued => "queued",
+            JobStatus::Processing => "processing",
+            JobStatus::Done => "done",
+            JobStatus::Failed { .. } => "failed",
+            JobStatus::Rejected { .. } => "rejected",
+            JobStatus::Cancelled => "cancelled",
+        }
+    }
+
+    /// job is waiting for validation, compression or is being compressed
+    pub fn is_active(&self) -> bool {
+        matches!(self, JobStatus::Validating | JobStatus::Queued | JobStatus::Processing)
+    }
+}
+
+/// Job as it is shown to admin clients
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct JobInfo {
+    pub filename: String,
+    pub status: String,
+}
+
+/// Statuses of all jobs since start of the service
+#[derive(Clone, Default)]
+pub struct Jobs {
+    statuses: Arc<Mutex<HashMap<VideoKey, JobStatus>>>,
+}
+
+impl Jobs {
+    pub fn new() -> Jobs {
+        Jobs::default()
+    }
+
+    pub fn set(&self, key: &VideoKey, status: JobStatus) {
+        self.statuses.lock().unwrap().insert(key.clone(), status);
+    }
+
+    pub fn get(&self, key: &VideoKey) -> Option<JobStatus> {
+        self.statuses.lock().unwrap().get(key).cloned()
+    }
+
+    /// move job to the next stage unless it was cancelled, returns `false` if it was
+    pub fn advance(&self, key: &VideoKey, status: JobStatus) -> bool {
+        let mut statuses = self.statuses.lock().unwrap();
+        if statuses.get(key) == Some(&JobStatus::Cancelled) {
+            return false;
+        }
+        statuses.insert(key.clone(), status);
+        true
+    }
+
+    /// move a queued job to processing, returns `false` if it is not queued anymore,
+    /// e.g. it was cancelled or a requeued copy of it was already taken from the queue
+    pub fn start(&self, key: &VideoKey) -> bool {
+        let mut statuses = self.statuses.lock().unwrap();
+        if statuses.get(key) != Some(&JobStatus::Queued) {
+            return false;
+        }
+        statuses.insert(key.clone(), JobStatus::Processing);
+        true
+    }
+
+    /// cancel job that is not compressed yet
+    pub fn cancel(&self, key: &VideoKey) -> std::result::Result<(), String> {
+        let mut statuses = self.statuses.lock().unwrap();
+        match statuses.get(key) {
+            Some(JobStatus::Validating) | Some(JobStatus::Queued) => {
+                statuses.insert(key.clone(), JobStatus::Cancelled);
+                Ok(())
+            },
+            Some(status) => Err(format!("job can not be cancelled, it is {}", status)),
+            None => Err(UNKNOWN_VIDEO.to_string()),
+        }
+    }
+
+    /// forget finished job
+    pub fn remove(&self, key: &VideoKey) {
+        self.statuses.lock().unwrap().remove(key);
+    }
+
+    /// all known jobs sorted by key
+    pub fn list(&self) -> Vec<(VideoKey, JobStatus)> {
+        let mut jobs = self.statuses.lock().unwrap()
+            .iter()
+            .map(|(key, status)| (key.clone(), status.clone()))
+            .collect::<Vec<(VideoKey, JobStatus)>>();
+        jobs.sort_by(|a, b| a.0.cmp(&b.0));
+        jobs
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key() -> VideoKey {
+        VideoKey::parse("a.mp4").unwrap()
+    }
+
+    #[test]
+    fn cancelled_jobs_do_not_advance() {
+        let jobs = Jobs::new();
+        jobs.set(&key(), JobStatus::Validating);
+        jobs.cancel(&key()).unwrap();
+        assert!(!jobs.advance(&key(), JobStatus::Queued));
+        assert_eq!(jobs.get(&key()), Some(JobStatus::Cancelled));
+        assert!(jobs.cancel(&key()).is_err());
+    }
+
+    #[test]
+    fn only_queued_jobs_start() {
+        let jobs = Jobs::new();
+        assert!(!jobs.start(&key()));
+        jobs.set(&key(), JobStatus::Queued);
+        assert!(jobs.start(&key()));
+        assert_eq!(jobs.get(&key()), Some(JobStatus::Processing));
+        // the same video taken from the queue a second time
+        assert!(!jobs.start(&key()));
+        assert!(jobs.cancel(&key()).is_err());
+    }
+
+    #[test]
+    fn requeued_job_runs_once() {
+        let jobs = Jobs::new();
+        // queued, cancelled and requeued before the processing loop took the first entry
+        jobs.set(&key(), JobStatus::Queued);
+        jobs.cancel(&key()).unwrap();
+        jobs.set(&key(), JobStatus::Validating);
+        assert!(jobs.advance(&key(), JobStatus::Queued));
+        // the first entry runs the job, the second one finds it done
+        assert!(jobs.start(&key()));
+        jobs.set(&key(), JobStatus::Done);
+        assert!(!jobs.start(&key()));
+        assert_eq!(jobs.get(&key()), Some(JobStatus::Done));
+    }
+
+    #[test]
+    fn stale_entry_is_skipped_while_requeued_job_validates() {
+        let jobs = Jobs::new();
+        jobs.set(&key(), JobStatus::Queued);
+        jobs.cancel(&key()).unwrap();
+        jobs.set(&key(), JobStatus::Validating);
+        assert!(!jobs.start(&key()));
+        assert_eq!(jobs.get(&key()), Some(JobStatus::Validating));
+    }
+
+    #[test]
+    fn lists_jobs_by_key() {
+        let jobs = Jobs::new();
+        for name in &["b.mp4", "a.mp4"] {
+            jobs.set(&VideoKey::parse(name).unwrap(), JobStatus::Failed { reason: "ffmpeg".to_string() });
+        }
+        let list = jobs.list();
+        assert_eq!(list.iter().map(|(key, _)| key.as_str()).collect::<Vec<_>>(), vec!["a.mp4", "b.mp4"]);
+        assert_eq!(list[0].1.to_string(), "failed: ffmpeg");
+        assert_eq!(list[0].1.name(), "failed");
+    }
+}
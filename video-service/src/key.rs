@@ -0,0 +1,121 @@
+use {
+    std::{fmt, str::FromStr},
+};
+
+/// Maximum length of a video key in bytes
+pub const MAX_KEY_LEN: usize = 128;
+
+/// Validated name of a video
+/// only ASCII letters, digits, `.`, `_` and `-` are allowed,
+/// so a key can be safely used as a file name or an object name
+/// and never escapes the storage directory
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct VideoKey(String);
+
+impl VideoKey {
+    /// check the input and wrap it into a key
+    pub fn parse(input: &str) -> Result<VideoKey, String> {
+        if input.is_empty() {
+            return Err("filename must not be empty".to_string());
+        }
+        if input.len() > MAX_KEY_LEN {
+            return Err(format!("filename must not be longer than {} characters", MAX_KEY_LEN));
+        }
+        if input.starts_with('.') {
+            return Err("filename must not start with a dot".to_string());
+        }
+        if input.contains("..") {
+            return Err("filename must not contain `..`".to_string());
+        }
+        if let Some(c) = input.chars().find(|c| !(c.is_ascii_alphanumeric() || *c == '.' || *c == '_' || *c == '-')) {
+            return Err(format!("filename contains forbidden character {:?}", c));
+        }
+        Ok(VideoKey(input.to_string()))
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    /// part of the key after the last dot
+    pub fn extension(&self) -> Option<&str> {
+        self.0.rfind('.').map(|i| &self.0[i + 1..])
+    }
+
+    /// replace extension of the key or add it if the key has none
+    pub fn with_extension(&self, ext: &str) -> Result<VideoKey, String> {
+        let stem = match self.0.rfind('.') {
+            Some(i) => &self.0[..i],
+            None => &self.0,
+        };
+        VideoKey::parse(&format!("{}.{}", stem, ext))
+    }
+}
+
+impl FromStr for VideoKey {
+    type Err = String;
+
+    fn from_str(input: &str) -> Result<VideoKey, String> {
+        VideoKey::parse(input)
+    }
+}
+
+impl fmt::Display for VideoKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl AsRef<str> for VideoKey {
+    fn as_ref(&self) -> &str {
+        &self.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn accepts_plain_names() {
+        for input in &["1234567.mp4", "a", "clip_01-final.webm", "a.b.c", &"a".repeat(MAX_KEY_LEN)] {
+            assert_eq!(VideoKey::parse(input).unwrap().as_str(), *input);
+        }
+    }
+
+    #[test]
+    fn refuses_traversal() {
+        for input in &["..", "../a.mp4", "a/../b.mp4", "a..mp4", ".hidden.mp4", "."] {
+            assert!(VideoKey::parse(input).is_err(), "{:?} must be refused", input);
+        }
+    }
+
+    #[test]
+    fn refuses_separators_and_absolute_paths() {
+        for input in &["/etc/passwd", "/a.mp4", "a/b.mp4", "a/", "\\a.mp4", "a\\b.mp4", "C:\\a.mp4", "c:a.mp4"] {
+            assert!(VideoKey::parse(input).is_err(), "{:?} must be refused", input);
+        }
+    }
+
+    #[test]
+    fn refuses_control_and_other_characters() {
+        for input in &["a\0.mp4", "\0", "a b.mp4", "a\n.mp4", "a%2f.mp4", "видео.mp4"] {
+            assert!(VideoKey::parse(input).is_err(), "{:?} must be refused", input);
+        }
+    }
+
+    #[test]
+    fn refuses_empty_and_overlong_names() {
+        assert_eq!(VideoKey::parse(""), Err("filename must not be empty".to_string()));
+        assert!(VideoKey::parse(&"a".repeat(MAX_KEY_LEN + 1)).is_err());
+    }
+
+    #[test]
+    fn replaces_extensions() {
+        let key = VideoKey::parse("1234567.mov").unwrap();
+        assert_eq!(key.extension(), Some("mov"));
+        assert_eq!(key.with_extension("mp4").unwrap().as_str(), "1234567.mp4");
+        assert_eq!(VideoKey::parse("clip").unwrap().with_extension("mp4").unwrap().as_str(), "clip.mp4");
+        assert!(key.with_extension("../mp4").is_err());
+    }
+}
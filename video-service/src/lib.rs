@@ -0,0 +1,26 @@
+#![warn(rust_2018_idioms)]
+pub mod auth;
+pub mod callbacks;
+pub mod client;
+pub mod config;
+pub mod events;
+pub mod health;
+pub mod http;
+pub mod jobs;
+pub mod key;
+pub mod limits;
+pub mod metrics;
+pub mod net;
+pub mod probe;
+pub mod sniff;
+pub mod storage;
+pub mod timeouts;
+pub mod processing;
+pub mod ratelimit;
+pub mod recovery;
+pub mod sendfile;
+pub mod replication;
+pub mod shutdown;
+pub mod tls;
+pub mod trace;
+pub mod validation;
@@ -0,0 +1,169 @@
+use {
+    std::{fmt, io, path::{Path, PathBuf}},
+    crate::config::LimitsConfig,
+};
+
+// free space is checked again every time this amount of bytes is received
+const DISK_CHECK_INTERVAL: u64 = 67108864; // 64 Mb
+
+/// Free and total space of the disk where uploads are stored
+#[derive(Debug, Clone, Copy)]
+pub struct DiskUsage {
+    pub free: u64,
+    pub total: u64,
+}
+
+impl DiskUsage {
+    /// read usage of the disk that holds `path`
+    pub fn of<P: AsRef<Path>>(path: P) -> io::Result<DiskUsage> {
+        Ok(DiskUsage {
+            free: fs2::available_space(&path)?,
+            total: fs2::total_space(&path)?,
+        })
+    }
+}
+
+impl fmt::Display for DiskUsage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} of {} free", human_size(self.free), human_size(self.total))
+    }
+}
+
+/// Rules every upload has to satisfy
+#[derive(Debug, Clone)]
+pub struct UploadLimits {
+    /// directory where uploads are stored while they wait for compression
+    pub dir: PathBuf,
+    /// maximum size of a single upload, unlimited if `None`
+    pub max_upload_size: Option<u64>,
+    /// uploads are refused when the disk has less free space than this
+    pub min_free_space: u64,
+}
+
+impl UploadLimits {
+    /// limits of uploads stored in `dir`
+    pub fn from_config<P: Into<PathBuf>>(dir: P, config: &LimitsConfig) -> UploadLimits {
+        UploadLimits {
+            dir: dir.into(),
+            max_upload_size: config.max_upload_size,
+            min_free_space: config.min_free_space,
+        }
+    }
+
+    /// current usage of the disk with uploads
+    pub fn usage(&self) -> io::Result<DiskUsage> {
+        DiskUsage::of(&self.dir)
+    }
+
+    /// decide whether a new upload can start
+    /// `size` is the size declared by the client if it is known
+    pub fn admit(&self, size: Option<u64>) -> std::result::Result<(), String> {
+        if let (Some(size), Some(max)) = (size, self.max_upload_size) {
+            if size > max {
+                return Err(too_large(max));
+            }
+        }
+        let usage = self.usage().map_err(|e| format!("can not check free disk space: {}", e))?;
+        // size comes from the client, so it must not overflow
+        if usage.free < self.min_free_space.saturating_add(size.unwrap_or(0)) {
+            return Err(no_space(usage));
+        }
+        Ok(())
+    }
+
+    /// check an upload that is being received, `received` is the amount of bytes so far
+    /// `checked` is the amount of bytes at the time of the previous disk check
+    pub fn check_received(&self, received: u64, checked: &mut u64) -> io::Result<()> {
+        if let Some(max) = self.max_upload_size {
+            if received > max {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, too_large(max)));
+            }
+        }
+        if received - *checked >= DISK_CHECK_INTERVAL {
+            *checked = received;
+            let usage = self.usage()?;
+            if usage.free < self.min_free_space {
+                return Err(io::Error::other(no_space(usage)));
+            }
+        }
+        Ok(())
+    }
+}
+
+fn too_large(max: u64) -> String {
+    format!("upload is too large: maximum size is {}", human_size(max))
+}
+
+fn no_space(usage: DiskUsage) -> String {
+    format!("not enough disk space: {}", usage)
+}
+
+// format amount of bytes for humans
+fn human_size(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} {}", bytes, UNITS[0])
+    } else {
+        format!("{:.1} {}", size, UNITS[unit])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limits(max_upload_size: Option<u64>, min_free_space: u64) -> UploadLimits {
+        UploadLimits { dir: std::env::temp_dir(), max_upload_size, min_free_space }
+    }
+
+    #[test]
+    fn admits_uploads_within_limits() {
+        assert_eq!(limits(None, 0).admit(None), Ok(()));
+        assert_eq!(limits(Some(100), 0).admit(Some(100)), Ok(()));
+        assert_eq!(limits(Some(100), 0).admit(None), Ok(()));
+    }
+
+    #[test]
+    fn refuses_declared_sizes_over_the_limits() {
+        assert_eq!(limits(Some(100), 0).admit(Some(101)), Err("upload is too large: maximum size is 100 B".to_string()));
+        // the declared size must fit on the disk too and can not overflow the sum
+        let e = limits(None, 1).admit(Some(u64::MAX)).unwrap_err();
+        assert!(e.starts_with("not enough disk space"), "{}", e);
+        assert!(limits(None, u64::MAX).admit(None).is_err());
+    }
+
+    #[test]
+    fn stops_uploads_that_grow_too_large() {
+        let limits = limits(Some(1000), 0);
+        let mut checked = 0;
+        assert!(limits.check_received(1000, &mut checked).is_ok());
+        let e = limits.check_received(1001, &mut checked).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn checks_the_disk_every_interval() {
+        let limits = limits(None, u64::MAX);
+        let mut checked = 0;
+        // the disk is not looked at between checks
+        assert!(limits.check_received(DISK_CHECK_INTERVAL - 1, &mut checked).is_ok());
+        assert_eq!(checked, 0);
+        assert!(limits.check_received(DISK_CHECK_INTERVAL, &mut checked).is_err());
+        assert_eq!(checked, DISK_CHECK_INTERVAL);
+        assert!(limits.check_received(DISK_CHECK_INTERVAL + 1, &mut checked).is_ok());
+    }
+
+    #[test]
+    fn formats_sizes_for_humans() {
+        assert_eq!(human_size(1023), "1023 B");
+        assert_eq!(human_size(1536), "1.5 KiB");
+        assert_eq!(human_size(5 << 30), "5.0 GiB");
+        assert_eq!(human_size(u64::MAX), "16777216.0 TiB");
+    }
+}
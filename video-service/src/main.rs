@@ -1,34 +1,107 @@
 #![warn(rust_2018_idioms)]
 use {
-    std::env,
-    std::process::{Command as OsCommand, Stdio},
-    tokio::net::TcpListener,
+    std::{env, iter, os::unix::io::RawFd, sync::Arc, time::Duration},
+    tokio::{io::AsyncWriteExt, time::timeout},
     tokio_util::codec::{Framed, BytesCodec, Decoder},
-    futures::{SinkExt, StreamExt, channel::mpsc, select, FutureExt},
+    futures::{future, lock::Mutex, pin_mut, stream, select, FutureExt, SinkExt, StreamExt},
     futures_util::stream::{SplitStream, SplitSink},
     bytes::{BytesMut, Bytes},
-    async_std::{fs::File, path::Path},
-    async_std::prelude::*,
+    video_service::{
+        auth::{Access, Auth, Grant, Need},
+        callbacks::{Callbacks, Target},
+        client::{Client, UNKNOWN_VIDEO},
+        config::{Args, Config},
+        events::{Event, Events},
+        key::VideoKey,
+        limits::UploadLimits,
+        http,
+        metrics::Metrics,
+        sniff::{Container, SNIFF_LEN},
+        jobs::{JobInfo, JobStatus, Jobs},
+        processing::{video_processing_loop, Queue},
+        recovery,
+        replication::{replication_loop, Replication},
+        sendfile,
+        shutdown::{self, Shutdown},
+        storage::{self, ByteRange, ByteStream, LocalStorage, ObjectInfo, Storage},
+        net::{Listener, Peer, Stream},
+        ratelimit::{ConnectionLimits, Throttled},
+        timeouts::Timeouts,
+        tls::{Acceptor, Connector},
+        trace,
+        validation::Validators,
+    },
+    tracing::{error, info, info_span, warn, Instrument},
 };
 
 // custom types to simplify code
-type FramedStream = Framed<tokio::net::TcpStream, tokio_util::codec::BytesCodec>;
+type FramedStream = Framed<Throttled<Stream>, tokio_util::codec::BytesCodec>;
 type Result<T> = std::result::Result<T, Box<dyn std::error::Error + Send + Sync>>;
-type ReadStream = SplitStream<Framed<tokio::net::TcpStream, tokio_util::codec::BytesCodec>>;
-type WriteStream = SplitSink<Framed<tokio::net::TcpStream, tokio_util::codec::BytesCodec>, Bytes>;
-type Sender<T> = mpsc::UnboundedSender<T>;
-type Receiver<T> = mpsc::UnboundedReceiver<T>;
+type ReadStream = SplitStream<Framed<Throttled<Stream>, tokio_util::codec::BytesCodec>>;
+type WriteStream = SplitSink<Framed<Throttled<Stream>, tokio_util::codec::BytesCodec>, Bytes>;
+
+// refused clients get this long to read the error before the connection is closed
+const REFUSAL_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Shared state every connection needs
+#[derive(Clone)]
+struct Context {
+    // uploads are validated and compressed in background
+    queue: Queue,
+    // local directory with uploads that wait for compression
+    staging: Arc<LocalStorage>,
+    // storage with compressed videos
+    dist: Arc<dyn Storage>,
+    limits: Arc<UploadLimits>,
+    // copies of committed videos on peers
+    replication: Replication,
+    // keys clients authenticate with
+    auth: Auth,
+    // slow clients are dropped
+    timeouts: Timeouts,
+    // downloads may bypass the service with `sendfile`
+    sendfile: bool,
+}
+
+/// State of a connection that lives from one request to the next
+struct Session {
+    peer: Peer,
+    // every response ends with a new line and `GET` tells the length of the content
+    keep_alive: bool,
+    // key and challenge sent in answer to `AUTH`, `PROOF` must follow
+    challenge: Option<(String, String)>,
+    // key the client proved to know the secret of
+    grant: Option<Grant>,
+}
 
 /// Possible requests our clients can send us
 enum Request {
-    Upload { filename: String },
-    Get { filename: String },
+    Upload { key: VideoKey, size: Option<u64>, callback: Option<Target> },
+    // already compressed video that goes straight into the storage,
+    // copies sent by the primary overwrite older ones and are not replicated further
+    Put { key: VideoKey, replica: bool },
+    Get { key: VideoKey, range: Option<ByteRange> },
+    Status { key: VideoKey },
+    Subscribe,
+    Ping,
+    // admin commands
+    List { prefix: String },
+    Stat { key: VideoKey },
+    // a replica delete removes only the local copy, the primary removes copies on its peers
+    Delete { key: VideoKey, replica: bool },
+    Queue,
+    Requeue { key: VideoKey },
+    Cancel { key: VideoKey },
+    Stats,
+    Replication,
     None,
 }
 
 /// Possible response to our client
 enum Command {
     Ok,
+    // OK followed by a value, e.g. final filename or MIME type
+    OkWith { msg: String },
     Err { msg: String },
 }
 
@@ -38,101 +111,508 @@ impl Request {
         let mut parts = input.splitn(2, " ");
         match parts.next() {
             Some("UPLOAD") => {
-                let filename = match parts.next() {
-                    Some(key) => key,
-                    None => return Err(format!("UPLOAD must be followed by a filename")),
+                // filename could be followed by a size of the file and a callback:
+                // `UPLOAD name 1048576 callback=http://host/video/callback`
+                let mut args = match parts.next() {
+                    Some(args) => args.split(' '),
+                    None => return Err("UPLOAD must be followed by a filename".to_string()),
                 };
+                let filename = args.next().unwrap_or_default();
+                let (mut size, mut callback) = (None, None);
+                for arg in args {
+                    if let Some(target) = arg.strip_prefix("callback=") {
+                        callback = Some(Target::parse(target)?);
+                    } else {
+                        size = Some(arg.parse::<u64>().map_err(|_| format!("invalid size: {}", arg))?);
+                    }
+                }
                 Ok(Request::Upload {
-                    filename: filename.to_string(),
+                    key: VideoKey::parse(filename)?,
+                    size,
+                    callback,
                 })
             }
             Some("GET") => {
-                let filename = match parts.next() {
-                    Some(key) => key,
-                    None => return Err(format!("GET must be followed by a filename")),
+                // filename could be followed by a range of bytes: `GET name 0-1023`
+                let mut args = match parts.next() {
+                    Some(args) => args.splitn(2, ' '),
+                    None => return Err("GET must be followed by a filename".to_string()),
+                };
+                let filename = args.next().unwrap_or_default();
+                let range = match args.next() {
+                    Some(range) => Some(ByteRange::parse(range)?),
+                    None => None,
                 };
                 Ok(Request::Get {
-                    filename: filename.to_string(),
+                    key: VideoKey::parse(filename)?,
+                    range,
                 })
             }
+            // `PUT <filename>` is used when videos move between nodes,
+            // `PUT <filename> replica` when the primary sends a copy
+            Some("PUT") => {
+                let mut args = parts.next().unwrap_or_default().splitn(2, ' ');
+                let key = parse_key("PUT", args.next().filter(|filename| !filename.is_empty()))?;
+                let replica = match args.next() {
+                    Some("replica") => true,
+                    Some(arg) => return Err(format!("unexpected argument: {}", arg)),
+                    None => false,
+                };
+                Ok(Request::Put { key, replica })
+            }
+            Some("STATUS") => Ok(Request::Status { key: parse_key("STATUS", parts.next())? }),
+            Some("SUBSCRIBE") => Ok(Request::Subscribe),
+            Some("PING") => Ok(Request::Ping),
+            // `LIST` or `LIST <prefix>`
+            Some("LIST") => Ok(Request::List { prefix: parts.next().unwrap_or_default().trim().to_string() }),
+            Some("STAT") => Ok(Request::Stat { key: parse_key("STAT", parts.next())? }),
+            // `DELETE <filename> replica` when the primary removes its copies or a video moves away
+            Some("DELETE") => {
+                let mut args = parts.next().unwrap_or_default().splitn(2, ' ');
+                let key = parse_key("DELETE", args.next().filter(|filename| !filename.is_empty()))?;
+                let replica = match args.next() {
+                    Some("replica") => true,
+                    Some(arg) => return Err(format!("unexpected argument: {}", arg)),
+                    None => false,
+                };
+                Ok(Request::Delete { key, replica })
+            }
+            Some("QUEUE") => Ok(Request::Queue),
+            Some("REQUEUE") => Ok(Request::Requeue { key: parse_key("REQUEUE", parts.next())? }),
+            Some("CANCEL") => Ok(Request::Cancel { key: parse_key("CANCEL", parts.next())? }),
+            Some("STATS") => Ok(Request::Stats),
+            Some("REPLICATION") => Ok(Request::Replication),
             Some(cmd) => Err(format!("unknown command: {}", cmd)),
-            None => Err(format!("empty input")),
+            None => Err("empty input".to_string()),
         }
     }
 }
 
+impl Request {
+    /// what the request needs from the key of the client, `None` if anyone may send it
+    fn need(&self) -> Option<Need<'_>> {
+        let (access, name) = match self {
+            Request::Upload { key, .. } | Request::Put { key, .. } | Request::Delete { key, .. }
+            | Request::Requeue { key } | Request::Cancel { key } => (Access::ReadWrite, Some(key.as_str())),
+            Request::Get { key, .. } | Request::Status { key } | Request::Stat { key } => (Access::ReadOnly, Some(key.as_str())),
+            Request::List { prefix } => (Access::ReadOnly, Some(prefix.as_str())),
+            Request::Subscribe | Request::Queue | Request::Stats | Request::Replication => (Access::ReadOnly, None),
+            Request::Ping | Request::None => return None,
+        };
+        // stored bytes skip sniffing and validators, so only nodes that hold a key may send them
+        let key_only = matches!(self, Request::Put { .. });
+        Some(Need { access, name, key_only })
+    }
+}
+
+// filename that must follow the command, e.g. `STAT 1234567.mp4`
+fn parse_key(cmd: &str, filename: Option<&str>) -> std::result::Result<VideoKey, String> {
+    match filename {
+        Some(filename) => VideoKey::parse(filename),
+        None => Err(format!("{} must be followed by a filename", cmd)),
+    }
+}
+
 #[tokio::main]
 async fn main() -> Result<()> {
-    // Allow passing an address to listen on as the first argument of this
-    // program, but otherwise we'll just set up our TCP listener on
-    // 127.0.0.1:8091 for connections.
-    let addr = env::args().nth(1).unwrap_or("127.0.0.1:8091".to_string());
-    // Next up we create a TCP listener which will listen for incoming
-    // connections. This TCP listener is bound to the address we determined
-    // above and must be associated with an event loop.
-    let mut listener = TcpListener::bind(&addr).await?;
-    println!("Listening on: {}", addr);
-
-    // create directory where to store temp videos before compressing
-    std::fs::create_dir_all("./tmp")?;
-    // create directory where to store compressed videos
-    std::fs::create_dir_all("./dist")?;
+    // settings come from defaults, a TOML file, env variables and command line flags,
+    // the address to listen on can still be passed as the first argument
+    let args = Args::parse(env::args().skip(1))?;
+    let config = Config::load(&args)?;
+    if args.print_config {
+        print!("{}", config.to_toml()?);
+        return Ok(());
+    }
+    let addr = config.addr.clone();
+    trace::init();
+    // Next up we create listeners for incoming connections, on TCP addresses and Unix sockets,
+    // sockets passed by systemd are used instead of configured addresses
+    let mut listeners = Listener::inherited()?;
+    if listeners.is_empty() {
+        let mode = config.socket_mode().unwrap_or(0o660);
+        for addr in iter::once(&config.addr).chain(&config.listen) {
+            listeners.push(Listener::bind(addr, mode).await?);
+        }
+    }
+    let addrs = listeners.iter().map(Listener::local_addr).collect::<Vec<String>>();
+    // with TLS only clients and peers with a certificate signed by our CA are served
+    let (acceptor, connector) = if config.tls.enabled() {
+        (Some(Acceptor::new(&config.tls)?), Some(Connector::new(&config.tls)?))
+    } else {
+        (None, None)
+    };
+    // clients must prove they know the secret of a key before other requests than `PING`
+    let auth = Auth::new(config.auth.keys.clone());
+    info!(?addrs, tls = acceptor.is_some(), auth = auth.enabled(), "listening");
+
+    // local directory where to store temp videos before compressing,
+    // ffmpeg needs real files, so it is always on the local disk
+    let staging = Arc::new(LocalStorage::new(&config.staging_dir)?);
+    // storage where to keep compressed videos
+    let dist = storage::from_config(&config.storage)?;
+    // rules for incomming uploads
+    let limits = Arc::new(UploadLimits::from_config(&config.staging_dir, &config.limits));
+    // how long to wait for uploads, downloads and processing on shutdown
+    let shutdown_timeout = Duration::from_secs(config.shutdown_timeout);
+    info!(usage = %limits.usage()?, "disk usage");
+
+    // clean up after the previous run and find videos that still wait for compression
+    let report = recovery::recover(&staging, dist.as_ref()).await?;
+    info!("{}", report);
+
+    // checks every upload has to pass before compression
+    let validators = Validators::parse(&config.validators)?;
+
+    // committed videos are copied to peers in background
+    // peers check our certificate and key like any other client
+    let mut peer_client = Client::new(&addr);
+    if let Some(connector) = connector {
+        peer_client = peer_client.with_tls(connector);
+    }
+    if !config.auth.peer_key.is_empty() {
+        peer_client = peer_client.with_auth(&config.auth.peer_key, &config.auth.peer_secret);
+    } else if !config.replication.peers.is_empty() {
+        warn!("auth.peer_key is not set, peers refuse copies from clients without a key");
+    }
+    let (replication, replication_receiver) = Replication::new(
+        config.replication.peers.clone(),
+        peer_client,
+        config.replication.deletes_file.clone(),
+    )?;
+    // videos stored by recovery did not reach their peers before the crash
+    for key in &report.promoted {
+        replication.replicate(key);
+    }
 
     // create video processing queue
-    let (video_sender, video_receiver) = mpsc::unbounded();
-    tokio::spawn(video_processing_loop(video_receiver));
+    let (queue, video_receiver) = Queue::new(
+        staging.clone(),
+        validators,
+        Jobs::new(),
+        Callbacks::new(config.callback_secret.as_bytes(), &config.callback_hosts),
+        Events::new(),
+        Metrics::new(),
+        Shutdown::new(),
+    );
+    for key in report.requeued {
+        queue.submit(key);
+    }
+    tokio::spawn(video_processing_loop(
+        video_receiver,
+        queue.clone(),
+        dist.clone(),
+        config.compression.clone(),
+        replication.clone(),
+    ));
+    let retry = Duration::from_secs(config.replication.retry_interval);
+    tokio::spawn(replication_loop(replication_receiver, replication.clone(), dist.clone(), retry));
+
+    // Prometheus metrics and health checks are served over HTTP next to the main port
+    let http_addr = config.http_addr.clone();
+    let http_server = http::serve(http_addr.parse()?, queue.clone(), limits.clone());
+    tokio::spawn(async move {
+        if let Err(e) = http_server.await {
+            error!(error = %e, "error serving HTTP");
+        }
+    });
+    info!(addr = %http_addr, "serving /metrics and /health");
 
+    let timeouts = Timeouts::from_config(&config.timeouts);
+    let sendfile = config.sendfile && sendfile::supported();
+    let ctx = Context { queue, staging, dist, limits, replication, auth, timeouts, sendfile };
+    // caps of open connections, bandwidth is limited for every connection on its own
+    let connections = ConnectionLimits::from_config(&config.limits);
+    let (upload_rate, download_rate) = (config.limits.upload_rate, config.limits.download_rate);
+
+    // stop accepting connections on SIGTERM or Ctrl-C
+    let stop = shutdown::signal().fuse();
+    pin_mut!(stop);
+    // connections of all listeners in a single stream
+    let mut incoming = stream::select_all(listeners.into_iter().map(|listener| {
+        Box::pin(stream::unfold(listener, |mut listener| async move {
+            let accepted = listener.accept().await;
+            Some((accepted, listener))
+        }))
+    }));
     loop {
-        match listener.accept().await {
-            Ok((socket, _)) => {
+        let accepted = select! {
+            accepted = incoming.select_next_some() => accepted,
+            result = stop => {
+                result?;
+                break;
+            },
+        };
+        match accepted {
+            Ok((socket, peer)) => {
                 // We'll `spawn` this client to ensure it
                 // runs concurrently with all other clients. The `move` keyword is used
                 // here to move ownership into the async closure.
-                let video_sender = video_sender.clone();
+                let ctx = ctx.clone();
+                let connection = ctx.queue.metrics().connection_opened();
+                let acceptor = acceptor.clone();
+                // the place is taken before the task starts, so a burst of connections can not get past the caps
+                let slot = connections.acquire(peer.ip());
                 tokio::spawn(async move {
+                    // connection is counted until this task ends
+                    let _connection = connection;
+                    let socket = match (acceptor, socket) {
+                        (Some(acceptor), Stream::Tcp(socket)) => match acceptor.accept(socket).await {
+                            Ok(stream) => stream,
+                            Err(e) => {
+                                warn!(%peer, error = %e, "TLS handshake failed");
+                                return;
+                            },
+                        },
+                        // permissions of the socket file protect Unix sockets instead of TLS
+                        (_, socket) => socket,
+                    };
                     // We're parsing each socket with the `BytesCodec`
-                    let framed = BytesCodec::new().framed(socket);
+                    let framed = BytesCodec::new().framed(Throttled::new(socket, upload_rate, download_rate));
+                    // the place is given back when this task ends
+                    let _slot = match slot {
+                        Ok(slot) => slot,
+                        Err(reason) => {
+                            warn!(%peer, %reason, "connection refused");
+                            ctx.queue.metrics().failure("connection_limit");
+                            if let Err(e) = refuse(framed, reason).await {
+                                warn!(%peer, error = %e, "error refusing connection");
+                            }
+                            return;
+                        },
+                    };
                     // handle request and errors
-                    handle_request(framed, video_sender).await.unwrap();
+                    if let Err(e) = handle_request(framed, peer, ctx).await {
+                        warn!(%peer, error = %e, "error handling request");
+                    }
 
                     // The connection will be closed at this point as `framed.next()` has returned `None`.
                 });
             },
-            Err(e) => println!("error accepting socket; error = {:?}", e),
+            Err(e) => error!(error = %e, "error accepting socket"),
         }
     }
+    drop(incoming);
+
+    // let work in progress finish, videos that wait in staging are requeued on the next start
+    let shutdown = ctx.queue.shutdown();
+    shutdown.stop();
+    info!(in_progress = ?shutdown.active(), timeout = ?shutdown_timeout, "shutting down");
+    for what in shutdown.drain(shutdown_timeout).await {
+        warn!(%what, "interrupted by shutdown");
+    }
+    let queued = ctx.queue.jobs().list()
+        .into_iter()
+        .filter(|(_, status)| *status == JobStatus::Queued)
+        .map(|(key, _)| key.to_string())
+        .collect::<Vec<String>>();
+    if !queued.is_empty() {
+        info!(videos = ?queued, "queued videos are left for the next start");
+    }
+    info!("stopped");
+    Ok(())
 }
 
 // handle incomming request
-async fn handle_request(mut framed: FramedStream, video_sender: Sender<String>) -> Result<()> {
-    // get response details
-    let request_details = get_request_details(&mut framed).await.unwrap_or(BytesMut::new());
-    let request_line = std::str::from_utf8(&request_details)?;
+// a connection serves a single request unless the client switches it into keep-alive mode,
+// `AUTH` and `PROOF` come before the request and do not close it
+async fn handle_request(mut framed: FramedStream, peer: Peer, ctx: Context) -> Result<()> {
+    let mut session = Session { peer, keep_alive: false, challenge: None, grant: None };
+    // the first request must come soon, later ones may wait as long as an idle connection
+    let mut first = true;
+    loop {
+        let wait = if first { ctx.timeouts.request } else { ctx.timeouts.idle };
+        // get response details
+        let next = match timeout(wait, get_request_details(&mut framed)).await {
+            Ok(next) => next,
+            Err(_) if !first => {
+                info!(peer = %session.peer, "idle connection closed");
+                return Ok(());
+            },
+            Err(_) => {
+                warn!(peer = %session.peer, timeout = ?wait, "no request in time");
+                let cmd = Command::Err{msg: "request timeout".to_string()};
+                framed.send(Bytes::from(encode_response(cmd, session.keep_alive))).await.ok();
+                return Ok(());
+            },
+        };
+        first = false;
+        let request_details = match next {
+            Ok(details) => details,
+            // client closed a kept-alive or authenticated connection between requests
+            Err(_) if session.keep_alive || session.challenge.is_some() || session.grant.is_some() => return Ok(()),
+            Err(_) => BytesMut::new(),
+        };
+        let request_line = std::str::from_utf8(&request_details)?.trim_end_matches(&['\r', '\n'][..]);
+        // every log line of the request carries ID the client sent or a new one
+        let (request_id, request_line) = trace::split_request_id(request_line);
+        let request_id = request_id.map_or_else(trace::new_request_id, str::to_string);
+        let span = info_span!("request", request_id = %request_id);
+        if request_line == "KEEPALIVE" {
+            // from now on every response ends with a new line and `GET` tells the length of the content
+            session.keep_alive = true;
+            framed.send(Bytes::from_static(b"OK\n")).await?;
+            continue;
+        }
+        if request_line.starts_with("AUTH ") || request_line.starts_with("PROOF ") {
+            let (cmd, close) = match span.in_scope(|| authenticate(request_line, &mut session, &ctx.auth)) {
+                Ok(cmd) => (cmd, false),
+                Err(cmd) => (cmd, true),
+            };
+            framed.send(Bytes::from(encode_response(cmd, session.keep_alive))).await?;
+            if close {
+                return Ok(());
+            }
+            continue;
+        }
+        framed = match dispatch_request(request_line, framed, ctx.clone(), &session).instrument(span).await? {
+            Some(framed) if session.keep_alive && !ctx.queue.shutdown().is_stopping() => framed,
+            _ => return Ok(()),
+        };
+    }
+}
+
+// tell a client over the limits why it is refused instead of resetting the connection
+async fn refuse(mut framed: FramedStream, reason: String) -> Result<()> {
+    framed.send(Bytes::from(encode_response(Command::Err{msg: reason}, false))).await?;
+    framed.get_mut().shutdown().await?;
+    // closing with an unread request resets the connection and the client could lose the error
+    let drain = async {
+        while let Some(Ok(_)) = framed.next().await {}
+    };
+    timeout(REFUSAL_TIMEOUT, drain).await.ok();
+    Ok(())
+}
+
+// tell a client why its upload is refused while it could still be sending, like `refuse` does
+async fn refuse_upload(mut ws: WriteStream, rs: &Mutex<ReadStream>, reason: String) -> Result<()> {
+    send_cmd(&mut ws, Command::Err{msg: reason}).await?;
+    // closes the writing half of the connection
+    ws.close().await?;
+    let drain = async {
+        let mut rs = rs.lock().await;
+        while let Some(Ok(_)) = rs.next().await {}
+    };
+    timeout(REFUSAL_TIMEOUT, drain).await.ok();
+    Ok(())
+}
+
+// answer `AUTH <key>` with a challenge and `PROOF <signature>` with `OK`,
+// the error of a wrong proof closes the connection
+fn authenticate(request_line: &str, session: &mut Session, auth: &Auth) -> std::result::Result<Command, Command> {
+    if let Some(key) = request_line.strip_prefix("AUTH ") {
+        // unknown keys get a challenge too, so clients can not find out which keys exist
+        let challenge = auth.challenge();
+        session.challenge = Some((key.trim().to_string(), challenge.clone()));
+        return Ok(Command::OkWith{msg: challenge});
+    }
+    let signature = request_line.trim_start_matches("PROOF ").trim();
+    let (key, challenge) = match session.challenge.take() {
+        Some(pending) => pending,
+        None => return Err(Command::Err{msg: "PROOF must follow AUTH".to_string()}),
+    };
+    // without keys every request is allowed, clients with a key still get through
+    // so they can be configured before the nodes
+    if !auth.enabled() {
+        return Ok(Command::Ok);
+    }
+    match auth.verify(&key, &challenge, signature) {
+        Ok(grant) => {
+            info!(key = %grant.key, "client authenticated");
+            session.grant = Some(grant);
+            Ok(Command::Ok)
+        },
+        Err(reason) => {
+            warn!(target: "audit", peer = %session.peer, %key, %reason, "authentication failed");
+            Err(Command::Err{msg: "access denied".to_string()})
+        },
+    }
+}
 
+// parse request command and pass it to the handler
+// returns the connection if the request left it usable for the next one
+async fn dispatch_request(request_line: &str, framed: FramedStream, ctx: Context, session: &Session) -> Result<Option<FramedStream>> {
+    let keep_alive = session.keep_alive;
+    let direct = direct_fd(&framed);
     // split framed stream into read/write streams
     let (mut ws, rs) = framed.split();
 
     // parse request command
     let mut request = Request::None;
-    match Request::parse(&request_line) {
-        Ok(req) => { request = req; },
+    match Request::parse(request_line) {
+        Ok(req) => {
+            info!(request = request_line, "request received");
+            request = req;
+        },
         Err(e) => { 
-            println!("error parsing request; error = {:?}", e);
-            send_cmd(&mut ws, Command::Err{msg: e}).await?;
+            warn!(request = request_line, error = %e, "error parsing request");
+            send_response(&mut ws, Command::Err{msg: e}, keep_alive).await?;
         },
     };
 
+    // requests the key of the client does not allow are refused and kept in the audit log
+    let denied = request.need().and_then(|need| ctx.auth.authorize(session.grant.as_ref(), &need).err());
+    if let Some(reason) = denied {
+        let key = session.grant.as_ref().map_or("-", |grant| grant.key.as_str());
+        warn!(target: "audit", peer = %session.peer, key, request = request_line, %reason, "access denied");
+        let msg = if session.grant.is_some() { "access denied" } else { "authentication required" };
+        send_response(&mut ws, Command::Err{msg: msg.to_string()}, keep_alive).await?;
+        request = Request::None;
+    }
+
     match request {
-        Request::Upload {filename} => {
-            upload_file(&filename, rs, ws, video_sender).await?;
+        // these commands read until the client closes its half of the connection
+        Request::Upload {..} | Request::Put {..} | Request::Subscribe if keep_alive => {
+            let msg = "command needs its own connection".to_string();
+            send_response(&mut ws, Command::Err{msg}, keep_alive).await?;
+        },
+        Request::Upload {key, size, callback} => {
+            let _guard = ctx.queue.shutdown().track(format!("upload of {}", key));
+            upload_file(key, size, callback, rs, ws, ctx).await?;
+            return Ok(None);
+        },
+        Request::Put {key, replica} => {
+            let _guard = ctx.queue.shutdown().track(format!("store of {}", key));
+            store_file(key, replica, rs, ws, ctx).await?;
+            return Ok(None);
         },
-        Request::Get {filename} => {
-            send_file(&filename, ws).await?;
+        Request::Get {key, range} => {
+            let _guard = ctx.queue.shutdown().track(format!("download of {}", key));
+            send_file(&key, range, &mut ws, direct, ctx, keep_alive).await?;
         },
-        Request::None => {unimplemented!()}
+        Request::Status {key} => {
+            send_status(&key, &mut ws, ctx, keep_alive).await?;
+        },
+        Request::Subscribe => {
+            send_events(rs, ws, ctx).await?;
+            return Ok(None);
+        },
+        Request::Ping => {
+            send_response(&mut ws, Command::OkWith{msg: "PONG".to_string()}, keep_alive).await?;
+        },
+        Request::List {prefix} => reply(&mut ws, list_videos(&prefix, &ctx).await, keep_alive).await?,
+        Request::Stat {key} => reply(&mut ws, stat_video(&key, &ctx).await, keep_alive).await?,
+        Request::Delete {key, replica} => reply(&mut ws, delete_video(&key, replica, &ctx).await, keep_alive).await?,
+        Request::Queue => reply(&mut ws, list_jobs(&ctx), keep_alive).await?,
+        Request::Requeue {key} => reply(&mut ws, requeue_video(key, &ctx).await, keep_alive).await?,
+        Request::Cancel {key} => reply(&mut ws, cancel_job(&key, &ctx), keep_alive).await?,
+        Request::Stats => reply(&mut ws, server_stats(&ctx), keep_alive).await?,
+        Request::Replication => reply(&mut ws, list_replicas(&ctx), keep_alive).await?,
+        // error was already sent to the client
+        Request::None => {}
     }
 
-    Ok(())
+    let framed = rs.reunite(ws).map_err(|_| "halves of the connection do not match")?;
+    Ok(Some(framed))
+}
+
+// descriptor downloads can be written to directly, unless TLS or throttling has to see every byte
+fn direct_fd(framed: &FramedStream) -> Option<RawFd> {
+    let stream = framed.get_ref();
+    if stream.unlimited_writes() {
+        stream.get_ref().plain_fd()
+    } else {
+        None
+    }
 }
 
 // read first bytes from stream of bytes
@@ -147,120 +627,555 @@ async fn get_request_details(framed: &mut FramedStream) -> Result<BytesMut> {
             },
         }
     }
-    Err(format!("nothing comes from the stream").into())
+    Err("nothing comes from the stream".into())
 }
 
 // create a file from incomming bytes
-async fn upload_file(filename: &str, mut rs: ReadStream, mut ws: WriteStream, mut video_sender: Sender<String>) -> Result<()> {
-    let filename = filename.to_owned();
-    let filepath = format!("./tmp/{}", &filename);
-    let dist_filepath = format!("./dist/{}", &filename);
-
-    if Path::new(&dist_filepath).exists().await {
-        let e = format!("file already exists");
+async fn upload_file(
+    key: VideoKey,
+    size: Option<u64>,
+    callback: Option<Target>,
+    rs: ReadStream,
+    mut ws: WriteStream,
+    ctx: Context,
+) -> Result<()> {
+    if ctx.dist.stat(key.as_str()).await?.is_some() {
+        let e = "file already exists".to_string();
         // send error back to the client
         send_cmd(&mut ws, Command::Err{msg: e}).await?;
         return Ok(());
     }
+    // clients may only ask for callbacks to configured hosts
+    if let Some(Err(e)) = callback.as_ref().map(|target| ctx.queue.callbacks().check(target)) {
+        info!(video = %key, error = %e, "upload refused");
+        send_cmd(&mut ws, Command::Err{msg: e}).await?;
+        return Ok(());
+    }
+    // refuse upload if it is too big or disk is almost full
+    if let Err(e) = ctx.limits.admit(size) {
+        info!(video = %key, error = %e, "upload refused");
+        ctx.queue.metrics().failure("upload");
+        send_cmd(&mut ws, Command::Err{msg: e}).await?;
+        return Ok(());
+    }
     // all is OK
     send_cmd(&mut ws, Command::Ok).await?;
-    // create temp file
-    let mut f = File::create(filepath).await?;
 
-    // We loop while there are messages coming from the Stream `framed`.
+    // We loop while there are messages coming from the Stream `rs`.
     // The stream will return None once the client disconnects.
-    while let Some(result) = rs.next().await {
-        match result {
-            Ok(bytes) => {
-                f = f.write_all(&bytes).await.map(|_| f)?;
+    // the read half is shared with the body, so a refused upload can still be drained
+    let rs = Arc::new(Mutex::new(rs));
+    let reader = stream::unfold(rs.clone(), |rs| async move {
+        let bytes = rs.lock().await.next().await;
+        bytes.map(|bytes| (bytes, rs))
+    });
+    let limits = ctx.limits.clone();
+    let metrics = ctx.queue.metrics().clone();
+    let (mut received, mut checked) = (0u64, 0u64);
+    // errors of the socket fail the upload, so a truncated file is never stored
+    let mut body: ByteStream = ctx.timeouts.guard(Box::pin(reader.map(move |bytes| {
+        // stop the upload as soon as it breaks the limits
+        let bytes = bytes?;
+        metrics.bytes_in(bytes.len() as u64);
+        received += bytes.len() as u64;
+        limits.check_received(received, &mut checked)?;
+        Ok(bytes.freeze())
+    })));
+
+    // look at the first bytes to make sure it is a video before storing anything
+    let mut head = BytesMut::new();
+    while head.len() < SNIFF_LEN {
+        match body.next().await {
+            Some(Ok(bytes)) => head.extend_from_slice(&bytes),
+            Some(Err(e)) => {
+                warn!(video = %key, error = %e, "upload failed");
+                ctx.queue.metrics().failure("upload");
+                refuse_upload(ws, &rs, e.to_string()).await.ok();
+                return Ok(());
             },
-            Err(e) => println!("error on decoding from socket; error = {:?}", e),
+            None => break,
         }
     }
+    let container = match Container::detect(&head) {
+        Some(container) => container,
+        None => {
+            let e = "unsupported file type, only video files are accepted".to_string();
+            info!(video = %key, error = %e, "upload refused");
+            ctx.queue.metrics().failure("upload");
+            refuse_upload(ws, &rs, e).await.ok();
+            return Ok(());
+        },
+    };
+    // extension of the stored file always matches its content
+    let key = match key.with_extension(container.extension()) {
+        Ok(key) => key,
+        Err(e) => {
+            refuse_upload(ws, &rs, e).await.ok();
+            return Ok(());
+        },
+    };
+    if ctx.dist.stat(key.as_str()).await?.is_some() {
+        refuse_upload(ws, &rs, "file already exists".to_string()).await.ok();
+        return Ok(());
+    }
 
-    // push video filename to video processing queue
-    video_sender.send(filename).await?;
+    // store temp file, half-written file is removed on error
+    let body = stream::once(future::ready(Ok(head.freeze()))).chain(body);
+    let size = match ctx.staging.put(key.as_str(), Box::pin(body)).await {
+        Ok(size) => size,
+        Err(e) => {
+            warn!(video = %key, error = %e, "upload failed");
+            ctx.queue.metrics().failure("upload");
+            // client could be gone already
+            refuse_upload(ws, &rs, e.to_string()).await.ok();
+            return Ok(());
+        },
+    };
+    info!(video = %key, size, "upload stored");
+    ctx.queue.events().publish(Event::UploadReceived { filename: key.to_string(), size });
+
+    // validate video and push it to video processing queue
+    if let Some(callback) = callback {
+        ctx.queue.callbacks().register(&key, callback);
+    }
+    ctx.queue.submit(key.clone());
+    // confirm the upload with the final filename to clients that wait for it
+    send_cmd(&mut ws, Command::OkWith{msg: key.to_string()}).await.ok();
 
     Ok(())
 }
 
-// send file to the client
-async fn send_file(filename: &str, mut ws: WriteStream) -> Result<()> {
-    let filepath = format!("./dist/{}", filename);
-
-    if !Path::new(&filepath).exists().await {
-        let e = format!("file does not exist");
-        // send error back to the client
+// store a video that is already compressed without validation and compression
+async fn store_file(key: VideoKey, replica: bool, rs: ReadStream, mut ws: WriteStream, ctx: Context) -> Result<()> {
+    if !replica && ctx.dist.stat(key.as_str()).await?.is_some() {
+        send_cmd(&mut ws, Command::Err{msg: "file already exists".to_string()}).await?;
+        return Ok(());
+    }
+    // videos stored directly are held to the same limits as uploads
+    if let Err(e) = ctx.limits.admit(None) {
+        info!(video = %key, error = %e, "video refused");
+        ctx.queue.metrics().failure("upload");
         send_cmd(&mut ws, Command::Err{msg: e}).await?;
         return Ok(());
     }
-    // all is OK
     send_cmd(&mut ws, Command::Ok).await?;
 
-    let mut f = File::open(filepath).await?;
+    let limits = ctx.limits.clone();
+    let metrics = ctx.queue.metrics().clone();
+    let (mut received, mut checked) = (0u64, 0u64);
+    let body: ByteStream = ctx.timeouts.guard(Box::pin(rs.map(move |bytes| {
+        let bytes = bytes?;
+        metrics.bytes_in(bytes.len() as u64);
+        received += bytes.len() as u64;
+        limits.check_received(received, &mut checked)?;
+        Ok(bytes.freeze())
+    })));
+    // half-written object is never visible, storage removes it on error
+    match ctx.dist.put(key.as_str(), body).await {
+        Ok(size) => {
+            info!(video = %key, size, replica, "video stored");
+            if !replica {
+                ctx.replication.replicate(key.as_str());
+            }
+            send_cmd(&mut ws, Command::OkWith{msg: key.to_string()}).await.ok();
+        },
+        Err(e) => {
+            warn!(video = %key, error = %e, "error storing video");
+            ctx.queue.metrics().failure("upload");
+            send_cmd(&mut ws, Command::Err{msg: e.to_string()}).await.ok();
+        },
+    }
+    Ok(())
+}
+
+// send file to the client
+// in keep-alive mode the response tells the length of the content, so the connection can be used again
+async fn send_file(
+    key: &VideoKey,
+    range: Option<ByteRange>,
+    ws: &mut WriteStream,
+    direct: Option<RawFd>,
+    ctx: Context,
+    keep_alive: bool,
+) -> Result<()> {
+    let metadata = match ctx.dist.stat(key.as_str()).await? {
+        Some(metadata) => metadata,
+        None => {
+            let e = "file does not exist".to_string();
+            // send error back to the client
+            return send_response(ws, Command::Err{msg: e}, keep_alive).await;
+        },
+    };
+    // all is OK, tell the client what kind of video it is going to receive
+    let mime_type = key.extension()
+        .and_then(Container::from_extension)
+        .map_or("application/octet-stream", Container::mime_type);
+    let msg = if keep_alive {
+        format!("{} {}", mime_type, range.map_or(metadata.size, |range| range.len(metadata.size)))
+    } else {
+        mime_type.to_string()
+    };
+    // the line ends with a new line in both modes, so clients can tell it apart from the content
+    send_response(ws, Command::OkWith{msg}, true).await?;
+
+    // local files go from the page cache straight into a plain connection,
+    // the response line is already flushed, so nothing is buffered in front of them
+    if let (Some(socket), Some(path), true) = (direct, ctx.dist.local_path(key.as_str()), ctx.sendfile) {
+        let (offset, len) = range.map_or((0, metadata.size), |range| (range.start, range.len(metadata.size)));
+        let metrics = ctx.queue.metrics().clone();
+        sendfile::send(socket, &path, offset, len, ctx.timeouts.idle, |n| metrics.bytes_out(n)).await?;
+        return Ok(());
+    }
 
-    const LEN: usize = 1572864; // 1.5 Mb  // 8388608; // 8 and something Mb
-    let mut buf = vec![0u8; LEN];
-    // iterate over the file, pass bytes into buffer and then flush buffer to client
-    loop {  
-        // Read a buffer from the file.
-        let n = f.read(&mut buf).await?;
+    // iterate over the file and flush chunks of bytes to the client
+    let mut chunks = ctx.dist.get(key.as_str(), range).await?;
+    while let Some(bytes) = chunks.next().await {
+        let bytes = bytes?;
+        let len = bytes.len() as u64;
+        // Write the buffer into stream, a client that stops reading does not hold the file open
+        timeout(ctx.timeouts.idle, ws.send(bytes)).await
+            .map_err(|_| format!("client did not read for {} seconds", ctx.timeouts.idle.as_secs()))??;
+        ctx.queue.metrics().bytes_out(len);
+    }
+    Ok(())
+}
 
-        // If this is the end of file, clean up and return.
-        if n == 0 {
-            return Ok(());
+// send status of the job that processes the video
+async fn send_status(key: &VideoKey, ws: &mut WriteStream, ctx: Context, keep_alive: bool) -> Result<()> {
+    let status = match ctx.queue.jobs().get(key) {
+        Some(status) => Some(status),
+        // video could be processed before restart
+        None if ctx.dist.stat(key.as_str()).await?.is_some() => Some(JobStatus::Done),
+        None => None,
+    };
+    match status {
+        Some(status) => send_response(ws, Command::OkWith{msg: status.to_string()}, keep_alive).await,
+        None => send_response(ws, Command::Err{msg: UNKNOWN_VIDEO.to_string()}, keep_alive).await,
+    }
+}
+
+// push events to the client until it disconnects
+// every event is a line `EVENT <json>`
+async fn send_events(rs: ReadStream, mut ws: WriteStream, ctx: Context) -> Result<()> {
+    let mut events = ctx.queue.events().subscribe().fuse();
+    // client is not supposed to send anything, the end of its stream means it is gone
+    let mut rs = rs.fuse();
+    send_cmd(&mut ws, Command::Ok).await?;
+    loop {
+        let event = select! {
+            event = events.next() => event,
+            input = rs.next() => match input {
+                Some(Ok(_)) => continue,
+                _ => return Ok(()),
+            },
+        };
+        let event = match event {
+            Some(event) => event,
+            None => return Ok(()),
+        };
+        let line = [b"EVENT ", serde_json::to_vec(&event)?.as_slice(), b"\n"].concat();
+        match timeout(ctx.timeouts.idle, ws.send(Bytes::from(line))).await {
+            Ok(Ok(())) => {},
+            // client is gone
+            Ok(Err(_)) => return Ok(()),
+            Err(_) => {
+                info!(idle = ctx.timeouts.idle.as_secs(), "subscriber did not read events, connection closed");
+                return Ok(());
+            },
         }
+    }
+}
+
+// stored videos whose names start with `prefix` as a JSON array
+async fn list_videos(prefix: &str, ctx: &Context) -> Result<Command> {
+    let keys = ctx.dist.list(prefix).await?;
+    Ok(Command::OkWith{msg: serde_json::to_string(&keys)?})
+}
+
+// size, modification time and stage of a video as JSON
+async fn stat_video(key: &VideoKey, ctx: &Context) -> Result<Command> {
+    let metadata = ctx.dist.stat(key.as_str()).await?;
+    let status = match ctx.queue.jobs().get(key) {
+        Some(status) => Some(status),
+        None if metadata.is_some() => Some(JobStatus::Done),
+        None => return Ok(Command::Err{msg: UNKNOWN_VIDEO.to_string()}),
+    };
+    let info = ObjectInfo::new(key.as_str(), metadata.as_ref(), status.map(|status| status.to_string()));
+    Ok(Command::OkWith{msg: serde_json::to_string(&info)?})
+}
+
+// remove compressed video and its upload that waits in staging
+async fn delete_video(key: &VideoKey, replica: bool, ctx: &Context) -> Result<Command> {
+    if let Some(status) = ctx.queue.jobs().get(key).filter(JobStatus::is_active) {
+        return Ok(Command::Err{msg: format!("video is {}, cancel the job first", status)});
+    }
+    let stored = ctx.dist.stat(key.as_str()).await?.is_some();
+    let staged = ctx.staging.stat(key.as_str()).await?.is_some();
+    if !stored && !staged {
+        return Ok(Command::Err{msg: UNKNOWN_VIDEO.to_string()});
+    }
+    if stored {
+        ctx.dist.delete(key.as_str()).await?;
+    }
+    if staged {
+        ctx.staging.delete(key.as_str()).await?;
+    }
+    ctx.queue.jobs().remove(key);
+    if replica {
+        ctx.replication.forget(key.as_str());
+    } else {
+        // copies on peers would be served again when this node is down
+        ctx.replication.remove(key.as_str());
+    }
+    info!(video = %key, stored, staged, replica, "video deleted");
+    ctx.queue.events().publish(Event::Deleted { filename: key.to_string() });
+    Ok(Command::Ok)
+}
+
+// jobs that are not done as a JSON array
+fn list_jobs(ctx: &Context) -> Result<Command> {
+    let jobs = ctx.queue.jobs().list()
+        .into_iter()
+        .filter(|(_, status)| *status != JobStatus::Done)
+        .map(|(key, status)| JobInfo { filename: key.to_string(), status: status.to_string() })
+        .collect::<Vec<JobInfo>>();
+    Ok(Command::OkWith{msg: serde_json::to_string(&jobs)?})
+}
+
+// validate and compress again a video that failed or was cancelled
+async fn requeue_video(key: VideoKey, ctx: &Context) -> Result<Command> {
+    match ctx.queue.jobs().get(&key) {
+        Some(status) if status.is_active() || status == JobStatus::Done => {
+            return Ok(Command::Err{msg: format!("video is {}", status)});
+        },
+        _ => {},
+    }
+    if ctx.staging.stat(key.as_str()).await?.is_none() {
+        return Ok(Command::Err{msg: "video is not in staging".to_string()});
+    }
+    info!(video = %key, "video requeued");
+    ctx.queue.submit(key);
+    Ok(Command::Ok)
+}
 
-        // Write the buffer into stream.
-        ws.send(Bytes::copy_from_slice(&buf[..n])).await?;
+// stop a job before compression, its upload stays in staging and can be requeued
+fn cancel_job(key: &VideoKey, ctx: &Context) -> Result<Command> {
+    match ctx.queue.jobs().cancel(key) {
+        Ok(()) => {
+            info!(video = %key, "job cancelled");
+            Ok(Command::Ok)
+        },
+        Err(msg) => Ok(Command::Err{msg}),
     }
+}
+
+// counters, jobs by stage and disk usage as JSON
+fn server_stats(ctx: &Context) -> Result<Command> {
+    let mut stats = ctx.queue.metrics().stats();
+    for (_, status) in ctx.queue.jobs().list() {
+        *stats.jobs.entry(status.name().to_string()).or_default() += 1;
+    }
+    if let Ok(usage) = ctx.limits.usage() {
+        stats.disk_free = Some(usage.free);
+        stats.disk_total = Some(usage.total);
+    }
+    stats.replicas_lagging = ctx.replication.lagging().len() as u64;
+    Ok(Command::OkWith{msg: serde_json::to_string(&stats)?})
+}
+
+// copies that replicas do not have yet as a JSON array
+fn list_replicas(ctx: &Context) -> Result<Command> {
+    Ok(Command::OkWith{msg: serde_json::to_string(&ctx.replication.lagging())?})
+}
+
+// send result of an admin command, internal errors are sent to the client too
+async fn reply(ws: &mut WriteStream, result: Result<Command>, keep_alive: bool) -> Result<()> {
+    let cmd = result.unwrap_or_else(|e| {
+        warn!(error = %e, "error handling request");
+        Command::Err{msg: e.to_string()}
+    });
+    send_response(ws, cmd, keep_alive).await
+}
+
+// send response, in keep-alive mode it ends with a new line
+async fn send_response(ws: &mut WriteStream, cmd: Command, keep_alive: bool) -> Result<()> {
+    ws.send(Bytes::from(encode_response(cmd, keep_alive))).await?;
+    Ok(())
+}
 
+// bytes of a response, in keep-alive mode it ends with a new line
+fn encode_response(cmd: Command, keep_alive: bool) -> Vec<u8> {
+    let mut line = encode(cmd);
+    if keep_alive {
+        line.push(b'\n');
+    }
+    line
 }
 
 // send response command to the client
 async fn send_cmd(ws: &mut WriteStream, cmd: Command) -> Result<()>{
-    let cmd = match cmd {
+    ws.send(Bytes::from(encode(cmd))).await?;
+    Ok(())
+}
+
+// bytes of a response command
+fn encode(cmd: Command) -> Vec<u8> {
+    match cmd {
         Command::Err{msg} => {
             [b"ERROR ", msg.as_bytes()].concat()
         },
         Command::Ok => {
             [b"OK", " ".as_bytes()].concat()
-        }
-    };
-    ws.send(Bytes::from(cmd)).await?;
-    Ok(())
+        },
+        Command::OkWith{msg} => {
+            [b"OK ", msg.as_bytes()].concat()
+        },
+    }
 }
 
-// reduce quality of incomming video file
-// it represents a queue of video files
-// queue will process only a single video file at time
-async fn video_processing_loop(videos: Receiver<String>) {
-    let mut videos = videos.fuse();
-    loop {
-        let filename = select! {
-            filename = videos.next().fuse() => filename.unwrap()
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(input: &str) -> std::result::Result<Request, String> {
+        Request::parse(trace::split_request_id(input).1)
+    }
+
+    #[test]
+    fn parses_uploads() {
+        assert!(matches!(parse("UPLOAD a.mp4"), Ok(Request::Upload { key, size: None, callback: None }) if key.as_str() == "a.mp4"));
+        assert!(matches!(parse("UPLOAD a.mp4 1048576"), Ok(Request::Upload { size: Some(1048576), .. })));
+        assert!(matches!(
+            parse("UPLOAD a.mp4 10 callback=http://host/video/callback"),
+            Ok(Request::Upload { size: Some(10), callback: Some(Target::Http(url)), .. }) if url == "http://host/video/callback"
+        ));
+        assert!(matches!(
+            parse("UPLOAD a.mp4 callback=tcp://host:9000"),
+            Ok(Request::Upload { size: None, callback: Some(Target::Tcp(addr)), .. }) if addr == "host:9000"
+        ));
+        assert!(matches!(parse("UPLOAD"), Err(msg) if msg == "UPLOAD must be followed by a filename"));
+        assert!(matches!(parse("UPLOAD a.mp4 big"), Err(msg) if msg == "invalid size: big"));
+        assert!(parse("UPLOAD a.mp4 18446744073709551616").is_err());
+        assert!(parse("UPLOAD a.mp4 callback=ftp://host/").is_err());
+    }
+
+    #[test]
+    fn parses_downloads() {
+        assert!(matches!(parse("GET a.mp4"), Ok(Request::Get { range: None, .. })));
+        assert!(matches!(
+            parse("GET a.mp4 0-1023"),
+            Ok(Request::Get { range: Some(ByteRange { start: 0, end: Some(1023) }), .. })
+        ));
+        assert!(matches!(parse("GET a.mp4 5-"), Ok(Request::Get { range: Some(ByteRange { start: 5, end: None }), .. })));
+        assert!(parse("GET a.mp4 9-1").is_err());
+        assert!(matches!(parse("GET"), Err(msg) if msg == "GET must be followed by a filename"));
+    }
+
+    #[test]
+    fn parses_replica_arguments() {
+        assert!(matches!(parse("PUT a.mp4"), Ok(Request::Put { replica: false, .. })));
+        assert!(matches!(parse("PUT a.mp4 replica"), Ok(Request::Put { replica: true, .. })));
+        assert!(matches!(parse("DELETE a.mp4"), Ok(Request::Delete { replica: false, .. })));
+        assert!(matches!(parse("DELETE a.mp4 replica"), Ok(Request::Delete { replica: true, .. })));
+        assert!(matches!(parse("PUT a.mp4 copy"), Err(msg) if msg == "unexpected argument: copy"));
+        assert!(matches!(parse("DELETE"), Err(msg) if msg == "DELETE must be followed by a filename"));
+        assert!(matches!(parse("PUT "), Err(msg) if msg == "PUT must be followed by a filename"));
+    }
+
+    #[test]
+    fn only_put_needs_a_key() {
+        let key_only = |input| parse(input).unwrap().need().is_some_and(|need| need.key_only);
+        assert!(key_only("PUT a.mp4"));
+        assert!(key_only("PUT a.mp4 replica"));
+        assert!(!key_only("UPLOAD a.mp4"));
+        assert!(!key_only("DELETE a.mp4 replica"));
+        assert!(!key_only("GET a.mp4"));
+        assert!(!key_only("PING"));
+    }
+
+    #[test]
+    fn parses_other_commands() {
+        assert!(matches!(parse("STATUS a.mp4"), Ok(Request::Status { .. })));
+        assert!(matches!(parse("STAT a.mp4"), Ok(Request::Stat { .. })));
+        assert!(matches!(parse("REQUEUE a.mp4"), Ok(Request::Requeue { .. })));
+        assert!(matches!(parse("CANCEL a.mp4"), Ok(Request::Cancel { .. })));
+        assert!(matches!(parse("LIST"), Ok(Request::List { prefix }) if prefix.is_empty()));
+        assert!(matches!(parse("LIST 12"), Ok(Request::List { prefix }) if prefix == "12"));
+        assert!(matches!(parse("SUBSCRIBE"), Ok(Request::Subscribe)));
+        assert!(matches!(parse("PING"), Ok(Request::Ping)));
+        assert!(matches!(parse("QUEUE"), Ok(Request::Queue)));
+        assert!(matches!(parse("STATS"), Ok(Request::Stats)));
+        assert!(matches!(parse("REPLICATION"), Ok(Request::Replication)));
+        assert!(matches!(parse("STAT"), Err(msg) if msg == "STAT must be followed by a filename"));
+        assert!(matches!(parse("FETCH a.mp4"), Err(msg) if msg == "unknown command: FETCH"));
+        assert!(matches!(parse("get a.mp4"), Err(msg) if msg == "unknown command: get"));
+        assert!(parse("").is_err());
+    }
+
+    #[test]
+    fn strips_request_ids() {
+        assert_eq!(trace::split_request_id("ID abc-1 GET a.mp4"), (Some("abc-1"), "GET a.mp4"));
+        assert_eq!(trace::split_request_id("GET a.mp4"), (None, "GET a.mp4"));
+        assert!(matches!(parse("ID abc-1 GET a.mp4 0-9"), Ok(Request::Get { range: Some(_), .. })));
+        assert!(matches!(parse("ID abc-1 PING"), Ok(Request::Ping)));
+    }
+
+    #[test]
+    fn challenge_answers_only_once() {
+        let mut keys = std::collections::BTreeMap::new();
+        keys.insert("uploader".to_string(), video_service::config::KeyConfig {
+            secret: "s3cret".to_string(),
+            access: Access::ReadWrite,
+            prefixes: vec![],
+        });
+        let auth = Auth::new(keys);
+        let mut session = Session { peer: Peer::Unix, keep_alive: false, challenge: None, grant: None };
+
+        let challenge = match authenticate("AUTH uploader", &mut session, &auth) {
+            Ok(Command::OkWith { msg }) => msg,
+            _ => panic!("AUTH must answer with a challenge"),
         };
-        let source = format!("{}{}", concat!(env!("CARGO_MANIFEST_DIR"), "/tmp/"), filename);
-        let dist = format!("{}{}", concat!(env!("CARGO_MANIFEST_DIR"), "/dist/"), filename);
-        //ffmpeg -i {input file}  -r {fps} -s {resolution} {output file}
-        let mut cmd = OsCommand::new("ffmpeg")
-            .args(&[
-                "-i",
-                &source,
-                "-r",
-                "30",
-                "-s",
-                "960x540",
-                &dist,
-            ])
-            .stdout(Stdio::null())
-            .spawn()
-            .expect("ffmpeg failed to start");
-    
-        cmd.wait().and_then(|_| {
-            // delete temp file
-            std::fs::remove_file(source).unwrap();
-            Ok(())
-        }).unwrap();
-    }
-}
\ No newline at end of file
+        let proof = format!("PROOF {}", video_service::auth::sign("uploader", "s3cret", &challenge));
+        assert!(matches!(authenticate(&proof, &mut session, &auth), Ok(Command::Ok)));
+        assert!(session.grant.is_some());
+
+        // the same proof can not be sent again, neither after a new challenge
+        let mut session = Session { peer: Peer::Unix, keep_alive: false, challenge: None, grant: None };
+        assert!(matches!(authenticate(&proof, &mut session, &auth), Err(Command::Err { .. })));
+        assert!(authenticate("AUTH uploader", &mut session, &auth).is_ok());
+        assert!(matches!(authenticate(&proof, &mut session, &auth), Err(Command::Err { msg }) if msg == "access denied"));
+        assert!(session.grant.is_none());
+    }
+
+    #[tokio::test]
+    async fn sendfile_only_over_plain_connections_without_download_limit() {
+        let mut listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let mut framed = Vec::new();
+        for (upload_rate, download_rate) in [(0, 0), (1000, 0), (0, 1000)] {
+            let _client = tokio::net::TcpStream::connect(addr).await.unwrap();
+            let (socket, _) = listener.accept().await.unwrap();
+            framed.push(BytesCodec::new().framed(Throttled::new(Stream::Tcp(socket), upload_rate, download_rate)));
+        }
+        assert!(direct_fd(&framed[0]).is_some());
+        // uploads do not go through `sendfile`, their limit does not matter
+        assert!(direct_fd(&framed[1]).is_some());
+        assert!(direct_fd(&framed[2]).is_none());
+    }
+
+    #[test]
+    fn sendfile_only_from_local_files() {
+        let root = std::env::temp_dir().join(format!("video-service-direct-{}", std::process::id()));
+        let local = LocalStorage::new(&root).unwrap();
+        assert!(local.local_path("a.mp4").is_some());
+        assert!(video_service::storage::MemoryStorage::new().local_path("a.mp4").is_none());
+        std::fs::remove_dir_all(&root).ok();
+    }
+
+    #[test]
+    fn refuses_unsafe_filenames() {
+        assert!(parse("GET ../secret.mp4").is_err());
+        assert!(parse("UPLOAD /etc/passwd").is_err());
+        assert!(parse("PUT a/b.mp4 replica").is_err());
+    }
+}
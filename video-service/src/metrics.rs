@@ -0,0 +1,192 @@
+use {
+    std::{
+        collections::BTreeMap,
+        fmt::Write,
+        sync::{Arc, Mutex, atomic::{AtomicI64, AtomicU64, Ordering}},
+        time::Duration,
+    },
+    serde::{Deserialize, Serialize},
+    crate::limits::UploadLimits,
+};
+
+// upper bounds of job duration buckets in seconds
+const JOB_BUCKETS: [f64; 10] = [1.0, 5.0, 10.0, 30.0, 60.0, 120.0, 300.0, 600.0, 1800.0, 3600.0];
+
+// cumulative histogram in Prometheus sense
+#[derive(Default)]
+struct Histogram {
+    buckets: [u64; JOB_BUCKETS.len()],
+    count: u64,
+    sum: f64,
+}
+
+impl Histogram {
+    fn observe(&mut self, value: f64) {
+        for (bucket, bound) in self.buckets.iter_mut().zip(JOB_BUCKETS.iter()) {
+            if value <= *bound {
+                *bucket += 1;
+            }
+        }
+        self.count += 1;
+        self.sum += value;
+    }
+}
+
+#[derive(Default)]
+struct Inner {
+    connections: AtomicI64,
+    bytes_in: AtomicU64,
+    bytes_out: AtomicU64,
+    queue_depth: AtomicI64,
+    // by profile of compression
+    jobs: Mutex<BTreeMap<String, Histogram>>,
+    // by kind of failure
+    failures: Mutex<BTreeMap<&'static str, u64>>,
+}
+
+/// Counters of the service exposed in Prometheus text format
+#[derive(Clone, Default)]
+pub struct Metrics {
+    inner: Arc<Inner>,
+}
+
+/// Snapshot of counters sent to admin clients by `STATS`
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Stats {
+    pub connections: i64,
+    pub bytes_in: u64,
+    pub bytes_out: u64,
+    pub queue_depth: i64,
+    /// compressed videos by profile
+    pub finished: BTreeMap<String, u64>,
+    /// failed uploads, jobs and refused connections by kind
+    pub failures: BTreeMap<String, u64>,
+    /// known jobs by stage
+    pub jobs: BTreeMap<String, u64>,
+    pub disk_free: Option<u64>,
+    pub disk_total: Option<u64>,
+    /// copies of videos that replicas do not have yet
+    #[serde(default)]
+    pub replicas_lagging: u64,
+}
+
+/// Open connection, it is counted until the guard is dropped
+pub struct ConnectionGuard {
+    metrics: Metrics,
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        self.metrics.inner.connections.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl Metrics {
+    pub fn new() -> Metrics {
+        Metrics::default()
+    }
+
+    pub fn connection_opened(&self) -> ConnectionGuard {
+        self.inner.connections.fetch_add(1, Ordering::Relaxed);
+        ConnectionGuard { metrics: self.clone() }
+    }
+
+    /// bytes received from clients
+    pub fn bytes_in(&self, n: u64) {
+        self.inner.bytes_in.fetch_add(n, Ordering::Relaxed);
+    }
+
+    /// bytes sent to clients
+    pub fn bytes_out(&self, n: u64) {
+        self.inner.bytes_out.fetch_add(n, Ordering::Relaxed);
+    }
+
+    /// video was put into the processing queue
+    pub fn queued(&self) {
+        self.inner.queue_depth.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// video was taken from the processing queue
+    pub fn dequeued(&self) {
+        self.inner.queue_depth.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    /// number of videos waiting for compression
+    pub fn queue_depth(&self) -> i64 {
+        self.inner.queue_depth.load(Ordering::Relaxed)
+    }
+
+    /// how long it took to process a video with `profile`
+    pub fn job_finished(&self, profile: &str, duration: Duration) {
+        self.inner.jobs.lock().unwrap()
+            .entry(profile.to_string())
+            .or_default()
+            .observe(duration.as_secs_f64());
+    }
+
+    /// `kind` is a short name like `upload`, `rejected` or `failed`
+    pub fn failure(&self, kind: &'static str) {
+        *self.inner.failures.lock().unwrap().entry(kind).or_default() += 1;
+    }
+
+    /// current values of counters, jobs and disk are filled by the caller
+    pub fn stats(&self) -> Stats {
+        let inner = &self.inner;
+        Stats {
+            connections: inner.connections.load(Ordering::Relaxed),
+            bytes_in: inner.bytes_in.load(Ordering::Relaxed),
+            bytes_out: inner.bytes_out.load(Ordering::Relaxed),
+            queue_depth: inner.queue_depth.load(Ordering::Relaxed),
+            finished: inner.jobs.lock().unwrap().iter()
+                .map(|(profile, histogram)| (profile.clone(), histogram.count))
+                .collect(),
+            failures: inner.failures.lock().unwrap().iter()
+                .map(|(kind, count)| (kind.to_string(), *count))
+                .collect(),
+            ..Stats::default()
+        }
+    }
+
+    /// render all metrics in Prometheus text format
+    pub fn render(&self, limits: &UploadLimits) -> String {
+        let mut out = String::new();
+        let inner = &self.inner;
+        metric(&mut out, "video_connections_active", "gauge", "Open client connections");
+        writeln!(out, "video_connections_active {}", inner.connections.load(Ordering::Relaxed)).ok();
+        metric(&mut out, "video_bytes_received_total", "counter", "Bytes received from clients");
+        writeln!(out, "video_bytes_received_total {}", inner.bytes_in.load(Ordering::Relaxed)).ok();
+        metric(&mut out, "video_bytes_sent_total", "counter", "Bytes sent to clients");
+        writeln!(out, "video_bytes_sent_total {}", inner.bytes_out.load(Ordering::Relaxed)).ok();
+        metric(&mut out, "video_queue_depth", "gauge", "Videos waiting for compression");
+        writeln!(out, "video_queue_depth {}", inner.queue_depth.load(Ordering::Relaxed)).ok();
+
+        metric(&mut out, "video_job_duration_seconds", "histogram", "Time spent on compression of a video");
+        for (profile, histogram) in inner.jobs.lock().unwrap().iter() {
+            for (count, bound) in histogram.buckets.iter().zip(JOB_BUCKETS.iter()) {
+                writeln!(out, "video_job_duration_seconds_bucket{{profile=\"{}\",le=\"{}\"}} {}", profile, bound, count).ok();
+            }
+            writeln!(out, "video_job_duration_seconds_bucket{{profile=\"{}\",le=\"+Inf\"}} {}", profile, histogram.count).ok();
+            writeln!(out, "video_job_duration_seconds_sum{{profile=\"{}\"}} {}", profile, histogram.sum).ok();
+            writeln!(out, "video_job_duration_seconds_count{{profile=\"{}\"}} {}", profile, histogram.count).ok();
+        }
+
+        metric(&mut out, "video_failures_total", "counter", "Failed uploads, jobs and refused connections by kind");
+        for (kind, count) in inner.failures.lock().unwrap().iter() {
+            writeln!(out, "video_failures_total{{kind=\"{}\"}} {}", kind, count).ok();
+        }
+
+        if let Ok(usage) = limits.usage() {
+            metric(&mut out, "video_disk_free_bytes", "gauge", "Free space on the disk with uploads");
+            writeln!(out, "video_disk_free_bytes {}", usage.free).ok();
+            metric(&mut out, "video_disk_total_bytes", "gauge", "Size of the disk with uploads");
+            writeln!(out, "video_disk_total_bytes {}", usage.total).ok();
+        }
+        out
+    }
+}
+
+// header of a metric
+fn metric(out: &mut String, name: &str, kind: &str, help: &str) {
+    writeln!(out, "# HELP {} {}", name, help).ok();
+    writeln!(out, "# TYPE {} {}", name, kind).ok();
+}
@@ -0,0 +1,225 @@
+use {
+    std::{
+        env, fmt, fs, io,
+        net::{IpAddr, SocketAddr},
+        os::unix::{fs::{FileTypeExt, PermissionsExt}, io::{AsRawFd, FromRawFd, IntoRawFd, RawFd}, net as unix},
+        path::{Path, PathBuf},
+        pin::Pin,
+        process,
+        task::{Context, Poll},
+    },
+    tokio::{io::{AsyncRead, AsyncWrite}, net::{TcpListener, TcpStream, UnixListener, UnixStream}},
+    tokio_rustls::TlsStream,
+    tracing::info,
+    crate::{storage::Result, tls::Connector},
+};
+
+/// Addresses of Unix sockets start with it, e.g. `unix:///run/video-service.sock`
+pub const UNIX_PREFIX: &str = "unix://";
+
+// systemd passes listeners starting with this descriptor
+const LISTEN_FDS_START: RawFd = 3;
+
+/// Connection between services, TCP ones are encrypted when TLS is configured
+pub enum Stream {
+    Tcp(TcpStream),
+    // local connections are never encrypted, permissions of the socket file protect them
+    Unix(UnixStream),
+    Tls(Box<TlsStream<TcpStream>>),
+}
+
+impl Stream {
+    /// connect to `host:port` or `unix://<path>`, over TLS if `connector` is set and the address is not local
+    pub async fn connect(addr: &str, connector: Option<&Connector>) -> Result<Stream> {
+        if let Some(path) = addr.strip_prefix(UNIX_PREFIX) {
+            let stream = UnixStream::connect(path).await
+                .map_err(|e| format!("can not connect to {}: {}", addr, e))?;
+            return Ok(Stream::Unix(stream));
+        }
+        let socket = TcpStream::connect(addr).await
+            .map_err(|e| format!("can not connect to {}: {}", addr, e))?;
+        match connector {
+            Some(connector) => connector.connect(socket).await
+                .map_err(|e| format!("TLS handshake with {} failed: {}", addr, e).into()),
+            None => Ok(Stream::Tcp(socket)),
+        }
+    }
+
+    /// descriptor of a connection that is not encrypted, bytes written to it go to the client as they are
+    pub fn plain_fd(&self) -> Option<RawFd> {
+        match self {
+            Stream::Tcp(stream) => Some(stream.as_raw_fd()),
+            Stream::Unix(stream) => Some(stream.as_raw_fd()),
+            Stream::Tls(_) => None,
+        }
+    }
+}
+
+impl AsyncRead for Stream {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            Stream::Tcp(stream) => Pin::new(stream).poll_read(cx, buf),
+            Stream::Unix(stream) => Pin::new(stream).poll_read(cx, buf),
+            Stream::Tls(stream) => Pin::new(stream.as_mut()).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for Stream {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            Stream::Tcp(stream) => Pin::new(stream).poll_write(cx, buf),
+            Stream::Unix(stream) => Pin::new(stream).poll_write(cx, buf),
+            Stream::Tls(stream) => Pin::new(stream.as_mut()).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Stream::Tcp(stream) => Pin::new(stream).poll_flush(cx),
+            Stream::Unix(stream) => Pin::new(stream).poll_flush(cx),
+            Stream::Tls(stream) => Pin::new(stream.as_mut()).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Stream::Tcp(stream) => Pin::new(stream).poll_shutdown(cx),
+            Stream::Unix(stream) => Pin::new(stream).poll_shutdown(cx),
+            Stream::Tls(stream) => Pin::new(stream.as_mut()).poll_shutdown(cx),
+        }
+    }
+}
+
+/// Address of a connected client
+#[derive(Debug, Clone, Copy)]
+pub enum Peer {
+    Tcp(SocketAddr),
+    // clients of Unix sockets are usually unnamed
+    Unix,
+}
+
+impl Peer {
+    /// IP address of a TCP client
+    pub fn ip(&self) -> Option<IpAddr> {
+        match self {
+            Peer::Tcp(addr) => Some(addr.ip()),
+            Peer::Unix => None,
+        }
+    }
+}
+
+impl fmt::Display for Peer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Peer::Tcp(addr) => write!(f, "{}", addr),
+            Peer::Unix => write!(f, "unix"),
+        }
+    }
+}
+
+/// Socket clients connect to
+pub enum Listener {
+    Tcp(TcpListener),
+    // socket file is removed on drop unless it was inherited
+    Unix(UnixListener, Option<PathBuf>),
+}
+
+impl Listener {
+    /// listen on `host:port` or on `unix://<path>` with permissions `mode`,
+    /// a socket file left by a previous run is replaced
+    pub async fn bind(addr: &str, mode: u32) -> Result<Listener> {
+        let path = match addr.strip_prefix(UNIX_PREFIX) {
+            Some(path) => Path::new(path),
+            None => {
+                let listener = TcpListener::bind(addr).await
+                    .map_err(|e| format!("can not listen on {}: {}", addr, e))?;
+                return Ok(Listener::Tcp(listener));
+            },
+        };
+        if fs::symlink_metadata(path).map(|metadata| metadata.file_type().is_socket()).unwrap_or(false) {
+            // nobody answers on a stale socket
+            if unix::UnixStream::connect(path).is_ok() {
+                return Err(format!("{} is used by another process", addr).into());
+            }
+            fs::remove_file(path)?;
+        }
+        let listener = UnixListener::bind(path)
+            .map_err(|e| format!("can not listen on {}: {}", addr, e))?;
+        fs::set_permissions(path, fs::Permissions::from_mode(mode))?;
+        Ok(Listener::Unix(listener, Some(path.to_path_buf())))
+    }
+
+    /// listeners passed by systemd socket activation, empty when the service was started without it
+    pub fn inherited() -> Result<Vec<Listener>> {
+        let pid = env::var("LISTEN_PID").ok().and_then(|pid| pid.parse::<u32>().ok());
+        let count = env::var("LISTEN_FDS").ok().and_then(|count| count.parse::<RawFd>().ok());
+        let names = env::var("LISTEN_FDNAMES").unwrap_or_default();
+        // variables are meant for this process only, not for ffmpeg and other children
+        for name in &["LISTEN_PID", "LISTEN_FDS", "LISTEN_FDNAMES"] {
+            env::remove_var(name);
+        }
+        let count = match (pid, count) {
+            (Some(pid), Some(count)) if pid == process::id() => count,
+            _ => return Ok(Vec::new()),
+        };
+        let mut names = names.split(':');
+        let mut listeners = Vec::new();
+        for fd in LISTEN_FDS_START..LISTEN_FDS_START + count {
+            let listener = from_fd(fd)?;
+            info!(fd, name = names.next().unwrap_or_default(), addr = %listener.local_addr(), "inherited listener");
+            listeners.push(listener);
+        }
+        Ok(listeners)
+    }
+
+    /// wait for the next client, returns its connection and address
+    pub async fn accept(&mut self) -> io::Result<(Stream, Peer)> {
+        match self {
+            Listener::Tcp(listener) => {
+                let (socket, peer) = listener.accept().await?;
+                Ok((Stream::Tcp(socket), Peer::Tcp(peer)))
+            },
+            Listener::Unix(listener, _) => {
+                let (socket, _) = listener.accept().await?;
+                Ok((Stream::Unix(socket), Peer::Unix))
+            },
+        }
+    }
+
+    /// address in the same format as in the config
+    pub fn local_addr(&self) -> String {
+        let addr = match self {
+            Listener::Tcp(listener) => listener.local_addr().map(|addr| addr.to_string()),
+            Listener::Unix(listener, _) => listener.local_addr().map(|addr| match addr.as_pathname() {
+                Some(path) => format!("{}{}", UNIX_PREFIX, path.display()),
+                None => format!("{}<unnamed>", UNIX_PREFIX),
+            }),
+        };
+        addr.unwrap_or_else(|e| format!("<{}>", e))
+    }
+}
+
+impl Drop for Listener {
+    fn drop(&mut self) {
+        if let Listener::Unix(_, Some(path)) = self {
+            fs::remove_file(path).ok();
+        }
+    }
+}
+
+// listener of a descriptor passed by systemd, it is a Unix or a TCP socket
+fn from_fd(fd: RawFd) -> Result<Listener> {
+    // safety: systemd hands the descriptors over to this process and nothing else owns them
+    let listener = unsafe { unix::UnixListener::from_raw_fd(fd) };
+    // `local_addr` fails when the socket is not a Unix one
+    if listener.local_addr().is_ok() {
+        listener.set_nonblocking(true)?;
+        return Ok(Listener::Unix(UnixListener::from_std(listener)?, None));
+    }
+    let fd = listener.into_raw_fd();
+    let listener = unsafe { std::net::TcpListener::from_raw_fd(fd) };
+    listener.local_addr().map_err(|e| format!("inherited descriptor {} is not a TCP or Unix socket: {}", fd, e))?;
+    listener.set_nonblocking(true)?;
+    Ok(Listener::Tcp(TcpListener::from_std(listener)?))
+}
@@ -0,0 +1,55 @@
+use {
+    std::{path::Path, process::Stdio, time::Duration},
+    tokio::{process::Command, time},
+    serde::Serialize,
+    crate::storage::Result,
+};
+
+/// Properties of a video file reported by ffprobe
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct MediaInfo {
+    /// duration in seconds
+    pub duration: Option<f64>,
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    pub codec: Option<String>,
+}
+
+/// read properties of the first video stream of the file with ffprobe
+pub async fn probe(path: &Path, timeout: Duration) -> Result<MediaInfo> {
+    //ffprobe -v error -select_streams v:0 -show_entries format=duration:stream=width,height,codec_name -of default=noprint_wrappers=1 {file}
+    let output = Command::new("ffprobe")
+        .args([
+            "-v", "error",
+            "-select_streams", "v:0",
+            "-show_entries", "format=duration:stream=width,height,codec_name",
+            "-of", "default=noprint_wrappers=1",
+        ])
+        .arg(path)
+        .stdin(Stdio::null())
+        .kill_on_drop(true)
+        .output();
+    let output = time::timeout(timeout, output).await
+        .map_err(|_| format!("ffprobe did not finish in {} seconds", timeout.as_secs()))??;
+    if !output.status.success() {
+        let err = String::from_utf8_lossy(&output.stderr);
+        return Err(format!("ffprobe exited with {}: {}", output.status, err.trim()).into());
+    }
+    Ok(parse(&String::from_utf8_lossy(&output.stdout)))
+}
+
+// ffprobe prints `key=value` lines
+fn parse(output: &str) -> MediaInfo {
+    let mut info = MediaInfo::default();
+    for line in output.lines() {
+        let mut parts = line.splitn(2, '=');
+        match (parts.next(), parts.next()) {
+            (Some("duration"), Some(value)) => info.duration = value.trim().parse().ok(),
+            (Some("width"), Some(value)) => info.width = value.trim().parse().ok(),
+            (Some("height"), Some(value)) => info.height = value.trim().parse().ok(),
+            (Some("codec_name"), Some(value)) => info.codec = Some(value.trim().to_string()),
+            _ => {},
+        }
+    }
+    info
+}
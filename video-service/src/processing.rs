@@ -0,0 +1,297 @@
+use {
+    std::{sync::Arc, time::{Duration, Instant}},
+    std::process::Stdio,
+    tokio::{io::{AsyncBufReadExt, BufReader}, process::Command as OsCommand, sync::Semaphore},
+    futures::{future, StreamExt, channel::mpsc, select, FutureExt},
+    tracing::{debug, error, info, info_span, warn, Instrument, Span},
+    crate::{
+        callbacks::Callbacks,
+        config::CompressionConfig,
+        events::{Event, Events},
+        metrics::Metrics,
+        jobs::{JobStatus, Jobs},
+        key::VideoKey,
+        probe::{self, MediaInfo},
+        replication::Replication,
+        shutdown::Shutdown,
+        storage::{LocalStorage, Result, Storage},
+        validation::Validators,
+    },
+};
+
+/// Prefix of staging keys where ffmpeg writes compressed videos
+pub const OUTPUT_PREFIX: &str = "out/";
+// how long ffprobe may read properties of a compressed video
+const PROBE_TIMEOUT: Duration = Duration::from_secs(30);
+// uploads validated at the same time, every validation runs ffprobe and external programs
+const MAX_VALIDATIONS: usize = 4;
+
+// custom types to simplify code
+type Sender<T> = mpsc::UnboundedSender<T>;
+type Receiver<T> = mpsc::UnboundedReceiver<T>;
+/// Video waiting for compression together with the span of the request that uploaded it
+pub type Job = (VideoKey, Span);
+
+/// Entry point of uploaded videos
+/// every video is validated first and only then goes to the processing loop
+#[derive(Clone)]
+pub struct Queue {
+    sender: Sender<Job>,
+    staging: Arc<LocalStorage>,
+    validators: Arc<Validators>,
+    // a burst of uploads waits here instead of starting a process for every one of them
+    validations: Arc<Semaphore>,
+    jobs: Jobs,
+    callbacks: Callbacks,
+    events: Events,
+    metrics: Metrics,
+    shutdown: Shutdown,
+}
+
+impl Queue {
+    /// create queue and receiver for `video_processing_loop`
+    pub fn new(
+        staging: Arc<LocalStorage>,
+        validators: Validators,
+        jobs: Jobs,
+        callbacks: Callbacks,
+        events: Events,
+        metrics: Metrics,
+        shutdown: Shutdown,
+    ) -> (Queue, Receiver<Job>) {
+        let (sender, receiver) = mpsc::unbounded();
+        let validators = Arc::new(validators);
+        let validations = Arc::new(Semaphore::new(MAX_VALIDATIONS));
+        let queue = Queue { sender, staging, validators, validations, jobs, callbacks, events, metrics, shutdown };
+        (queue, receiver)
+    }
+
+    pub fn jobs(&self) -> &Jobs {
+        &self.jobs
+    }
+
+    pub fn callbacks(&self) -> &Callbacks {
+        &self.callbacks
+    }
+
+    pub fn events(&self) -> &Events {
+        &self.events
+    }
+
+    pub fn metrics(&self) -> &Metrics {
+        &self.metrics
+    }
+
+    pub fn shutdown(&self) -> &Shutdown {
+        &self.shutdown
+    }
+    /// `false` when the processing loop is gone and videos are not processed anymore
+    pub fn is_running(&self) -> bool {
+        !self.sender.is_closed()
+    }
+
+    /// validate uploaded video in background and put it into the processing queue
+    /// rejected videos are removed from staging
+    /// logs of the job belong to the span of the request that submitted it
+    pub fn submit(&self, key: VideoKey) {
+        let queue = self.clone();
+        self.jobs.set(&key, JobStatus::Validating);
+        let span = info_span!("job", video = %key);
+        let job_span = span.clone();
+        // interrupted validation is started again after restart, because the video stays in staging
+        let guard = self.shutdown.track(format!("validation of {}", key));
+        tokio::spawn(async move {
+            let _guard = guard;
+            let _permit = queue.validations.acquire().await;
+            if queue.shutdown.is_stopping() {
+                info!("service is stopping, video is validated on the next start");
+                return;
+            }
+            if let Err(reason) = queue.validators.validate(&queue.staging.path(key.as_str())).await {
+                info!(%reason, "video rejected");
+                queue.metrics.failure("rejected");
+                queue.events.publish(Event::Rejected { filename: key.to_string(), reason: reason.clone() });
+                let status = JobStatus::Rejected { reason };
+                queue.callbacks.notify(&key, &status, None);
+                queue.jobs.set(&key, status);
+                match queue.staging.delete(key.as_str()).await {
+                    Ok(()) => queue.events.publish(Event::Deleted { filename: key.to_string() }),
+                    Err(e) => error!(error = %e, "error deleting rejected video"),
+                }
+                return;
+            }
+            if !queue.jobs.advance(&key, JobStatus::Queued) {
+                info!("job was cancelled, video is left in staging");
+                return;
+            }
+            info!("video queued");
+            queue.events.publish(Event::Queued { filename: key.to_string() });
+            queue.metrics.queued();
+            if queue.sender.unbounded_send((key, job_span)).is_err() {
+                error!("video processing queue is closed");
+            }
+        }.instrument(span));
+    }
+}
+
+/// reduce quality of incomming video file
+/// it represents a queue of video files
+/// queue will process only a single video file at time
+/// compressed videos are passed to `replication`
+pub async fn video_processing_loop(
+    videos: Receiver<Job>,
+    queue: Queue,
+    dist: Arc<dyn Storage>,
+    compression: CompressionConfig,
+    replication: Replication,
+) {
+    let Queue { staging, jobs, callbacks, events, metrics, shutdown, .. } = queue;
+    // name of ffmpeg settings videos are compressed with
+    let profile = compression.profile();
+    let mut videos = videos.fuse();
+    loop {
+        let (key, span) = select! {
+            job = videos.next().fuse() => job.unwrap()
+        };
+        if shutdown.is_stopping() {
+            // video stays in staging and is requeued on the next start
+            info!(parent: &span, "service is stopping, video is left for the next start");
+            continue;
+        }
+        metrics.dequeued();
+        // a job that was cancelled and requeued could be in the queue twice
+        if !jobs.start(&key) {
+            info!(parent: &span, status = ?jobs.get(&key), "job is not queued anymore, skipped");
+            continue;
+        }
+        let _guard = shutdown.track(format!("processing of {}", key));
+        async {
+            info!(%profile, "processing started");
+            let started = Instant::now();
+            let (status, info) = match process_video(&key, &staging, dist.as_ref(), &events, &compression).await {
+                Ok(info) => {
+                    info!(elapsed = ?started.elapsed(), "processing finished");
+                    metrics.job_finished(&profile, started.elapsed());
+                    events.publish(Event::Done { filename: key.to_string(), info: info.clone() });
+                    replication.replicate(key.as_str());
+                    (JobStatus::Done, info)
+                },
+                Err(e) => {
+                    error!(error = %e, "error processing video");
+                    metrics.failure("failed");
+                    events.publish(Event::Failed { filename: key.to_string(), reason: e.to_string() });
+                    (JobStatus::Failed { reason: e.to_string() }, None)
+                },
+            };
+            callbacks.notify(&key, &status, info);
+            jobs.set(&key, status);
+        }.instrument(span).await;
+    }
+}
+
+// compress a single video and move it from staging into the storage
+// returns properties of the compressed video if ffprobe could read them
+async fn process_video(
+    key: &VideoKey,
+    staging: &LocalStorage,
+    dist: &dyn Storage,
+    events: &Events,
+    compression: &CompressionConfig,
+) -> Result<Option<MediaInfo>> {
+    let source = staging.path(key.as_str());
+    let output_key = format!("{}{}", OUTPUT_PREFIX, key);
+    let output = staging.path(&output_key);
+    if let Some(parent) = output.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    // progress is reported relative to duration of the source
+    let duration = probe::probe(&source, PROBE_TIMEOUT).await.ok().and_then(|info| info.duration);
+
+    //ffmpeg -i {input file}  -r {fps} -s {resolution} {output file}
+    // ffmpeg prints `key=value` lines with its progress into stdout and its log into stderr
+    let mut child = OsCommand::new("ffmpeg")
+        .arg("-i")
+        .arg(&source)
+        .args(["-r", &compression.fps.to_string(), "-s", &compression.resolution])
+        .args(["-y", "-nostats", "-progress", "pipe:1"])
+        .arg(&output)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()?;
+    // read all the output even if duration is unknown, so ffmpeg never blocks on a full pipe
+    let (stdout, stderr) = (child.stdout.take(), child.stderr.take());
+    let progress_lines = async {
+        let stdout = match stdout {
+            Some(stdout) => stdout,
+            None => return,
+        };
+        let mut lines = BufReader::new(stdout).lines();
+        let mut reported = 0;
+        while let Some(Ok(line)) = lines.next().await {
+            let percent = match duration.and_then(|duration| progress(&line, duration)) {
+                Some(percent) => percent,
+                None => continue,
+            };
+            // report every percent only once
+            if percent > reported {
+                reported = percent;
+                events.publish(Event::Progress { filename: key.to_string(), percent });
+            }
+        }
+    };
+    let log_lines = async {
+        let stderr = match stderr {
+            Some(stderr) => stderr,
+            None => return,
+        };
+        let mut lines = BufReader::new(stderr).lines();
+        while let Some(Ok(line)) = lines.next().await {
+            debug!(target: "ffmpeg", "{}", line);
+        }
+    };
+    future::join(progress_lines, log_lines).await;
+    let status = child.await?;
+
+    if !status.success() {
+        // source stays in staging, so the job can be requeued
+        staging.delete(&output_key).await?;
+        return Err(format!("ffmpeg exited with {}", status).into());
+    }
+
+    let info = match probe::probe(&output, PROBE_TIMEOUT).await {
+        Ok(info) => Some(info),
+        Err(e) => {
+            warn!(error = %e, "error probing video");
+            None
+        },
+    };
+
+    // move compressed video into the storage, the source is deleted only once it is stored,
+    // so a failed write or a crash never loses the only copy
+    let body = staging.get(&output_key, None).await?;
+    if let Err(e) = dist.put(key.as_str(), body).await {
+        // source stays in staging, so the job can be requeued, the output is made again then
+        staging.delete(&output_key).await?;
+        return Err(format!("error storing compressed video: {}", e).into());
+    }
+    staging.delete(key.as_str()).await?;
+    staging.delete(&output_key).await?;
+    Ok(info)
+}
+
+// percent of compressed video from a line of ffmpeg progress,
+// `out_time_ms` is in microseconds despite its name
+fn progress(line: &str, duration: f64) -> Option<u8> {
+    let mut parts = line.splitn(2, '=');
+    let time = match (parts.next(), parts.next()) {
+        (Some("out_time_us"), Some(value)) | (Some("out_time_ms"), Some(value)) => value.trim().parse::<f64>().ok()?,
+        (Some("progress"), Some("end")) => return Some(100),
+        _ => return None,
+    };
+    if duration <= 0.0 {
+        return None;
+    }
+    Some((time / 1_000_000.0 / duration * 100.0).clamp(0.0, 100.0) as u8)
+}
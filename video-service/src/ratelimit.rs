@@ -0,0 +1,288 @@
+use {
+    std::{
+        collections::HashMap,
+        future::Future,
+        io,
+        net::IpAddr,
+        pin::Pin,
+        sync::{Arc, Mutex},
+        task::{Context, Poll},
+        time::{Duration, Instant},
+    },
+    tokio::{io::{AsyncRead, AsyncWrite}, time::{delay_for, Delay}},
+    crate::config::LimitsConfig,
+};
+
+/// Caps of open connections, of all of them and of a single IP address,
+/// 0 means no cap
+#[derive(Clone)]
+pub struct ConnectionLimits {
+    max_connections: usize,
+    max_per_ip: usize,
+    open: Arc<Mutex<Open>>,
+}
+
+#[derive(Default)]
+struct Open {
+    total: usize,
+    by_ip: HashMap<IpAddr, usize>,
+}
+
+/// Place of an open connection, it is given back on drop
+pub struct Slot {
+    limits: ConnectionLimits,
+    ip: Option<IpAddr>,
+}
+
+impl ConnectionLimits {
+    pub fn from_config(config: &LimitsConfig) -> ConnectionLimits {
+        ConnectionLimits {
+            max_connections: config.max_connections,
+            max_per_ip: config.max_connections_per_ip,
+            open: Arc::default(),
+        }
+    }
+
+    /// take a place for a new connection, clients of Unix sockets have no IP and count into the total only
+    pub fn acquire(&self, ip: Option<IpAddr>) -> Result<Slot, String> {
+        let mut open = self.open.lock().unwrap();
+        if self.max_connections > 0 && open.total >= self.max_connections {
+            return Err("too many connections".to_string());
+        }
+        if let Some(ip) = ip {
+            let count = open.by_ip.entry(ip).or_default();
+            if self.max_per_ip > 0 && *count >= self.max_per_ip {
+                return Err(format!("too many connections from {}", ip));
+            }
+            *count += 1;
+        }
+        open.total += 1;
+        Ok(Slot { limits: self.clone(), ip })
+    }
+}
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        let mut open = self.limits.open.lock().unwrap();
+        open.total -= 1;
+        if let Some(ip) = self.ip {
+            if let Some(count) = open.by_ip.get_mut(&ip) {
+                *count -= 1;
+                if *count == 0 {
+                    open.by_ip.remove(&ip);
+                }
+            }
+        }
+    }
+}
+
+/// Connection with bandwidth limited separately for uploads (reading) and downloads (writing)
+pub struct Throttled<S> {
+    inner: S,
+    read: Bucket,
+    write: Bucket,
+}
+
+impl<S> Throttled<S> {
+    /// `upload_rate` and `download_rate` are bytes per second, 0 means no limit
+    pub fn new(inner: S, upload_rate: u64, download_rate: u64) -> Throttled<S> {
+        Throttled { inner, read: Bucket::new(upload_rate), write: Bucket::new(download_rate) }
+    }
+
+    pub fn get_ref(&self) -> &S {
+        &self.inner
+    }
+
+    /// writes are not throttled, so they may bypass this wrapper
+    pub fn unlimited_writes(&self) -> bool {
+        self.write.rate == 0
+    }
+}
+
+// token bucket that holds up to a second of traffic,
+// a transfer may take more tokens than there are and the debt is waited out before the next one
+struct Bucket {
+    // bytes per second, unlimited with 0
+    rate: u64,
+    tokens: f64,
+    updated: Instant,
+    delay: Option<Delay>,
+}
+
+impl Bucket {
+    fn new(rate: u64) -> Bucket {
+        Bucket { rate, tokens: rate as f64, updated: Instant::now(), delay: None }
+    }
+
+    // wait until the debt of the previous transfer is paid
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<()> {
+        if let Some(delay) = &mut self.delay {
+            if Pin::new(delay).poll(cx).is_pending() {
+                return Poll::Pending;
+            }
+            self.delay = None;
+        }
+        Poll::Ready(())
+    }
+
+    // bytes a single transfer may take, so it does not run into a long debt
+    fn limit(&self, len: usize) -> usize {
+        match self.rate {
+            0 => len,
+            rate => len.min(rate as usize),
+        }
+    }
+
+    fn consume(&mut self, bytes: usize) {
+        if self.rate == 0 {
+            return;
+        }
+        let rate = self.rate as f64;
+        let now = Instant::now();
+        self.tokens = (self.tokens + (now - self.updated).as_secs_f64() * rate).min(rate) - bytes as f64;
+        self.updated = now;
+        if self.tokens < 0.0 {
+            self.delay = Some(delay_for(Duration::from_secs_f64(-self.tokens / rate)));
+        }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Throttled<S> {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        if this.read.poll_ready(cx).is_pending() {
+            return Poll::Pending;
+        }
+        let len = this.read.limit(buf.len());
+        let result = Pin::new(&mut this.inner).poll_read(cx, &mut buf[..len]);
+        if let Poll::Ready(Ok(n)) = result {
+            this.read.consume(n);
+        }
+        result
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Throttled<S> {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        if this.write.poll_ready(cx).is_pending() {
+            return Poll::Pending;
+        }
+        let len = this.write.limit(buf.len());
+        let result = Pin::new(&mut this.inner).poll_write(cx, &buf[..len]);
+        if let Poll::Ready(Ok(n)) = result {
+            this.write.consume(n);
+        }
+        result
+    }
+
+    // the debt of the last write is paid before the data counts as sent
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if this.write.poll_ready(cx).is_pending() {
+            return Poll::Pending;
+        }
+        Pin::new(&mut this.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if this.write.poll_ready(cx).is_pending() {
+            return Poll::Pending;
+        }
+        Pin::new(&mut this.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        tokio::io::{AsyncReadExt, AsyncWriteExt},
+    };
+
+    fn limits(max_connections: usize, max_connections_per_ip: usize) -> ConnectionLimits {
+        ConnectionLimits::from_config(&LimitsConfig { max_connections, max_connections_per_ip, ..LimitsConfig::default() })
+    }
+
+    #[test]
+    fn caps_connections_per_ip() {
+        let limits = limits(0, 2);
+        let (a, b): (IpAddr, IpAddr) = ("10.0.0.1".parse().unwrap(), "10.0.0.2".parse().unwrap());
+        let first = limits.acquire(Some(a)).unwrap();
+        let _second = limits.acquire(Some(a)).unwrap();
+        assert_eq!(limits.acquire(Some(a)).err().unwrap(), "too many connections from 10.0.0.1");
+        let _other = limits.acquire(Some(b)).unwrap();
+        // a closed connection gives its place back
+        drop(first);
+        let _third = limits.acquire(Some(a)).unwrap();
+    }
+
+    #[test]
+    fn caps_all_connections() {
+        let limits = limits(2, 0);
+        let first = limits.acquire(Some("10.0.0.1".parse().unwrap())).unwrap();
+        // clients of Unix sockets have no IP, but count into the total
+        let _unix = limits.acquire(None).unwrap();
+        assert_eq!(limits.acquire(None).err().unwrap(), "too many connections");
+        drop(first);
+        let _next = limits.acquire(Some("10.0.0.2".parse().unwrap())).unwrap();
+        assert!(!limits.open.lock().unwrap().by_ip.contains_key(&"10.0.0.1".parse().unwrap()));
+    }
+
+    #[test]
+    fn unlimited_without_caps() {
+        let limits = limits(0, 0);
+        let slots: Vec<Slot> = (0..100).map(|_| limits.acquire(Some("10.0.0.1".parse().unwrap())).unwrap()).collect();
+        assert_eq!(limits.open.lock().unwrap().total, slots.len());
+    }
+
+    #[tokio::test]
+    async fn bucket_waits_out_its_debt() {
+        let mut bucket = Bucket::new(1000);
+        // a single transfer never takes more than a second of traffic
+        assert_eq!(bucket.limit(5000), 1000);
+        assert_eq!(bucket.limit(10), 10);
+        bucket.consume(1000);
+        assert!(bucket.delay.is_none());
+        bucket.consume(500);
+        let start = Instant::now();
+        futures::future::poll_fn(|cx| bucket.poll_ready(cx)).await;
+        assert!(start.elapsed() >= Duration::from_millis(450), "{:?}", start.elapsed());
+
+        let mut unlimited = Bucket::new(0);
+        assert_eq!(unlimited.limit(1 << 20), 1 << 20);
+        unlimited.consume(1 << 30);
+        assert!(unlimited.delay.is_none());
+    }
+
+    #[tokio::test]
+    async fn throttles_reads() {
+        let content = vec![7u8; 250_000];
+        let mut throttled = Throttled::new(&content[..], 100_000, 0);
+        let start = Instant::now();
+        let mut read = Vec::new();
+        throttled.read_to_end(&mut read).await.unwrap();
+        assert_eq!(read, content);
+        // the first second of traffic is free, the rest is paid with time
+        assert!(start.elapsed() >= Duration::from_millis(1400), "{:?}", start.elapsed());
+        assert!(throttled.unlimited_writes());
+    }
+
+    #[tokio::test]
+    async fn throttles_writes() {
+        let mut throttled = Throttled::new(Vec::new(), 0, 100_000);
+        assert!(!throttled.unlimited_writes());
+        let start = Instant::now();
+        throttled.write_all(&[7u8; 150_000]).await.unwrap();
+        throttled.flush().await.unwrap();
+        assert_eq!(throttled.get_ref().len(), 150_000);
+        assert!(start.elapsed() >= Duration::from_millis(450), "{:?}", start.elapsed());
+
+        let mut unlimited = Throttled::new(Vec::new(), 0, 0);
+        let start = Instant::now();
+        unlimited.write_all(&[7u8; 1 << 20]).await.unwrap();
+        unlimited.flush().await.unwrap();
+        assert!(start.elapsed() < Duration::from_millis(400));
+    }
+}
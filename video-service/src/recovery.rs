@@ -0,0 +1,222 @@
+use {
+    std::{collections::HashSet, fmt},
+    crate::{
+        key::VideoKey,
+        processing::OUTPUT_PREFIX,
+        storage::{LocalStorage, Result, Storage},
+    },
+};
+
+/// What was found and fixed after the previous run
+#[derive(Debug, Default)]
+pub struct RecoveryReport {
+    /// uploads that were cut off, their temp files are deleted
+    pub partial_uploads: Vec<String>,
+    /// half-written outputs of ffmpeg or storage, they are deleted
+    pub partial_outputs: Vec<String>,
+    /// sources and outputs whose compressed video is already stored, they are deleted
+    pub finished: Vec<String>,
+    /// complete outputs whose source was already deleted, they are moved into the storage
+    pub promoted: Vec<String>,
+    /// complete sources that must be compressed again
+    pub requeued: Vec<VideoKey>,
+    /// files with names that are not valid keys, they are left untouched
+    pub ignored: Vec<String>,
+}
+
+impl RecoveryReport {
+    /// true if the previous run left nothing behind
+    pub fn is_clean(&self) -> bool {
+        self.partial_uploads.is_empty()
+            && self.partial_outputs.is_empty()
+            && self.finished.is_empty()
+            && self.promoted.is_empty()
+            && self.requeued.is_empty()
+            && self.ignored.is_empty()
+    }
+}
+
+impl fmt::Display for RecoveryReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.is_clean() {
+            return write!(f, "recovery: nothing to recover");
+        }
+        write!(
+            f,
+            "recovery: removed {} partial uploads {:?}, removed {} partial outputs {:?}, \
+            removed {} already processed files {:?}, stored {} processed videos {:?}, requeued {} videos {:?}, \
+            ignored {} unknown files {:?}",
+            self.partial_uploads.len(), self.partial_uploads,
+            self.partial_outputs.len(), self.partial_outputs,
+            self.finished.len(), self.finished,
+            self.promoted.len(), self.promoted,
+            self.requeued.len(), self.requeued.iter().map(VideoKey::as_str).collect::<Vec<&str>>(),
+            self.ignored.len(), self.ignored,
+        )
+    }
+}
+
+/// scan staging and storage after a crash
+/// files are written under a `.part` name and renamed when complete,
+/// so anything that still has this marker was interrupted
+/// must be called before the server starts accepting connections
+pub async fn recover(staging: &LocalStorage, dist: &dyn Storage) -> Result<RecoveryReport> {
+    let mut report = RecoveryReport::default();
+
+    // uploads and ffmpeg outputs that were not finished
+    for key in staging.cleanup().await? {
+        match key.strip_prefix(OUTPUT_PREFIX) {
+            Some(key) => report.partial_outputs.push(key.to_string()),
+            None => report.partial_uploads.push(key),
+        }
+    }
+    // storage writes that were not finished
+    report.partial_outputs.extend(dist.cleanup().await?);
+
+    let keys = staging.list("").await?;
+    let sources: HashSet<&str> = keys.iter().filter(|key| !key.starts_with(OUTPUT_PREFIX)).map(String::as_str).collect();
+    for key in &keys {
+        let (source, output) = match key.strip_prefix(OUTPUT_PREFIX) {
+            Some(source) => (source, true),
+            None => (key.as_str(), false),
+        };
+        let source = match VideoKey::parse(source) {
+            Ok(source) => source,
+            Err(_) => {
+                report.ignored.push(key.clone());
+                continue;
+            },
+        };
+        let stored = dist.stat(source.as_str()).await?.is_some();
+        if output {
+            // source is deleted only after the output is stored, so ffmpeg could have been killed
+            // in the middle of writing while the source is there, the source is compressed again then
+            if sources.contains(source.as_str()) {
+                staging.delete(key).await?;
+                report.partial_outputs.push(source.to_string());
+            } else if stored {
+                staging.delete(key).await?;
+                report.finished.push(key.clone());
+            } else {
+                // crash happened after the source was deleted but before the output was stored
+                let body = staging.get(key, None).await?;
+                dist.put(source.as_str(), body).await?;
+                staging.delete(key).await?;
+                report.promoted.push(source.to_string());
+            }
+            continue;
+        }
+        // crash happened after the video was stored but before the source was deleted
+        if stored {
+            staging.delete(key).await?;
+            report.finished.push(key.clone());
+            continue;
+        }
+        report.requeued.push(source);
+    }
+
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        std::{fs, path::{Path, PathBuf}},
+        futures::{stream, StreamExt},
+        bytes::Bytes,
+        crate::storage::MemoryStorage,
+    };
+
+    fn staging(name: &str) -> (LocalStorage, PathBuf) {
+        let root = std::env::temp_dir().join(format!("video-service-recovery-{}-{}", name, std::process::id()));
+        fs::remove_dir_all(&root).ok();
+        (LocalStorage::new(&root).unwrap(), root)
+    }
+
+    fn write(root: &Path, name: &str, content: &[u8]) {
+        let path = root.join(name);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, content).unwrap();
+    }
+
+    async fn store(dist: &dyn Storage, key: &str, content: &'static [u8]) {
+        dist.put(key, Box::pin(stream::iter(vec![Ok(Bytes::from_static(content))]))).await.unwrap();
+    }
+
+    async fn read(dist: &dyn Storage, key: &str) -> Vec<u8> {
+        let mut body = dist.get(key, None).await.unwrap();
+        let mut data = Vec::new();
+        while let Some(bytes) = body.next().await {
+            data.extend_from_slice(&bytes.unwrap());
+        }
+        data
+    }
+
+    #[tokio::test]
+    async fn clean_start_has_nothing_to_recover() {
+        let (staging, root) = staging("clean");
+        let report = recover(&staging, &MemoryStorage::new()).await.unwrap();
+        assert!(report.is_clean());
+        assert_eq!(report.to_string(), "recovery: nothing to recover");
+        fs::remove_dir_all(&root).ok();
+    }
+
+    #[tokio::test]
+    async fn removes_partial_files() {
+        let (staging, root) = staging("partial");
+        write(&root, "a.mp4.part", b"half an upload");
+        write(&root, "out/b.mp4.part", b"half an output");
+        write(&root, "out/c.mp4", b"output of a source that is still there");
+        write(&root, "c.mp4", b"source");
+
+        let report = recover(&staging, &MemoryStorage::new()).await.unwrap();
+        assert_eq!(report.partial_uploads, vec!["a.mp4"]);
+        assert_eq!(report.partial_outputs, vec!["b.mp4", "c.mp4"]);
+        // the source is compressed again
+        assert_eq!(report.requeued, vec![VideoKey::parse("c.mp4").unwrap()]);
+        assert_eq!(staging.list("").await.unwrap(), vec!["c.mp4"]);
+        fs::remove_dir_all(&root).ok();
+    }
+
+    #[tokio::test]
+    async fn removes_files_of_stored_videos() {
+        let (staging, root) = staging("finished");
+        let dist = MemoryStorage::new();
+        store(&dist, "a.mp4", b"compressed").await;
+        store(&dist, "b.mp4", b"compressed").await;
+        write(&root, "a.mp4", b"source");
+        write(&root, "out/b.mp4", b"output");
+
+        let report = recover(&staging, &dist).await.unwrap();
+        assert_eq!(report.finished, vec!["a.mp4", "out/b.mp4"]);
+        assert!(report.requeued.is_empty() && report.promoted.is_empty());
+        assert!(staging.list("").await.unwrap().is_empty());
+        assert_eq!(read(&dist, "b.mp4").await, b"compressed");
+        fs::remove_dir_all(&root).ok();
+    }
+
+    #[tokio::test]
+    async fn promotes_outputs_without_source() {
+        let (staging, root) = staging("promoted");
+        let dist = MemoryStorage::new();
+        write(&root, "out/a.mp4", b"compressed");
+
+        let report = recover(&staging, &dist).await.unwrap();
+        assert_eq!(report.promoted, vec!["a.mp4"]);
+        assert_eq!(read(&dist, "a.mp4").await, b"compressed");
+        assert!(staging.list("").await.unwrap().is_empty());
+        fs::remove_dir_all(&root).ok();
+    }
+
+    #[tokio::test]
+    async fn leaves_unknown_files_alone() {
+        let (staging, root) = staging("ignored");
+        write(&root, "not a key.mp4", b"?");
+
+        let report = recover(&staging, &MemoryStorage::new()).await.unwrap();
+        assert_eq!(report.ignored, vec!["not a key.mp4"]);
+        assert!(root.join("not a key.mp4").exists());
+        fs::remove_dir_all(&root).ok();
+    }
+}
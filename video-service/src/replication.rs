@@ -0,0 +1,456 @@
+use {
+    std::{collections::{BTreeMap, HashSet}, fmt, fs, future::Future, io, path::PathBuf, sync::{Arc, Mutex}, time::Duration},
+    tokio::time::timeout,
+    futures::{channel::mpsc, future, select, FutureExt, StreamExt},
+    serde::{Deserialize, Serialize},
+    tracing::{info, warn},
+    crate::{client::{Client, UnknownVideo}, storage::{Result, Storage}},
+};
+
+// limit of a request to a peer that does not move a video, so a hung peer can not stall replication
+const PEER_TIMEOUT: Duration = Duration::from_secs(30);
+// slowest copy that is still waited for, the limit of a copy grows with the size of the video
+const MIN_COPY_RATE: u64 = 64 * 1024;
+
+/// State of the copy of a video on a peer
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ReplicaState {
+    /// copy is not sent yet
+    Pending,
+    /// peer has the same number of bytes as this node
+    Done,
+    /// the last attempt failed, it is retried later
+    Failed { reason: String },
+    /// video was deleted here and the copy must be removed from the peer
+    Deleting,
+}
+
+impl fmt::Display for ReplicaState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ReplicaState::Pending => write!(f, "pending"),
+            ReplicaState::Done => write!(f, "done"),
+            ReplicaState::Failed { reason } => write!(f, "failed: {}", reason),
+            ReplicaState::Deleting => write!(f, "deleting"),
+        }
+    }
+}
+
+/// Copy of a video as it is shown to admin clients
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ReplicaInfo {
+    pub filename: String,
+    pub peer: String,
+    pub state: String,
+}
+
+type State = BTreeMap<String, BTreeMap<String, ReplicaState>>;
+
+/// Stored videos and the state of their copies on every peer
+/// copies live in memory, after restart every stored video is compared with peers again,
+/// deletes peers did not confirm yet are kept in `deletes_file`, because nothing else remembers them
+#[derive(Clone)]
+pub struct Replication {
+    peers: Arc<Vec<String>>,
+    // filename -> peer -> state
+    state: Arc<Mutex<State>>,
+    sender: mpsc::UnboundedSender<String>,
+    // certificate and key peers require, only the address changes from peer to peer
+    client: Client,
+    deletes_file: Arc<PathBuf>,
+}
+
+impl Replication {
+    /// create replication to `peers` and receiver for `replication_loop`,
+    /// deletes saved by the previous run are sent again
+    pub fn new(peers: Vec<String>, client: Client, deletes_file: PathBuf) -> Result<(Replication, mpsc::UnboundedReceiver<String>)> {
+        let mut state = State::new();
+        if !peers.is_empty() {
+            let saved: BTreeMap<String, Vec<String>> = match fs::read(&deletes_file) {
+                Ok(json) => serde_json::from_slice(&json)
+                    .map_err(|e| format!("invalid {}: {}", deletes_file.display(), e))?,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => BTreeMap::new(),
+                Err(e) => return Err(format!("can not read {}: {}", deletes_file.display(), e).into()),
+            };
+            for (filename, deleting) in saved {
+                // peers that were removed from the configuration are not reachable anymore
+                let deleting: BTreeMap<String, ReplicaState> = deleting.into_iter()
+                    .filter(|peer| peers.contains(peer))
+                    .map(|peer| (peer, ReplicaState::Deleting))
+                    .collect();
+                if !deleting.is_empty() {
+                    state.insert(filename, deleting);
+                }
+            }
+        }
+        let (sender, receiver) = mpsc::unbounded();
+        let replication = Replication {
+            peers: Arc::new(peers),
+            state: Arc::new(Mutex::new(state)),
+            sender,
+            client,
+            deletes_file: Arc::new(deletes_file),
+        };
+        Ok((replication, receiver))
+    }
+
+    pub fn peers(&self) -> &[String] {
+        &self.peers
+    }
+
+    /// copy a video that was just committed to the storage to every peer in background
+    pub fn replicate(&self, filename: &str) {
+        if self.peers.is_empty() {
+            return;
+        }
+        let peers = self.peers.iter()
+            .map(|peer| (peer.clone(), ReplicaState::Pending))
+            .collect();
+        let mut state = self.state.lock().unwrap();
+        // a video stored again is copied instead of deleted
+        if state.insert(filename.to_string(), peers).is_some_and(|peers| has_deletes(&peers)) {
+            self.save_deletes(&state);
+        }
+        self.sender.unbounded_send(filename.to_string()).ok();
+    }
+
+    /// stop tracking a deleted video, copies on peers are left untouched
+    pub fn forget(&self, filename: &str) {
+        let mut state = self.state.lock().unwrap();
+        if state.remove(filename).is_some_and(|peers| has_deletes(&peers)) {
+            self.save_deletes(&state);
+        }
+    }
+
+    /// remove copies of a deleted video from every peer in background,
+    /// failed deletes are retried like copies
+    pub fn remove(&self, filename: &str) {
+        if self.peers.is_empty() {
+            return;
+        }
+        let peers = self.peers.iter()
+            .map(|peer| (peer.clone(), ReplicaState::Deleting))
+            .collect();
+        let mut state = self.state.lock().unwrap();
+        state.insert(filename.to_string(), peers);
+        self.save_deletes(&state);
+        self.sender.unbounded_send(filename.to_string()).ok();
+    }
+
+    /// copies that peers do not have yet
+    pub fn lagging(&self) -> Vec<ReplicaInfo> {
+        self.state.lock().unwrap().iter()
+            .flat_map(|(filename, peers)| peers.iter()
+                .filter(|(_, state)| **state != ReplicaState::Done)
+                .map(move |(peer, state)| ReplicaInfo {
+                    filename: filename.clone(),
+                    peer: peer.clone(),
+                    state: state.to_string(),
+                }))
+            .collect()
+    }
+
+    // videos with at least one copy that is not done
+    fn unfinished(&self) -> Vec<String> {
+        self.state.lock().unwrap().iter()
+            .filter(|(_, peers)| peers.values().any(|state| *state != ReplicaState::Done))
+            .map(|(filename, _)| filename.clone())
+            .collect()
+    }
+
+    // peers that still need the video
+    fn missing(&self, filename: &str) -> Vec<String> {
+        match self.state.lock().unwrap().get(filename) {
+            Some(peers) => peers.iter()
+                .filter(|(_, state)| **state != ReplicaState::Done)
+                .map(|(peer, _)| peer.clone())
+                .collect(),
+            None => Vec::new(),
+        }
+    }
+
+    // peers that still have a copy of a deleted video
+    fn deleting(&self, filename: &str) -> Vec<String> {
+        match self.state.lock().unwrap().get(filename) {
+            Some(peers) => peers.iter()
+                .filter(|(_, state)| **state == ReplicaState::Deleting)
+                .map(|(peer, _)| peer.clone())
+                .collect(),
+            None => Vec::new(),
+        }
+    }
+
+    // state is not changed if the video was deleted in the meantime
+    fn set(&self, filename: &str, peer: &str, state: ReplicaState) {
+        if let Some(peers) = self.state.lock().unwrap().get_mut(filename) {
+            if peers.get(peer) != Some(&ReplicaState::Deleting) {
+                peers.insert(peer.to_string(), state);
+            }
+        }
+    }
+
+    // the copy is gone from the peer, the video is not tracked once all copies are gone,
+    // nothing changes if the video was stored again in the meantime
+    fn removed(&self, filename: &str, peer: &str) {
+        let mut state = self.state.lock().unwrap();
+        let peers = match state.get_mut(filename) {
+            Some(peers) if peers.get(peer) == Some(&ReplicaState::Deleting) => peers,
+            _ => return,
+        };
+        peers.remove(peer);
+        if peers.is_empty() {
+            state.remove(filename);
+        }
+        self.save_deletes(&state);
+    }
+
+    // write deletes that are not confirmed yet to a new file and replace the old one with it,
+    // so a crash leaves either of them whole
+    fn save_deletes(&self, state: &State) {
+        let deletes: BTreeMap<&String, Vec<&String>> = state.iter()
+            .map(|(filename, peers)| (filename, peers.iter()
+                .filter(|(_, state)| **state == ReplicaState::Deleting)
+                .map(|(peer, _)| peer)
+                .collect::<Vec<_>>()))
+            .filter(|(_, peers)| !peers.is_empty())
+            .collect();
+        let part = self.deletes_file.with_extension("part");
+        let saved = serde_json::to_vec(&deletes)
+            .map_err(io::Error::from)
+            .and_then(|json| fs::write(&part, json))
+            .and_then(|_| fs::rename(&part, self.deletes_file.as_ref()));
+        if let Err(e) = saved {
+            warn!(file = %self.deletes_file.display(), error = %e, "error saving deletes of replicas");
+        }
+    }
+
+    // send the video to peers that miss it or remove copies of a deleted one, peers in `down` are skipped
+    // and peers that fail are added to it, so a dead peer costs one attempt per pass,
+    // peers are handled at the same time, so a slow one does not hold back the others
+    async fn sync(&self, filename: &str, dist: &dyn Storage, down: &mut HashSet<String>) {
+        let deleting = self.deleting(filename);
+        if !deleting.is_empty() {
+            let peers = deleting.into_iter().filter(|peer| !down.contains(peer));
+            let results = future::join_all(peers.map(|peer| async move {
+                let result = remove(&self.client.at(&peer), filename).await;
+                (peer, result)
+            })).await;
+            for (peer, result) in results {
+                match result {
+                    Ok(()) => {
+                        info!(video = filename, %peer, "replica deleted");
+                        self.removed(filename, &peer);
+                    },
+                    Err(e) => {
+                        warn!(video = filename, %peer, error = %e, "error deleting replica");
+                        down.insert(peer);
+                    },
+                }
+            }
+            return;
+        }
+        let size = match dist.stat(filename).await {
+            Ok(Some(metadata)) => metadata.size,
+            Ok(None) => {
+                self.forget(filename);
+                return;
+            },
+            Err(e) => {
+                warn!(video = filename, error = %e, "error reading video for replication");
+                return;
+            },
+        };
+        let peers = self.missing(filename).into_iter().filter(|peer| !down.contains(peer));
+        let results = future::join_all(peers.map(|peer| async move {
+            let result = copy(&self.client.at(&peer), filename, size, dist).await;
+            (peer, result)
+        })).await;
+        for (peer, result) in results {
+            match result {
+                Ok(()) => {
+                    info!(video = filename, %peer, size, "video replicated");
+                    self.set(filename, &peer, ReplicaState::Done);
+                },
+                Err(e) => {
+                    warn!(video = filename, %peer, error = %e, "error replicating video");
+                    self.set(filename, &peer, ReplicaState::Failed { reason: e.to_string() });
+                    down.insert(peer);
+                },
+            }
+        }
+    }
+}
+
+/// send every committed video to peers and retry failed copies every `retry` interval
+/// stored videos are compared with peers on start, so peers catch up on what they missed
+pub async fn replication_loop(
+    videos: mpsc::UnboundedReceiver<String>,
+    replication: Replication,
+    dist: Arc<dyn Storage>,
+    retry: Duration,
+) {
+    if replication.peers.is_empty() {
+        return;
+    }
+    match dist.list("").await {
+        Ok(filenames) => {
+            info!(videos = filenames.len(), peers = ?replication.peers, "checking replicas");
+            for filename in filenames {
+                replication.replicate(&filename);
+            }
+        },
+        Err(e) => warn!(error = %e, "error listing videos for replication"),
+    }
+    let mut videos = videos.fuse();
+    let mut ticks = tokio::time::interval(retry);
+    loop {
+        select! {
+            filename = videos.next() => match filename {
+                Some(filename) => replication.sync(&filename, dist.as_ref(), &mut HashSet::new()).await,
+                None => return,
+            },
+            _ = ticks.tick().fuse() => {
+                let mut down = HashSet::new();
+                for filename in replication.unfinished() {
+                    replication.sync(&filename, dist.as_ref(), &mut down).await;
+                }
+            },
+        }
+    }
+}
+
+fn has_deletes(peers: &BTreeMap<String, ReplicaState>) -> bool {
+    peers.values().any(|state| *state == ReplicaState::Deleting)
+}
+
+// fail a request to a peer that does not finish in time
+async fn within<T>(limit: Duration, request: impl Future<Output = Result<T>>) -> Result<T> {
+    match timeout(limit, request).await {
+        Ok(result) => result,
+        Err(_) => Err(format!("peer did not answer in {} seconds", limit.as_secs()).into()),
+    }
+}
+
+// delete the copy on a peer, a peer that never got the copy has nothing to delete
+async fn remove(client: &Client, filename: &str) -> Result<()> {
+    match within(PEER_TIMEOUT, client.delete_replica(filename)).await {
+        Err(e) if e.is::<UnknownVideo>() => Ok(()),
+        result => result,
+    }
+}
+
+// stream the video to a peer unless it already has the same number of bytes
+async fn copy(client: &Client, filename: &str, size: u64, dist: &dyn Storage) -> Result<()> {
+    // copy could be left by an attempt that lost its response
+    if let Ok(info) = within(PEER_TIMEOUT, client.stat(filename)).await {
+        if info.size == Some(size) {
+            return Ok(());
+        }
+    }
+    let limit = PEER_TIMEOUT + Duration::from_secs(size / MIN_COPY_RATE);
+    within(limit, async { client.replicate(filename, dist.get(filename, None).await?).await }).await
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        tokio::{net::TcpListener, io::{AsyncReadExt, AsyncWriteExt}},
+        crate::{client::ClientTimeouts, storage::MemoryStorage},
+    };
+
+    fn deletes_file(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("video-service-replication-{}-{}.json", name, std::process::id()));
+        fs::remove_file(&path).ok();
+        path
+    }
+
+    fn client() -> Client {
+        let wait = Duration::from_millis(200);
+        Client::new("127.0.0.1:1").with_timeouts(ClientTimeouts { connect: wait, read: wait, idle: wait })
+    }
+
+    fn states(replication: &Replication) -> Vec<(String, String, String)> {
+        replication.lagging().into_iter().map(|info| (info.filename, info.peer, info.state)).collect()
+    }
+
+    // peer that answers every request with `answer`, or never answers if it is empty
+    async fn peer(answer: &'static [u8]) -> String {
+        let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        tokio::spawn(async move {
+            loop {
+                let (mut socket, _) = listener.accept().await.unwrap();
+                tokio::spawn(async move {
+                    let mut buf = [0u8; 1024];
+                    let _ = socket.read(&mut buf).await;
+                    if answer.is_empty() {
+                        tokio::time::delay_for(Duration::from_secs(60)).await;
+                    }
+                    socket.write_all(answer).await.ok();
+                });
+            }
+        });
+        addr
+    }
+
+    #[test]
+    fn deletes_survive_restart() {
+        let file = deletes_file("restart");
+        let peers = vec!["10.0.0.2:8091".to_string(), "10.0.0.3:8091".to_string()];
+        let (replication, _) = Replication::new(peers.clone(), client(), file.clone()).unwrap();
+        replication.remove("a.mp4");
+        replication.remove("b.mp4");
+        replication.removed("a.mp4", "10.0.0.2:8091");
+        // stored again, so it is copied instead
+        replication.replicate("b.mp4");
+
+        let (restarted, _) = Replication::new(peers, client(), file.clone()).unwrap();
+        assert_eq!(states(&restarted), vec![("a.mp4".to_string(), "10.0.0.3:8091".to_string(), "deleting".to_string())]);
+        restarted.removed("a.mp4", "10.0.0.3:8091");
+        let (restarted, _) = Replication::new(vec!["10.0.0.3:8091".to_string()], client(), file.clone()).unwrap();
+        assert!(restarted.lagging().is_empty());
+        fs::remove_file(&file).ok();
+    }
+
+    #[test]
+    fn deletes_of_removed_peers_are_dropped() {
+        let file = deletes_file("removed-peers");
+        fs::write(&file, r#"{"a.mp4":["10.0.0.2:8091","10.0.0.9:8091"]}"#).unwrap();
+        let (replication, _) = Replication::new(vec!["10.0.0.2:8091".to_string()], client(), file.clone()).unwrap();
+        assert_eq!(states(&replication), vec![("a.mp4".to_string(), "10.0.0.2:8091".to_string(), "deleting".to_string())]);
+
+        fs::write(&file, "not json").unwrap();
+        assert!(Replication::new(vec!["10.0.0.2:8091".to_string()], client(), file.clone()).is_err());
+        // the file is not read without peers
+        assert!(Replication::new(Vec::new(), client(), file.clone()).is_ok());
+        fs::remove_file(&file).ok();
+    }
+
+    #[tokio::test]
+    async fn peer_without_copy_has_nothing_to_delete() {
+        let file = deletes_file("unknown");
+        let peer = peer(b"ERROR unknown video").await;
+        let (replication, _) = Replication::new(vec![peer.clone()], client(), file.clone()).unwrap();
+        replication.remove("a.mp4");
+        let mut down = HashSet::new();
+        replication.sync("a.mp4", &MemoryStorage::new(), &mut down).await;
+        assert!(down.is_empty());
+        assert!(replication.lagging().is_empty());
+        assert_eq!(fs::read_to_string(&file).unwrap(), "{}");
+        fs::remove_file(&file).ok();
+    }
+
+    #[tokio::test]
+    async fn hung_peer_does_not_hold_back_others() {
+        let file = deletes_file("hung");
+        let (hung, healthy) = (peer(b"").await, peer(b"OK").await);
+        let (replication, _) = Replication::new(vec![hung.clone(), healthy], client(), file.clone()).unwrap();
+        replication.remove("a.mp4");
+        let mut down = HashSet::new();
+        replication.sync("a.mp4", &MemoryStorage::new(), &mut down).await;
+        assert_eq!(down, vec![hung.clone()].into_iter().collect());
+        assert_eq!(states(&replication), vec![("a.mp4".to_string(), hung, "deleting".to_string())]);
+        fs::remove_file(&file).ok();
+    }
+}
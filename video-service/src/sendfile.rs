@@ -0,0 +1,171 @@
+use {
+    std::{fs::File, io, os::unix::io::{AsRawFd, RawFd}, path::Path, task::Poll, time::Duration},
+    futures::future,
+    mio::{unix::EventedFd, Evented, PollOpt, Ready, Token},
+    tokio::{io::PollEvented, time::timeout},
+};
+
+// bytes handed to a single `sendfile` call, a download shows progress at least this often
+const CHUNK: usize = 4194304; // 4 Mb
+
+/// `sendfile` is available on this platform
+pub fn supported() -> bool {
+    cfg!(target_os = "linux")
+}
+
+/// send `len` bytes of the file at `path` starting at `offset` to the connection `socket`,
+/// bytes go from the page cache into the socket without being copied through the service,
+/// `on_sent` is called with the amount of every chunk and the download fails when the client
+/// does not take a chunk for `idle`
+pub async fn send(
+    socket: RawFd,
+    path: &Path,
+    offset: u64,
+    len: u64,
+    idle: Duration,
+    mut on_sent: impl FnMut(u64),
+) -> io::Result<()> {
+    let file = File::open(path)?;
+    let socket = PollEvented::new(Socket::dup(socket)?)?;
+    let end = offset + len;
+    let mut offset = offset;
+    while offset < end {
+        let count = CHUNK.min((end - offset) as usize);
+        let sent = future::poll_fn(|cx| loop {
+            if let Poll::Ready(Err(e)) = socket.poll_write_ready(cx) {
+                return Poll::Ready(Err(e));
+            }
+            match sendfile(socket.get_ref().0, &file, &mut offset, count) {
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    // the task is woken up once the client reads and the socket has room again
+                    socket.clear_write_ready(cx)?;
+                    return Poll::Pending;
+                },
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                result => return Poll::Ready(result),
+            }
+        });
+        let sent = timeout(idle, sent).await
+            .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, format!("client did not read for {} seconds", idle.as_secs())))??;
+        if sent == 0 {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "file is shorter than expected"));
+        }
+        on_sent(sent as u64);
+    }
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+fn sendfile(socket: RawFd, file: &File, offset: &mut u64, count: usize) -> io::Result<usize> {
+    let mut off = *offset as libc::off_t;
+    // safety: both descriptors stay open for the call and `off` outlives it
+    let sent = unsafe { libc::sendfile(socket, file.as_raw_fd(), &mut off, count) };
+    if sent < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    *offset = off as u64;
+    Ok(sent as usize)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn sendfile(_socket: RawFd, _file: &File, _offset: &mut u64, _count: usize) -> io::Result<usize> {
+    Err(io::Error::other("sendfile is not supported on this platform"))
+}
+
+// copy of the connection's descriptor, the connection itself is already registered in the reactor,
+// so readiness of writes is watched through a descriptor of its own
+struct Socket(RawFd);
+
+impl Socket {
+    fn dup(fd: RawFd) -> io::Result<Socket> {
+        // safety: `fd` is an open socket of a connection the caller holds
+        match unsafe { libc::fcntl(fd, libc::F_DUPFD_CLOEXEC, 0) } {
+            -1 => Err(io::Error::last_os_error()),
+            fd => Ok(Socket(fd)),
+        }
+    }
+}
+
+impl Drop for Socket {
+    fn drop(&mut self) {
+        // safety: the copy belongs to this struct only
+        unsafe { libc::close(self.0) };
+    }
+}
+
+impl Evented for Socket {
+    fn register(&self, poll: &mio::Poll, token: Token, interest: Ready, opts: PollOpt) -> io::Result<()> {
+        EventedFd(&self.0).register(poll, token, interest, opts)
+    }
+
+    fn reregister(&self, poll: &mio::Poll, token: Token, interest: Ready, opts: PollOpt) -> io::Result<()> {
+        EventedFd(&self.0).reregister(poll, token, interest, opts)
+    }
+
+    fn deregister(&self, poll: &mio::Poll) -> io::Result<()> {
+        EventedFd(&self.0).deregister(poll)
+    }
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use {
+        super::*,
+        std::path::PathBuf,
+        tokio::{io::AsyncReadExt, net::{TcpListener, TcpStream}},
+    };
+
+    fn file(name: &str, content: &[u8]) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("video-service-sendfile-{}-{}", name, std::process::id()));
+        std::fs::write(&path, content).unwrap();
+        path
+    }
+
+    async fn pair() -> (TcpStream, TcpStream) {
+        let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+        let (server, _) = listener.accept().await.unwrap();
+        (server, client)
+    }
+
+    #[tokio::test]
+    async fn sends_range_of_file() {
+        let content: Vec<u8> = (0..10_000_000u32).map(|i| i as u8).collect();
+        let path = file("range", &content);
+        let (server, mut client) = pair().await;
+        let reader = tokio::spawn(async move {
+            let mut received = Vec::new();
+            client.read_to_end(&mut received).await.unwrap();
+            received
+        });
+        let mut sent = 0;
+        send(server.as_raw_fd(), &path, 1000, 9_000_000, Duration::from_secs(5), |n| sent += n).await.unwrap();
+        drop(server);
+        assert_eq!(sent, 9_000_000);
+        assert!(reader.await.unwrap() == content[1000..9_001_000]);
+        std::fs::remove_file(&path).ok();
+    }
+
+    #[tokio::test]
+    async fn fails_when_file_is_shorter() {
+        let path = file("short", b"0123456789");
+        let (server, _client) = pair().await;
+        let e = send(server.as_raw_fd(), &path, 5, 10, Duration::from_secs(5), |_| {}).await.unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::UnexpectedEof);
+        std::fs::remove_file(&path).ok();
+    }
+
+    #[tokio::test]
+    async fn fails_when_client_does_not_read() {
+        let path = file("idle", &vec![0u8; 64 << 20]);
+        let (server, _client) = pair().await;
+        let e = send(server.as_raw_fd(), &path, 0, 64 << 20, Duration::from_millis(200), |_| {}).await.unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::TimedOut);
+        std::fs::remove_file(&path).ok();
+    }
+
+    #[test]
+    fn supported_on_linux() {
+        assert!(supported());
+    }
+}
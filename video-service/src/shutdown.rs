@@ -0,0 +1,81 @@
+use {
+    std::{
+        collections::BTreeMap,
+        sync::{Arc, Mutex, atomic::{AtomicBool, AtomicU64, Ordering}},
+        time::{Duration, Instant},
+    },
+    tokio::{signal::{self, unix::{self, SignalKind}}, time},
+    futures::{future, FutureExt},
+    crate::storage::Result,
+};
+
+// how often draining checks whether work is done
+const DRAIN_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Graceful shutdown: the flag telling the service to stop and the work it has to wait for
+#[derive(Clone, Default)]
+pub struct Shutdown {
+    stopping: Arc<AtomicBool>,
+    // descriptions of work in progress, e.g. `upload 1234567.mp4`
+    active: Arc<Mutex<BTreeMap<u64, String>>>,
+    next_id: Arc<AtomicU64>,
+}
+
+/// Work in progress, it is done when the guard is dropped
+pub struct Guard {
+    shutdown: Shutdown,
+    id: u64,
+}
+
+impl Drop for Guard {
+    fn drop(&mut self) {
+        self.shutdown.active.lock().unwrap().remove(&self.id);
+    }
+}
+
+impl Shutdown {
+    pub fn new() -> Shutdown {
+        Shutdown::default()
+    }
+
+    /// register work the service must wait for before exit
+    pub fn track(&self, what: String) -> Guard {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        self.active.lock().unwrap().insert(id, what);
+        Guard { shutdown: self.clone(), id }
+    }
+
+    /// work in progress in the order it was started
+    pub fn active(&self) -> Vec<String> {
+        self.active.lock().unwrap().values().cloned().collect()
+    }
+
+    /// no new work should be started after this
+    pub fn stop(&self) {
+        self.stopping.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_stopping(&self) -> bool {
+        self.stopping.load(Ordering::SeqCst)
+    }
+
+    /// wait until all work is done or `timeout` passes
+    /// returns work that is still in progress and is going to be interrupted
+    pub async fn drain(&self, timeout: Duration) -> Vec<String> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            let active = self.active();
+            if active.is_empty() || Instant::now() >= deadline {
+                return active;
+            }
+            time::delay_for(DRAIN_INTERVAL).await;
+        }
+    }
+}
+
+/// resolves on SIGTERM or Ctrl-C
+pub async fn signal() -> Result<()> {
+    let mut terminate = unix::signal(SignalKind::terminate())?;
+    future::select(terminate.recv().boxed(), signal::ctrl_c().boxed()).await;
+    Ok(())
+}
@@ -0,0 +1,259 @@
+use {
+    std::fmt,
+};
+
+/// Amount of first bytes that is enough to recognize any supported container
+pub const SNIFF_LEN: usize = 1024;
+
+// MPEG transport stream packets
+const TS_PACKET: usize = 188;
+const M2TS_PACKET: usize = 192;
+const TS_SYNC: u8 = 0x47;
+// packets in a row that must start with a sync byte, `SNIFF_LEN` bytes hold six of them
+const TS_MIN_PACKETS: usize = 5;
+
+/// Video containers video service accepts
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Container {
+    Mp4,
+    QuickTime,
+    Matroska,
+    WebM,
+    Avi,
+    MpegTs,
+    MpegPs,
+    Flv,
+    Ogg,
+}
+
+impl Container {
+    /// recognize container by magic bytes and headers at the beginning of a file
+    /// `head` should contain at least `SNIFF_LEN` bytes unless the file is shorter
+    pub fn detect(head: &[u8]) -> Option<Container> {
+        // ISO base media: size of the first box followed by its type
+        if head.len() >= 12 && &head[4..8] == b"ftyp" {
+            return match &head[8..12] {
+                b"qt  " => Some(Container::QuickTime),
+                _ => Some(Container::Mp4),
+            };
+        }
+        // old QuickTime files have no `ftyp`, padding atoms may come before the movie or its data
+        if has_movie_atom(head) {
+            return Some(Container::QuickTime);
+        }
+        // EBML header, document type tells WebM from Matroska
+        if head.starts_with(&[0x1A, 0x45, 0xDF, 0xA3]) {
+            let header = &head[..head.len().min(64)];
+            return if header.windows(4).any(|w| w == b"webm") {
+                Some(Container::WebM)
+            } else {
+                Some(Container::Matroska)
+            };
+        }
+        if head.len() >= 12 && &head[0..4] == b"RIFF" && &head[8..12] == b"AVI " {
+            return Some(Container::Avi);
+        }
+        if head.starts_with(&[0x00, 0x00, 0x01, 0xBA]) {
+            return Some(Container::MpegPs);
+        }
+        if head.starts_with(b"FLV") {
+            return Some(Container::Flv);
+        }
+        // Ogg may carry audio only, so one of its streams must be video
+        if head.starts_with(b"OggS") && has_ogg_video(head) {
+            return Some(Container::Ogg);
+        }
+        // transport stream has no header, but every packet starts with a sync byte
+        if is_transport_stream(head, 0, TS_PACKET) || is_transport_stream(head, 4, M2TS_PACKET) {
+            return Some(Container::MpegTs);
+        }
+        None
+    }
+
+    /// find container by file extension
+    pub fn from_extension(ext: &str) -> Option<Container> {
+        let container = match ext.to_ascii_lowercase().as_str() {
+            "mp4" | "m4v" => Container::Mp4,
+            "mov" => Container::QuickTime,
+            "mkv" => Container::Matroska,
+            "webm" => Container::WebM,
+            "avi" => Container::Avi,
+            "ts" | "m2ts" => Container::MpegTs,
+            "mpg" | "mpeg" => Container::MpegPs,
+            "flv" => Container::Flv,
+            "ogv" | "ogg" => Container::Ogg,
+            _ => return None,
+        };
+        Some(container)
+    }
+
+    /// extension of files with this container, ffmpeg picks output format by it
+    pub fn extension(self) -> &'static str {
+        match self {
+            Container::Mp4 => "mp4",
+            Container::QuickTime => "mov",
+            Container::Matroska => "mkv",
+            Container::WebM => "webm",
+            Container::Avi => "avi",
+            Container::MpegTs => "ts",
+            Container::MpegPs => "mpg",
+            Container::Flv => "flv",
+            Container::Ogg => "ogv",
+        }
+    }
+
+    pub fn mime_type(self) -> &'static str {
+        match self {
+            Container::Mp4 => "video/mp4",
+            Container::QuickTime => "video/quicktime",
+            Container::Matroska => "video/x-matroska",
+            Container::WebM => "video/webm",
+            Container::Avi => "video/x-msvideo",
+            Container::MpegTs => "video/mp2t",
+            Container::MpegPs => "video/mpeg",
+            Container::Flv => "video/x-flv",
+            Container::Ogg => "video/ogg",
+        }
+    }
+}
+
+// padding atoms QuickTime files may start with
+const QT_PADDING: [&[u8; 4]; 3] = [b"wide", b"free", b"skip"];
+// first bytes of the identification header of Ogg video codecs: Theora, Daala, Dirac and VP8
+const OGG_VIDEO: [&[u8]; 4] = [b"\x80theora", b"\x80daala", b"BBCD\x00", b"OVP80"];
+
+// top level atoms are skipped while they are padding, a `moov` or `mdat` atom must follow within `head`
+fn has_movie_atom(head: &[u8]) -> bool {
+    let mut offset = 0;
+    while offset + 8 <= head.len() {
+        let kind = &head[offset + 4..offset + 8];
+        if kind == b"moov" || kind == b"mdat" {
+            return true;
+        }
+        if !QT_PADDING.iter().any(|padding| &padding[..] == kind) {
+            return false;
+        }
+        let size = u32::from_be_bytes([head[offset], head[offset + 1], head[offset + 2], head[offset + 3]]) as usize;
+        // 0 is an atom up to the end of the file and 1 a 64-bit size, neither is plain padding
+        if size < 8 {
+            return false;
+        }
+        offset += size;
+    }
+    false
+}
+
+// streams of an Ogg file start with pages marked as beginning of stream,
+// the first packet of such a page names the codec
+fn has_ogg_video(head: &[u8]) -> bool {
+    let mut offset = 0;
+    while head.len() >= offset + 27 && &head[offset..offset + 4] == b"OggS" {
+        // beginning of stream pages of all streams come before any other page
+        if head[offset + 5] & 0x02 == 0 {
+            return false;
+        }
+        let segments = head[offset + 26] as usize;
+        let table = offset + 27;
+        if head.len() < table + segments {
+            return false;
+        }
+        let payload = &head[table + segments..];
+        if OGG_VIDEO.iter().any(|codec| payload.starts_with(codec)) {
+            return true;
+        }
+        offset = table + segments + head[table..table + segments].iter().map(|&len| len as usize).sum::<usize>();
+    }
+    false
+}
+
+impl fmt::Display for Container {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.extension())
+    }
+}
+
+// every packet in `head` must start with a sync byte and there must be enough of them,
+// a few bytes of `0x47` at the right distance are common in any binary or text data
+fn is_transport_stream(head: &[u8], offset: usize, packet: usize) -> bool {
+    let syncs = (offset..head.len()).step_by(packet).collect::<Vec<usize>>();
+    syncs.len() >= TS_MIN_PACKETS && syncs.iter().all(|&i| head[i] == TS_SYNC)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Ogg page that begins a stream with a single packet
+    fn ogg_page(first: bool, packet: &[u8]) -> Vec<u8> {
+        let mut page = b"OggS\x00".to_vec();
+        page.push(if first { 0x02 } else { 0x00 });
+        page.extend_from_slice(&[0; 20]);
+        page.push(1);
+        page.push(packet.len() as u8);
+        page.extend_from_slice(packet);
+        page
+    }
+
+    fn atom(kind: &[u8; 4], size: u32) -> Vec<u8> {
+        let mut atom = size.to_be_bytes().to_vec();
+        atom.extend_from_slice(kind);
+        atom.resize(size as usize, 0);
+        atom
+    }
+
+    #[test]
+    fn detects_iso_media_by_brand() {
+        assert_eq!(Container::detect(b"\x00\x00\x00\x18ftypisom\x00\x00\x02\x00"), Some(Container::Mp4));
+        assert_eq!(Container::detect(b"\x00\x00\x00\x14ftypqt  \x00\x00\x02\x00"), Some(Container::QuickTime));
+    }
+
+    #[test]
+    fn quicktime_needs_movie_after_padding() {
+        let head = [atom(b"wide", 8), atom(b"mdat", 16)].concat();
+        assert_eq!(Container::detect(&head), Some(Container::QuickTime));
+        let head = [atom(b"free", 32), atom(b"skip", 8), atom(b"moov", 8)].concat();
+        assert_eq!(Container::detect(&head), Some(Container::QuickTime));
+        assert_eq!(Container::detect(&atom(b"free", 64)), None);
+        assert_eq!(Container::detect(&[atom(b"skip", 8), atom(b"junk", 8)].concat()), None);
+    }
+
+    #[test]
+    fn ogg_needs_video_stream() {
+        let head = [ogg_page(true, b"\x01vorbis"), ogg_page(true, b"\x80theora")].concat();
+        assert_eq!(Container::detect(&head), Some(Container::Ogg));
+        let head = [ogg_page(true, b"OpusHead"), ogg_page(false, b"audio")].concat();
+        assert_eq!(Container::detect(&head), None);
+    }
+
+    // packets of `packet` bytes with a sync byte after `offset` bytes of a timestamp
+    fn transport_stream(len: usize, offset: usize, packet: usize) -> Vec<u8> {
+        let mut head = vec![0xFFu8; len];
+        for i in (offset..len).step_by(packet) {
+            head[i] = TS_SYNC;
+        }
+        head
+    }
+
+    #[test]
+    fn transport_stream_needs_sync_in_every_packet() {
+        assert_eq!(Container::detect(&transport_stream(SNIFF_LEN, 0, TS_PACKET)), Some(Container::MpegTs));
+        assert_eq!(Container::detect(&transport_stream(SNIFF_LEN, 4, M2TS_PACKET)), Some(Container::MpegTs));
+        // two or three sync bytes at the right distance are not enough
+        assert_eq!(Container::detect(&transport_stream(TS_PACKET * 2, 0, TS_PACKET)), None);
+        assert_eq!(Container::detect(&transport_stream(TS_PACKET * 3 + 1, 0, TS_PACKET)), None);
+        // one packet without a sync byte breaks the stream
+        let mut head = transport_stream(SNIFF_LEN, 0, TS_PACKET);
+        head[TS_PACKET * 4] = 0;
+        assert_eq!(Container::detect(&head), None);
+        let mut text = vec![b' '; SNIFF_LEN];
+        text[0] = b'G';
+        text[TS_PACKET] = b'G';
+        assert_eq!(Container::detect(&text), None);
+    }
+
+    #[test]
+    fn refuses_unknown_content() {
+        assert_eq!(Container::detect(b""), None);
+        assert_eq!(Container::detect(b"plain text, not a video"), None);
+    }
+}
@@ -0,0 +1,255 @@
+use {
+    std::{io::{self, SeekFrom}, path::PathBuf},
+    futures::{stream, StreamExt},
+    bytes::Bytes,
+    async_std::{fs::{self, File}, path::Path},
+    async_std::prelude::*,
+    async_trait::async_trait,
+    sha2::{Digest, Sha256},
+    super::{ByteRange, ByteStream, Metadata, Result, Storage},
+};
+
+// size of a chunk that is read from a file at once
+const LEN: usize = 1572864; // 1.5 Mb
+// suffix of files that are being written right now
+const PART_SUFFIX: &str = ".part";
+
+/// Storage that keeps objects as files inside of a root directory
+pub struct LocalStorage {
+    root: PathBuf,
+    shard_depth: usize,
+}
+
+impl LocalStorage {
+    /// create storage and its root directory if needed
+    pub fn new<P: Into<PathBuf>>(root: P) -> Result<LocalStorage> {
+        let root = root.into();
+        std::fs::create_dir_all(&root)?;
+        Ok(LocalStorage { root, shard_depth: 0 })
+    }
+
+    /// spread files over `depth` levels of nested directories named after the hash of a key,
+    /// e.g. `ab/cd/1234567.mp4` for depth 2, so a single directory never holds too many entries
+    /// depth must not change for existing storage, otherwise stored files can't be found
+    pub fn with_sharding(mut self, depth: usize) -> LocalStorage {
+        self.shard_depth = depth;
+        self
+    }
+
+    /// path of the file that holds object with `key`
+    pub fn path(&self, key: &str) -> PathBuf {
+        if self.shard_depth == 0 {
+            return self.root.join(key);
+        }
+        let hash = hex::encode(Sha256::digest(key.as_bytes()));
+        let mut path = self.root.clone();
+        for level in 0..self.shard_depth.min(hash.len() / 2) {
+            path.push(&hash[level * 2..level * 2 + 2]);
+        }
+        path.join(key)
+    }
+
+    // path of the file that holds half-written object with `key`
+    fn part_path(&self, key: &str) -> PathBuf {
+        PathBuf::from(format!("{}{}", self.path(key).display(), PART_SUFFIX))
+    }
+
+    // list every file in the tree including half-written ones,
+    // keys may contain nested directories
+    async fn walk(&self) -> Result<Vec<String>> {
+        let mut keys = Vec::new();
+        let mut dirs = vec![self.root.clone()];
+        while let Some(dir) = dirs.pop() {
+            let mut entries = fs::read_dir(&dir).await?;
+            while let Some(entry) = entries.next().await {
+                let entry = entry?;
+                let path: PathBuf = entry.path().into();
+                if entry.file_type().await?.is_dir() {
+                    dirs.push(path);
+                    continue;
+                }
+                if let Ok(key) = path.strip_prefix(&self.root) {
+                    // skip directories of shards, the rest is the key
+                    let key = key.components()
+                        .skip(self.shard_depth)
+                        .map(|c| c.as_os_str().to_string_lossy())
+                        .collect::<Vec<_>>()
+                        .join("/");
+                    keys.push(key);
+                }
+            }
+        }
+        keys.sort();
+        Ok(keys)
+    }
+}
+
+#[async_trait]
+impl Storage for LocalStorage {
+    async fn put(&self, key: &str, mut body: ByteStream) -> Result<u64> {
+        let path = self.path(key);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).await?;
+        }
+        // write into temp file first, so readers never see half-written object
+        let part = self.part_path(key);
+        let result = async {
+            let mut f = File::create(&part).await?;
+            let mut written = 0u64;
+            while let Some(bytes) = body.next().await {
+                let bytes = bytes?;
+                f.write_all(&bytes).await?;
+                written += bytes.len() as u64;
+            }
+            f.sync_all().await?;
+            drop(f);
+            fs::rename(&part, &path).await?;
+            Ok::<u64, io::Error>(written)
+        }.await;
+        // a broken upload, a full disk or a failed rename leave nothing behind
+        if result.is_err() {
+            fs::remove_file(&part).await.ok();
+        }
+        Ok(result?)
+    }
+
+    async fn get(&self, key: &str, range: Option<ByteRange>) -> Result<ByteStream> {
+        let mut f = File::open(self.path(key)).await?;
+        let size = f.metadata().await?.len();
+        let range = range.unwrap_or(ByteRange { start: 0, end: None });
+        let remaining = range.len(size);
+        if remaining > 0 {
+            f.seek(SeekFrom::Start(range.start)).await?;
+        }
+
+        // iterate over the file and pass chunks of bytes into the stream
+        let chunks = stream::unfold((f, remaining), |(mut f, remaining)| async move {
+            if remaining == 0 {
+                return None;
+            }
+            let mut buf = vec![0u8; LEN.min(remaining as usize)];
+            match f.read(&mut buf).await {
+                Ok(0) => None,
+                Ok(n) => {
+                    buf.truncate(n);
+                    Some((Ok(Bytes::from(buf)), (f, remaining - n as u64)))
+                },
+                Err(e) => Some((Err(e), (f, 0))),
+            }
+        });
+        Ok(Box::pin(chunks))
+    }
+
+    fn local_path(&self, key: &str) -> Option<PathBuf> {
+        Some(self.path(key))
+    }
+
+    async fn stat(&self, key: &str) -> Result<Option<Metadata>> {
+        let path = self.path(key);
+        if !Path::new(&path).exists().await {
+            return Ok(None);
+        }
+        let meta = fs::metadata(&path).await?;
+        Ok(Some(Metadata {
+            size: meta.len(),
+            modified: meta.modified().ok(),
+        }))
+    }
+
+    async fn delete(&self, key: &str) -> Result<()> {
+        match fs::remove_file(self.path(key)).await {
+            Ok(()) => Ok(()),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    async fn list(&self, prefix: &str) -> Result<Vec<String>> {
+        let keys = self.walk().await?
+            .into_iter()
+            .filter(|key| !key.ends_with(PART_SUFFIX) && key.starts_with(prefix))
+            .collect();
+        Ok(keys)
+    }
+
+    async fn cleanup(&self) -> Result<Vec<String>> {
+        let mut removed = Vec::new();
+        for key in self.walk().await? {
+            if let Some(key) = key.strip_suffix(PART_SUFFIX) {
+                fs::remove_file(self.part_path(key)).await?;
+                removed.push(key.to_string());
+            }
+        }
+        Ok(removed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        crate::key::VideoKey,
+    };
+
+    fn root(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("video-service-local-{}-{}", name, std::process::id()))
+    }
+
+    #[test]
+    fn keys_map_to_hash_directories() {
+        let root = root("paths");
+        let key = VideoKey::parse("1234567.mp4").unwrap();
+        let hash = hex::encode(Sha256::digest(key.as_str().as_bytes()));
+
+        let storage = LocalStorage::new(&root).unwrap();
+        assert_eq!(storage.path(key.as_str()), root.join("1234567.mp4"));
+
+        let storage = storage.with_sharding(2);
+        let path = storage.path(key.as_str());
+        assert_eq!(path, root.join(&hash[0..2]).join(&hash[2..4]).join("1234567.mp4"));
+        // the same key always lands in the same place
+        assert_eq!(storage.path(key.as_str()), path);
+
+        // depth is limited by the length of the hash
+        let path = storage.with_sharding(100).path(key.as_str());
+        assert_eq!(path.strip_prefix(&root).unwrap().components().count(), 33);
+        std::fs::remove_dir_all(&root).ok();
+    }
+
+    #[tokio::test]
+    async fn sharded_files_are_listed_by_key() {
+        let root = root("list");
+        let storage = LocalStorage::new(&root).unwrap().with_sharding(2);
+        for key in &["b.mp4", "a.mp4"] {
+            let body: ByteStream = Box::pin(stream::iter(vec![Ok(Bytes::from_static(b"video"))]));
+            storage.put(key, body).await.unwrap();
+            assert!(storage.path(key).starts_with(&root));
+        }
+        assert_eq!(storage.list("").await.unwrap(), vec!["a.mp4", "b.mp4"]);
+        assert_eq!(storage.stat("a.mp4").await.unwrap().map(|metadata| metadata.size), Some(5));
+        std::fs::remove_dir_all(&root).ok();
+    }
+
+    #[tokio::test]
+    async fn failed_writes_leave_no_part_file() {
+        let root = root("part");
+        let storage = LocalStorage::new(&root).unwrap();
+
+        // the stream of the client breaks in the middle
+        let body: ByteStream = Box::pin(stream::iter(vec![
+            Ok(Bytes::from_static(b"video")),
+            Err(io::Error::new(io::ErrorKind::ConnectionReset, "reset")),
+        ]));
+        assert!(storage.put("a.mp4", body).await.is_err());
+        assert!(!root.join("a.mp4.part").exists());
+        assert!(storage.stat("a.mp4").await.unwrap().is_none());
+
+        // the file can not take the place of the temp file
+        std::fs::create_dir_all(root.join("b.mp4").join("inner")).unwrap();
+        let body: ByteStream = Box::pin(stream::iter(vec![Ok(Bytes::from_static(b"video"))]));
+        assert!(storage.put("b.mp4", body).await.is_err());
+        assert!(!root.join("b.mp4.part").exists());
+        assert!(storage.cleanup().await.unwrap().is_empty());
+        std::fs::remove_dir_all(&root).ok();
+    }
+}
@@ -0,0 +1,133 @@
+use {
+    std::{collections::HashMap, io, sync::Mutex, time::SystemTime},
+    futures::{stream, StreamExt},
+    bytes::{Bytes, BytesMut},
+    async_trait::async_trait,
+    super::{ByteRange, ByteStream, Metadata, Result, Storage},
+};
+
+/// Storage that keeps all objects in RAM
+/// useful for tests and local experiments
+#[derive(Default)]
+pub struct MemoryStorage {
+    objects: Mutex<HashMap<String, (Bytes, SystemTime)>>,
+}
+
+impl MemoryStorage {
+    /// create empty storage
+    pub fn new() -> MemoryStorage {
+        MemoryStorage::default()
+    }
+}
+
+#[async_trait]
+impl Storage for MemoryStorage {
+    async fn put(&self, key: &str, mut body: ByteStream) -> Result<u64> {
+        let mut buf = BytesMut::new();
+        while let Some(bytes) = body.next().await {
+            buf.extend_from_slice(&bytes?);
+        }
+        let written = buf.len() as u64;
+        self.objects.lock().unwrap().insert(key.to_string(), (buf.freeze(), SystemTime::now()));
+        Ok(written)
+    }
+
+    async fn get(&self, key: &str, range: Option<ByteRange>) -> Result<ByteStream> {
+        let data = match self.objects.lock().unwrap().get(key) {
+            Some((data, _)) => data.clone(),
+            None => return Err(io::Error::new(io::ErrorKind::NotFound, "object does not exist").into()),
+        };
+        let data = match range {
+            Some(range) => {
+                let start = (range.start as usize).min(data.len());
+                data.slice(start..start + range.len(data.len() as u64) as usize)
+            },
+            None => data,
+        };
+        Ok(Box::pin(stream::once(async move { Ok(data) })))
+    }
+
+    async fn stat(&self, key: &str) -> Result<Option<Metadata>> {
+        Ok(self.objects.lock().unwrap().get(key).map(|(data, modified)| Metadata {
+            size: data.len() as u64,
+            modified: Some(*modified),
+        }))
+    }
+
+    async fn delete(&self, key: &str) -> Result<()> {
+        self.objects.lock().unwrap().remove(key);
+        Ok(())
+    }
+
+    async fn list(&self, prefix: &str) -> Result<Vec<String>> {
+        let mut keys = self.objects.lock().unwrap()
+            .keys()
+            .filter(|key| key.starts_with(prefix))
+            .cloned()
+            .collect::<Vec<String>>();
+        keys.sort();
+        Ok(keys)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn body(chunks: &[&'static [u8]]) -> ByteStream {
+        Box::pin(stream::iter(chunks.iter().map(|chunk| Ok(Bytes::from_static(chunk))).collect::<Vec<_>>()))
+    }
+
+    async fn read(storage: &MemoryStorage, key: &str, range: Option<ByteRange>) -> Vec<u8> {
+        let mut body = storage.get(key, range).await.unwrap();
+        let mut data = Vec::new();
+        while let Some(bytes) = body.next().await {
+            data.extend_from_slice(&bytes.unwrap());
+        }
+        data
+    }
+
+    #[tokio::test]
+    async fn put_get_and_delete() {
+        let storage = MemoryStorage::new();
+        assert_eq!(storage.put("a.mp4", body(&[b"hello ", b"world"])).await.unwrap(), 11);
+        assert_eq!(read(&storage, "a.mp4", None).await, b"hello world");
+        assert_eq!(storage.stat("a.mp4").await.unwrap().map(|metadata| metadata.size), Some(11));
+
+        storage.delete("a.mp4").await.unwrap();
+        assert!(storage.stat("a.mp4").await.unwrap().is_none());
+        assert!(storage.get("a.mp4", None).await.is_err());
+        // deleting a missing object is not an error
+        storage.delete("a.mp4").await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn put_overwrites() {
+        let storage = MemoryStorage::new();
+        storage.put("a.mp4", body(&[b"old content"])).await.unwrap();
+        storage.put("a.mp4", body(&[b"new"])).await.unwrap();
+        assert_eq!(read(&storage, "a.mp4", None).await, b"new");
+    }
+
+    #[tokio::test]
+    async fn get_ranges() {
+        let storage = MemoryStorage::new();
+        storage.put("a.mp4", body(&[b"0123456789"])).await.unwrap();
+        let range = |start, end| Some(ByteRange { start, end });
+        assert_eq!(read(&storage, "a.mp4", range(2, Some(4))).await, b"234");
+        assert_eq!(read(&storage, "a.mp4", range(7, None)).await, b"789");
+        assert_eq!(read(&storage, "a.mp4", range(8, Some(100))).await, b"89");
+        assert_eq!(read(&storage, "a.mp4", range(10, None)).await, b"");
+    }
+
+    #[tokio::test]
+    async fn lists_by_prefix() {
+        let storage = MemoryStorage::new();
+        for key in &["b.mp4", "a.mp4", "ab.mp4"] {
+            storage.put(key, body(&[b"x"])).await.unwrap();
+        }
+        assert_eq!(storage.list("").await.unwrap(), vec!["a.mp4", "ab.mp4", "b.mp4"]);
+        assert_eq!(storage.list("a").await.unwrap(), vec!["a.mp4", "ab.mp4"]);
+        assert!(storage.list("c").await.unwrap().is_empty());
+    }
+}
@@ -0,0 +1,165 @@
+use {
+    std::{io, path::PathBuf, pin::Pin, sync::Arc, time::{SystemTime, UNIX_EPOCH}},
+    futures::Stream,
+    bytes::Bytes,
+    async_trait::async_trait,
+    serde::{Deserialize, Serialize},
+    crate::config::StorageConfig,
+};
+
+mod local;
+mod memory;
+mod s3;
+
+pub use {
+    local::LocalStorage,
+    memory::MemoryStorage,
+    s3::S3Storage,
+};
+
+// custom types to simplify code
+pub type Result<T> = std::result::Result<T, Box<dyn std::error::Error + Send + Sync>>;
+/// Stream of bytes that is written to or read from a storage backend
+pub type ByteStream = Pin<Box<dyn Stream<Item = io::Result<Bytes>> + Send>>;
+
+/// Part of the object requested by the client, `end` is inclusive
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ByteRange {
+    pub start: u64,
+    pub end: Option<u64>,
+}
+
+impl ByteRange {
+    /// parse range in `start-end` or `start-` form
+    pub fn parse(input: &str) -> std::result::Result<ByteRange, String> {
+        let mut parts = input.splitn(2, '-');
+        let start = parts.next()
+            .and_then(|s| s.parse::<u64>().ok())
+            .ok_or_else(|| format!("invalid range: {}", input))?;
+        let end = match parts.next() {
+            Some("") => None,
+            Some(s) => Some(s.parse::<u64>().map_err(|_| format!("invalid range: {}", input))?),
+            None => return Err(format!("invalid range: {}", input)),
+        };
+        if end.is_some_and(|end| end < start) {
+            return Err(format!("invalid range: {}", input));
+        }
+        Ok(ByteRange { start, end })
+    }
+
+    /// number of bytes covered by the range in an object of `size` bytes
+    pub fn len(&self, size: u64) -> u64 {
+        if self.start >= size {
+            return 0;
+        }
+        let end = self.end.map_or(size - 1, |end| end.min(size - 1));
+        end - self.start + 1
+    }
+}
+
+/// Information about a stored object
+#[derive(Debug, Clone)]
+pub struct Metadata {
+    pub size: u64,
+    pub modified: Option<SystemTime>,
+}
+
+/// Stored object as it is shown to admin clients
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ObjectInfo {
+    pub filename: String,
+    pub size: Option<u64>,
+    /// seconds since Unix epoch
+    pub modified: Option<u64>,
+    /// stage of the job if the service knows it
+    pub status: Option<String>,
+}
+
+impl ObjectInfo {
+    pub fn new(filename: &str, metadata: Option<&Metadata>, status: Option<String>) -> ObjectInfo {
+        ObjectInfo {
+            filename: filename.to_string(),
+            size: metadata.map(|m| m.size),
+            modified: metadata
+                .and_then(|m| m.modified)
+                .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+                .map(|d| d.as_secs()),
+            status,
+        }
+    }
+}
+
+/// Place where finished videos live
+/// keys are relative names like `1234567.mp4`
+#[async_trait]
+pub trait Storage: Send + Sync {
+    /// write the whole stream under `key` and return number of written bytes
+    /// object becomes visible only after the stream is fully written
+    async fn put(&self, key: &str, body: ByteStream) -> Result<u64>;
+
+    /// read object or a part of it
+    async fn get(&self, key: &str, range: Option<ByteRange>) -> Result<ByteStream>;
+
+    /// get object metadata, `None` if object does not exist
+    async fn stat(&self, key: &str) -> Result<Option<Metadata>>;
+
+    /// delete object, deleting missing object is not an error
+    async fn delete(&self, key: &str) -> Result<()>;
+
+    /// list keys that start with `prefix`
+    async fn list(&self, prefix: &str) -> Result<Vec<String>>;
+
+    /// file of the object if it is kept on the local disk, downloads send it with `sendfile`
+    fn local_path(&self, _key: &str) -> Option<PathBuf> {
+        None
+    }
+
+    /// remove leftovers of writes that were interrupted by a crash
+    /// and return keys of objects that were never completed
+    async fn cleanup(&self) -> Result<Vec<String>> {
+        Ok(Vec::new())
+    }
+}
+
+/// create storage backend selected by `storage.backend`
+/// possible values are `local` (default), `memory` and `s3`
+pub fn from_config(config: &StorageConfig) -> Result<Arc<dyn Storage>> {
+    match config.backend.as_str() {
+        "local" => Ok(Arc::new(LocalStorage::new(&config.dir)?.with_sharding(config.shard_depth))),
+        "memory" => Ok(Arc::new(MemoryStorage::new())),
+        "s3" => {
+            let s3 = &config.s3;
+            Ok(Arc::new(S3Storage::new(&s3.endpoint, &s3.bucket, &s3.region, &s3.access_key, &s3.secret_key)))
+        },
+        other => Err(format!("unknown storage backend: {}", other).into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_ranges() {
+        assert_eq!(ByteRange::parse("0-1023"), Ok(ByteRange { start: 0, end: Some(1023) }));
+        assert_eq!(ByteRange::parse("100-"), Ok(ByteRange { start: 100, end: None }));
+        assert_eq!(ByteRange::parse("7-7"), Ok(ByteRange { start: 7, end: Some(7) }));
+    }
+
+    #[test]
+    fn refuses_invalid_ranges() {
+        for input in &["", "5", "-5", "a-b", "1-x", "10-5", "1-2-3", "18446744073709551616-"] {
+            assert!(ByteRange::parse(input).is_err(), "{} must be refused", input);
+        }
+    }
+
+    #[test]
+    fn range_length_is_clamped_to_the_object() {
+        let range = ByteRange { start: 10, end: Some(19) };
+        assert_eq!(range.len(100), 10);
+        assert_eq!(range.len(15), 5);
+        assert_eq!(range.len(10), 0);
+        assert_eq!(ByteRange { start: 90, end: None }.len(100), 10);
+        assert_eq!(ByteRange { start: 0, end: None }.len(0), 0);
+    }
+}
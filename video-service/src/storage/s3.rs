@@ -0,0 +1,562 @@
+use {
+    std::{io, time::SystemTime},
+    futures::{stream, StreamExt, TryStreamExt},
+    bytes::{Bytes, BytesMut},
+    hyper::{client::HttpConnector, Body, Client, Method, Request, Response, StatusCode, Uri},
+    hmac::{Hmac, Mac},
+    sha2::{Digest, Sha256},
+    chrono::{DateTime, Utc},
+    async_trait::async_trait,
+    super::{ByteRange, ByteStream, Metadata, Result, Storage},
+};
+
+// size of a single part of multipart upload, S3 requires at least 5 Mb
+const PART_SIZE: usize = 8388608; // 8 Mb
+// sha256 of an empty payload
+const EMPTY_SHA256: &str = "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855";
+// headers that take part in the signature
+const SIGNED_HEADERS: &str = "host;x-amz-content-sha256;x-amz-date";
+
+/// Storage that keeps objects in a bucket of S3-compatible object storage
+/// requests are signed with AWS signature v4 and use path-style addressing,
+/// so it works with AWS S3 as well as with MinIO and similar servers
+pub struct S3Storage {
+    client: Client<HttpConnector>,
+    endpoint: String,
+    bucket: String,
+    region: String,
+    access_key: String,
+    secret_key: String,
+}
+
+impl S3Storage {
+    /// create new client for `bucket` behind `endpoint` (e.g. `http://127.0.0.1:9000`)
+    pub fn new(endpoint: &str, bucket: &str, region: &str, access_key: &str, secret_key: &str) -> S3Storage {
+        S3Storage {
+            client: Client::new(),
+            endpoint: endpoint.trim_end_matches('/').to_string(),
+            bucket: bucket.to_string(),
+            region: region.to_string(),
+            access_key: access_key.to_string(),
+            secret_key: secret_key.to_string(),
+        }
+    }
+
+    // send signed request to the bucket
+    // `query` must contain pairs that are not encoded yet
+    async fn send(
+        &self,
+        method: Method,
+        key: &str,
+        query: &[(&str, &str)],
+        headers: &[(&str, String)],
+        body: Bytes,
+    ) -> Result<Response<Body>> {
+        let path = format!("/{}/{}", uri_encode(&self.bucket, true), uri_encode(key, false));
+        let path = if key.is_empty() { path.trim_end_matches('/').to_string() } else { path };
+        let mut query = query.iter()
+            .map(|(k, v)| (uri_encode(k, true), uri_encode(v, true)))
+            .collect::<Vec<(String, String)>>();
+        query.sort();
+        let query = query.iter()
+            .map(|(k, v)| format!("{}={}", k, v))
+            .collect::<Vec<String>>()
+            .join("&");
+
+        let uri: Uri = if query.is_empty() {
+            format!("{}{}", self.endpoint, path)
+        } else {
+            format!("{}{}?{}", self.endpoint, path, query)
+        }.parse()?;
+        let host = uri.authority().map(|a| a.as_str().to_string()).ok_or("S3 endpoint has no host")?;
+
+        let payload_hash = if body.is_empty() {
+            EMPTY_SHA256.to_string()
+        } else {
+            hex::encode(Sha256::digest(&body))
+        };
+        let now: DateTime<Utc> = Utc::now();
+        let amz_date = now.format("%Y%m%dT%H%M%SZ").to_string();
+        let date = now.format("%Y%m%d").to_string();
+        let scope = format!("{}/{}/s3/aws4_request", date, self.region);
+
+        let canonical_request = format!(
+            "{}\n{}\n{}\nhost:{}\nx-amz-content-sha256:{}\nx-amz-date:{}\n\n{}\n{}",
+            method.as_str(), path, query, host, payload_hash, amz_date, SIGNED_HEADERS, payload_hash,
+        );
+        let string_to_sign = format!(
+            "AWS4-HMAC-SHA256\n{}\n{}\n{}",
+            amz_date, scope, hex::encode(Sha256::digest(canonical_request.as_bytes())),
+        );
+        let key = hmac_sha256(format!("AWS4{}", self.secret_key).as_bytes(), date.as_bytes());
+        let key = hmac_sha256(&key, self.region.as_bytes());
+        let key = hmac_sha256(&key, b"s3");
+        let key = hmac_sha256(&key, b"aws4_request");
+        let signature = hex::encode(hmac_sha256(&key, string_to_sign.as_bytes()));
+
+        let mut req = Request::builder()
+            .method(method)
+            .uri(uri)
+            .header("host", host)
+            .header("x-amz-date", amz_date)
+            .header("x-amz-content-sha256", payload_hash)
+            .header("authorization", format!(
+                "AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}",
+                self.access_key, scope, SIGNED_HEADERS, signature,
+            ));
+        for (name, value) in headers {
+            req = req.header(*name, value.as_str());
+        }
+        let res = self.client.request(req.body(Body::from(body))?).await?;
+        Ok(res)
+    }
+
+    // upload an object that is bigger than a single part
+    async fn put_multipart(&self, key: &str, first: Bytes, mut body: ByteStream) -> Result<u64> {
+        let res = self.send(Method::POST, key, &[("uploads", "")], &[], Bytes::new()).await?;
+        let xml = read_ok(res).await?;
+        let upload_id = xml_values(&xml, "UploadId").pop().ok_or("S3 response has no UploadId")?;
+
+        let result = async {
+            let mut etags = Vec::new();
+            let mut written = 0u64;
+            let mut part = Some(first);
+            while let Some(data) = part.take() {
+                written += data.len() as u64;
+                let number = (etags.len() + 1).to_string();
+                let res = self.send(
+                    Method::PUT,
+                    key,
+                    &[("partNumber", &number), ("uploadId", &upload_id)],
+                    &[("content-length", data.len().to_string())],
+                    data,
+                ).await?;
+                let etag = res.headers().get("etag")
+                    .and_then(|v| v.to_str().ok())
+                    .map(|v| v.to_string())
+                    .ok_or("S3 response has no ETag")?;
+                read_ok(res).await?;
+                etags.push(etag);
+
+                let next = read_part(&mut body).await?;
+                if !next.is_empty() {
+                    part = Some(next);
+                }
+            }
+
+            let parts = etags.iter().enumerate()
+                .map(|(i, etag)| format!("<Part><PartNumber>{}</PartNumber><ETag>{}</ETag></Part>", i + 1, etag))
+                .collect::<String>();
+            let complete = format!("<CompleteMultipartUpload>{}</CompleteMultipartUpload>", parts);
+            let res = self.send(Method::POST, key, &[("uploadId", &upload_id)], &[], Bytes::from(complete)).await?;
+            let xml = read_ok(res).await?;
+            // S3 could report an error with 200 status code
+            if xml.contains("<Error>") {
+                return Err(format!("S3 error: {}", xml).into());
+            }
+            Ok(written)
+        }.await;
+
+        if result.is_err() {
+            // do not leave orphaned parts in the bucket
+            let _ = self.send(Method::DELETE, key, &[("uploadId", &upload_id)], &[], Bytes::new()).await;
+        }
+        result
+    }
+}
+
+#[async_trait]
+impl Storage for S3Storage {
+    async fn put(&self, key: &str, mut body: ByteStream) -> Result<u64> {
+        let first = read_part(&mut body).await?;
+        if first.len() < PART_SIZE {
+            // whole object fits into a single request
+            let len = first.len() as u64;
+            let res = self.send(Method::PUT, key, &[], &[("content-length", len.to_string())], first).await?;
+            read_ok(res).await?;
+            return Ok(len);
+        }
+        self.put_multipart(key, first, body).await
+    }
+
+    async fn get(&self, key: &str, range: Option<ByteRange>) -> Result<ByteStream> {
+        let headers = match range {
+            Some(ByteRange { start, end: Some(end) }) => vec![("range", format!("bytes={}-{}", start, end))],
+            Some(ByteRange { start, end: None }) => vec![("range", format!("bytes={}-", start))],
+            None => vec![],
+        };
+        let res = self.send(Method::GET, key, &[], &headers, Bytes::new()).await?;
+        match res.status() {
+            StatusCode::OK | StatusCode::PARTIAL_CONTENT => {},
+            StatusCode::RANGE_NOT_SATISFIABLE => return Ok(Box::pin(stream::empty())),
+            StatusCode::NOT_FOUND => {
+                return Err(io::Error::new(io::ErrorKind::NotFound, "object does not exist").into());
+            },
+            _ => {
+                let e = read_ok(res).await.err().unwrap_or_else(|| "unexpected S3 response".into());
+                return Err(e);
+            },
+        }
+        let body = res.into_body()
+            .map_err(io::Error::other);
+        Ok(Box::pin(body))
+    }
+
+    async fn stat(&self, key: &str) -> Result<Option<Metadata>> {
+        let res = self.send(Method::HEAD, key, &[], &[], Bytes::new()).await?;
+        if res.status() == StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+        let header = |name: &str| res.headers().get(name).and_then(|v| v.to_str().ok()).map(|v| v.to_string());
+        let size = header("content-length").and_then(|v| v.parse::<u64>().ok()).unwrap_or(0);
+        let modified = header("last-modified")
+            .and_then(|v| DateTime::parse_from_rfc2822(&v).ok())
+            .map(SystemTime::from);
+        read_ok(res).await?;
+        Ok(Some(Metadata { size, modified }))
+    }
+
+    async fn delete(&self, key: &str) -> Result<()> {
+        let res = self.send(Method::DELETE, key, &[], &[], Bytes::new()).await?;
+        if res.status() == StatusCode::NOT_FOUND {
+            return Ok(());
+        }
+        read_ok(res).await?;
+        Ok(())
+    }
+
+    async fn list(&self, prefix: &str) -> Result<Vec<String>> {
+        let mut keys = Vec::new();
+        let mut token: Option<String> = None;
+        // results are paginated, keep asking while S3 says there are more keys
+        loop {
+            let mut query = vec![("list-type", "2"), ("prefix", prefix)];
+            if let Some(token) = token.as_ref() {
+                query.push(("continuation-token", token));
+            }
+            let res = self.send(Method::GET, "", &query, &[], Bytes::new()).await?;
+            let xml = read_ok(res).await?;
+            keys.extend(xml_values(&xml, "Key"));
+            token = match xml_values(&xml, "IsTruncated").first().map(|v| v.as_str()) {
+                Some("true") => xml_values(&xml, "NextContinuationToken").pop(),
+                _ => None,
+            };
+            if token.is_none() {
+                break;
+            }
+        }
+        keys.sort();
+        Ok(keys)
+    }
+}
+
+// read the whole response body and fail on error status codes
+async fn read_ok(res: Response<Body>) -> Result<String> {
+    let status = res.status();
+    let body = hyper::body::to_bytes(res.into_body()).await?;
+    let body = String::from_utf8_lossy(&body).into_owned();
+    if !status.is_success() {
+        let code = xml_values(&body, "Code").pop().unwrap_or_default();
+        return Err(format!("S3 request failed with {}: {}", status, code).into());
+    }
+    Ok(body)
+}
+
+// collect bytes from the stream until there is enough for a single part
+async fn read_part(body: &mut ByteStream) -> Result<Bytes> {
+    let mut buf = BytesMut::new();
+    while buf.len() < PART_SIZE {
+        match body.next().await {
+            Some(bytes) => buf.extend_from_slice(&bytes?),
+            None => break,
+        }
+    }
+    Ok(buf.freeze())
+}
+
+// S3 responses are small and flat, so looking for tags is enough
+fn xml_values(xml: &str, tag: &str) -> Vec<String> {
+    let open = format!("<{}>", tag);
+    let close = format!("</{}>", tag);
+    let mut values = Vec::new();
+    let mut rest = xml;
+    while let Some(start) = rest.find(&open) {
+        rest = &rest[start + open.len()..];
+        match rest.find(&close) {
+            Some(end) => {
+                values.push(xml_unescape(&rest[..end]));
+                rest = &rest[end + close.len()..];
+            },
+            None => break,
+        }
+    }
+    values
+}
+
+fn xml_unescape(value: &str) -> String {
+    value.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&apos;", "'")
+        .replace("&amp;", "&")
+}
+
+// percent-encode everything except unreserved characters as signature v4 requires
+fn uri_encode(input: &str, encode_slash: bool) -> String {
+    let mut encoded = String::with_capacity(input.len());
+    for b in input.bytes() {
+        match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => encoded.push(b as char),
+            b'/' if !encode_slash => encoded.push('/'),
+            _ => encoded.push_str(&format!("%{:02X}", b)),
+        }
+    }
+    encoded
+}
+
+fn hmac_sha256(key: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC accepts keys of any size");
+    mac.update(data);
+    mac.finalize().into_bytes().to_vec()
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        std::{collections::BTreeMap, convert::Infallible, net::SocketAddr, sync::{Arc, Mutex}},
+        hyper::{service::{make_service_fn, service_fn}, Server},
+    };
+
+    // keys a single page of the stand-in's listing holds, so pagination is exercised
+    const PAGE: usize = 2;
+
+    // objects and unfinished multipart uploads of the single bucket of the stand-in
+    #[derive(Default)]
+    struct Bucket {
+        objects: BTreeMap<String, Vec<u8>>,
+        parts: BTreeMap<u32, Vec<u8>>,
+        // requests the stand-in got, e.g. `PUT a.mp4 partNumber=1&uploadId=1`
+        log: Vec<String>,
+    }
+
+    type Shared = Arc<Mutex<Bucket>>;
+
+    // MinIO-style server that keeps objects of bucket `videos` in memory
+    async fn stand_in() -> (S3Storage, Shared) {
+        let bucket = Shared::default();
+        let shared = bucket.clone();
+        let make_service = make_service_fn(move |_| {
+            let bucket = shared.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |req: Request<Body>| {
+                    let bucket = bucket.clone();
+                    async move { Ok::<_, Infallible>(answer(req, &bucket).await) }
+                }))
+            }
+        });
+        let server = Server::bind(&SocketAddr::from(([127, 0, 0, 1], 0))).serve(make_service);
+        let endpoint = format!("http://{}", server.local_addr());
+        tokio::spawn(server);
+        (S3Storage::new(&endpoint, "videos", "us-east-1", "access", "secret"), bucket)
+    }
+
+    async fn answer(req: Request<Body>, bucket: &Shared) -> Response<Body> {
+        let (parts, body) = req.into_parts();
+        let body = hyper::body::to_bytes(body).await.unwrap();
+        let response = |status: StatusCode, body: Vec<u8>| Response::builder().status(status).body(Body::from(body)).unwrap();
+        // every request is signed and carries the hash of its payload
+        let authorization = parts.headers.get("authorization").and_then(|v| v.to_str().ok()).unwrap_or_default();
+        let hash = parts.headers.get("x-amz-content-sha256").and_then(|v| v.to_str().ok()).unwrap_or_default();
+        if !authorization.starts_with("AWS4-HMAC-SHA256 Credential=access/") || hash != hex::encode(Sha256::digest(&body)) {
+            return response(StatusCode::FORBIDDEN, b"<Error><Code>SignatureDoesNotMatch</Code></Error>".to_vec());
+        }
+        let path = parts.uri.path().to_string();
+        let key = path.trim_start_matches("/videos").trim_start_matches('/').to_string();
+        let query = parts.uri.query().unwrap_or_default().to_string();
+        let param = |name: &str| query.split('&')
+            .filter_map(|pair| pair.split_once('='))
+            .find(|(k, _)| *k == name)
+            .map(|(_, v)| v.replace("%2F", "/"));
+        let mut bucket = bucket.lock().unwrap();
+        bucket.log.push(format!("{} {} {}", parts.method, key, query));
+        match (parts.method.clone(), key.is_empty()) {
+            (Method::GET, true) => {
+                let prefix = param("prefix").unwrap_or_default();
+                let after = param("continuation-token").unwrap_or_default();
+                let keys = bucket.objects.keys()
+                    .filter(|key| key.starts_with(&prefix) && key.as_str() > after.as_str())
+                    .cloned()
+                    .collect::<Vec<String>>();
+                let page = &keys[..keys.len().min(PAGE)];
+                let mut xml = page.iter().map(|key| format!("<Contents><Key>{}</Key></Contents>", key)).collect::<String>();
+                if keys.len() > PAGE {
+                    xml.push_str(&format!("<IsTruncated>true</IsTruncated><NextContinuationToken>{}</NextContinuationToken>", page[PAGE - 1]));
+                } else {
+                    xml.push_str("<IsTruncated>false</IsTruncated>");
+                }
+                response(StatusCode::OK, format!("<ListBucketResult>{}</ListBucketResult>", xml).into_bytes())
+            },
+            (Method::POST, _) if query == "uploads=" => {
+                bucket.parts.clear();
+                response(StatusCode::OK, b"<InitiateMultipartUploadResult><UploadId>1</UploadId></InitiateMultipartUploadResult>".to_vec())
+            },
+            (Method::PUT, _) if param("uploadId").is_some() => {
+                let number: u32 = param("partNumber").unwrap().parse().unwrap();
+                bucket.parts.insert(number, body.to_vec());
+                Response::builder().header("etag", format!("\"etag-{}\"", number)).body(Body::empty()).unwrap()
+            },
+            (Method::POST, _) if param("uploadId").is_some() => {
+                let complete = String::from_utf8_lossy(&body).into_owned();
+                if xml_values(&complete, "PartNumber").len() != bucket.parts.len() {
+                    return response(StatusCode::BAD_REQUEST, b"<Error><Code>InvalidPart</Code></Error>".to_vec());
+                }
+                let data = std::mem::take(&mut bucket.parts).into_values().flatten().collect();
+                bucket.objects.insert(key, data);
+                response(StatusCode::OK, b"<CompleteMultipartUploadResult></CompleteMultipartUploadResult>".to_vec())
+            },
+            (Method::DELETE, _) if param("uploadId").is_some() => {
+                bucket.parts.clear();
+                response(StatusCode::NO_CONTENT, vec![])
+            },
+            (Method::PUT, false) => {
+                bucket.objects.insert(key, body.to_vec());
+                response(StatusCode::OK, vec![])
+            },
+            (Method::GET, false) | (Method::HEAD, false) => {
+                let data = match bucket.objects.get(&key) {
+                    Some(data) => data.clone(),
+                    None => return response(StatusCode::NOT_FOUND, b"<Error><Code>NoSuchKey</Code></Error>".to_vec()),
+                };
+                if parts.method == Method::HEAD {
+                    return Response::builder()
+                        .header("content-length", data.len())
+                        .header("last-modified", "Mon, 19 Oct 2026 10:00:00 GMT")
+                        .body(Body::empty())
+                        .unwrap();
+                }
+                let range = parts.headers.get("range").and_then(|v| v.to_str().ok())
+                    .and_then(|v| v.strip_prefix("bytes="))
+                    .map(|v| ByteRange::parse(v).unwrap());
+                match range {
+                    Some(range) if range.len(data.len() as u64) == 0 => response(StatusCode::RANGE_NOT_SATISFIABLE, vec![]),
+                    Some(range) => {
+                        let start = range.start as usize;
+                        let end = start + range.len(data.len() as u64) as usize;
+                        response(StatusCode::PARTIAL_CONTENT, data[start..end].to_vec())
+                    },
+                    None => response(StatusCode::OK, data),
+                }
+            },
+            (Method::DELETE, false) => match bucket.objects.remove(&key) {
+                Some(_) => response(StatusCode::NO_CONTENT, vec![]),
+                None => response(StatusCode::NOT_FOUND, vec![]),
+            },
+            _ => response(StatusCode::BAD_REQUEST, vec![]),
+        }
+    }
+
+    fn body(data: Vec<u8>) -> ByteStream {
+        // chunks of a socket are much smaller than a part
+        let chunks = data.chunks(65536).map(|chunk| Ok(Bytes::copy_from_slice(chunk))).collect::<Vec<_>>();
+        Box::pin(stream::iter(chunks))
+    }
+
+    async fn read(storage: &S3Storage, key: &str, range: Option<ByteRange>) -> Vec<u8> {
+        let mut body = storage.get(key, range).await.unwrap();
+        let mut data = Vec::new();
+        while let Some(bytes) = body.next().await {
+            data.extend_from_slice(&bytes.unwrap());
+        }
+        data
+    }
+
+    #[tokio::test]
+    async fn small_objects_go_in_a_single_request() {
+        let (storage, bucket) = stand_in().await;
+        assert_eq!(storage.put("a.mp4", body(b"0123456789".to_vec())).await.unwrap(), 10);
+        assert_eq!(bucket.lock().unwrap().log, vec!["PUT a.mp4 "]);
+
+        assert_eq!(read(&storage, "a.mp4", None).await, b"0123456789");
+        assert_eq!(read(&storage, "a.mp4", Some(ByteRange { start: 2, end: Some(4) })).await, b"234");
+        assert_eq!(read(&storage, "a.mp4", Some(ByteRange { start: 7, end: None })).await, b"789");
+        assert_eq!(read(&storage, "a.mp4", Some(ByteRange { start: 10, end: None })).await, b"");
+
+        let metadata = storage.stat("a.mp4").await.unwrap().unwrap();
+        assert_eq!(metadata.size, 10);
+        assert!(metadata.modified.is_some());
+        assert!(storage.stat("b.mp4").await.unwrap().is_none());
+        assert!(storage.get("b.mp4", None).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn big_objects_go_in_parts() {
+        let (storage, bucket) = stand_in().await;
+        let data = (0..PART_SIZE * 2 + 1000).map(|i| (i % 251) as u8).collect::<Vec<u8>>();
+        assert_eq!(storage.put("big.mp4", body(data.clone())).await.unwrap(), data.len() as u64);
+        {
+            let bucket = bucket.lock().unwrap();
+            let requests = bucket.log.iter().map(|line| line.split(' ').next().unwrap()).collect::<Vec<_>>();
+            // start, three parts and completion
+            assert_eq!(requests, vec!["POST", "PUT", "PUT", "PUT", "POST"]);
+            assert_eq!(bucket.objects["big.mp4"], data);
+        }
+        let tail = read(&storage, "big.mp4", Some(ByteRange { start: PART_SIZE as u64 * 2, end: None })).await;
+        assert_eq!(tail, &data[PART_SIZE * 2..]);
+    }
+
+    #[tokio::test]
+    async fn broken_multipart_upload_is_aborted() {
+        let (storage, bucket) = stand_in().await;
+        let data = vec![7u8; PART_SIZE + 10];
+        let mut chunks = vec![Ok(Bytes::from(data))];
+        chunks.push(Err(io::Error::new(io::ErrorKind::ConnectionReset, "reset")));
+        assert!(storage.put("big.mp4", Box::pin(stream::iter(chunks))).await.is_err());
+        let bucket = bucket.lock().unwrap();
+        assert!(bucket.log.last().unwrap().starts_with("DELETE big.mp4 uploadId=1"));
+        assert!(bucket.parts.is_empty());
+        assert!(bucket.objects.is_empty());
+    }
+
+    #[tokio::test]
+    async fn deletes_objects() {
+        let (storage, _) = stand_in().await;
+        storage.put("a.mp4", body(b"video".to_vec())).await.unwrap();
+        storage.delete("a.mp4").await.unwrap();
+        assert!(storage.stat("a.mp4").await.unwrap().is_none());
+        // deleting a missing object is not an error
+        storage.delete("a.mp4").await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn lists_every_page() {
+        let (storage, bucket) = stand_in().await;
+        for key in &["a1.mp4", "a2.mp4", "a3.mp4", "a4.mp4", "a5.mp4", "b.mp4"] {
+            storage.put(key, body(b"x".to_vec())).await.unwrap();
+        }
+        bucket.lock().unwrap().log.clear();
+        assert_eq!(storage.list("a").await.unwrap(), vec!["a1.mp4", "a2.mp4", "a3.mp4", "a4.mp4", "a5.mp4"]);
+        // five keys in pages of two
+        let log = bucket.lock().unwrap().log.clone();
+        assert_eq!(log.len(), 3);
+        assert!(log[1].contains("continuation-token=a2.mp4"));
+        assert_eq!(storage.list("").await.unwrap().len(), 6);
+        assert!(storage.list("c").await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn errors_of_the_server_are_reported() {
+        let (storage, _) = stand_in().await;
+        let endpoint = storage.endpoint.clone();
+        let wrong = S3Storage::new(&endpoint, "videos", "us-east-1", "other", "secret");
+        let e = wrong.put("a.mp4", body(b"x".to_vec())).await.unwrap_err();
+        assert!(e.to_string().contains("SignatureDoesNotMatch"), "{}", e);
+    }
+
+    #[test]
+    fn encodes_like_signature_v4() {
+        assert_eq!(uri_encode("a b/c~d", false), "a%20b/c~d");
+        assert_eq!(uri_encode("a b/c", true), "a%20b%2Fc");
+        assert_eq!(xml_values("<K>a&amp;b</K><K>c</K>", "K"), vec!["a&b", "c"]);
+    }
+}
@@ -0,0 +1,145 @@
+use {
+    std::{io, time::{Duration, Instant}},
+    futures::{stream, StreamExt},
+    tokio::time::timeout,
+    crate::{config::TimeoutsConfig, storage::ByteStream},
+};
+
+/// Limits of waiting for clients, so a slow or silent client does not hold a connection forever
+#[derive(Debug, Clone, Copy)]
+pub struct Timeouts {
+    /// a new connection must send its request line within this time
+    pub request: Duration,
+    /// longest pause between chunks of an upload or a download and between requests of a kept-alive connection
+    pub idle: Duration,
+    /// uploads slower than this amount of bytes per second over `rate_window` are dropped, no floor with 0
+    pub min_upload_rate: u64,
+    pub rate_window: Duration,
+}
+
+// throughput of an upload in the current window
+struct Window {
+    started: Instant,
+    bytes: u64,
+}
+
+impl Timeouts {
+    pub fn from_config(config: &TimeoutsConfig) -> Timeouts {
+        Timeouts {
+            request: Duration::from_secs(config.request),
+            idle: Duration::from_secs(config.idle),
+            min_upload_rate: config.min_upload_rate,
+            rate_window: Duration::from_secs(config.rate_window),
+        }
+    }
+
+    /// body of an upload that fails when the client pauses longer than `idle`
+    /// or sends slower than `min_upload_rate`, storages remove half-written files on errors
+    pub fn guard(self, body: ByteStream) -> ByteStream {
+        let window = Window { started: Instant::now(), bytes: 0 };
+        Box::pin(stream::unfold(Some((body, window)), move |state| async move {
+            let (mut body, mut window) = state?;
+            let chunk = match timeout(self.idle, body.next()).await {
+                Ok(chunk) => chunk?,
+                Err(_) => {
+                    let e = format!("no data for {} seconds", self.idle.as_secs());
+                    return Some((Err(io::Error::new(io::ErrorKind::TimedOut, e)), None));
+                },
+            };
+            if let Ok(bytes) = &chunk {
+                window.bytes += bytes.len() as u64;
+            }
+            let elapsed = window.started.elapsed();
+            if elapsed >= self.rate_window {
+                let rate = window.bytes as f64 / elapsed.as_secs_f64();
+                if rate < self.min_upload_rate as f64 {
+                    let e = format!("upload is slower than {} bytes per second", self.min_upload_rate);
+                    return Some((Err(io::Error::new(io::ErrorKind::TimedOut, e)), None));
+                }
+                window = Window { started: Instant::now(), bytes: 0 };
+            }
+            Some((chunk, Some((body, window))))
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        bytes::Bytes,
+        tokio::time::delay_for,
+    };
+
+    fn timeouts(idle_millis: u64, min_upload_rate: u64, window_millis: u64) -> Timeouts {
+        Timeouts {
+            request: Duration::from_secs(1),
+            idle: Duration::from_millis(idle_millis),
+            min_upload_rate,
+            rate_window: Duration::from_millis(window_millis),
+        }
+    }
+
+    // `count` chunks of `size` bytes, one every `pause_millis`
+    fn body(count: usize, size: usize, pause_millis: u64) -> ByteStream {
+        Box::pin(stream::unfold(0, move |sent| async move {
+            if sent == count {
+                return None;
+            }
+            delay_for(Duration::from_millis(pause_millis)).await;
+            Some((Ok(Bytes::from(vec![0u8; size])), sent + 1))
+        }))
+    }
+
+    async fn collect(mut body: ByteStream) -> (u64, Option<io::Error>) {
+        let mut received = 0;
+        while let Some(chunk) = body.next().await {
+            match chunk {
+                Ok(bytes) => received += bytes.len() as u64,
+                Err(e) => {
+                    // nothing comes after the error
+                    assert!(body.next().await.is_none());
+                    return (received, Some(e));
+                },
+            }
+        }
+        (received, None)
+    }
+
+    #[tokio::test]
+    async fn steady_upload_passes() {
+        let (received, error) = collect(timeouts(200, 10_000, 100).guard(body(8, 1000, 20))).await;
+        assert_eq!(received, 8000);
+        assert!(error.is_none());
+    }
+
+    #[tokio::test]
+    async fn pause_longer_than_idle_fails() {
+        let chunks = stream::iter(vec![Ok(Bytes::from_static(b"head"))]).chain(stream::pending());
+        let (received, error) = collect(timeouts(100, 0, 1000).guard(Box::pin(chunks))).await;
+        assert_eq!(received, 4);
+        let error = error.unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::TimedOut);
+        assert!(error.to_string().starts_with("no data for"));
+    }
+
+    #[tokio::test]
+    async fn upload_below_the_floor_fails() {
+        // 100 bytes every 50 ms is 2000 bytes per second
+        let (received, error) = collect(timeouts(1000, 10_000, 200).guard(body(20, 100, 50))).await;
+        assert!(received < 2000);
+        let error = error.unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::TimedOut);
+        assert_eq!(error.to_string(), "upload is slower than 10000 bytes per second");
+        // without a floor the same upload passes
+        assert_eq!(collect(timeouts(1000, 0, 200).guard(body(20, 100, 10))).await.0, 2000);
+    }
+
+    #[tokio::test]
+    async fn errors_of_the_body_pass_through() {
+        let chunks = stream::iter(vec![Ok(Bytes::from_static(b"head")), Err(io::Error::new(io::ErrorKind::ConnectionReset, "reset"))]);
+        let (received, error) = collect(timeouts(1000, 0, 1000).guard(Box::pin(chunks))).await;
+        assert_eq!(received, 4);
+        assert_eq!(error.unwrap().kind(), io::ErrorKind::ConnectionReset);
+    }
+}
@@ -0,0 +1,97 @@
+use {
+    std::{fmt, fs::File, io::{self, BufReader}, path::Path, sync::Arc, time::Duration},
+    tokio::{net::TcpStream, time::timeout},
+    tokio_rustls::{
+        rustls::{internal::pemfile, AllowAnyAuthenticatedClient, Certificate, ClientConfig, PrivateKey, RootCertStore, ServerConfig},
+        webpki::DNSNameRef,
+        TlsAcceptor, TlsConnector,
+    },
+    crate::{config::TlsConfig, net::Stream, storage::Result},
+};
+
+// clients that do not speak TLS are dropped after this
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Server side of TLS, only clients with a certificate signed by our CA get through the handshake
+#[derive(Clone)]
+pub struct Acceptor(TlsAcceptor);
+
+impl Acceptor {
+    pub fn new(config: &TlsConfig) -> Result<Acceptor> {
+        let (certs, key, roots) = load(config)?;
+        let mut server = ServerConfig::new(AllowAnyAuthenticatedClient::new(roots));
+        server.set_single_cert(certs, key)
+            .map_err(|e| format!("invalid TLS certificate: {}", e))?;
+        Ok(Acceptor(TlsAcceptor::from(Arc::new(server))))
+    }
+
+    /// handshake with a client that just connected
+    pub async fn accept(&self, socket: TcpStream) -> io::Result<Stream> {
+        let stream = timeout(HANDSHAKE_TIMEOUT, self.0.accept(socket)).await
+            .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "no TLS handshake"))??;
+        Ok(Stream::Tls(Box::new(stream.into())))
+    }
+}
+
+/// Client side of TLS, it shows our certificate and checks the one of the service
+#[derive(Clone)]
+pub struct Connector {
+    connector: TlsConnector,
+    // name the certificate of the service is issued for, services are addressed by IP
+    server_name: String,
+}
+
+impl Connector {
+    pub fn new(config: &TlsConfig) -> Result<Connector> {
+        let (certs, key, roots) = load(config)?;
+        let mut client = ClientConfig::new();
+        client.root_store = roots;
+        client.set_single_client_cert(certs, key)
+            .map_err(|e| format!("invalid TLS certificate: {}", e))?;
+        let connector = TlsConnector::from(Arc::new(client));
+        Ok(Connector { connector, server_name: config.server_name.clone() })
+    }
+
+    pub(crate) async fn connect(&self, socket: TcpStream) -> io::Result<Stream> {
+        let name = DNSNameRef::try_from_ascii_str(&self.server_name)
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, format!("invalid server name {}", self.server_name)))?;
+        let stream = self.connector.connect(name, socket).await?;
+        Ok(Stream::Tls(Box::new(stream.into())))
+    }
+}
+
+impl fmt::Debug for Connector {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Connector").field("server_name", &self.server_name).finish()
+    }
+}
+
+// certificate chain, private key and CA of the other side
+fn load(config: &TlsConfig) -> Result<(Vec<Certificate>, PrivateKey, RootCertStore)> {
+    let (cert, key, ca) = match (&config.cert, &config.key, &config.ca) {
+        (Some(cert), Some(key), Some(ca)) => (cert, key, ca),
+        _ => return Err("tls.cert, tls.key and tls.ca must be set together".into()),
+    };
+    let certs = pemfile::certs(&mut open(cert)?)
+        .map_err(|_| format!("invalid certificate {}", cert.display()))?;
+    if certs.is_empty() {
+        return Err(format!("no certificate in {}", cert.display()).into());
+    }
+    // keys made by openssl are either PKCS#8 or RSA
+    let mut keys = pemfile::pkcs8_private_keys(&mut open(key)?).unwrap_or_default();
+    if keys.is_empty() {
+        keys = pemfile::rsa_private_keys(&mut open(key)?).unwrap_or_default();
+    }
+    let key = keys.pop().ok_or_else(|| format!("no private key in {}", key.display()))?;
+    let mut roots = RootCertStore::empty();
+    match roots.add_pem_file(&mut open(ca)?) {
+        Ok((added, _)) if added > 0 => {},
+        _ => return Err(format!("no CA certificate in {}", ca.display()).into()),
+    }
+    Ok((certs, key, roots))
+}
+
+fn open(path: &Path) -> Result<BufReader<File>> {
+    let file = File::open(path).map_err(|e| format!("can not read {}: {}", path.display(), e))?;
+    Ok(BufReader::new(file))
+}
@@ -0,0 +1,45 @@
+use {
+    std::{sync::atomic::{AtomicU64, Ordering}, time::{SystemTime, UNIX_EPOCH}},
+    tracing_subscriber::EnvFilter,
+};
+
+// longest request ID accepted from clients
+const MAX_REQUEST_ID_LEN: usize = 64;
+
+// makes IDs generated within the same nanosecond unique
+static COUNTER: AtomicU64 = AtomicU64::new(0);
+
+/// print logs as JSON lines, one object per event with all its spans
+/// verbosity is set by `RUST_LOG`, `info` by default
+pub fn init() {
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info"));
+    tracing_subscriber::fmt()
+        .json()
+        .with_current_span(false)
+        .with_env_filter(filter)
+        .init();
+}
+
+/// ID for requests that came without one
+pub fn new_request_id() -> String {
+    let nanos = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_nanos() as u64).unwrap_or(0);
+    let n = COUNTER.fetch_add(1, Ordering::Relaxed);
+    format!("{:016x}", nanos ^ n.rotate_right(16))
+}
+
+/// split `ID <request id> <command>` into the ID and the command,
+/// request line without a valid ID is returned as is
+pub fn split_request_id(input: &str) -> (Option<&str>, &str) {
+    let mut parts = input.splitn(3, ' ');
+    match (parts.next(), parts.next(), parts.next()) {
+        (Some("ID"), Some(id), Some(command)) if is_valid(id) => (Some(id), command),
+        _ => (None, input),
+    }
+}
+
+// IDs end up in logs, so only short and plain ones are accepted
+fn is_valid(id: &str) -> bool {
+    !id.is_empty()
+        && id.len() <= MAX_REQUEST_ID_LEN
+        && id.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.')
+}
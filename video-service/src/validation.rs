@@ -0,0 +1,239 @@
+use {
+    std::{path::Path, process::Stdio, time::Duration},
+    tokio::{process::Command, time},
+    crate::probe::{self, MediaInfo},
+};
+
+// how long a single validator may run by default
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Single check an upload has to pass before it is compressed
+#[derive(Debug, Clone, PartialEq)]
+pub enum Validator {
+    /// video must not be longer than this amount of seconds
+    MaxDuration(f64),
+    /// video must be at least this wide and high
+    MinResolution { width: u32, height: u32 },
+    /// external program, e.g. antivirus, that must exit with status 0
+    /// `{}` in arguments is replaced with path of the file, otherwise path is the last argument
+    Command { program: String, args: Vec<String>, timeout: Duration },
+}
+
+impl Validator {
+    /// parse validator from `name:arguments` form:
+    /// `max-duration:3600`, `min-resolution:640x360` or `command:30:clamscan --no-summary {}`
+    pub fn parse(input: &str) -> std::result::Result<Validator, String> {
+        let mut parts = input.trim().splitn(2, ':');
+        let name = parts.next().unwrap_or_default();
+        let args = parts.next().unwrap_or_default().trim();
+        match name {
+            "max-duration" => {
+                // NaN is never greater than a duration, so it would let every video through
+                match args.parse::<f64>() {
+                    Ok(seconds) if seconds.is_finite() && seconds > 0.0 => Ok(Validator::MaxDuration(seconds)),
+                    _ => Err(format!("invalid duration: {}", args)),
+                }
+            },
+            "min-resolution" => {
+                let mut dims = args.splitn(2, 'x').map(|d| d.parse::<u32>());
+                match (dims.next(), dims.next()) {
+                    (Some(Ok(width)), Some(Ok(height))) => Ok(Validator::MinResolution { width, height }),
+                    _ => Err(format!("invalid resolution: {}", args)),
+                }
+            },
+            "command" => {
+                // timeout is optional: `command:clamscan {}` or `command:30:clamscan {}`
+                let mut parts = args.splitn(2, ':');
+                let secs = parts.next().and_then(|secs| secs.parse::<u64>().ok());
+                let (timeout, cmdline) = match (secs, parts.next()) {
+                    (Some(secs), Some(cmdline)) => (Duration::from_secs(secs), cmdline),
+                    _ => (DEFAULT_TIMEOUT, args),
+                };
+                let mut words = cmdline.split_whitespace().map(|w| w.to_string());
+                let program = words.next().ok_or("command validator needs a program")?;
+                Ok(Validator::Command { program, args: words.collect(), timeout })
+            },
+            other => Err(format!("unknown validator: {}", other)),
+        }
+    }
+
+    /// human readable name that is used in rejection reasons
+    pub fn name(&self) -> String {
+        match self {
+            Validator::MaxDuration(_) => "max-duration".to_string(),
+            Validator::MinResolution { .. } => "min-resolution".to_string(),
+            Validator::Command { program, .. } => program.clone(),
+        }
+    }
+
+    // check `info` or run external program, `Err` holds reason of rejection
+    async fn check(&self, path: &Path, info: Option<&MediaInfo>) -> std::result::Result<(), String> {
+        match self {
+            Validator::MaxDuration(max) => {
+                let duration = info.and_then(|info| info.duration).ok_or("duration is unknown")?;
+                if duration > *max {
+                    return Err(format!("video is {:.0} seconds long, maximum is {:.0}", duration, max));
+                }
+                Ok(())
+            },
+            Validator::MinResolution { width, height } => {
+                let (w, h) = match info.map(|info| (info.width, info.height)) {
+                    Some((Some(w), Some(h))) => (w, h),
+                    _ => return Err("resolution is unknown".to_string()),
+                };
+                if w < *width || h < *height {
+                    return Err(format!("resolution is {}x{}, minimum is {}x{}", w, h, width, height));
+                }
+                Ok(())
+            },
+            Validator::Command { program, args, timeout } => {
+                let path = path.to_string_lossy();
+                let mut args = args.iter().map(|arg| arg.replace("{}", &path)).collect::<Vec<String>>();
+                if !args.iter().any(|arg| arg.contains(path.as_ref())) {
+                    args.push(path.to_string());
+                }
+                let output = Command::new(program)
+                    .args(&args)
+                    .stdin(Stdio::null())
+                    .kill_on_drop(true)
+                    .output();
+                let output = match time::timeout(*timeout, output).await {
+                    Ok(Ok(output)) => output,
+                    Ok(Err(e)) => return Err(format!("can not run: {}", e)),
+                    Err(_) => return Err(format!("did not finish in {} seconds", timeout.as_secs())),
+                };
+                if !output.status.success() {
+                    // the first line of output usually explains the problem
+                    let out = [output.stdout, output.stderr].concat();
+                    let out = String::from_utf8_lossy(&out);
+                    let line = out.lines().map(str::trim).find(|l| !l.is_empty()).unwrap_or_default();
+                    if line.is_empty() {
+                        return Err(format!("exited with {}", output.status));
+                    }
+                    return Err(line.to_string());
+                }
+                Ok(())
+            },
+        }
+    }
+
+    // built-in checks need properties of the video
+    fn needs_probe(&self) -> bool {
+        !matches!(self, Validator::Command { .. })
+    }
+}
+
+/// Chain of validators that is run in order until the first failure
+#[derive(Debug, Clone, Default)]
+pub struct Validators {
+    validators: Vec<Validator>,
+    probe_timeout: Duration,
+}
+
+impl Validators {
+    pub fn new(validators: Vec<Validator>) -> Validators {
+        Validators { validators, probe_timeout: DEFAULT_TIMEOUT }
+    }
+
+    /// parse chain of validators separated with `;`
+    /// e.g. `max-duration:3600;min-resolution:640x360;command:30:clamscan --no-summary {}`
+    pub fn parse(spec: &str) -> std::result::Result<Validators, String> {
+        let validators = spec.split(';')
+            .filter(|v| !v.trim().is_empty())
+            .map(Validator::parse)
+            .collect::<std::result::Result<Vec<Validator>, String>>()?;
+        Ok(Validators::new(validators))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.validators.is_empty()
+    }
+
+    /// run every validator against the file, `Err` holds reason of rejection
+    pub async fn validate(&self, path: &Path) -> std::result::Result<(), String> {
+        // probe the file once for all built-in checks
+        let info = if self.validators.iter().any(Validator::needs_probe) {
+            match probe::probe(path, self.probe_timeout).await {
+                Ok(info) => Some(info),
+                Err(e) => return Err(format!("can not read video properties: {}", e)),
+            }
+        } else {
+            None
+        };
+        for validator in &self.validators {
+            validator.check(path, info.as_ref()).await
+                .map_err(|reason| format!("{}: {}", validator.name(), reason))?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_validators() {
+        assert_eq!(Validator::parse("max-duration:3600"), Ok(Validator::MaxDuration(3600.0)));
+        assert_eq!(Validator::parse(" max-duration: 0.5 "), Ok(Validator::MaxDuration(0.5)));
+        assert_eq!(Validator::parse("min-resolution:640x360"), Ok(Validator::MinResolution { width: 640, height: 360 }));
+        assert_eq!(
+            Validator::parse("command:30:clamscan --no-summary {}"),
+            Ok(Validator::Command {
+                program: "clamscan".to_string(),
+                args: vec!["--no-summary".to_string(), "{}".to_string()],
+                timeout: Duration::from_secs(30),
+            })
+        );
+        assert_eq!(
+            Validator::parse("command:clamscan"),
+            Ok(Validator::Command { program: "clamscan".to_string(), args: vec![], timeout: DEFAULT_TIMEOUT })
+        );
+    }
+
+    #[test]
+    fn refuses_durations_that_are_not_positive() {
+        for input in &["max-duration:0", "max-duration:-1", "max-duration:NaN", "max-duration:inf", "max-duration:", "max-duration:1h"] {
+            assert!(Validator::parse(input).is_err(), "{} must be refused", input);
+        }
+    }
+
+    #[test]
+    fn refuses_invalid_validators() {
+        for input in &["min-resolution:640", "min-resolution:axb", "command:", "command:30:", "max-size:10"] {
+            assert!(Validator::parse(input).is_err(), "{} must be refused", input);
+        }
+        assert!(Validators::parse("max-duration:60;;min-resolution:1x1").is_ok());
+        assert!(Validators::parse("max-duration:60;nope").is_err());
+        assert!(Validators::parse("").unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn checks_properties_of_the_video() {
+        let info = MediaInfo { duration: Some(61.0), width: Some(640), height: Some(360), ..MediaInfo::default() };
+        let path = Path::new("video.mp4");
+        assert!(Validator::MaxDuration(60.0).check(path, Some(&info)).await.is_err());
+        assert!(Validator::MaxDuration(61.0).check(path, Some(&info)).await.is_ok());
+        assert!(Validator::MaxDuration(60.0).check(path, None).await.is_err());
+        assert!(Validator::MinResolution { width: 640, height: 360 }.check(path, Some(&info)).await.is_ok());
+        assert!(Validator::MinResolution { width: 1280, height: 360 }.check(path, Some(&info)).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn runs_commands_with_the_path() {
+        let path = Path::new("/dev/null");
+        let command = |program: &str, args: &[&str]| Validator::Command {
+            program: program.to_string(),
+            args: args.iter().map(|arg| arg.to_string()).collect(),
+            timeout: Duration::from_secs(5),
+        };
+        assert!(command("test", &["-e"]).check(path, None).await.is_ok());
+        assert!(command("test", &["-d", "{}"]).check(path, None).await.is_err());
+        assert_eq!(command("sh", &["-c", "echo infected; exit 1"]).check(path, None).await, Err("infected".to_string()));
+        let args = vec!["-c".to_string(), "sleep 10".to_string()];
+        let slow = Validator::Command { program: "sh".to_string(), args, timeout: Duration::from_millis(100) };
+        let e = slow.check(path, None).await.unwrap_err();
+        assert!(e.starts_with("did not finish"), "{}", e);
+        assert!(command("no-such-program", &[]).check(path, None).await.is_err());
+    }
+}
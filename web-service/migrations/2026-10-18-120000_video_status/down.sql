@@ -0,0 +1,7 @@
+-- This file should undo anything in `up.sql`
+ALTER TABLE `videos`
+  DROP COLUMN `status`,
+  DROP COLUMN `reason`,
+  DROP COLUMN `duration`,
+  DROP COLUMN `width`,
+  DROP COLUMN `height`;
@@ -0,0 +1,7 @@
+-- outcome of processing reported by video-service
+ALTER TABLE `videos`
+  ADD COLUMN `status` varchar(16) NOT NULL DEFAULT 'processing',
+  ADD COLUMN `reason` text DEFAULT NULL,
+  ADD COLUMN `duration` double DEFAULT NULL,
+  ADD COLUMN `width` int(10) DEFAULT NULL,
+  ADD COLUMN `height` int(10) DEFAULT NULL;
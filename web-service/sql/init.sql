@@ -14,5 +14,10 @@ CREATE TABLE `videos` (
   `id` int(10) unsigned NOT NULL AUTO_INCREMENT,
   `name` varchar(50) NOT NULL,
   `createdat` datetime DEFAULT NULL,
+  `status` varchar(16) NOT NULL DEFAULT 'processing',
+  `reason` text DEFAULT NULL,
+  `duration` double DEFAULT NULL,
+  `width` int(10) DEFAULT NULL,
+  `height` int(10) DEFAULT NULL,
   PRIMARY KEY (`id`)
 );
\ No newline at end of file
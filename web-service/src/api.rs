@@ -2,21 +2,29 @@ extern crate rand;
 extern crate chrono;
 
 use {
-    web_service::video_client::VideoClient,
+    std::time::{SystemTime, UNIX_EPOCH},
+    web_service::video_client::{mime_type, Unavailable, VideoClient, VideoConnection},
     actix_multipart::Multipart,
-    actix_web::{web, dev, http, error, Error, HttpResponse, Result},
+    actix_web::{web, dev, http, error, Error, HttpRequest, HttpResponse, Result},
     actix_web::middleware::errhandlers::ErrorHandlerResponse,
     actix_files::NamedFile,
-    futures::{StreamExt, channel::mpsc},
+    futures::{stream, StreamExt},
     failure::Fail,
     tera::Tera,
-    actix,
     rand::{thread_rng, Rng},
     rand::distributions::Standard,
     serde::Deserialize,
-    crate::db,
+    hmac::{Hmac, Mac},
+    sha2::Sha256,
+    tracing::info,
+    crate::{db, events::Broadcaster, health::{self, Health}, metrics::Metrics, models::VideoOutcome, shutdown::{Guard, InFlight}, trace::RequestId},
 };
 
+// callbacks older than this amount of seconds are refused
+const CALLBACK_MAX_AGE: u64 = 300;
+// clocks of video service nodes may be ahead of ours by this amount of seconds
+const CALLBACK_MAX_SKEW: u64 = 60;
+
 // custom errors
 #[derive(Fail, Debug)]
 #[fail(display = "video client error")]
@@ -25,6 +33,20 @@ pub enum VideoError {
     InternalError {msg: String},
     #[fail(display = "Vido file was not found. Err: {}", msg)]
     NotFound {msg: String},
+    #[fail(display = "Video service refused the upload. Err: {}", msg)]
+    Refused {msg: String},
+    #[fail(display = "Video service is not available. Err: {}", msg)]
+    Unavailable {msg: String, retry_after: Option<u64>},
+}
+
+impl VideoError {
+    // errors of transport and open circuits become `Unavailable`, the rest become `otherwise`
+    fn from_client(e: Box<dyn std::error::Error + Send + Sync>, otherwise: fn(String) -> VideoError) -> VideoError {
+        match e.downcast_ref::<Unavailable>() {
+            Some(Unavailable { msg, retry_after }) => VideoError::Unavailable{msg: msg.clone(), retry_after: *retry_after},
+            None => otherwise(e.to_string()),
+        }
+    }
 }
 
 // implement trait for custom VideoError to use it as actix-web error
@@ -33,6 +55,16 @@ impl error::ResponseError for VideoError {
         match self {
             VideoError::InternalError{msg} => HttpResponse::InternalServerError().json(msg),
             VideoError::NotFound{msg} => HttpResponse::NotFound().json(msg),
+            // e.g. file is too large or there is no free disk space left
+            VideoError::Refused{msg} => HttpResponse::UnprocessableEntity().json(msg),
+            // browsers and proxies learn when the video service is tried again
+            VideoError::Unavailable{msg, retry_after} => {
+                let mut res = HttpResponse::ServiceUnavailable();
+                if let Some(secs) = retry_after {
+                    res.header(http::header::RETRY_AFTER, secs.to_string());
+                }
+                res.json(msg)
+            },
         }
     }
 }
@@ -46,35 +78,46 @@ pub async fn index(tmpl: web::Data<Tera>) -> Result<HttpResponse, Error> {
 
 // upload video file in chunks to remote video service
 pub async fn save_file(
-    (mut payload, video_client, pool): 
-    (Multipart, web::Data<VideoClient>, web::Data<db::MysqlPool>)
+    (mut payload, video_client, pool, metrics, in_flight, request_id):
+    (Multipart, web::Data<VideoClient>, web::Data<db::MysqlPool>, web::Data<Metrics>, web::Data<InFlight>, RequestId)
 ) -> Result<HttpResponse, Error> {
+    // shutdown waits for the upload to finish
+    let _guard = in_flight.track(format!("upload of request {}", request_id.0));
     let mut files = Vec::new();
     // iterate over multipart stream
     while let Some(item) = payload.next().await {
-        // establish connection to remote video service
-        let mut video_conn = video_client.conn()
-            .await
-            .expect("Can not connect to remote video-service");
-
         let mut field = item.unwrap();
         // generate random filename
         let filename: Vec<u32> = thread_rng()
             .sample_iter(&Standard)
             .take(1)
             .collect();
-        // only need first 7 chars for now
-        let filename = filename[0].to_string()[0..7].to_string() + ".mp4";
+        // only need first 7 chars for now,
+        // video service adds an extension that matches content of the file
+        let filename = filename[0].to_string()[0..7].to_string();
+
+        // establish connection to the video service node that owns the filename
+        let mut video_conn = video_client.conn(&filename)
+            .await
+            .map_err(|e| VideoError::from_client(e, |msg| VideoError::Unavailable{msg, retry_after: None}))?
+            .with_request_id(&request_id.0);
         video_conn.start_uploading(&filename).await
-            .map_err(|e| VideoError::InternalError{msg: e.to_string()})?;
+            .map_err(|e| VideoError::from_client(e, |msg| VideoError::Refused{msg}))?;
 
         // send each chunk to remote video service
         while let Some(chunk) = field.next().await {
             let data = chunk.unwrap();
-            video_conn.buffered_send(data).await.unwrap();
+            metrics.uploaded(data.len() as u64);
+            if video_conn.buffered_send(data).await.is_err() {
+                // remote video service stopped the upload, find out why
+                break;
+            }
         }
-        // flush buffer and send rest of bytes
-        video_conn.flush().await.unwrap();
+        // flush buffer, send rest of bytes and wait for confirmation
+        let filename = video_conn.finish_uploading().await
+            .map_err(|e| VideoError::from_client(e, |msg| VideoError::Refused{msg}))?
+            .unwrap_or(filename);
+        info!(video = %filename, "video uploaded");
 
         files.push(filename);
         
@@ -105,6 +148,7 @@ pub async fn show_video(
     // pass data from populated video into template
     let mut ctx = tera::Context::new();
     ctx.insert("name", &video.name);
+    ctx.insert("mime_type", mime_type(&video.name));
     let s = tmpl.render("video.html", &ctx)
             .map_err(|_| error::ErrorInternalServerError("Template error"))?;
     Ok(HttpResponse::Ok().content_type("text/html").body(s))
@@ -112,27 +156,171 @@ pub async fn show_video(
 
 // get video file from remote video service
 pub async fn get_file(
-    (filename, video_client): 
-    (web::Path<String>, web::Data<VideoClient>)
+    (filename, video_client, metrics, in_flight, request_id):
+    (web::Path<String>, web::Data<VideoClient>, web::Data<Metrics>, web::Data<InFlight>, RequestId)
 ) -> Result<HttpResponse, Error> {
 
-    let mut video_conn = video_client.conn()
-            .await
-            .expect("Can not connect to remote video-service");
-
-    video_conn.start_receiving(&filename).await
-        .map_err(|e| VideoError::NotFound{msg: e.to_string()})?;
+    // unavailable nodes are retried, then replicas are tried
+    let (known_type, video_conn) = video_client.download(&filename, &request_id.0)
+        .await
+        .map_err(|e| VideoError::from_client(e, |msg| VideoError::NotFound{msg}))?;
+    let mime_type = known_type.unwrap_or_else(|| mime_type(&filename).to_string());
     
-    let (tx, rx_body) = mpsc::unbounded();
-
-    // spawn new task to receive video in many parts and push in stream
-    actix::spawn(async move {
-        while let Some(chunk) = video_conn.read_next().await {
-            tx.unbounded_send(chunk).unwrap();
-        }
+    // the body is pulled from video service only as fast as the browser takes it,
+    // shutdown waits for the download to finish
+    let guard = in_flight.track(format!("download of {}", filename));
+    let download = Download { conn: Some(video_conn), video_client, metrics, filename: filename.into_inner(), _guard: guard };
+    let body = stream::unfold(download, |mut download| async move {
+        download.next().await.map(|chunk| (chunk, download))
     });
     // response with HTTP Streaming body
-    Ok(HttpResponse::Ok().streaming(rx_body))
+    Ok(HttpResponse::Ok().content_type(mime_type).streaming(Box::pin(body)))
+}
+
+// video proxied from video service to a browser,
+// a browser that goes away drops the body and with it the connection to video service
+struct Download {
+    // none once the video was read to the end or failed
+    conn: Option<VideoConnection>,
+    video_client: web::Data<VideoClient>,
+    metrics: web::Data<Metrics>,
+    filename: String,
+    _guard: Guard,
+}
+
+impl Download {
+    async fn next(&mut self) -> Option<std::io::Result<web::Bytes>> {
+        let chunk = self.conn.as_mut()?.read_next().await;
+        match &chunk {
+            Some(Ok(bytes)) => self.metrics.downloaded(bytes.len() as u64),
+            // connection is reused by the next download if the whole video was read
+            None => self.video_client.release(self.conn.take()?),
+            Some(Err(_)) => self.conn = None,
+        }
+        chunk
+    }
+}
+
+impl Drop for Download {
+    fn drop(&mut self) {
+        if self.conn.is_some() {
+            info!(filename = %self.filename, "client went away, download cancelled");
+        }
+    }
+}
+
+/// Shared secret video service signs callbacks with
+pub struct CallbackSecret(pub String);
+
+#[derive(Deserialize)]
+pub struct MediaInfo {
+    pub duration: Option<f64>,
+    pub width: Option<i32>,
+    pub height: Option<i32>,
+}
+
+// outcome of processing sent by video service
+#[derive(Deserialize)]
+pub struct Callback {
+    pub filename: String,
+    pub status: String,
+    pub reason: Option<String>,
+    pub info: Option<MediaInfo>,
+    pub timestamp: u64,
+}
+
+// receive outcome of processing from remote video service and update the video
+pub async fn video_callback(
+    (req, body, secret, pool):
+    (HttpRequest, web::Bytes, web::Data<CallbackSecret>, web::Data<db::MysqlPool>)
+) -> Result<HttpResponse, Error> {
+    // callbacks are off without a secret, an empty key would accept signatures made by anyone
+    if secret.0.is_empty() {
+        return Err(error::ErrorNotFound("Callbacks are not enabled"));
+    }
+    // signature is a hex encoded HMAC-SHA256 of the body
+    let signature = req.headers().get("x-signature")
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("sha256="))
+        .and_then(|v| hex::decode(v).ok())
+        .ok_or_else(|| error::ErrorUnauthorized("Missing signature"))?;
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.0.as_bytes())
+        .map_err(|_| error::ErrorInternalServerError("Invalid callback secret"))?;
+    mac.update(&body);
+    mac.verify_slice(&signature).map_err(|_| error::ErrorUnauthorized("Invalid signature"))?;
+
+    let callback: Callback = serde_json::from_slice(&body)
+        .map_err(|e| error::ErrorBadRequest(e.to_string()))?;
+    // do not let anyone replay old callbacks
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+    if now.saturating_sub(callback.timestamp) > CALLBACK_MAX_AGE {
+        return Err(error::ErrorUnauthorized("Callback is too old"));
+    }
+    if callback.timestamp > now + CALLBACK_MAX_SKEW {
+        return Err(error::ErrorUnauthorized("Callback is from the future"));
+    }
+
+    let info = callback.info.unwrap_or(MediaInfo { duration: None, width: None, height: None });
+    let outcome = VideoOutcome {
+        status: callback.status,
+        reason: callback.reason,
+        duration: info.duration,
+        width: info.width,
+        height: info.height,
+    };
+    let filename = callback.filename;
+    let updated = web::block(move || db::update_video_outcome(&filename, outcome, &pool)).await?;
+    if updated == 0 {
+        // video could be not inserted yet, video service will retry
+        return Err(VideoError::NotFound{msg: "Video was not found".to_string()})?;
+    }
+    Ok(HttpResponse::Ok().finish())
+}
+
+// stream events of videos to the browser as server-sent events
+pub async fn video_events(broadcaster: web::Data<Broadcaster>) -> Result<HttpResponse, Error> {
+    let rx = broadcaster.add_client().map(Ok::<_, Error>);
+    Ok(HttpResponse::Ok()
+        .content_type("text/event-stream")
+        .header(http::header::CACHE_CONTROL, "no-cache")
+        .streaming(rx))
+}
+
+// liveness, web service is alive while it can talk to its database
+pub async fn healthz(pool: web::Data<db::MysqlPool>) -> HttpResponse {
+    let mut health = Health::new();
+    health.add("database", health::check_db(pool.get_ref().clone()).await);
+    health_response(health)
+}
+
+// readiness, web service can serve videos only when all dependencies answer
+pub async fn readyz(
+    (pool, video_client):
+    (web::Data<db::MysqlPool>, web::Data<VideoClient>)
+) -> HttpResponse {
+    let mut health = Health::new();
+    health.add("database", health::check_db(pool.get_ref().clone()).await);
+    health.add("video_service", health::check_video_service(&video_client).await);
+    health_response(health)
+}
+
+// JSON with results of all checks, status is 503 if any check failed
+fn health_response(health: Health) -> HttpResponse {
+    if health.is_ok() {
+        HttpResponse::Ok().json(health)
+    } else {
+        HttpResponse::ServiceUnavailable().json(health)
+    }
+}
+
+// expose metrics in Prometheus text format
+pub async fn metrics(
+    (metrics, pool):
+    (web::Data<Metrics>, web::Data<db::MysqlPool>)
+) -> HttpResponse {
+    HttpResponse::Ok()
+        .content_type("text/plain; version=0.0.4")
+        .body(metrics.render(&pool))
 }
 
 // list all available videos
@@ -179,6 +367,18 @@ pub fn not_found<B>(res: dev::ServiceResponse<B>) -> Result<ErrorHandlerResponse
     )
 }
 
+// custom handler for 503 ServiceUnavailable Error of video routes,
+// `Retry-After` of the original response is kept
+pub fn service_unavailable<B>(mut res: dev::ServiceResponse<B>) -> Result<ErrorHandlerResponse<B>> {
+    let new_resp = NamedFile::open("static/errors/503.html")?
+        .set_status_code(res.status())
+        .into_response(res.request())?;
+    res.headers_mut().insert(http::header::CONTENT_TYPE, http::HeaderValue::from_static("text/html; charset=utf-8"));
+    Ok(ErrorHandlerResponse::Response(
+        res.into_response(new_resp.into_body()),
+    ))
+}
+
 // custom handler for 500 InternalServer Error
 pub fn internal_server_error<B>(
     res: dev::ServiceResponse<B>,
@@ -1,8 +1,8 @@
 use {
-    std::ops::Deref,
-    diesel::mysql::MysqlConnection,
+    std::{ops::Deref, time::Duration},
+    diesel::{RunQueryDsl, mysql::MysqlConnection},
     diesel::r2d2::{ConnectionManager, Pool, PoolError, PooledConnection},
-    crate::models::{NewVideo, Video},
+    crate::models::{NewVideo, Video, VideoOutcome},
 };
 
 // custom types to simplify code
@@ -20,6 +20,14 @@ fn get_conn(pool: &MysqlPool) -> Result<MysqlPooledConnection, &'static str> {
     pool.get().map_err(|_| "Can't get connection")
 }
 
+// check that database answers in time, returns usage of the pool
+pub fn ping(pool: &MysqlPool, timeout: Duration) -> Result<String, &'static str> {
+    let conn = pool.get_timeout(timeout).map_err(|_| "Can't get connection")?;
+    diesel::sql_query("SELECT 1").execute(conn.deref()).map_err(|_| "Database does not answer")?;
+    let state = pool.state();
+    Ok(format!("{} of {} connections are idle", state.idle_connections, state.connections))
+}
+
 // list all videos
 pub fn get_all_videos(pool: &MysqlPool) -> Result<Vec<Video>, &'static str> {
     Video::all(get_conn(pool)?.deref()).map_err(|_| "Error getting all videos")
@@ -52,6 +60,12 @@ pub fn get_video(id: i32, pool: &MysqlPool) -> Result<Video, &'static str> {
         .map_err(|_| "Video not found")
 }
 
+// save outcome of processing, returns number of updated videos
+pub fn update_video_outcome(name: &str, outcome: VideoOutcome, pool: &MysqlPool) -> Result<usize, &'static str> {
+    Video::update_outcome(name, &outcome, get_conn(pool)?.deref())
+        .map_err(|_| "Error updating video")
+}
+
 // delete video
 pub fn delete_video(id: i32, pool: &MysqlPool) -> Result<(), &'static str> {
     Video::delete_with_id(id, get_conn(pool)?.deref())
@@ -0,0 +1,156 @@
+use {
+    std::{sync::Mutex, time::Duration},
+    web_service::video_client::{Event, Unauthorized, VideoClient},
+    actix_web::web,
+    futures::{StreamExt, channel::mpsc},
+    bytes::Bytes,
+    tracing::{error, info, warn},
+    crate::{db, models::VideoOutcome},
+};
+
+// how long to wait before subscribing again after video service went away,
+// the delay doubles with every failure in a row up to the maximum
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(300);
+
+// events a browser may fall behind by before it is dropped
+const QUEUE_SIZE: usize = 256;
+
+/// Browsers that listen for events of videos
+#[derive(Default)]
+pub struct Broadcaster {
+    clients: Mutex<Vec<mpsc::Sender<Bytes>>>,
+}
+
+impl Broadcaster {
+    pub fn new() -> Broadcaster {
+        Broadcaster::default()
+    }
+
+    /// register new browser, it receives server-sent events
+    /// until it falls too far behind, then the response ends and the browser reconnects
+    pub fn add_client(&self) -> mpsc::Receiver<Bytes> {
+        let (tx, rx) = mpsc::channel(QUEUE_SIZE);
+        self.clients.lock().unwrap().push(tx);
+        rx
+    }
+
+    /// send event to every browser, disconnected and lagging browsers are dropped
+    pub fn send(&self, event: &Event) {
+        let data = match serde_json::to_string(event) {
+            Ok(data) => Bytes::from(format!("data: {}\n\n", data)),
+            Err(_) => return,
+        };
+        self.clients.lock().unwrap()
+            .retain_mut(|client| client.try_send(data.clone()).is_ok());
+    }
+}
+
+// receive events from remote video service forever,
+// save outcome of processing and pass every event to browsers
+pub async fn watch(
+    video_client: web::Data<VideoClient>,
+    pool: db::MysqlPool,
+    broadcaster: web::Data<Broadcaster>,
+) {
+    let mut delay = RECONNECT_DELAY;
+    loop {
+        match video_client.subscribe().await {
+            Ok(mut events) => {
+                delay = RECONNECT_DELAY;
+                while let Some(event) = events.next().await {
+                    match event {
+                        Ok(event) => {
+                            save_outcome(&event, &pool).await;
+                            broadcaster.send(&event);
+                        },
+                        Err(e) => warn!(error = %e, "error receiving event"),
+                    }
+                }
+                info!("video service closed the stream of events");
+            },
+            // live status stays off until the key is fixed, so it must not fail quietly
+            Err(e) if e.downcast_ref::<Unauthorized>().is_some() => {
+                error!(error = %e, retry_in = delay.as_secs(), "video service refused the subscription");
+                delay = (delay * 2).min(MAX_RECONNECT_DELAY);
+            },
+            Err(e) => {
+                warn!(error = %e, retry_in = delay.as_secs(), "error subscribing to events");
+                delay = (delay * 2).min(MAX_RECONNECT_DELAY);
+            },
+        }
+        actix_rt::time::delay_for(delay).await;
+    }
+}
+
+// update status of the video if it is finished
+async fn save_outcome(event: &Event, pool: &db::MysqlPool) {
+    let (filename, outcome) = match event.clone() {
+        Event::Done { filename, info } => {
+            let info = info.unwrap_or_default();
+            (filename, VideoOutcome {
+                status: "done".to_string(),
+                reason: None,
+                duration: info.duration,
+                width: info.width,
+                height: info.height,
+            })
+        },
+        Event::Failed { filename, reason } => (filename, VideoOutcome {
+            status: "failed".to_string(),
+            reason: Some(reason),
+            duration: None,
+            width: None,
+            height: None,
+        }),
+        Event::Rejected { filename, reason } => (filename, VideoOutcome {
+            status: "rejected".to_string(),
+            reason: Some(reason),
+            duration: None,
+            width: None,
+            height: None,
+        }),
+        _ => return,
+    };
+    let pool = pool.clone();
+    if let Err(e) = web::block(move || db::update_video_outcome(&filename, outcome, &pool)).await {
+        warn!(error = %e, "error saving outcome of video");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn queued(n: usize) -> Event {
+        Event::Queued { filename: format!("{}.mp4", n) }
+    }
+
+    #[test]
+    fn browsers_get_server_sent_events() {
+        let broadcaster = Broadcaster::new();
+        let mut browser = broadcaster.add_client();
+        broadcaster.send(&queued(1));
+        let data = browser.try_next().unwrap().unwrap();
+        assert_eq!(&data[..], &b"data: {\"event\":\"queued\",\"filename\":\"1.mp4\"}\n\n"[..]);
+    }
+
+    #[test]
+    fn lagging_and_closed_browsers_are_dropped() {
+        let broadcaster = Broadcaster::new();
+        let mut lagging = broadcaster.add_client();
+        drop(broadcaster.add_client());
+        let mut reading = broadcaster.add_client();
+        // the channel holds one more event than its size for the sender
+        for n in 0..QUEUE_SIZE + 2 {
+            broadcaster.send(&queued(n));
+            assert!(reading.try_next().unwrap().is_some());
+        }
+        assert_eq!(broadcaster.clients.lock().unwrap().len(), 1);
+        let mut delivered = 0;
+        while let Ok(Some(_)) = lagging.try_next() {
+            delivered += 1;
+        }
+        assert_eq!(delivered, QUEUE_SIZE + 1);
+    }
+}
@@ -0,0 +1,68 @@
+use {
+    std::{collections::BTreeMap, time::Duration},
+    web_service::video_client::VideoClient,
+    actix_web::web,
+    serde::Serialize,
+    crate::db,
+};
+
+// how long a single dependency may take to answer
+const CHECK_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Result of a single check
+#[derive(Debug, Clone, Serialize)]
+pub struct Check {
+    pub ok: bool,
+    pub detail: String,
+}
+
+impl Check {
+    fn new(result: Result<String, String>) -> Check {
+        match result {
+            Ok(detail) => Check { ok: true, detail },
+            Err(detail) => Check { ok: false, detail },
+        }
+    }
+}
+
+/// Results of all checks, service is healthy only when every check passed
+#[derive(Debug, Clone, Serialize)]
+pub struct Health {
+    /// `ok` or `failing`
+    pub status: &'static str,
+    pub checks: BTreeMap<&'static str, Check>,
+}
+
+impl Health {
+    pub fn new() -> Health {
+        Health { status: "ok", checks: BTreeMap::new() }
+    }
+
+    /// add result of a check, a single failed check makes the whole service failing
+    pub fn add(&mut self, name: &'static str, result: Result<String, String>) {
+        let check = Check::new(result);
+        if !check.ok {
+            self.status = "failing";
+        }
+        self.checks.insert(name, check);
+    }
+
+    pub fn is_ok(&self) -> bool {
+        self.status == "ok"
+    }
+}
+
+/// database answers to a simple query
+pub async fn check_db(pool: db::MysqlPool) -> Result<String, String> {
+    web::block(move || db::ping(&pool, CHECK_TIMEOUT)).await
+        .map_err(|e| e.to_string())
+}
+
+/// video service answers to `PING`
+pub async fn check_video_service(video_client: &VideoClient) -> Result<String, String> {
+    match tokio::time::timeout(CHECK_TIMEOUT, video_client.ping()).await {
+        Ok(Ok(())) => Ok("video service answers".to_string()),
+        Ok(Err(e)) => Err(e.to_string()),
+        Err(_) => Err(format!("video service did not answer in {} seconds", CHECK_TIMEOUT.as_secs())),
+    }
+}
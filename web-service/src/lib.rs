@@ -1,140 +1,1296 @@
 #![warn(rust_2018_idioms)]
+pub mod ring {
+
+    use {
+        sha2::{Digest, Sha256},
+        crate::net::NodeAddr,
+    };
+
+    // points of every node on the ring, more points spread keys more evenly
+    const POINTS_PER_NODE: usize = 128;
+
+    /// Consistent hash ring of video service nodes
+    /// adding or removing a node moves only keys that belong to it
+    #[derive(Debug, Clone)]
+    pub struct Ring {
+        nodes: Vec<NodeAddr>,
+        // sorted by hash, with the index of the node
+        points: Vec<(u64, usize)>,
+    }
+
+    impl Ring {
+        pub fn new(nodes: Vec<NodeAddr>) -> Ring {
+            let mut points = nodes.iter()
+                .enumerate()
+                .flat_map(|(n, node)| (0..POINTS_PER_NODE).map(move |i| (hash(&format!("{}#{}", node, i)), n)))
+                .collect::<Vec<(u64, usize)>>();
+            points.sort();
+            Ring { nodes, points }
+        }
+
+        pub fn nodes(&self) -> &[NodeAddr] {
+            &self.nodes
+        }
+
+        /// node that stores the video
+        /// extension is ignored, because video service changes it by content of the upload
+        pub fn owner(&self, filename: &str) -> &NodeAddr {
+            let stem = filename.split('.').next().unwrap_or(filename);
+            let key = hash(stem);
+            let i = self.points.partition_point(|(point, _)| *point < key);
+            // first point after the end of the ring is the first one
+            &self.nodes[self.points.get(i).unwrap_or(&self.points[0]).1]
+        }
+    }
+
+    // first 8 bytes of SHA-256, it is the same on every machine and version of Rust
+    fn hash(input: &str) -> u64 {
+        let digest = Sha256::digest(input.as_bytes());
+        let mut bytes = [0u8; 8];
+        bytes.copy_from_slice(&digest[..8]);
+        u64::from_be_bytes(bytes)
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        fn nodes(count: u16) -> Vec<NodeAddr> {
+            (0..count).map(|i| format!("10.0.0.{}:9000", i + 1).parse().unwrap()).collect()
+        }
+
+        fn keys() -> Vec<String> {
+            (0..10000).map(|i| format!("{}.mp4", i)).collect()
+        }
+
+        #[test]
+        fn adding_a_node_moves_keys_only_to_it() {
+            let before = Ring::new(nodes(4));
+            let after = Ring::new(nodes(5));
+            let added = &after.nodes()[4];
+            let mut moved = 0;
+            for key in keys() {
+                if before.owner(&key) != after.owner(&key) {
+                    assert_eq!(after.owner(&key), added, "{} moved between old nodes", key);
+                    moved += 1;
+                }
+            }
+            // the new node takes about a fifth of the keys
+            assert!((1000..3000).contains(&moved), "{} of 10000 keys moved", moved);
+        }
+
+        #[test]
+        fn removing_a_node_moves_only_its_keys() {
+            let before = Ring::new(nodes(5));
+            let mut remaining = nodes(5);
+            let removed = remaining.remove(2);
+            let after = Ring::new(remaining);
+            let mut moved = 0;
+            for key in keys() {
+                if before.owner(&key) != after.owner(&key) {
+                    assert_eq!(before.owner(&key), &removed, "{} moved from a node that stayed", key);
+                    moved += 1;
+                }
+            }
+            assert!((1000..3000).contains(&moved), "{} of 10000 keys moved", moved);
+        }
+
+        #[test]
+        fn extension_does_not_change_the_owner() {
+            let ring = Ring::new(nodes(5));
+            for i in 0..100 {
+                assert_eq!(ring.owner(&format!("{}.mov", i)), ring.owner(&format!("{}.mp4", i)));
+            }
+        }
+    }
+}
+
+pub mod net {
+
+    use {
+        std::{fmt, io, net::SocketAddr, path::PathBuf, pin::Pin, str::FromStr, task::{Context, Poll}},
+        tokio::{io::{AsyncRead, AsyncWrite}, net::{TcpStream, UnixStream}},
+        tokio_rustls::client::TlsStream,
+        crate::tls::Connector,
+    };
+
+    // addresses of Unix sockets start with it, e.g. `unix:///run/video-service.sock`
+    const UNIX_PREFIX: &str = "unix://";
+
+    /// Address of a video service node, `host:port` or `unix://<path>`
+    #[derive(Debug, Clone, PartialEq, Eq, Hash)]
+    pub enum NodeAddr {
+        Tcp(SocketAddr),
+        Unix(PathBuf),
+    }
+
+    impl FromStr for NodeAddr {
+        type Err = String;
+
+        fn from_str(input: &str) -> Result<NodeAddr, String> {
+            match input.strip_prefix(UNIX_PREFIX) {
+                Some("") => Err(format!("invalid address {}, path of the socket is missing", input)),
+                Some(path) => Ok(NodeAddr::Unix(PathBuf::from(path))),
+                None => input.parse().map(NodeAddr::Tcp).map_err(|_| format!("invalid address {}", input)),
+            }
+        }
+    }
+
+    impl From<SocketAddr> for NodeAddr {
+        fn from(addr: SocketAddr) -> NodeAddr {
+            NodeAddr::Tcp(addr)
+        }
+    }
+
+    // the same as in the config, nodes keep their place on the ring
+    impl fmt::Display for NodeAddr {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            match self {
+                NodeAddr::Tcp(addr) => write!(f, "{}", addr),
+                NodeAddr::Unix(path) => write!(f, "{}{}", UNIX_PREFIX, path.display()),
+            }
+        }
+    }
+
+    /// Connection to video service, encrypted when TLS is configured
+    pub enum Stream {
+        Tcp(TcpStream),
+        // local connections are never encrypted, permissions of the socket file protect them
+        Unix(UnixStream),
+        Tls(Box<TlsStream<TcpStream>>),
+    }
+
+    impl Stream {
+        /// open connection to a node, TCP ones over TLS when `connector` is set
+        pub async fn connect(addr: &NodeAddr, connector: Option<&Connector>) -> io::Result<Stream> {
+            match (addr, connector) {
+                (NodeAddr::Unix(path), _) => Ok(Stream::Unix(UnixStream::connect(path).await?)),
+                (NodeAddr::Tcp(addr), Some(connector)) => connector.connect(TcpStream::connect(addr).await?).await,
+                (NodeAddr::Tcp(addr), None) => Ok(Stream::Tcp(TcpStream::connect(addr).await?)),
+            }
+        }
+    }
+
+    impl AsyncRead for Stream {
+        fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<io::Result<usize>> {
+            match self.get_mut() {
+                Stream::Tcp(stream) => Pin::new(stream).poll_read(cx, buf),
+                Stream::Unix(stream) => Pin::new(stream).poll_read(cx, buf),
+                Stream::Tls(stream) => Pin::new(stream.as_mut()).poll_read(cx, buf),
+            }
+        }
+    }
+
+    impl AsyncWrite for Stream {
+        fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+            match self.get_mut() {
+                Stream::Tcp(stream) => Pin::new(stream).poll_write(cx, buf),
+                Stream::Unix(stream) => Pin::new(stream).poll_write(cx, buf),
+                Stream::Tls(stream) => Pin::new(stream.as_mut()).poll_write(cx, buf),
+            }
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            match self.get_mut() {
+                Stream::Tcp(stream) => Pin::new(stream).poll_flush(cx),
+                Stream::Unix(stream) => Pin::new(stream).poll_flush(cx),
+                Stream::Tls(stream) => Pin::new(stream.as_mut()).poll_flush(cx),
+            }
+        }
+
+        fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            match self.get_mut() {
+                Stream::Tcp(stream) => Pin::new(stream).poll_shutdown(cx),
+                Stream::Unix(stream) => Pin::new(stream).poll_shutdown(cx),
+                Stream::Tls(stream) => Pin::new(stream.as_mut()).poll_shutdown(cx),
+            }
+        }
+    }
+}
+
+pub mod tls {
+
+    use {
+        std::{fs::File, io::{self, BufReader}, path::Path, sync::Arc},
+        tokio::net::TcpStream,
+        tokio_rustls::{rustls::{internal::pemfile, ClientConfig, RootCertStore}, webpki::DNSNameRef, TlsConnector},
+        crate::net::Stream,
+    };
+
+    type Result<T> = std::result::Result<T, Box<dyn std::error::Error + Send + Sync>>;
+
+    /// Client side of mutual TLS, it shows our certificate and checks the one of video service
+    #[derive(Clone)]
+    pub struct Connector {
+        connector: TlsConnector,
+        // name certificates of nodes are issued for, nodes are addressed by IP
+        server_name: String,
+    }
+
+    impl Connector {
+        /// PEM certificate chain and private key of this service and certificate of the CA that signs nodes
+        pub fn new(cert: &Path, key: &Path, ca: &Path, server_name: &str) -> Result<Connector> {
+            DNSNameRef::try_from_ascii_str(server_name)
+                .map_err(|_| format!("invalid server name {}", server_name))?;
+            let certs = pemfile::certs(&mut open(cert)?)
+                .map_err(|_| format!("invalid certificate {}", cert.display()))?;
+            if certs.is_empty() {
+                return Err(format!("no certificate in {}", cert.display()).into());
+            }
+            // keys made by openssl are either PKCS#8 or RSA
+            let mut keys = pemfile::pkcs8_private_keys(&mut open(key)?).unwrap_or_default();
+            if keys.is_empty() {
+                keys = pemfile::rsa_private_keys(&mut open(key)?).unwrap_or_default();
+            }
+            let private_key = keys.pop().ok_or_else(|| format!("no private key in {}", key.display()))?;
+            let mut roots = RootCertStore::empty();
+            match roots.add_pem_file(&mut open(ca)?) {
+                Ok((added, _)) if added > 0 => {},
+                _ => return Err(format!("no CA certificate in {}", ca.display()).into()),
+            }
+            let mut config = ClientConfig::new();
+            config.root_store = roots;
+            config.set_single_client_cert(certs, private_key)
+                .map_err(|e| format!("invalid TLS certificate: {}", e))?;
+            Ok(Connector { connector: TlsConnector::from(Arc::new(config)), server_name: server_name.to_string() })
+        }
+
+        /// handshake with a node over a connection that was just opened
+        pub async fn connect(&self, socket: TcpStream) -> io::Result<Stream> {
+            // the name is checked by `new`
+            let name = DNSNameRef::try_from_ascii_str(&self.server_name).expect("server name is validated");
+            let stream = self.connector.connect(name, socket).await?;
+            Ok(Stream::Tls(Box::new(stream)))
+        }
+    }
+
+    fn open(path: &Path) -> Result<BufReader<File>> {
+        let file = File::open(path).map_err(|e| format!("can not read {}: {}", path.display(), e))?;
+        Ok(BufReader::new(file))
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use {
+            super::*,
+            std::{path::PathBuf, process::{Command, Stdio}},
+            tokio::{io::{AsyncReadExt, AsyncWriteExt}, net::TcpListener},
+            tokio_rustls::{rustls::{AllowAnyAuthenticatedClient, ServerConfig}, TlsAcceptor},
+        };
+
+        // certificates of `scripts/gen-test-certs.sh` in a directory of their own
+        fn certs(name: &str) -> PathBuf {
+            let dir = std::env::temp_dir().join(format!("web-service-certs-{}-{}", name, std::process::id()));
+            let script = Path::new(env!("CARGO_MANIFEST_DIR")).join("../scripts/gen-test-certs.sh");
+            let status = Command::new("sh").arg(script).arg(&dir).stdout(Stdio::null()).status().expect("sh is installed");
+            assert!(status.success(), "gen-test-certs.sh failed");
+            dir
+        }
+
+        // node that requires a client certificate signed by the test CA and answers `OK`
+        async fn node(dir: &Path) -> (std::net::SocketAddr, tokio::task::JoinHandle<io::Result<()>>) {
+            let mut roots = RootCertStore::empty();
+            roots.add_pem_file(&mut open(&dir.join("ca.pem")).unwrap()).unwrap();
+            let mut config = ServerConfig::new(AllowAnyAuthenticatedClient::new(roots));
+            let certs = pemfile::certs(&mut open(&dir.join("video.pem")).unwrap()).unwrap();
+            let mut keys = pemfile::pkcs8_private_keys(&mut open(&dir.join("video-key.pem")).unwrap()).unwrap();
+            if keys.is_empty() {
+                keys = pemfile::rsa_private_keys(&mut open(&dir.join("video-key.pem")).unwrap()).unwrap();
+            }
+            config.set_single_cert(certs, keys.remove(0)).unwrap();
+            let acceptor = TlsAcceptor::from(Arc::new(config));
+            let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let addr = listener.local_addr().unwrap();
+            let server = tokio::spawn(async move {
+                let (socket, _) = listener.accept().await?;
+                let mut stream = acceptor.accept(socket).await?;
+                stream.write_all(b"OK").await?;
+                stream.flush().await
+            });
+            (addr, server)
+        }
+
+        // open a connection to the node and read its answer
+        async fn exchange(connector: &Connector, addr: std::net::SocketAddr) -> io::Result<Vec<u8>> {
+            let mut stream = connector.connect(TcpStream::connect(addr).await?).await?;
+            let mut answer = Vec::new();
+            stream.read_to_end(&mut answer).await?;
+            Ok(answer)
+        }
+
+        #[tokio::test]
+        async fn mutual_handshake() {
+            let dir = certs("mutual");
+            let connector = Connector::new(&dir.join("web.pem"), &dir.join("web-key.pem"), &dir.join("ca.pem"), "video-service").unwrap();
+            let (addr, server) = node(&dir).await;
+            assert_eq!(exchange(&connector, addr).await.unwrap(), b"OK");
+            server.await.unwrap().unwrap();
+            std::fs::remove_dir_all(&dir).ok();
+        }
+
+        #[tokio::test]
+        async fn node_refuses_client_of_another_ca() {
+            let dir = certs("rogue");
+            // the rogue certificate is self-signed, so it is its own CA on our side
+            let connector = Connector::new(&dir.join("rogue.pem"), &dir.join("rogue-key.pem"), &dir.join("ca.pem"), "video-service").unwrap();
+            let (addr, server) = node(&dir).await;
+            assert!(exchange(&connector, addr).await.is_err());
+            assert!(server.await.unwrap().is_err());
+            std::fs::remove_dir_all(&dir).ok();
+        }
+
+        #[tokio::test]
+        async fn client_refuses_node_with_another_name() {
+            let dir = certs("name");
+            let connector = Connector::new(&dir.join("web.pem"), &dir.join("web-key.pem"), &dir.join("ca.pem"), "other-service").unwrap();
+            let (addr, server) = node(&dir).await;
+            assert!(exchange(&connector, addr).await.is_err());
+            assert!(server.await.unwrap().is_err());
+            std::fs::remove_dir_all(&dir).ok();
+        }
+
+        #[test]
+        fn refuses_invalid_configuration() {
+            let dir = certs("config");
+            let (cert, key, ca) = (dir.join("web.pem"), dir.join("web-key.pem"), dir.join("ca.pem"));
+            assert!(Connector::new(&cert, &key, &ca, "not a name!").is_err());
+            assert!(Connector::new(&cert, &key, &dir.join("missing.pem"), "video-service").is_err());
+            // a private key is not a certificate
+            assert!(Connector::new(&key, &key, &ca, "video-service").is_err());
+            assert!(Connector::new(&cert, &cert, &ca, "video-service").is_err());
+            std::fs::remove_dir_all(&dir).ok();
+        }
+    }
+}
+
 pub mod video_client{
         
     use {
-        tokio::net::{TcpStream},
         tokio_util::{codec::{Framed, BytesCodec, Decoder}},
         futures_util::stream::{SplitStream, SplitSink},
         bytes::{Bytes, BytesMut},
-        futures::{SinkExt, StreamExt},
-        std::{net::SocketAddr},
+        futures::{future, stream, FutureExt, SinkExt, Stream, StreamExt},
+        hmac::{Hmac, Mac},
+        sha2::Sha256,
+        serde::{Deserialize, Serialize},
+        std::{collections::HashMap, fmt, future::Future, io, pin::Pin, sync::{Arc, Mutex}, time::{Duration, Instant}},
+        crate::{net::{self, NodeAddr}, ring::Ring, tls::Connector},
     };
 
     /// Client allows to communicate with remote video-service 
+    /// every video lives on a single node chosen by consistent hashing of its filename
     pub struct VideoClient {
-        addr: SocketAddr, 
+        ring: Ring,
+        // nodes that keep copies of videos of a node, downloads fall back to them
+        replicas: HashMap<NodeAddr, Vec<NodeAddr>>,
+        callback: Option<String>,
+        // uploads are sent in chunks of this size
+        buffer_size: usize,
+        pool: Pool,
+        timeouts: Timeouts,
+        // idempotent requests are sent again this many times when a node is unavailable
+        retries: u32,
+        breaker: Breaker,
+        // connections are encrypted and show our certificate when it is set
+        tls: Option<Connector>,
+        // ID and secret of the key every connection proves before its first request
+        auth: Option<(String, String)>,
+    }
+
+    /// Limits of waiting for video service
+    #[derive(Debug, Clone, Copy)]
+    pub struct Timeouts {
+        /// opening a connection
+        pub connect: Duration,
+        /// response to a command, e.g. confirmation of a stored upload
+        pub read: Duration,
+        /// the next chunk of a download or sending a chunk of an upload
+        pub idle: Duration,
+    }
+
+    impl Default for Timeouts {
+        fn default() -> Timeouts {
+            Timeouts { connect: Duration::from_secs(3), read: Duration::from_secs(30), idle: Duration::from_secs(60) }
+        }
+    }
+
+    /// Circuit breaker of every node
+    /// after `threshold` failures in a row requests to the node fail at once for `cooldown`,
+    /// then a single request per `cooldown` is let through and its success closes the circuit
+    #[derive(Clone)]
+    struct Breaker {
+        nodes: Arc<Mutex<HashMap<NodeAddr, NodeHealth>>>,
+        threshold: u32,
+        cooldown: Duration,
+    }
+
+    // state of a node in the circuit breaker
+    #[derive(Debug, Default)]
+    struct NodeHealth {
+        // failures in a row
+        failures: u32,
+        // circuit is open until this moment
+        retry_at: Option<Instant>,
+    }
+
+    /// Video service could not be reached, did not answer in time or is known to be unhealthy
+    #[derive(Debug)]
+    pub struct Unavailable {
+        pub msg: String,
+        /// seconds until the node is tried again when its circuit is open
+        pub retry_after: Option<u64>,
+    }
+
+    impl fmt::Display for Unavailable {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            write!(f, "{}", self.msg)
+        }
+    }
+
+    impl std::error::Error for Unavailable {}
+
+    /// Video service refused the key of this client, retrying does not help until the config is fixed
+    #[derive(Debug)]
+    pub struct Unauthorized {
+        pub node: NodeAddr,
+        pub reason: String,
+    }
+
+    impl fmt::Display for Unauthorized {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            write!(f, "authentication with video service {} failed: {}", self.node, self.reason)
+        }
+    }
+
+    impl std::error::Error for Unauthorized {}
+
+    /// Idle connections in keep-alive mode that are reused by downloads and commands
+    /// uploads and events always open a new connection, because they end by closing it
+    struct Pool {
+        idle: Mutex<HashMap<NodeAddr, Vec<(VideoConnection, Instant)>>>,
+        // idle connections kept per node, pooling is off with 0
+        max_idle: usize,
+        idle_timeout: Duration,
+    }
+
+    /// Outcome of moving videos to the nodes that own them
+    #[derive(Debug, Default)]
+    pub struct Rebalance {
+        /// videos that were already on their node
+        pub kept: usize,
+        /// `(filename, from, to)`
+        pub moved: Vec<(String, NodeAddr, NodeAddr)>,
+        /// `(filename, reason)`, these videos stay where they were
+        pub failed: Vec<(String, String)>,
+    }
+
+    // part of `STAT` response the client needs
+    #[derive(Debug, Deserialize)]
+    struct ObjectInfo {
+        size: Option<u64>,
     }
 
     // custom types to simplify code
     type Result<T> = std::result::Result<T, Box<dyn std::error::Error + Send + Sync>>;
-    type ReadStream = SplitStream<Framed<tokio::net::TcpStream, tokio_util::codec::BytesCodec>>;
-    type WriteStream = SplitSink<Framed<tokio::net::TcpStream, tokio_util::codec::BytesCodec>, Bytes>;
+    // pause before the first retry, it doubles with every next one
+    const RETRY_DELAY: Duration = Duration::from_millis(100);
+    // response line to `GET` is `OK <MIME type>`, a longer one means the node sends something else
+    const MAX_GET_LINE: usize = 1024;
+    type ReadStream = SplitStream<Framed<net::Stream, tokio_util::codec::BytesCodec>>;
+    type WriteStream = SplitSink<Framed<net::Stream, tokio_util::codec::BytesCodec>, Bytes>;
+    /// Stream of events pushed by video service
+    pub type EventStream = Pin<Box<dyn Stream<Item = Result<Event>> + Send>>;
+
+    /// Properties of a compressed video
+    #[derive(Debug, Clone, Default, Deserialize, Serialize)]
+    pub struct MediaInfo {
+        pub duration: Option<f64>,
+        pub width: Option<i32>,
+        pub height: Option<i32>,
+        pub codec: Option<String>,
+    }
+
+    /// Something that happened to a video on remote video service
+    #[derive(Debug, Clone, Deserialize, Serialize)]
+    #[serde(tag = "event", rename_all = "snake_case")]
+    pub enum Event {
+        UploadReceived { filename: String, size: u64 },
+        Queued { filename: String },
+        /// from 0 to 100
+        Progress { filename: String, percent: u8 },
+        Done { filename: String, info: Option<MediaInfo> },
+        Failed { filename: String, reason: String },
+        Rejected { filename: String, reason: String },
+        Deleted { filename: String },
+    }
 
     impl VideoClient {
-        /// create new client
-        pub fn new(addr: SocketAddr) -> VideoClient {
-            VideoClient{addr}
+        /// create new client of a single node
+        pub fn new(addr: impl Into<NodeAddr>) -> VideoClient {
+            VideoClient::cluster(vec![addr.into()])
+        }
+
+        /// create new client of several nodes, there must be at least one
+        pub fn cluster(nodes: Vec<NodeAddr>) -> VideoClient {
+            assert!(!nodes.is_empty(), "video client needs at least one node");
+            // 2^24 = 16777216
+            VideoClient{
+                ring: Ring::new(nodes),
+                replicas: HashMap::new(),
+                callback: None,
+                buffer_size: 16777216,
+                pool: Pool::new(16, Duration::from_secs(30)),
+                timeouts: Timeouts::default(),
+                retries: 2,
+                breaker: Breaker::new(5, Duration::from_secs(10)),
+                tls: None,
+                auth: None,
+            }
+        }
+
+        pub fn ring(&self) -> &Ring {
+            &self.ring
+        }
+
+        /// change size of the buffer uploads are sent with, 16 Mb by default
+        pub fn with_buffer_size(mut self, buffer_size: usize) -> VideoClient {
+            self.buffer_size = buffer_size;
+            self
+        }
+
+        /// keep up to `max_idle` connections to every node open for `idle_timeout`, 0 turns pooling off
+        /// 16 connections for 30 seconds by default
+        pub fn with_pool(mut self, max_idle: usize, idle_timeout: Duration) -> VideoClient {
+            self.pool = Pool::new(max_idle, idle_timeout);
+            self
+        }
+
+        /// connect to nodes over mutual TLS, required when they listen with TLS
+        pub fn with_tls(mut self, connector: Connector) -> VideoClient {
+            self.tls = Some(connector);
+            self
+        }
+
+        /// authenticate every connection with a key, required when nodes have `auth.keys`
+        pub fn with_auth(mut self, key: &str, secret: &str) -> VideoClient {
+            self.auth = Some((key.to_string(), secret.to_string()));
+            self
+        }
+
+        /// change limits of waiting for video service, see `Timeouts::default`
+        pub fn with_timeouts(mut self, timeouts: Timeouts) -> VideoClient {
+            self.timeouts = timeouts;
+            self
+        }
+
+        /// send idempotent requests again up to `retries` times when a node is unavailable, 2 by default
+        pub fn with_retries(mut self, retries: u32) -> VideoClient {
+            self.retries = retries;
+            self
+        }
+
+        /// fail fast for `cooldown` after `threshold` failures of a node in a row, 5 failures and 10 seconds by default
+        pub fn with_breaker(mut self, threshold: u32, cooldown: Duration) -> VideoClient {
+            self.breaker = Breaker::new(threshold, cooldown);
+            self
+        }
+
+        /// download videos from replicas of a node when the node is unreachable
+        pub fn with_replicas(mut self, replicas: HashMap<NodeAddr, Vec<NodeAddr>>) -> VideoClient {
+            self.replicas = replicas;
+            self
+        }
+
+        /// ask remote video service to report outcome of every upload to `url`
+        pub fn with_callback(mut self, url: &str) -> VideoClient {
+            self.callback = Some(url.to_string());
+            self
         }
 
-        /// create new socket connection to remote video service
-        pub async fn conn(& self) -> Result<VideoConnection> {
-            let stream = TcpStream::connect(&self.addr).await?;
+        /// create new socket connection to the node that owns the video
+        pub async fn conn(&self, filename: &str) -> Result<VideoConnection> {
+            self.conn_to(self.ring.owner(filename)).await
+        }
+
+        /// create new socket connection for downloading a video,
+        /// replicas of the owner are tried in order when the owner is unreachable
+        pub async fn read_conn(&self, filename: &str) -> Result<VideoConnection> {
+            let owner = self.ring.owner(filename);
+            let mut error = match self.pooled(owner).await {
+                Ok(conn) => return Ok(conn),
+                Err(e) => e,
+            };
+            for replica in self.replicas.get(owner).into_iter().flatten() {
+                match self.pooled(replica).await {
+                    Ok(conn) => {
+                        tracing::warn!(node = %owner, %replica, error = %error, "video service is unreachable, reading from replica");
+                        return Ok(conn);
+                    },
+                    Err(e) => error = e,
+                }
+            }
+            Err(error)
+        }
+
+        /// start downloading a video, returns its MIME type if video service knows it
+        /// and the connection to read the content from, unavailable nodes are retried
+        pub async fn download(&self, filename: &str, request_id: &str) -> Result<(Option<String>, VideoConnection)> {
+            self.retry(|| async move {
+                let mut conn = self.read_conn(filename).await?.with_request_id(request_id);
+                match conn.start_receiving(filename).await {
+                    Ok(mime_type) => Ok((mime_type, conn)),
+                    Err(e) => {
+                        self.release(conn);
+                        Err(e)
+                    },
+                }
+            }).await
+        }
+
+        /// return connection to the pool when its request and content were read to the end,
+        /// otherwise it is closed
+        pub fn release(&self, conn: VideoConnection) {
+            if conn.keep_alive && !conn.busy && conn.pending.is_empty() {
+                self.pool.put(conn);
+            }
+        }
+
+        // idle connection to a node or a new one in keep-alive mode
+        async fn pooled(&self, addr: &NodeAddr) -> Result<VideoConnection> {
+            self.breaker.check(addr)?;
+            if let Some(conn) = self.pool.take(addr) {
+                return Ok(conn);
+            }
+            let mut conn = self.connect(addr).await?;
+            if self.pool.max_idle == 0 {
+                return Ok(conn);
+            }
+            match conn.keep_alive().await {
+                Ok(()) => Ok(conn),
+                // node without keep-alive mode closes the connection after refusing it
+                Err(e) => {
+                    tracing::debug!(node = %addr, error = %e, "keep-alive is refused");
+                    self.connect(addr).await
+                },
+            }
+        }
+
+        // run an idempotent request again when video service is unavailable,
+        // nodes with an open circuit fail at once
+        async fn retry<T, F, Fut>(&self, mut request: F) -> Result<T>
+        where
+            F: FnMut() -> Fut,
+            Fut: Future<Output = Result<T>>,
+        {
+            let (mut attempt, mut delay) = (0, RETRY_DELAY);
+            loop {
+                match request().await {
+                    Err(e) if attempt < self.retries && is_retriable(e.as_ref()) => {
+                        attempt += 1;
+                        tracing::warn!(attempt, error = %e, "video service is unavailable, retrying");
+                        tokio::time::delay_for(delay).await;
+                        delay *= 2;
+                    },
+                    result => return result,
+                }
+            }
+        }
+
+        // create new socket connection to a node unless its circuit is open
+        async fn conn_to(&self, addr: &NodeAddr) -> Result<VideoConnection> {
+            self.breaker.check(addr)?;
+            self.connect(addr).await
+        }
+
+        // open connection to a node
+        async fn connect(&self, addr: &NodeAddr) -> Result<VideoConnection> {
+            // TLS handshake counts into the time of connecting
+            let connect = net::Stream::connect(addr, self.tls.as_ref());
+            let stream = timed(&self.breaker, addr, self.timeouts.connect, "connecting to", connect).await?;
             let framed = BytesCodec::new().framed(stream);
             // split framed stream into write/read parts
             let (sink, stream) = framed.split();
-            // 2^24 = 16777216
-            Ok(VideoConnection{ stream, sink, buffer: BytesMut::with_capacity(16777216) })
+            let mut conn = VideoConnection{
+                addr: addr.clone(),
+                stream,
+                sink,
+                // buffer grows with the first upload, downloads never need it
+                buffer: BytesMut::new(),
+                flush_at: self.buffer_size / 2,
+                keep_alive: false,
+                pending: BytesMut::new(),
+                remaining: None,
+                busy: false,
+                timeouts: self.timeouts,
+                breaker: self.breaker.clone(),
+                callback: self.callback.clone(),
+                request_id: None,
+            };
+            if let Some((key, secret)) = &self.auth {
+                conn.authenticate(key, secret).await?;
+            }
+            Ok(conn)
+        }
+
+        /// check that every node answers
+        pub async fn ping(&self) -> Result<()> {
+            let results = future::join_all(self.ring.nodes().iter().map(|addr| self.ping_node(addr))).await;
+            let failed = self.ring.nodes().iter()
+                .zip(results)
+                .filter_map(|(addr, result)| result.err().map(|e| format!("{}: {}", addr, e)))
+                .collect::<Vec<String>>();
+            if !failed.is_empty() {
+                Err(failed.join("; "))?
+            }
+            Ok(())
+        }
+
+        // check that a single node answers
+        async fn ping_node(&self, addr: &NodeAddr) -> Result<()> {
+            match self.query(addr, "PING".to_string()).await? {
+                Some(ref pong) if pong == "PONG" => Ok(()),
+                _ => Err("Unexpected response to PING")?,
+            }
+        }
+
+        /// receive events about all videos of all nodes
+        /// stream ends when any node closes the connection
+        pub async fn subscribe(&self) -> Result<EventStream> {
+            let mut streams = Vec::new();
+            for addr in self.ring.nodes() {
+                streams.push(self.subscribe_node(addr).await?);
+            }
+            // merged stream would go on without a node that is gone, so it ends with the first one,
+            // `None` marks the end of a node
+            let events = stream::select_all(streams.into_iter().map(|events| {
+                events.map(Some).chain(stream::iter(vec![None]))
+            }))
+            .take_while(|event| future::ready(event.is_some()))
+            .filter_map(future::ready);
+            Ok(Box::pin(events))
+        }
+
+        /// move every video to the node that owns it, e.g. after nodes were added or removed
+        /// `retired` are nodes that are not in the ring anymore but still have videos
+        pub async fn rebalance(&self, retired: &[NodeAddr]) -> Result<Rebalance> {
+            // all nodes are listed before anything moves, so moved videos are not counted twice
+            let mut listed = Vec::new();
+            for source in self.ring.nodes().iter().chain(retired) {
+                let names: Vec<String> = serde_json::from_str(
+                    &self.query(source, "LIST".to_string()).await?.unwrap_or_default()
+                )?;
+                listed.push((source, names));
+            }
+            let mut report = Rebalance::default();
+            for (source, names) in listed {
+                for name in names {
+                    let owner = self.ring.owner(&name);
+                    if owner == source {
+                        report.kept += 1;
+                        continue;
+                    }
+                    match self.move_video(&name, source, owner).await {
+                        Ok(()) => report.moved.push((name, source.clone(), owner.clone())),
+                        Err(e) => report.failed.push((name, e.to_string())),
+                    }
+                }
+            }
+            Ok(report)
+        }
+
+        // copy video to its owner, check the copy and delete the original
+        async fn move_video(&self, filename: &str, from: &NodeAddr, to: &NodeAddr) -> Result<()> {
+            let size = self.size(from, filename).await?;
+            match self.size(to, filename).await {
+                // copy is left from a previous interrupted run
+                Ok(copied) if copied == size => {},
+                _ => {
+                    let mut source = self.conn_to(from).await?;
+                    source.start_receiving(filename).await?;
+                    let mut target = self.conn_to(to).await?;
+                    target.send_command([b"PUT ", filename.as_bytes()].concat()).await?;
+                    target.get_response().await?;
+                    while let Some(chunk) = source.read_next().await {
+                        target.buffered_send(chunk?).await?;
+                    }
+                    target.finish_uploading().await?;
+                    let copied = self.size(to, filename).await?;
+                    if copied != size {
+                        Err(format!("copy has {} bytes instead of {}", copied, size))?
+                    }
+                },
+            }
+            // only the moved copy is deleted, peers of `from` could hold the copy of the new owner
+            self.command(from, format!("DELETE {} replica", filename)).await?;
+            Ok(())
+        }
+
+        // size of a stored video on a node
+        async fn size(&self, addr: &NodeAddr, filename: &str) -> Result<u64> {
+            let info: ObjectInfo = serde_json::from_str(
+                &self.query(addr, format!("STAT {}", filename)).await?.unwrap_or_default()
+            )?;
+            Ok(info.size.ok_or("video is not stored")?)
+        }
+
+        // send command that only reads and can be retried
+        async fn query(&self, addr: &NodeAddr, cmd: String) -> Result<Option<String>> {
+            self.retry(|| self.command(addr, cmd.clone())).await
+        }
+
+        // send command that is answered with a single response
+        async fn command(&self, addr: &NodeAddr, cmd: String) -> Result<Option<String>> {
+            let mut conn = self.pooled(addr).await?;
+            conn.send_command(cmd.into_bytes()).await?;
+            let response = conn.get_response().await;
+            self.release(conn);
+            response
+        }
+
+        // open long-lived connection and receive events about videos of a node
+        async fn subscribe_node(&self, addr: &NodeAddr) -> Result<EventStream> {
+            // same TLS, authentication and timeouts as every other connection
+            let mut conn = self.conn_to(addr).await?;
+            conn.send_command(b"SUBSCRIBE".to_vec()).await?;
+            conn.get_response().await?;
+
+            // every event is a line `EVENT <json>`, lines could be split between chunks
+            let events = stream::unfold((conn, BytesMut::new()), |(mut conn, mut buffer)| async move {
+                loop {
+                    if let Some(end) = buffer.iter().position(|b| *b == b'\n') {
+                        let line = buffer.split_to(end + 1);
+                        let event = std::str::from_utf8(&line[..end])
+                            .map_err(|e| e.into())
+                            .and_then(Event::parse);
+                        return Some((event, (conn, buffer)));
+                    }
+                    match conn.stream.next().await {
+                        Some(Ok(bytes)) => buffer.extend_from_slice(&bytes),
+                        Some(Err(e)) => return Some((Err(e.into()), (conn, BytesMut::new()))),
+                        None => return None,
+                    }
+                }
+            });
+            Ok(Box::pin(events))
+        }
+    }
+
+    impl Event {
+        fn parse(input: &str) -> Result<Event> {
+            match input.strip_prefix("EVENT ") {
+                Some(json) => Ok(serde_json::from_str(json)?),
+                None => Err(format!("unexpected line in events: {}", input).into()),
+            }
+        }
+
+        /// name of the video the event is about
+        pub fn filename(&self) -> &str {
+            match self {
+                Event::UploadReceived { filename, .. }
+                | Event::Queued { filename }
+                | Event::Progress { filename, .. }
+                | Event::Done { filename, .. }
+                | Event::Failed { filename, .. }
+                | Event::Rejected { filename, .. }
+                | Event::Deleted { filename } => filename,
+            }
         }
     }
 
     pub struct VideoConnection {
+        // node on the other side
+        addr: NodeAddr,
         stream: ReadStream,
         sink: WriteStream,
         buffer: BytesMut,
+        // buffer is sent when it is half full
+        flush_at: usize,
+        callback: Option<String>,
+        request_id: Option<String>,
+        // every response is a line and content of `GET` has a known length
+        keep_alive: bool,
+        // bytes that arrived after the last response line
+        pending: BytesMut,
+        // bytes of `GET` content that are not read yet in keep-alive mode
+        remaining: Option<u64>,
+        // command was sent and its response or content is not read to the end
+        busy: bool,
+        timeouts: Timeouts,
+        // failures of the connection count against the node
+        breaker: Breaker,
+    }
+
+    impl Breaker {
+        fn new(threshold: u32, cooldown: Duration) -> Breaker {
+            Breaker { nodes: Arc::default(), threshold: threshold.max(1), cooldown }
+        }
+
+        // fail at once while the circuit of the node is open,
+        // after the cooldown a single request is let through to check the node
+        fn check(&self, addr: &NodeAddr) -> Result<()> {
+            let mut nodes = self.nodes.lock().unwrap();
+            let node = nodes.entry(addr.clone()).or_default();
+            let now = Instant::now();
+            match node.retry_at {
+                Some(at) if now < at => {
+                    let secs = (at - now).as_secs() + 1;
+                    Err(Unavailable {
+                        msg: format!("video service {} is unhealthy, it is tried again in {} seconds", addr, secs),
+                        retry_after: Some(secs),
+                    })?
+                },
+                Some(_) => {
+                    node.retry_at = Some(now + self.cooldown);
+                    Ok(())
+                },
+                None => Ok(()),
+            }
+        }
+
+        fn success(&self, addr: &NodeAddr) {
+            if let Some(NodeHealth { retry_at: Some(_), .. }) = self.nodes.lock().unwrap().insert(addr.clone(), NodeHealth::default()) {
+                tracing::info!(node = %addr, "video service is healthy again");
+            }
+        }
+
+        fn failure(&self, addr: &NodeAddr) {
+            let mut nodes = self.nodes.lock().unwrap();
+            let node = nodes.entry(addr.clone()).or_default();
+            node.failures += 1;
+            if node.failures >= self.threshold {
+                if node.retry_at.is_none() {
+                    tracing::warn!(node = %addr, failures = node.failures, cooldown = ?self.cooldown, "video service is unhealthy, failing fast");
+                }
+                node.retry_at = Some(Instant::now() + self.cooldown);
+            }
+        }
+    }
+
+    // wait at most `limit` for an operation on the connection to a node,
+    // errors and timeouts count against the node
+    async fn timed<T, E: fmt::Display>(
+        breaker: &Breaker,
+        addr: &NodeAddr,
+        limit: Duration,
+        what: &str,
+        operation: impl Future<Output = std::result::Result<T, E>>,
+    ) -> Result<T> {
+        let msg = match tokio::time::timeout(limit, operation).await {
+            Ok(Ok(value)) => return Ok(value),
+            Ok(Err(e)) => format!("error {} video service {}: {}", what, addr, e),
+            Err(_) => format!("timeout {} video service {} after {} seconds", what, addr, limit.as_secs()),
+        };
+        breaker.failure(addr);
+        Err(Unavailable { msg, retry_after: None })?
+    }
+
+    /// MIME type of a video by the extension of its filename, the same video service sends
+    pub fn mime_type(filename: &str) -> &'static str {
+        let ext = filename.rsplit('.').next().unwrap_or_default().to_ascii_lowercase();
+        match ext.as_str() {
+            "mp4" | "m4v" => "video/mp4",
+            "mov" => "video/quicktime",
+            "mkv" => "video/x-matroska",
+            "webm" => "video/webm",
+            "avi" => "video/x-msvideo",
+            "ts" | "m2ts" => "video/mp2t",
+            "mpg" | "mpeg" => "video/mpeg",
+            "flv" => "video/x-flv",
+            "ogv" | "ogg" => "video/ogg",
+            _ => "application/octet-stream",
+        }
+    }
+
+    // node could answer the next attempt, the circuit was not open yet
+    fn is_retriable(e: &(dyn std::error::Error + Send + Sync + 'static)) -> bool {
+        matches!(e.downcast_ref::<Unavailable>(), Some(Unavailable { retry_after: None, .. }))
+    }
+
+    impl Pool {
+        fn new(max_idle: usize, idle_timeout: Duration) -> Pool {
+            Pool { idle: Mutex::new(HashMap::new()), max_idle, idle_timeout }
+        }
+
+        // idle connection to a node that is still open
+        fn take(&self, addr: &NodeAddr) -> Option<VideoConnection> {
+            let mut idle = self.idle.lock().unwrap();
+            let conns = idle.get_mut(addr)?;
+            while let Some((mut conn, since)) = conns.pop() {
+                // node could close the connection while it was idle
+                if since.elapsed() < self.idle_timeout && conn.is_open() {
+                    return Some(conn);
+                }
+            }
+            None
+        }
+
+        // keep connection for the next request, connections over the limit are closed
+        fn put(&self, mut conn: VideoConnection) {
+            conn.request_id = None;
+            let mut idle = self.idle.lock().unwrap();
+            let conns = idle.entry(conn.addr.clone()).or_default();
+            conns.retain(|(_, since)| since.elapsed() < self.idle_timeout);
+            if conns.len() < self.max_idle {
+                conns.push((conn, Instant::now()));
+            }
+        }
     }
 
     impl VideoConnection {
+        /// send `id` in front of commands, video service logs it with every line of the request
+        pub fn with_request_id(mut self, id: &str) -> VideoConnection {
+            self.request_id = Some(id.to_string());
+            self
+        }
+
+        // send command with request ID in front of it: `ID <request id> <command>`
+        async fn send_command(&mut self, cmd: Vec<u8>) -> Result<()> {
+            let mut cmd = match self.request_id.as_ref() {
+                Some(id) => [b"ID ", id.as_bytes(), b" ", &cmd].concat(),
+                None => cmd,
+            };
+            if self.keep_alive {
+                cmd.push(b'\n');
+            }
+            self.busy = true;
+            timed(&self.breaker, &self.addr, self.timeouts.idle, "sending to", self.sink.send(Bytes::from(cmd))).await
+        }
+
+        // answer the challenge of the node with the signature of our key
+        async fn authenticate(&mut self, key: &str, secret: &str) -> Result<()> {
+            self.send_command(format!("AUTH {}", key).into_bytes()).await?;
+            let challenge = self.get_response().await?.ok_or("video service sent no challenge")?;
+            let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts keys of any size");
+            mac.update(format!("{} {}", key, challenge).as_bytes());
+            self.send_command(format!("PROOF {}", hex::encode(mac.finalize().into_bytes())).into_bytes()).await?;
+            match self.get_response().await {
+                Ok(_) => Ok(()),
+                Err(e) => Err(Unauthorized { node: self.addr.clone(), reason: e.to_string() })?,
+            }
+        }
+
+        // switch the connection into keep-alive mode, so it serves many requests
+        async fn keep_alive(&mut self) -> Result<()> {
+            self.keep_alive = true;
+            self.send_command(b"KEEPALIVE".to_vec()).await?;
+            self.get_response().await.map(|_| ())
+        }
+
+        // `false` when the node closed the connection or sent something nobody asked for
+        fn is_open(&mut self) -> bool {
+            self.stream.next().now_or_never().is_none()
+        }
+
         /// start uploading and send a filename of video file to remote video service
         pub async fn start_uploading(&mut self, filename: &str) -> Result<()> {
-            let cmd = [b"UPLOAD ", filename.as_bytes()].concat();
-            self.sink.send(Bytes::from(cmd)).await?;
+            let mut cmd = [b"UPLOAD ", filename.as_bytes()].concat();
+            if let Some(callback) = self.callback.as_ref() {
+                cmd.extend_from_slice(format!(" callback={}", callback).as_bytes());
+            }
+            self.send_command(cmd).await?;
             // get response from remote service
-            self.get_response().await
+            self.get_response().await.map(|_| ())
         }
 
         /// send a chunk of data to remote video service using buffer
         pub async fn buffered_send(&mut self, bytes: Bytes) -> Result<()> {
             self.buffer.extend_from_slice(&bytes);
-            // 2^23 = 8388608
-            if self.buffer.len() >= 8388608 {
-                self.sink.send(Bytes::copy_from_slice(&self.buffer)).await?;
-                self.buffer.clear();
+            if self.buffer.len() >= self.flush_at {
+                self.flush().await?;
             }
             Ok(())
         }
 
         /// flush the rest of bytes in buffer into stream
         pub async fn flush(&mut self) -> Result<()> {
-            self.sink.send(Bytes::copy_from_slice(&self.buffer)).await?;
+            let chunk = Bytes::copy_from_slice(&self.buffer);
+            timed(&self.breaker, &self.addr, self.timeouts.idle, "sending to", self.sink.send(chunk)).await?;
             self.buffer.clear();
             Ok(())
         }
 
+        /// flush the rest of bytes, close writing half of the connection
+        /// and wait until remote video service confirms the upload
+        /// upload could be refused at this point, e.g. when it is too large or it is not a video
+        /// returns final filename, video service sets its extension by the content of the file
+        pub async fn finish_uploading(&mut self) -> Result<Option<String>> {
+            // remote service could stop reading already, its response explains why
+            let sent = match self.flush().await {
+                Ok(()) => timed(&self.breaker, &self.addr, self.timeouts.idle, "sending to", self.sink.close()).await,
+                Err(e) => Err(e),
+            };
+            let filename = self.get_response().await?;
+            sent.map(|_| filename)
+        }
+
         /// start receiving a videofile
-        pub async fn start_receiving(&mut self, filename: &str) -> Result<()> {
+        /// returns MIME type of the video if video service knows it
+        pub async fn start_receiving(&mut self, filename: &str) -> Result<Option<String>> {
             let cmd = [b"GET ", filename.as_bytes()].concat();
-            self.sink.send(Bytes::from(cmd)).await?;
+            self.send_command(cmd).await?;
+            if !self.keep_alive {
+                return self.get_legacy_response().await.map(Some);
+            }
             // get response from remote service
-            self.get_response().await
+            let value = self.get_response().await?;
+            // `<MIME type> <length>` in keep-alive mode
+            let (mime_type, length) = value.as_deref()
+                .and_then(|value| value.rsplit_once(' '))
+                .and_then(|(mime_type, length)| Some((mime_type.to_string(), length.parse::<u64>().ok()?)))
+                .ok_or("video service did not send length of the video")?;
+            self.remaining = Some(length);
+            self.busy = length > 0;
+            Ok(Some(mime_type))
+        }
+
+        // response to `GET` without keep-alive is `OK <MIME type>` and a new line followed by the content,
+        // errors have no new line, content that came in the same chunk is kept for `read_next`
+        async fn get_legacy_response(&mut self) -> Result<String> {
+            let mut frame = match self.next_chunk(self.timeouts.read).await? {
+                Some(frame) => frame,
+                None => Err("There is no response from video service")?,
+            };
+            if frame.starts_with(b"ERROR") {
+                let line = String::from_utf8_lossy(&frame).into_owned();
+                return match Response::parse(&line)? {
+                    Response::Error { msg } => Err(msg)?,
+                    _ => Err(format!("unexpected response: {}", line))?,
+                };
+            }
+            let end = loop {
+                if let Some(end) = frame.iter().position(|b| *b == b'\n') {
+                    break end;
+                }
+                if frame.len() > MAX_GET_LINE {
+                    Err("response to GET has no end of line")?
+                }
+                match self.next_chunk(self.timeouts.read).await? {
+                    Some(bytes) => frame.extend_from_slice(&bytes),
+                    None => Err("There is no response from video service")?,
+                }
+            };
+            self.pending = frame.split_off(end + 1);
+            let line = String::from_utf8_lossy(&frame[..end]).into_owned();
+            match Response::parse(&line)? {
+                Response::Ok { value: Some(mime_type) } => {
+                    self.busy = true;
+                    Ok(mime_type)
+                },
+                _ => Err(format!("unexpected response: {}", line))?,
+            }
         }
 
         /// read incomming bytes from the stream
         pub async fn read_next(&mut self) -> Option<std::result::Result<Bytes, std::io::Error>> {
-            if let Some(bytes) = self.stream.next().await {
+            if self.keep_alive {
+                return self.read_content().await;
+            }
+            if !self.pending.is_empty() {
+                return Some(Ok(self.pending.split().freeze()));
+            }
+            match self.next_chunk(self.timeouts.idle).await {
                 // convert BytesMut to Bytes to satisfy HTTP Streaming trait
-                Some(Ok(bytes.unwrap().freeze()))
+                Ok(Some(bytes)) => Some(Ok(bytes.freeze())),
+                Ok(None) => None,
+                Err(e) => Some(Err(io::Error::other(e.to_string()))),
+            }
+        }
+
+        // read content of `GET` up to its length, the connection stays open for the next request
+        async fn read_content(&mut self) -> Option<std::result::Result<Bytes, std::io::Error>> {
+            let remaining = self.remaining.unwrap_or(0);
+            if remaining == 0 {
+                return None;
+            }
+            let mut bytes = if self.pending.is_empty() {
+                match self.next_chunk(self.timeouts.idle).await {
+                    Ok(Some(bytes)) => bytes,
+                    Ok(None) => return Some(Err(io::Error::new(io::ErrorKind::UnexpectedEof, "video service closed the connection"))),
+                    Err(e) => return Some(Err(io::Error::other(e.to_string()))),
+                }
             } else {
-                None
+                self.pending.split()
+            };
+            if bytes.len() as u64 > remaining {
+                // bytes after the content belong to nothing we asked for, the connection is not reused
+                self.pending = bytes.split_off(remaining as usize);
             }
+            let remaining = remaining - bytes.len() as u64;
+            self.remaining = Some(remaining);
+            self.busy = remaining > 0;
+            Some(Ok(bytes.freeze()))
         }
 
         /// get response to our sended command
-        /// possible response is OK and ERROR with message why its happend
-        async fn get_response(&mut self) -> Result<()> {
+        /// possible response is OK with optional value and ERROR with message why its happend
+        async fn get_response(&mut self) -> Result<Option<String>> {
             let response_details = self.get_response_details().await?;
             let response_line = std::str::from_utf8(&response_details)?;
 
             let mut response = Response::None;
-            match Response::parse(&response_line) {
+            match Response::parse(response_line) {
                 Ok(req) => { response = req; },
                 Err(e) => { 
-                    println!("error parsing request; error = {:?}", e);
+                    tracing::warn!(error = %e, "error parsing response of video service");
                 },
             };
 
+            if !matches!(response, Response::None) {
+                self.breaker.success(&self.addr);
+            }
             match response {
-                Response::Ok => {
-                    Ok(())
+                Response::Ok {value} => {
+                    self.busy = false;
+                    Ok(value)
                 },
                 Response::Error {msg} => {
-                    Err(format!("{}", msg))?
+                    self.busy = false;
+                    Err(msg)?
                 },
-                Response::None => {unimplemented!()}
+                Response::None => {
+                    Err("Unexpected response from video service")?
+                }
             }
         }
 
         /// helper to read response bytes from stream
         async fn get_response_details(&mut self) -> Result<BytesMut> {
-            if let Some(result) = self.stream.next().await {
-                match result {
-                    Ok(bytes) => {
-                        Ok(bytes)
-                    },
-                    Err(e) => {
-                        Err(format!("error on decoding from socket; error = {:?}", e))?
-                    },
+            if self.keep_alive {
+                return self.read_line().await;
+            }
+            match self.next_chunk(self.timeouts.read).await? {
+                Some(bytes) => Ok(bytes),
+                None => Err("There is no response from video service")?,
+            }
+        }
+
+        // response line without its new line, bytes after it are kept for the content
+        async fn read_line(&mut self) -> Result<BytesMut> {
+            loop {
+                if let Some(end) = self.pending.iter().position(|b| *b == b'\n') {
+                    let mut line = self.pending.split_to(end + 1);
+                    line.truncate(end);
+                    return Ok(line);
+                }
+                match self.next_chunk(self.timeouts.read).await? {
+                    Some(bytes) => self.pending.extend_from_slice(&bytes),
+                    None => Err("There is no response from video service")?,
                 }
-            } else {
-                Err(format!("There is no response from video service"))?
             }
         }
+
+        // next chunk of bytes from the node, `None` when it closed the connection
+        async fn next_chunk(&mut self, limit: Duration) -> Result<Option<BytesMut>> {
+            let next = self.stream.next().map(|chunk| chunk.transpose());
+            timed(&self.breaker, &self.addr, limit, "reading from", next).await
+        }
        
     }
 
 
     /// Possible response video service could response with
     enum Response {
-        Ok,
+        Ok { value: Option<String> },
         Error { msg: String },
         None,
     }
@@ -144,7 +1300,12 @@ pub mod video_client{
             let mut parts = input.splitn(2, " ");
             match parts.next() {
                 Some("OK") => {
-                    Ok(Response::Ok)
+                    // OK could be followed by a value, e.g. final filename
+                    let value = parts.next()
+                        .map(|value| value.trim())
+                        .filter(|value| !value.is_empty())
+                        .map(|value| value.to_string());
+                    Ok(Response::Ok { value })
                 }
                 Some("ERROR") => {
                     let msg = match parts.next() {
@@ -161,6 +1322,66 @@ pub mod video_client{
         }
     }
 
+    #[cfg(test)]
+    mod tests {
+        use {
+            super::*,
+            tokio::{io::{AsyncReadExt, AsyncWriteExt}, net::TcpListener},
+        };
+
+        // node without keep-alive that answers `GET` with `chunks`, written one by one
+        async fn node(chunks: &'static [&'static [u8]]) -> NodeAddr {
+            let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let addr = listener.local_addr().unwrap().to_string();
+            tokio::spawn(async move {
+                let (mut socket, _) = listener.accept().await.unwrap();
+                let mut buf = [0u8; 1024];
+                let _ = socket.read(&mut buf).await;
+                for chunk in chunks {
+                    socket.write_all(chunk).await.unwrap();
+                    socket.flush().await.unwrap();
+                    tokio::time::delay_for(Duration::from_millis(20)).await;
+                }
+            });
+            addr.parse().unwrap()
+        }
+
+        async fn download(chunks: &'static [&'static [u8]]) -> Result<(Option<String>, Vec<u8>)> {
+            let client = VideoClient::new(node(chunks).await).with_pool(0, Duration::from_secs(1)).with_retries(0);
+            let (mime_type, mut conn) = client.download("a.mp4", "test").await?;
+            let mut content = Vec::new();
+            while let Some(chunk) = conn.read_next().await {
+                content.extend_from_slice(&chunk?);
+            }
+            Ok((mime_type, content))
+        }
+
+        #[tokio::test]
+        async fn splits_get_response_at_end_of_line() {
+            let (mime_type, content) = download(&[b"OK video/mp4\n\nhead", b"tail"]).await.unwrap();
+            assert_eq!(mime_type.as_deref(), Some("video/mp4"));
+            assert_eq!(content, b"\nheadtail");
+            // the line could come in pieces
+            let (mime_type, content) = download(&[b"OK video/", b"webm\n", b"body"]).await.unwrap();
+            assert_eq!(mime_type.as_deref(), Some("video/webm"));
+            assert_eq!(content, b"body");
+        }
+
+        #[tokio::test]
+        async fn refused_downloads_fail() {
+            assert_eq!(download(&[b"ERROR file does not exist"]).await.unwrap_err().to_string(), "file does not exist");
+            static LONG: [&[u8]; 1] = [&[b'O'; 2048]];
+            assert_eq!(download(&LONG).await.unwrap_err().to_string(), "response to GET has no end of line");
+        }
+
+        #[test]
+        fn mime_type_follows_extension() {
+            assert_eq!(mime_type("a.MP4"), "video/mp4");
+            assert_eq!(mime_type("a.ts"), "video/mp2t");
+            assert_eq!(mime_type("a.ogv"), "video/ogg");
+            assert_eq!(mime_type("a"), "application/octet-stream");
+        }
+    }
 }
 
 pub mod video_service {
@@ -4,50 +4,103 @@ extern crate diesel;
 extern crate serde_derive;
 
 use {
-    actix_web::{middleware, web, http, App, HttpServer},
-    std::{env, net::SocketAddr},
-    web_service::video_client::VideoClient,
+    actix_web::{middleware, web, http, dev::Service, App, HttpMessage, HttpServer},
+    futures::FutureExt,
+    std::{env, io, time::{Duration, Instant}},
+    web_service::{net::NodeAddr, tls::Connector, video_client::{Timeouts, VideoClient}},
     tera::Tera,
-    env_logger,
+    tracing::{info, info_span, warn, Instrument},
     dotenv,
 };
 
 mod api;
+mod config;
+mod events;
+mod health;
+mod metrics;
 mod models;
 mod schema;
+mod shutdown;
 mod db;
+mod trace;
 
 #[actix_rt::main]
 async fn main() -> std::io::Result<()> {
-    // init logging
-    std::env::set_var("RUST_LOG", "actix_web=info");
-    env_logger::init();
     // read env variables from `.env`
     dotenv::dotenv().ok();
+    // settings come from defaults, a TOML file, env variables and command line flags,
+    // addresses of the server and of video service nodes can still be passed as arguments
+    let args = config::Args::parse(env::args().skip(1)).map_err(invalid_config)?;
+    let config = config::Config::load(&args).map_err(invalid_config)?;
+    if args.print_config {
+        print!("{}", config.to_toml().map_err(invalid_config)?);
+        return Ok(());
+    }
+    // init logging
+    trace::init();
+
+    // nodes of video-service
+    let video_nodes = config.video_nodes();
 
-    let addr = env::args().nth(1).unwrap_or("localhost:8090".to_string());
-    println!("Server is running on http://{}", addr);
+    // create new client, video service reports outcome of processing to `callback_url`
+    let video_client = VideoClient::cluster(video_nodes)
+        .with_buffer_size(config.client_buffer_size)
+        .with_pool(config.pool_size, Duration::from_secs(config.pool_idle_timeout))
+        .with_timeouts(Timeouts {
+            connect: Duration::from_secs(config.connect_timeout),
+            read: Duration::from_secs(config.read_timeout),
+            idle: Duration::from_secs(config.idle_timeout),
+        })
+        .with_retries(config.retries)
+        .with_breaker(config.breaker_threshold, Duration::from_secs(config.breaker_cooldown))
+        .with_replicas(config.replicas());
+    let video_client = match config.tls() {
+        Some((cert, key, ca)) => {
+            let connector = Connector::new(cert, key, ca, &config.tls_server_name)
+                .map_err(|e| invalid_config(e.to_string()))?;
+            video_client.with_tls(connector)
+        },
+        None => video_client,
+    };
+    let video_client = if config.auth_key.is_empty() {
+        video_client
+    } else {
+        video_client.with_auth(&config.auth_key, &config.auth_secret)
+    };
+    if args.rebalance {
+        return rebalance(&video_client, &config.retired_nodes()).await;
+    }
 
-    // address of video-service
-    let remote_adr: SocketAddr = env::args()
-        .nth(2)
-        .unwrap_or("127.0.0.1:8091".into())
-        .parse()
-        .expect("Remote adress structure is not valid");
+    let addr = config.addr.clone();
+    info!("Server is running on http://{}", addr);
 
-    // create new client
-    let video_client = web::Data::new(VideoClient::new(remote_adr));
+    let video_client = match &config.callback_url {
+        Some(url) => video_client.with_callback(url),
+        None => video_client,
+    };
+    let video_client = web::Data::new(video_client);
+    // callbacks are signed with this secret
+    let callback_secret = web::Data::new(api::CallbackSecret(config.callback_secret.clone()));
 
     // connect to database and create pool of connections
-    let database_url = std::env::var("DATABASE_URL").expect("DATABASE_URL");
-    let pool = db::init_pool(&database_url).expect("Failed to create pool");
+    let pool = db::init_pool(&config.database_url).expect("Failed to create pool");
+
+    // receive events from video service, save outcome of processing and pass events to browsers
+    let broadcaster = web::Data::new(events::Broadcaster::new());
+    // counters of requests and proxied bytes
+    let metrics = web::Data::new(metrics::Metrics::new());
+    // uploads and downloads the server waits for on shutdown
+    let in_flight = web::Data::new(shutdown::InFlight::new());
+    let shutdown_timeout = Duration::from_secs(config.shutdown_timeout);
+    actix_rt::spawn(events::watch(video_client.clone(), pool.clone(), broadcaster.clone()));
 
     // create new HTTPServer with dependency injections and custom wrappers
-    HttpServer::new(move || {
+    let in_flight_app = in_flight.clone();
+    let templates = format!("{}/**/*", config.templates_dir.display());
+    let server = HttpServer::new(move || {
 
         // create template engine and register folder where to look for defined templates
-        let tera =
-        Tera::new(concat!(env!("CARGO_MANIFEST_DIR"), "/templates/**/*")).unwrap();
+        let tera = Tera::new(&templates).unwrap();
 
         // all errors from web server will be wrapped by those handlers
         let error_handlers = middleware::errhandlers::ErrorHandlers::new()
@@ -58,28 +111,116 @@ async fn main() -> std::io::Result<()> {
             .handler(http::StatusCode::BAD_REQUEST, api::bad_request)
             .handler(http::StatusCode::NOT_FOUND, api::not_found);
 
+        // count every request with its route, status and latency
+        let request_metrics = metrics.clone();
+
         App::new()
             // inject template engine
             .data(tera)
             // inject database pool
             .data(pool.clone())
-            .wrap(middleware::Logger::default())
+            .app_data(metrics.clone())
+            .app_data(in_flight_app.clone())
+            .wrap(middleware::Logger::new(r#"%a "%r" %s %b %T request_id=%{x-request-id}o"#))
             .wrap(error_handlers)
+            .wrap_fn(move |req, srv| {
+                let (path, method) = (req.path().to_string(), req.method().to_string());
+                let started = Instant::now();
+                let metrics = request_metrics.clone();
+                srv.call(req).map(move |res| {
+                    let status = res.as_ref().map_or(500, |res| res.status().as_u16());
+                    metrics.request(&path, &method, status, started.elapsed());
+                    res
+                })
+            })
+            // every request gets an ID that is logged and passed to video service
+            .wrap_fn(|req, srv| {
+                let request_id = trace::request_id(req.headers());
+                let span = info_span!("request", request_id = %request_id.0);
+                req.extensions_mut().insert(request_id.clone());
+                srv.call(req).map(move |res| {
+                    res.map(|mut res| {
+                        if let Ok(value) = http::HeaderValue::from_str(&request_id.0) {
+                            res.headers_mut().insert(http::HeaderName::from_static(trace::REQUEST_ID_HEADER), value);
+                        }
+                        res
+                    })
+                }).instrument(span)
+            })
             // group routes by `video` into a service
             .service(
                 web::scope("/video")
                     // dependency injection of video client
                     .app_data(video_client.clone())
+                    // video service is down, answer with a page instead of JSON
+                    .wrap(middleware::errhandlers::ErrorHandlers::new()
+                        .handler(http::StatusCode::SERVICE_UNAVAILABLE, api::service_unavailable))
                     .route("/upload", web::post().to(api::save_file))
                     .route("/", web::get().to(api::list_videos))
                     .route("/show", web::get().to(api::show_video))
-                    .route("/file/{filename}", web::get().to(api::get_file)))        
+                    .route("/file/{filename}", web::get().to(api::get_file))
+                    .service(
+                        web::resource("/events")
+                            .app_data(broadcaster.clone())
+                            .route(web::get().to(api::video_events)))
+                    .service(
+                        web::resource("/callback")
+                            .app_data(callback_secret.clone())
+                            .route(web::post().to(api::video_callback))))        
             .service(
                 web::resource("/")
                     .route(web::get().to(api::index)),   
             )
+            .route("/metrics", web::get().to(api::metrics))
+            .route("/healthz", web::get().to(api::healthz))
+            .service(
+                web::resource("/readyz")
+                    .app_data(video_client.clone())
+                    .route(web::get().to(api::readyz)))
     })
+    // signals are handled below to drain uploads and downloads first
+    .disable_signals()
     .bind(addr)?
-    .run()
-    .await
-}
\ No newline at end of file
+    .run();
+
+    // on SIGTERM stop accepting connections and let requests in progress finish
+    let srv = server.clone();
+    actix_rt::spawn(async move {
+        if let Err(e) = shutdown::signal().await {
+            warn!(error = %e, "can not listen for signals, graceful shutdown is disabled");
+            return;
+        }
+        info!(in_progress = ?in_flight.active(), timeout = ?shutdown_timeout, "shutting down");
+        srv.pause().await;
+        let interrupted = in_flight.drain(shutdown_timeout).await;
+        for what in &interrupted {
+            warn!(%what, "interrupted by shutdown");
+        }
+        srv.stop(interrupted.is_empty()).await;
+    });
+    server.await?;
+    info!("stopped");
+    Ok(())
+}
+
+// move videos to the nodes that own them after nodes were added or removed
+async fn rebalance(video_client: &VideoClient, retired: &[NodeAddr]) -> io::Result<()> {
+    let report = video_client.rebalance(retired).await
+        .map_err(|e| io::Error::other(e.to_string()))?;
+    for (filename, from, to) in &report.moved {
+        info!(video = %filename, %from, %to, "video moved");
+    }
+    for (filename, reason) in &report.failed {
+        warn!(video = %filename, %reason, "video was not moved");
+    }
+    info!(kept = report.kept, moved = report.moved.len(), failed = report.failed.len(), "rebalance finished");
+    if !report.failed.is_empty() {
+        return Err(io::Error::other("some videos were not moved, run rebalance again"));
+    }
+    Ok(())
+}
+
+// server can not start with broken settings
+fn invalid_config(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, msg)
+}
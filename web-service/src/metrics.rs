@@ -0,0 +1,118 @@
+use {
+    std::{
+        collections::BTreeMap,
+        fmt::Write,
+        sync::{Mutex, atomic::{AtomicU64, Ordering}},
+        time::Duration,
+    },
+    crate::db,
+};
+
+// upper bounds of request latency buckets in seconds
+const LATENCY_BUCKETS: [f64; 11] = [0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0];
+
+// routes with a dynamic part, their requests are counted under the pattern
+const ROUTE_PATTERNS: [(&str, &str); 1] = [("/video/file/", "/video/file/{filename}")];
+// routes without dynamic parts
+const ROUTES: [&str; 7] = ["/", "/metrics", "/video/", "/video/upload", "/video/show", "/video/events", "/video/callback"];
+
+// cumulative histogram in Prometheus sense
+#[derive(Default)]
+struct Histogram {
+    buckets: [u64; LATENCY_BUCKETS.len()],
+    count: u64,
+    sum: f64,
+}
+
+impl Histogram {
+    fn observe(&mut self, value: f64) {
+        for (bucket, bound) in self.buckets.iter_mut().zip(LATENCY_BUCKETS.iter()) {
+            if value <= *bound {
+                *bucket += 1;
+            }
+        }
+        self.count += 1;
+        self.sum += value;
+    }
+}
+
+/// Counters of the web service exposed in Prometheus text format
+#[derive(Default)]
+pub struct Metrics {
+    // by route, method and status
+    requests: Mutex<BTreeMap<(&'static str, String, u16), u64>>,
+    // by route
+    latencies: Mutex<BTreeMap<&'static str, Histogram>>,
+    bytes_uploaded: AtomicU64,
+    bytes_downloaded: AtomicU64,
+}
+
+impl Metrics {
+    pub fn new() -> Metrics {
+        Metrics::default()
+    }
+
+    /// count finished request
+    pub fn request(&self, path: &str, method: &str, status: u16, latency: Duration) {
+        let route = route_of(path);
+        *self.requests.lock().unwrap().entry((route, method.to_string(), status)).or_default() += 1;
+        self.latencies.lock().unwrap().entry(route).or_default().observe(latency.as_secs_f64());
+    }
+
+    /// bytes sent to video service
+    pub fn uploaded(&self, n: u64) {
+        self.bytes_uploaded.fetch_add(n, Ordering::Relaxed);
+    }
+
+    /// bytes received from video service and sent to the browser
+    pub fn downloaded(&self, n: u64) {
+        self.bytes_downloaded.fetch_add(n, Ordering::Relaxed);
+    }
+
+    /// render all metrics in Prometheus text format
+    pub fn render(&self, pool: &db::MysqlPool) -> String {
+        let mut out = String::new();
+        metric(&mut out, "web_requests_total", "counter", "Handled HTTP requests");
+        for ((route, method, status), count) in self.requests.lock().unwrap().iter() {
+            writeln!(out, "web_requests_total{{route=\"{}\",method=\"{}\",status=\"{}\"}} {}", route, method, status, count).ok();
+        }
+
+        metric(&mut out, "web_request_duration_seconds", "histogram", "Time spent on a HTTP request");
+        for (route, histogram) in self.latencies.lock().unwrap().iter() {
+            for (count, bound) in histogram.buckets.iter().zip(LATENCY_BUCKETS.iter()) {
+                writeln!(out, "web_request_duration_seconds_bucket{{route=\"{}\",le=\"{}\"}} {}", route, bound, count).ok();
+            }
+            writeln!(out, "web_request_duration_seconds_bucket{{route=\"{}\",le=\"+Inf\"}} {}", route, histogram.count).ok();
+            writeln!(out, "web_request_duration_seconds_sum{{route=\"{}\"}} {}", route, histogram.sum).ok();
+            writeln!(out, "web_request_duration_seconds_count{{route=\"{}\"}} {}", route, histogram.count).ok();
+        }
+
+        metric(&mut out, "web_proxied_bytes_total", "counter", "Bytes passed between browsers and video service");
+        writeln!(out, "web_proxied_bytes_total{{direction=\"upload\"}} {}", self.bytes_uploaded.load(Ordering::Relaxed)).ok();
+        writeln!(out, "web_proxied_bytes_total{{direction=\"download\"}} {}", self.bytes_downloaded.load(Ordering::Relaxed)).ok();
+
+        let state = pool.state();
+        metric(&mut out, "web_db_connections", "gauge", "Connections of the database pool");
+        writeln!(out, "web_db_connections{{state=\"idle\"}} {}", state.idle_connections).ok();
+        writeln!(out, "web_db_connections{{state=\"active\"}} {}", state.connections - state.idle_connections).ok();
+        metric(&mut out, "web_db_connections_max", "gauge", "Maximum size of the database pool");
+        writeln!(out, "web_db_connections_max {}", pool.max_size()).ok();
+        out
+    }
+}
+
+// header of a metric
+fn metric(out: &mut String, name: &str, kind: &str, help: &str) {
+    writeln!(out, "# HELP {} {}", name, help).ok();
+    writeln!(out, "# TYPE {} {}", name, kind).ok();
+}
+
+// route of the path, so filenames do not produce a new series each
+fn route_of(path: &str) -> &'static str {
+    if let Some(route) = ROUTES.iter().find(|route| **route == path) {
+        return route;
+    }
+    ROUTE_PATTERNS.iter()
+        .find(|(prefix, _)| path.starts_with(prefix))
+        .map_or("other", |(_, pattern)| pattern)
+}
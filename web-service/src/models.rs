@@ -15,6 +15,11 @@ pub struct Video {
     pub name: String,
     #[serde(with = "my_date_format")]
     pub createdat: Option<NaiveDateTime>,
+    pub status: String,
+    pub reason: Option<String>,
+    pub duration: Option<f64>,
+    pub width: Option<i32>,
+    pub height: Option<i32>,
 }
 
 mod my_date_format {
@@ -75,6 +80,17 @@ impl NewVideo {
     }
 }
 
+// outcome of processing reported by video-service
+#[derive(AsChangeset)]
+#[table_name = "videos"]
+pub struct VideoOutcome {
+    pub status: String,
+    pub reason: Option<String>,
+    pub duration: Option<f64>,
+    pub width: Option<i32>,
+    pub height: Option<i32>,
+}
+
 impl Video {
     pub fn all(conn: &MysqlConnection) -> QueryResult<Vec<Video>> {
         all_videos.order(videos::createdat.desc()).load::<Video>(conn)
@@ -96,6 +112,12 @@ impl Video {
         all_videos.find(id).get_result::<Video>(conn)
     }
 
+    pub fn update_outcome(name: &str, outcome: &VideoOutcome, conn: &MysqlConnection) -> QueryResult<usize> {
+        diesel::update(all_videos.filter(videos::name.eq(name)))
+            .set(outcome)
+            .execute(conn)
+    }
+
     pub fn delete_with_id(id: i32, conn: &MysqlConnection) -> QueryResult<usize> {
         diesel::delete(all_videos.find(id)).execute(conn)
     }
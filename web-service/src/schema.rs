@@ -3,5 +3,10 @@ table! {
         id -> Integer,
         name -> Varchar,
         createdat -> Nullable<Timestamp>,
+        status -> Varchar,
+        reason -> Nullable<Text>,
+        duration -> Nullable<Double>,
+        width -> Nullable<Integer>,
+        height -> Nullable<Integer>,
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,70 @@
+use {
+    std::{
+        collections::BTreeMap,
+        sync::{Arc, Mutex, atomic::{AtomicU64, Ordering}},
+        time::{Duration, Instant},
+    },
+    tokio::{signal::{self, unix::{self, SignalKind}}, time},
+    futures::{future, FutureExt},
+};
+
+// how often draining checks whether requests are done
+const DRAIN_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Uploads and downloads in progress, server waits for them on shutdown
+#[derive(Clone, Default)]
+pub struct InFlight {
+    // descriptions of requests in progress, e.g. `download of 1234567.mp4`
+    active: Arc<Mutex<BTreeMap<u64, String>>>,
+    next_id: Arc<AtomicU64>,
+}
+
+/// Request in progress, it is done when the guard is dropped
+pub struct Guard {
+    in_flight: InFlight,
+    id: u64,
+}
+
+impl Drop for Guard {
+    fn drop(&mut self) {
+        self.in_flight.active.lock().unwrap().remove(&self.id);
+    }
+}
+
+impl InFlight {
+    pub fn new() -> InFlight {
+        InFlight::default()
+    }
+
+    /// register request the server must wait for before exit
+    pub fn track(&self, what: String) -> Guard {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        self.active.lock().unwrap().insert(id, what);
+        Guard { in_flight: self.clone(), id }
+    }
+
+    /// requests in progress in the order they were started
+    pub fn active(&self) -> Vec<String> {
+        self.active.lock().unwrap().values().cloned().collect()
+    }
+
+    /// wait until all requests are done or `timeout` passes
+    /// returns requests that are still in progress and are going to be interrupted
+    pub async fn drain(&self, timeout: Duration) -> Vec<String> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            let active = self.active();
+            if active.is_empty() || Instant::now() >= deadline {
+                return active;
+            }
+            time::delay_for(DRAIN_INTERVAL).await;
+        }
+    }
+}
+
+/// resolves on SIGTERM or Ctrl-C
+pub async fn signal() -> std::io::Result<()> {
+    let mut terminate = unix::signal(SignalKind::terminate())?;
+    future::select(terminate.recv().boxed(), signal::ctrl_c().boxed()).await;
+    Ok(())
+}
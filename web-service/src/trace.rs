@@ -0,0 +1,58 @@
+use {
+    actix_web::{dev, http::HeaderMap, Error, FromRequest, HttpRequest},
+    futures::future::{ok, Ready},
+    rand::{thread_rng, Rng},
+    tracing_subscriber::EnvFilter,
+};
+
+/// Header with ID of the request, it is accepted from clients and returned in responses
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+// longest request ID accepted from clients
+const MAX_REQUEST_ID_LEN: usize = 64;
+
+/// ID of the HTTP request, video service logs it with every line of the same request
+#[derive(Debug, Clone)]
+pub struct RequestId(pub String);
+
+impl FromRequest for RequestId {
+    type Error = Error;
+    type Future = Ready<Result<RequestId, Error>>;
+    type Config = ();
+
+    fn from_request(req: &HttpRequest, _: &mut dev::Payload) -> Self::Future {
+        // middleware puts ID into extensions, generate one if it did not
+        let id = req.extensions().get::<RequestId>().cloned();
+        ok(id.unwrap_or_else(|| RequestId(new_request_id())))
+    }
+}
+
+/// print logs as JSON lines, one object per event with all its spans
+/// verbosity is set by `RUST_LOG`, `info` by default
+pub fn init() {
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info"));
+    tracing_subscriber::fmt()
+        .json()
+        .with_current_span(false)
+        .with_env_filter(filter)
+        .init();
+}
+
+/// take ID from `X-Request-Id` header if it is valid or generate a new one
+pub fn request_id(headers: &HeaderMap) -> RequestId {
+    let id = headers.get(REQUEST_ID_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .filter(|id| is_valid(id));
+    RequestId(id.map_or_else(new_request_id, str::to_string))
+}
+
+// 16 random hex chars
+fn new_request_id() -> String {
+    format!("{:016x}", thread_rng().gen::<u64>())
+}
+
+// IDs end up in logs and in the protocol of video service, so only short and plain ones are accepted
+fn is_valid(id: &str) -> bool {
+    !id.is_empty()
+        && id.len() <= MAX_REQUEST_ID_LEN
+        && id.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.')
+}